@@ -0,0 +1,319 @@
+//! Board resources as configured `hal::gpio::Pin`s, grouped by function.
+//!
+//! Every `new` sets up the pins the same way the examples used to do by hand,
+//! so a resource must be created only once.
+
+use hal::gpio::{self, Edge, Pin, PinMode, Pull};
+
+use crate::pins::{self, AltPin, PinId, Port};
+
+impl From<Port> for gpio::Port {
+    fn from(port: Port) -> Self {
+        match port {
+            Port::A => gpio::Port::A,
+            Port::B => gpio::Port::B,
+            Port::C => gpio::Port::C,
+        }
+    }
+}
+
+impl PinId {
+    pub fn output(self) -> Pin {
+        Pin::new(self.port.into(), self.num, PinMode::Output)
+    }
+
+    pub fn input(self) -> Pin {
+        Pin::new(self.port.into(), self.num, PinMode::Input)
+    }
+}
+
+impl AltPin {
+    pub fn alt(self) -> Pin {
+        Pin::new(self.id.port.into(), self.id.num, PinMode::Alt(self.af))
+    }
+}
+
+/// RGB led, every color is active low.
+pub struct Leds {
+    pub red: Pin,
+    pub green: Pin,
+    pub blue: Pin,
+}
+
+impl Leds {
+    /// Configures the led pins, all colors are off.
+    pub fn new() -> Self {
+        let mut leds = Self {
+            red: pins::LED_RED.output(),
+            green: pins::LED_GREEN.output(),
+            blue: pins::LED_BLUE.output(),
+        };
+
+        leds.red.set_high();
+        leds.green.set_high();
+        leds.blue.set_high();
+
+        leds
+    }
+}
+
+impl Default for Leds {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// SW1 button with the internal pull up.
+pub struct Sw1 {
+    pub pin: Pin,
+}
+
+impl Sw1 {
+    pub fn new() -> Self {
+        let mut pin = pins::SW1.input();
+        pin.pull(Pull::Up);
+
+        Self { pin }
+    }
+
+    pub fn enable_interrupt(&mut self, edge: Edge) {
+        self.pin.enable_interrupt(edge);
+    }
+
+    pub fn clear_interrupt(&self) {
+        gpio::clear_exti_interrupt(pins::SW1.exti_line());
+    }
+
+    pub fn is_pressed(&self) -> bool {
+        self.pin.is_low()
+    }
+}
+
+impl Default for Sw1 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// DRV8844 enable and reset pins.
+pub struct DriverControl {
+    pub enable: Pin,
+    pub reset: Pin,
+}
+
+impl DriverControl {
+    /// Configures the pins with the driver disabled and held in reset.
+    pub fn new() -> Self {
+        let mut ctrl = Self {
+            enable: pins::DRV_EN.output(),
+            reset: pins::DRV_RESET.output(),
+        };
+
+        ctrl.disable();
+
+        ctrl
+    }
+
+    pub fn enable(&mut self) {
+        self.reset.set_high();
+        self.enable.set_high();
+    }
+
+    pub fn disable(&mut self) {
+        self.enable.set_low();
+        self.reset.set_low();
+    }
+}
+
+impl Default for DriverControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// DRV8844 nFAULT input with the internal pull up, EXTI line 4 (`EXTI4`
+/// interrupt).
+pub struct DriverFault {
+    pub pin: Pin,
+}
+
+impl DriverFault {
+    pub fn new() -> Self {
+        let mut pin = pins::DRV_FAULT.input();
+        pin.pull(Pull::Up);
+
+        Self { pin }
+    }
+
+    /// Interrupt on the falling edge, raised when the driver shuts down.
+    pub fn enable_interrupt(&mut self) {
+        self.pin.enable_interrupt(Edge::Falling);
+    }
+
+    pub fn clear_interrupt(&self) {
+        gpio::clear_exti_interrupt(pins::DRV_FAULT.exti_line());
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.pin.is_low()
+    }
+}
+
+impl Default for DriverFault {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// DRV8844 bridge inputs on TIM2 channels.
+///
+/// On v1.0 IN3/IN4 share pins with [`Uart`], use only one of them.
+pub struct Bridge {
+    pub in1: Pin,
+    pub in2: Pin,
+    pub in3: Pin,
+    pub in4: Pin,
+}
+
+impl Bridge {
+    pub fn new() -> Self {
+        Self {
+            in1: pins::DRV_IN1.alt(),
+            in2: pins::DRV_IN2.alt(),
+            in3: pins::DRV_IN3.alt(),
+            in4: pins::DRV_IN4.alt(),
+        }
+    }
+}
+
+impl Default for Bridge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Step/dir interface, step is driven by TIM3 channel 4.
+pub struct StepDir {
+    pub step: Pin,
+    pub dir: Pin,
+}
+
+impl StepDir {
+    pub fn new() -> Self {
+        let mut dir = pins::DIR.output();
+        dir.set_low();
+
+        Self {
+            step: pins::STEP.alt(),
+            dir,
+        }
+    }
+}
+
+impl Default for StepDir {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// TMC2209 DIAG input, EXTI line 5 (`EXTI9_5` interrupt).
+pub struct Diag {
+    pub pin: Pin,
+}
+
+impl Diag {
+    pub fn new() -> Self {
+        let mut pin = pins::DIAG.input();
+        pin.pull(Pull::Dn);
+
+        Self { pin }
+    }
+
+    /// Interrupt on the rising edge, raised when the driver detects a stall.
+    pub fn enable_interrupt(&mut self) {
+        self.pin.enable_interrupt(Edge::Rising);
+    }
+
+    pub fn clear_interrupt(&self) {
+        gpio::clear_exti_interrupt(pins::DIAG.exti_line());
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.pin.is_high()
+    }
+}
+
+impl Default for Diag {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// SPI1 pins of the magnetic encoder, the CS pin is driven by software.
+pub struct EncoderSpi {
+    pub cs: Pin,
+}
+
+impl EncoderSpi {
+    /// Configures SCK/MISO/MOSI for SPI1, CS is released (high).
+    pub fn new() -> Self {
+        pins::ENC_SCK.alt();
+        pins::ENC_MISO.alt();
+        pins::ENC_MOSI.alt();
+
+        let mut cs = pins::ENC_CS.output();
+        cs.set_high();
+
+        Self { cs }
+    }
+}
+
+impl Default for EncoderSpi {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// USART3 pins.
+///
+/// On v1.0 they share pins with the [`Bridge`] IN3/IN4, use only one of them.
+pub struct Uart {
+    pub tx: Pin,
+    pub rx: Pin,
+}
+
+impl Uart {
+    pub fn new() -> Self {
+        Self {
+            tx: pins::UART_TX.alt(),
+            rx: pins::UART_RX.alt(),
+        }
+    }
+}
+
+impl Default for Uart {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// USART1 pins of the debug console.
+pub struct Console {
+    pub tx: Pin,
+    pub rx: Pin,
+}
+
+impl Console {
+    pub fn new() -> Self {
+        Self {
+            tx: pins::CONSOLE_TX.alt(),
+            rx: pins::CONSOLE_RX.alt(),
+        }
+    }
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,22 @@
+//! Board support for the [creapunk CLN17](https://creapunk.com/) board.
+//!
+//! Pin numbers differ between board revisions, select one with a cargo feature:
+//!
+//! - `v1_0` - the early v1.0 board (default)
+//! - `v2_0` - v2.0 and newer boards
+//!
+//! [`pins`] holds the plain pin tables, [`board`] turns them into configured
+//! `hal::gpio::Pin`s grouped by function.
+
+#![no_std]
+
+#[cfg(all(feature = "v1_0", feature = "v2_0"))]
+compile_error!("features `v1_0` and `v2_0` are mutually exclusive, select one board revision");
+
+#[cfg(not(any(feature = "v1_0", feature = "v2_0")))]
+compile_error!("select a board revision with the `v1_0` or `v2_0` feature");
+
+pub mod pins;
+
+#[cfg(feature = "hal")]
+pub mod board;
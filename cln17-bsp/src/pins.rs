@@ -0,0 +1,176 @@
+//! Pin tables of the CLN17 board.
+//!
+//! The tables are plain data and do not touch the hardware, so they can be
+//! used from host code as well. Alternate function numbers are taken from the
+//! stm32g431 datasheet - Table 13. Alternate function.
+
+#[cfg(feature = "v1_0")]
+mod v1_0;
+#[cfg(feature = "v1_0")]
+pub use v1_0::*;
+
+#[cfg(feature = "v2_0")]
+mod v2_0;
+#[cfg(feature = "v2_0")]
+pub use v2_0::*;
+
+/// GPIO ports used on the board.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Port {
+    A,
+    B,
+    C,
+}
+
+/// A GPIO pin, e.g. `PinId::new(Port::B, 13)` for PB13.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PinId {
+    pub port: Port,
+    pub num: u8,
+}
+
+impl PinId {
+    pub const fn new(port: Port, num: u8) -> Self {
+        Self { port, num }
+    }
+
+    /// EXTI line of the pin, line N is shared between the PN pins of all ports.
+    pub const fn exti_line(&self) -> u8 {
+        self.num
+    }
+}
+
+/// A pin driven by a peripheral through an alternate function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AltPin {
+    pub id: PinId,
+    pub af: u8,
+}
+
+impl AltPin {
+    pub const fn new(port: Port, num: u8, af: u8) -> Self {
+        Self {
+            id: PinId::new(port, num),
+            af,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // TIM2 channels on AF1 of the stm32g431 pins
+    fn tim2_channel(id: PinId) -> Option<u8> {
+        match (id.port, id.num) {
+            (Port::A, 0) | (Port::A, 5) | (Port::A, 15) => Some(1),
+            (Port::A, 1) | (Port::B, 3) => Some(2),
+            (Port::A, 2) | (Port::B, 10) => Some(3),
+            (Port::A, 3) | (Port::B, 11) => Some(4),
+            _ => None,
+        }
+    }
+
+    fn all_pins() -> [(&'static str, PinId); 22] {
+        [
+            ("LED_RED", LED_RED),
+            ("LED_GREEN", LED_GREEN),
+            ("LED_BLUE", LED_BLUE),
+            ("SW1", SW1),
+            ("DRV_EN", DRV_EN),
+            ("DRV_RESET", DRV_RESET),
+            ("DRV_FAULT", DRV_FAULT),
+            ("DRV_IN1", DRV_IN1.id),
+            ("DRV_IN2", DRV_IN2.id),
+            ("DRV_IN3", DRV_IN3.id),
+            ("DRV_IN4", DRV_IN4.id),
+            ("STEP", STEP.id),
+            ("DIR", DIR),
+            ("DIAG", DIAG),
+            ("ENC_SCK", ENC_SCK.id),
+            ("ENC_MISO", ENC_MISO.id),
+            ("ENC_MOSI", ENC_MOSI.id),
+            ("ENC_CS", ENC_CS),
+            ("UART_TX", UART_TX.id),
+            ("UART_RX", UART_RX.id),
+            ("CONSOLE_TX", CONSOLE_TX.id),
+            ("CONSOLE_RX", CONSOLE_RX.id),
+        ]
+    }
+
+    #[test]
+    fn no_pin_is_used_twice() {
+        // the only documented sharing: USART3 on the DRV_IN3/DRV_IN4 pins
+        let shared = [("DRV_IN4", "UART_TX"), ("DRV_IN3", "UART_RX")];
+
+        let pins = all_pins();
+        for (i, (name, id)) in pins.iter().enumerate() {
+            assert!(id.num < 16, "{} has no pin {}", name, id.num);
+
+            for (other, other_id) in &pins[i + 1..] {
+                if id == other_id {
+                    assert!(
+                        shared.contains(&(*name, *other)),
+                        "{} and {} are both on {:?}",
+                        name,
+                        other,
+                        id
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bridge_inputs_match_their_tim2_channels() {
+        let inputs = [DRV_IN1, DRV_IN2, DRV_IN3, DRV_IN4];
+
+        for (input, channel) in inputs.iter().zip(DRV_IN_CHANNELS) {
+            assert_eq!(input.af, 1, "TIM2 is AF1");
+            assert_eq!(tim2_channel(input.id), Some(channel), "{:?}", input.id);
+        }
+
+        // every channel drives exactly one input
+        let mut channels = DRV_IN_CHANNELS;
+        channels.sort_unstable();
+        assert_eq!(channels, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn peripheral_pins_use_their_alternate_function() {
+        // TIM3_CH4 on PB1
+        assert_eq!(STEP, AltPin::new(Port::B, 1, 2));
+
+        for pin in [ENC_SCK, ENC_MISO, ENC_MOSI] {
+            assert_eq!(pin.af, 5, "SPI1 is AF5 on {:?}", pin.id);
+        }
+        assert_eq!(
+            [ENC_SCK.id, ENC_MISO.id, ENC_MOSI.id],
+            [
+                PinId::new(Port::A, 5),
+                PinId::new(Port::A, 6),
+                PinId::new(Port::A, 7)
+            ]
+        );
+
+        // USART3 TX/RX on PB10/PB11 and USART1 TX/RX on PB6/PB7, all AF7
+        for (pin, num) in [
+            (UART_TX, 10),
+            (UART_RX, 11),
+            (CONSOLE_TX, 6),
+            (CONSOLE_RX, 7),
+        ] {
+            assert_eq!(pin, AltPin::new(Port::B, num, 7));
+        }
+    }
+
+    #[test]
+    fn exti_lines_do_not_collide() {
+        // SW1, DIAG and nFAULT raise interrupts, each needs its own EXTI line
+        let lines = [SW1.exti_line(), DIAG.exti_line(), DRV_FAULT.exti_line()];
+
+        assert_ne!(lines[0], lines[1]);
+        assert_ne!(lines[0], lines[2]);
+        assert_ne!(lines[1], lines[2]);
+    }
+}
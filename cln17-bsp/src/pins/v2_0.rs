@@ -0,0 +1,52 @@
+//! CLN17 v2.0 pin map.
+//!
+//! SW1 moved to PA15, where the first blink example, written for the newer
+//! board, reads it. The other assignments are taken over from v1.0 and not
+//! checked against a v2.0 board yet.
+
+use super::{AltPin, PinId, Port};
+
+// RGB led, active low
+pub const LED_RED: PinId = PinId::new(Port::B, 13);
+pub const LED_GREEN: PinId = PinId::new(Port::B, 14);
+pub const LED_BLUE: PinId = PinId::new(Port::B, 15);
+
+/// SW1 button, closes to ground.
+pub const SW1: PinId = PinId::new(Port::A, 15);
+
+// DRV8844 control pins
+pub const DRV_EN: PinId = PinId::new(Port::A, 4);
+pub const DRV_RESET: PinId = PinId::new(Port::B, 2);
+
+/// DRV8844 nFAULT, open drain, low on overcurrent, overtemperature or undervoltage.
+pub const DRV_FAULT: PinId = PinId::new(Port::B, 4);
+
+// DRV8844 bridge inputs, TIM2 channels
+pub const DRV_IN1: AltPin = AltPin::new(Port::A, 1, 1); //  a2 -- TIM2_CH2
+pub const DRV_IN2: AltPin = AltPin::new(Port::A, 0, 1); //  a1 -- TIM2_CH1
+pub const DRV_IN3: AltPin = AltPin::new(Port::B, 11, 1); // b1 -- TIM2_CH4
+pub const DRV_IN4: AltPin = AltPin::new(Port::B, 10, 1); // b2 -- TIM2_CH3
+
+/// TIM2 channel of DRV_IN1 to DRV_IN4, the inputs are not in channel order.
+pub const DRV_IN_CHANNELS: [u8; 4] = [2, 1, 4, 3];
+
+// step/dir interface, step is TIM3_CH4
+pub const STEP: AltPin = AltPin::new(Port::B, 1, 2);
+pub const DIR: PinId = PinId::new(Port::B, 0);
+
+/// TMC2209 DIAG output on the step/dir header, high on a stall.
+pub const DIAG: PinId = PinId::new(Port::B, 5);
+
+// SPI1 magnetic encoder
+pub const ENC_SCK: AltPin = AltPin::new(Port::A, 5, 5);
+pub const ENC_MISO: AltPin = AltPin::new(Port::A, 6, 5);
+pub const ENC_MOSI: AltPin = AltPin::new(Port::A, 7, 5);
+pub const ENC_CS: PinId = PinId::new(Port::C, 4);
+
+// USART3, shares PB10/PB11 with DRV_IN4/DRV_IN3
+pub const UART_TX: AltPin = AltPin::new(Port::B, 10, 7);
+pub const UART_RX: AltPin = AltPin::new(Port::B, 11, 7);
+
+// USART1 debug console on the free PB6/PB7
+pub const CONSOLE_TX: AltPin = AltPin::new(Port::B, 6, 7);
+pub const CONSOLE_RX: AltPin = AltPin::new(Port::B, 7, 7);
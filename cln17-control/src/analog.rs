@@ -0,0 +1,200 @@
+//! ADC scan readings to physical units.
+//!
+//! The firmware converts one regular sequence per trigger: the VBUS divider,
+//! the internal temperature sensor, VREFINT and the two coil shunts, and
+//! hands the raw counts to [`Scaling::snapshot`]. VREFINT gives the actual
+//! VDDA, the divider and temperature readings are scaled with it. The
+//! temperature uses the two point factory calibration of the G431,
+//! measured at [`TS_CAL1_C`] and [`TS_CAL2_C`] with VDDA at
+//! [`CAL_VDDA`].
+//!
+//! All readings are right aligned 12 bit counts.
+
+use crate::current::{PhaseCurrents, Shunt};
+
+/// VDDA of the factory calibration in V.
+pub const CAL_VDDA: f32 = 3.0;
+pub const TS_CAL1_C: f32 = 30.0;
+pub const TS_CAL2_C: f32 = 130.0;
+
+/// System memory addresses of the factory calibration values.
+pub const VREFINT_CAL_ADDR: usize = 0x1FFF_75AA;
+pub const TS_CAL1_ADDR: usize = 0x1FFF_75A8;
+pub const TS_CAL2_ADDR: usize = 0x1FFF_75CA;
+
+const FULL_SCALE: f32 = 4095.0;
+
+/// Factory calibration of VREFINT and the temperature sensor, readings
+/// taken at [`CAL_VDDA`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FactoryCal {
+    pub vrefint: u16,
+    pub ts_cal1: u16,
+    pub ts_cal2: u16,
+}
+
+impl FactoryCal {
+    /// Reads the values from system memory.
+    ///
+    /// # Safety
+    ///
+    /// Only on a G4 device, elsewhere the addresses may not be mapped.
+    pub unsafe fn read() -> Self {
+        Self {
+            vrefint: core::ptr::read_volatile(VREFINT_CAL_ADDR as *const u16),
+            ts_cal1: core::ptr::read_volatile(TS_CAL1_ADDR as *const u16),
+            ts_cal2: core::ptr::read_volatile(TS_CAL2_ADDR as *const u16),
+        }
+    }
+
+    /// VDDA in V from a VREFINT reading.
+    pub fn vdda(&self, vrefint: u16) -> f32 {
+        if vrefint == 0 {
+            return CAL_VDDA;
+        }
+
+        CAL_VDDA * self.vrefint as f32 / vrefint as f32
+    }
+
+    /// Sensor temperature in °C from a reading taken at `vdda` V.
+    pub fn temperature(&self, counts: u16, vdda: f32) -> f32 {
+        let slope = (TS_CAL2_C - TS_CAL1_C) / (self.ts_cal2 as f32 - self.ts_cal1 as f32);
+        // the calibration points were taken at CAL_VDDA
+        let counts = counts as f32 * vdda / CAL_VDDA;
+
+        TS_CAL1_C + (counts - self.ts_cal1 as f32) * slope
+    }
+}
+
+/// Resistor divider in front of an ADC input.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Divider {
+    /// Input over output voltage.
+    pub ratio: f32,
+}
+
+impl Divider {
+    pub fn new(r_top: f32, r_bottom: f32) -> Self {
+        Self {
+            ratio: (r_top + r_bottom) / r_bottom,
+        }
+    }
+
+    /// Input voltage in V from a reading taken at `vdda` V.
+    pub fn volts(&self, counts: u16, vdda: f32) -> f32 {
+        counts as f32 * vdda / FULL_SCALE * self.ratio
+    }
+}
+
+/// Counts of one scan.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RawScan {
+    pub vbus: u16,
+    pub temperature: u16,
+    pub vrefint: u16,
+    pub shunt_a: u16,
+    pub shunt_b: u16,
+}
+
+/// Calibrated values of one scan.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    /// Time of the scan in us, from the firmware time base.
+    pub timestamp_us: u64,
+    /// Analog supply in V.
+    pub vdda: f32,
+    /// Motor supply in V.
+    pub vbus: f32,
+    /// MCU die temperature in °C.
+    pub temperature: f32,
+    pub current: PhaseCurrents,
+}
+
+/// Scaling of every channel of the scan.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Scaling {
+    pub cal: FactoryCal,
+    pub vbus: Divider,
+    pub shunts: [Shunt; 2],
+}
+
+impl Scaling {
+    pub fn snapshot(&self, raw: &RawScan, timestamp_us: u64) -> Snapshot {
+        let vdda = self.cal.vdda(raw.vrefint);
+        let [shunt_a, shunt_b] = &self.shunts;
+
+        Snapshot {
+            timestamp_us,
+            vdda,
+            vbus: self.vbus.volts(raw.vbus, vdda),
+            temperature: self.cal.temperature(raw.temperature, vdda),
+            current: PhaseCurrents {
+                a: shunt_a.current(raw.shunt_a),
+                b: shunt_b.current(raw.shunt_b),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAL: FactoryCal = FactoryCal {
+        vrefint: 1655,
+        ts_cal1: 1034,
+        ts_cal2: 1382,
+    };
+
+    #[test]
+    fn vdda_from_vrefint() {
+        assert!((CAL.vdda(1655) - 3.0).abs() < 1e-6);
+        // fewer counts of the same reference at a higher VDDA
+        assert!((CAL.vdda(1505) - 3.299).abs() < 0.01);
+        assert_eq!(CAL.vdda(0), CAL_VDDA);
+    }
+
+    #[test]
+    fn temperature_between_the_calibration_points() {
+        assert!((CAL.temperature(1034, 3.0) - 30.0).abs() < 1e-4);
+        assert!((CAL.temperature(1382, 3.0) - 130.0).abs() < 1e-4);
+        assert!((CAL.temperature(1208, 3.0) - 80.0).abs() < 1e-3);
+
+        // the same sensor voltage read at 3.3 V
+        let counts = (1208.0 * 3.0 / 3.3) as u16;
+        assert!((CAL.temperature(counts, 3.3) - 80.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn divider_scales_to_the_input() {
+        let divider = Divider::new(100_000.0, 4_700.0);
+
+        assert!((divider.ratio - 104.7 / 4.7).abs() < 1e-4);
+        assert_eq!(divider.volts(0, 3.3), 0.0);
+        assert!((divider.volts(4095, 3.3) - 3.3 * divider.ratio).abs() < 1e-3);
+    }
+
+    #[test]
+    fn snapshot_of_a_scan() {
+        let scaling = Scaling {
+            cal: CAL,
+            vbus: Divider::new(100_000.0, 4_700.0),
+            shunts: [Shunt::new(100.0, 10.0, 3300.0, 12); 2],
+        };
+        let raw = RawScan {
+            vbus: 1200,
+            temperature: 1034,
+            vrefint: 1655,
+            shunt_a: 2048,
+            shunt_b: 2048 + 1241,
+        };
+
+        let snapshot = scaling.snapshot(&raw, 42);
+        assert_eq!(snapshot.timestamp_us, 42);
+        assert!((snapshot.vdda - 3.0).abs() < 1e-6);
+        assert!((snapshot.vbus - 1200.0 * 3.0 / 4095.0 * 104.7 / 4.7).abs() < 1e-3);
+        assert!((snapshot.temperature - 30.0).abs() < 1e-4);
+        assert_eq!(snapshot.current.a, 0.0);
+        assert!((snapshot.current.b - 1.0).abs() < 1e-3);
+    }
+}
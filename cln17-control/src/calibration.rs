@@ -0,0 +1,450 @@
+//! Encoder to electrical angle calibration.
+//!
+//! The bridges drive the rotor open loop one microstep at a time through
+//! `revolutions` electrical revolutions forward and the same way back, the
+//! encoder is read at every microstep once the rotor settled. From the pairs
+//! of commanded electrical angle and shaft angle the [`Calibrator`] finds
+//!
+//! - the direction of the encoder against the electrical rotation
+//! - the offset of the electrical angle at shaft angle 0
+//! - a table of shaft angle corrections over one revolution for the
+//!   encoder nonlinearity and the tooth to tooth error of the motor
+//!
+//! Friction makes the rotor lag the field, forward one way and back the
+//! other, the two passes average it out. Nothing is stored per sample, each
+//! one is summed into the bin of its shaft angle.
+//!
+//! One mechanical revolution, `revolutions` equal to the pole pairs, fills
+//! every bin of the table, bins without samples get no correction.
+
+use libm::{atan2f, cosf, roundf, sinf};
+
+use crate::foc::{wrap, ElectricalAngle};
+
+/// Bins of the correction table over one mechanical revolution.
+pub const LUT_LEN: usize = 64;
+
+/// Bytes of a stored [`Calibration`].
+pub const STORED_LEN: usize = 16 + 4 * LUT_LEN;
+
+const MAGIC: [u8; 4] = *b"CAL1";
+
+/// Travel of the forward pass may differ this much from the pole pairs.
+const TRAVEL_TOLERANCE: f32 = 0.2;
+
+/// Circular sums of `pole_pairs * shaft angle - commanded angle`, for both
+/// encoder directions.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Bin {
+    cos: [f32; 2],
+    sin: [f32; 2],
+    count: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CalibrationError {
+    /// Not every microstep was recorded yet.
+    Incomplete,
+    /// The shaft did not follow the field.
+    NoMotion,
+    /// The shaft travel does not match the pole pairs, `measured` fits it.
+    PolePairs { measured: u16 },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Calibrator {
+    pole_pairs: u16,
+    steps_per_cycle: u32,
+    /// Microsteps of one pass.
+    steps: u32,
+    /// Samples recorded, forward pass first.
+    recorded: u32,
+    last: Option<f32>,
+    /// Unwrapped shaft travel of the forward pass.
+    travel: f32,
+    bins: [Bin; LUT_LEN],
+}
+
+impl Calibrator {
+    /// Calibration over `revolutions` electrical revolutions each way with
+    /// `steps_per_cycle` microsteps per electrical revolution.
+    pub fn new(pole_pairs: u16, steps_per_cycle: u32, revolutions: u16) -> Self {
+        debug_assert!(pole_pairs > 0 && steps_per_cycle > 0 && revolutions > 0);
+
+        Self {
+            pole_pairs,
+            steps_per_cycle,
+            steps: steps_per_cycle * revolutions as u32,
+            recorded: 0,
+            last: None,
+            travel: 0.0,
+            bins: [Bin::default(); LUT_LEN],
+        }
+    }
+
+    /// Samples of both passes, each includes its end points.
+    pub fn len(&self) -> u32 {
+        2 * (self.steps + 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.recorded == 0
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.recorded >= self.len()
+    }
+
+    /// Microstep to drive now, the index of the electrical revolution. The
+    /// first one should be held longer so the rotor settles from anywhere.
+    pub fn command(&self) -> Option<u32> {
+        self.position().map(|k| k % self.steps_per_cycle)
+    }
+
+    /// Microsteps from the start of the forward pass.
+    fn position(&self) -> Option<u32> {
+        if self.is_done() {
+            None
+        } else if self.recorded <= self.steps {
+            Some(self.recorded)
+        } else {
+            Some(2 * self.steps + 1 - self.recorded)
+        }
+    }
+
+    /// Records the shaft angle in radians at [`Calibrator::command`] and
+    /// moves on to the next microstep.
+    pub fn record(&mut self, mechanical: f32) {
+        let Some(k) = self.position() else {
+            return;
+        };
+
+        let mechanical = wrap(mechanical);
+        if self.recorded <= self.steps {
+            if let Some(last) = self.last {
+                self.travel += wrap_pi(mechanical - last);
+            }
+            self.last = Some(mechanical);
+        }
+
+        let commanded = k as f32 * core::f32::consts::TAU / self.steps_per_cycle as f32;
+        let electrical = self.pole_pairs as f32 * mechanical;
+        let bin = &mut self.bins[bin_index(mechanical)];
+        for (n, v) in [electrical - commanded, -electrical - commanded]
+            .into_iter()
+            .enumerate()
+        {
+            bin.cos[n] += cosf(v);
+            bin.sin[n] += sinf(v);
+        }
+        bin.count += 1;
+
+        self.recorded += 1;
+    }
+
+    pub fn finish(&self) -> Result<Calibration, CalibrationError> {
+        if !self.is_done() {
+            return Err(CalibrationError::Incomplete);
+        }
+
+        let expected = self.steps as f32 / self.steps_per_cycle as f32 * core::f32::consts::TAU
+            / self.pole_pairs as f32;
+        let travel = self.travel.abs();
+        if travel < expected / 4.0 {
+            return Err(CalibrationError::NoMotion);
+        }
+        if (travel / expected - 1.0).abs() > TRAVEL_TOLERANCE {
+            let measured = self.pole_pairs as f32 * expected / travel;
+            return Err(CalibrationError::PolePairs {
+                measured: roundf(measured) as u16,
+            });
+        }
+
+        let reversed = self.travel < 0.0;
+        let n = reversed as usize;
+
+        let (cos, sin) = self
+            .bins
+            .iter()
+            .fold((0.0, 0.0), |(c, s), bin| (c + bin.cos[n], s + bin.sin[n]));
+        let offset = wrap(atan2f(sin, cos));
+
+        // the bin mean is off the offset by the electrical error at that shaft angle
+        let sign = if reversed { 1.0 } else { -1.0 };
+        let mut lut = [0.0; LUT_LEN];
+        for (correction, bin) in lut.iter_mut().zip(&self.bins) {
+            if bin.count > 0 {
+                let error = wrap_pi(atan2f(bin.sin[n], bin.cos[n]) - offset);
+                *correction = sign * error / self.pole_pairs as f32;
+            }
+        }
+
+        Ok(Calibration {
+            angle: ElectricalAngle {
+                pole_pairs: self.pole_pairs,
+                offset,
+                reversed,
+            },
+            lut,
+        })
+    }
+}
+
+/// Result of a [`Calibrator`] run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Calibration {
+    pub angle: ElectricalAngle,
+    /// Shaft angle correction in radians at the center of each bin.
+    pub lut: [f32; LUT_LEN],
+}
+
+impl Calibration {
+    /// Shaft angle with the table correction, interpolated between bins.
+    pub fn correct(&self, mechanical: f32) -> f32 {
+        let position = wrap(mechanical) / core::f32::consts::TAU * LUT_LEN as f32 - 0.5;
+        let position = if position < 0.0 {
+            position + LUT_LEN as f32
+        } else {
+            position
+        };
+
+        let below = position as usize % LUT_LEN;
+        let above = (below + 1) % LUT_LEN;
+        let fraction = position - (position as usize) as f32;
+
+        mechanical + self.lut[below] + (self.lut[above] - self.lut[below]) * fraction
+    }
+
+    /// Electrical angle, 0 to 2π, of the shaft at `mechanical` radians.
+    pub fn electrical_angle(&self, mechanical: f32) -> f32 {
+        self.angle.from_mechanical(self.correct(mechanical))
+    }
+
+    /// Serializes for flash, little endian with a checksum.
+    pub fn to_bytes(&self) -> [u8; STORED_LEN] {
+        let mut buf = [0; STORED_LEN];
+
+        buf[0..4].copy_from_slice(&MAGIC);
+        buf[4..6].copy_from_slice(&self.angle.pole_pairs.to_le_bytes());
+        buf[6] = self.angle.reversed as u8;
+        buf[8..12].copy_from_slice(&self.angle.offset.to_le_bytes());
+        for (chunk, correction) in buf[16..].chunks_exact_mut(4).zip(&self.lut) {
+            chunk.copy_from_slice(&correction.to_le_bytes());
+        }
+
+        let sum = checksum(&buf);
+        buf[12..16].copy_from_slice(&sum.to_le_bytes());
+
+        buf
+    }
+
+    /// `None` for erased flash or a damaged copy.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let buf = buf.get(..STORED_LEN)?;
+        if buf[0..4] != MAGIC {
+            return None;
+        }
+
+        let stored = u32::from_le_bytes(buf[12..16].try_into().ok()?);
+        let mut copy = [0; STORED_LEN];
+        copy.copy_from_slice(buf);
+        copy[12..16].fill(0);
+        if checksum(&copy) != stored {
+            return None;
+        }
+
+        let mut lut = [0.0; LUT_LEN];
+        for (correction, chunk) in lut.iter_mut().zip(buf[16..].chunks_exact(4)) {
+            *correction = f32::from_le_bytes(chunk.try_into().ok()?);
+        }
+
+        Some(Self {
+            angle: ElectricalAngle {
+                pole_pairs: u16::from_le_bytes([buf[4], buf[5]]),
+                offset: f32::from_le_bytes(buf[8..12].try_into().ok()?),
+                reversed: buf[6] != 0,
+            },
+            lut,
+        })
+    }
+}
+
+fn bin_index(mechanical: f32) -> usize {
+    (mechanical / core::f32::consts::TAU * LUT_LEN as f32) as usize % LUT_LEN
+}
+
+/// Wraps `angle` into -π to π.
+fn wrap_pi(angle: f32) -> f32 {
+    wrap(angle + core::f32::consts::PI) - core::f32::consts::PI
+}
+
+/// FNV-1a over `buf`.
+fn checksum(buf: &[u8]) -> u32 {
+    buf.iter().fold(0x811c_9dc5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::TAU;
+
+    const POLE_PAIRS: u16 = 50;
+    const STEPS_PER_CYCLE: u32 = 64;
+
+    /// Electrical angle at shaft angle 0.
+    const OFFSET: f32 = 0.7;
+    /// Encoder reading at shaft angle 0.
+    const ENCODER_ZERO: f32 = 2.1;
+
+    #[derive(Clone, Copy)]
+    struct Rig {
+        reversed: bool,
+        /// Amplitude of the encoder error in radians.
+        nonlinearity: f32,
+        /// Rotor lag behind the field in electrical radians.
+        lag: f32,
+    }
+
+    impl Rig {
+        fn encoder(&self, shaft: f32) -> f32 {
+            let sign = if self.reversed { -1.0 } else { 1.0 };
+            let error = self.nonlinearity * (sinf(shaft) + 0.3 * sinf(2.0 * shaft + 1.0));
+
+            wrap(sign * shaft + ENCODER_ZERO + error)
+        }
+
+        // drives the calibrator through both passes
+        fn run(&self, calibrator: &mut Calibrator) {
+            let steps = STEPS_PER_CYCLE as i64;
+            let (mut position, mut forward) = (0i64, true);
+
+            while let Some(command) = calibrator.command() {
+                // unwrapped microstep, the command only wraps over one revolution
+                let delta = (command as i64 - position.rem_euclid(steps) + steps / 2)
+                    .rem_euclid(steps)
+                    - steps / 2;
+                forward &= delta >= 0;
+                position += delta;
+
+                let lag = if forward { -self.lag } else { self.lag };
+                let electrical = position as f32 * TAU / steps as f32 + OFFSET + lag;
+                calibrator.record(self.encoder(electrical / POLE_PAIRS as f32));
+            }
+        }
+
+        // largest electrical angle error of the calibration over a revolution
+        fn worst_error(&self, calibration: &Calibration) -> f32 {
+            (0..1000)
+                .map(|n| {
+                    let shaft = n as f32 / 1000.0 * TAU;
+                    let expected = POLE_PAIRS as f32 * shaft - OFFSET;
+                    let angle = calibration.electrical_angle(self.encoder(shaft));
+                    wrap_pi(angle - expected).abs()
+                })
+                .fold(0.0, f32::max)
+        }
+
+        fn calibrate(&self) -> Calibration {
+            let mut calibrator = Calibrator::new(POLE_PAIRS, STEPS_PER_CYCLE, POLE_PAIRS);
+            self.run(&mut calibrator);
+
+            calibrator.finish().unwrap()
+        }
+    }
+
+    #[test]
+    fn finds_the_offset() {
+        let rig = Rig {
+            reversed: false,
+            nonlinearity: 0.0,
+            lag: 0.05,
+        };
+        let calibration = rig.calibrate();
+
+        assert!(!calibration.angle.reversed);
+        assert_eq!(calibration.angle.pole_pairs, POLE_PAIRS);
+        // electrical 0 where the encoder reads `ENCODER_ZERO`
+        let expected = wrap(POLE_PAIRS as f32 * ENCODER_ZERO + OFFSET);
+        assert!(wrap_pi(calibration.angle.offset - expected).abs() < 0.01);
+        assert!(rig.worst_error(&calibration) < 0.01);
+    }
+
+    #[test]
+    fn finds_a_reversed_encoder() {
+        let rig = Rig {
+            reversed: true,
+            nonlinearity: 0.0,
+            lag: 0.05,
+        };
+        let calibration = rig.calibrate();
+
+        assert!(calibration.angle.reversed);
+        assert!(rig.worst_error(&calibration) < 0.01);
+    }
+
+    #[test]
+    fn table_corrects_the_nonlinearity() {
+        for reversed in [false, true] {
+            let rig = Rig {
+                reversed,
+                nonlinearity: 0.01,
+                lag: 0.05,
+            };
+            let calibration = rig.calibrate();
+
+            // without the table the error is up to 50 * 0.013 rad
+            let uncorrected = Calibration {
+                lut: [0.0; LUT_LEN],
+                ..calibration
+            };
+            assert!(rig.worst_error(&uncorrected) > 0.4);
+            assert!(rig.worst_error(&calibration) < 0.06);
+        }
+    }
+
+    #[test]
+    fn bytes_round_trip_with_a_checksum() {
+        let calibration = Rig {
+            reversed: true,
+            nonlinearity: 0.01,
+            lag: 0.0,
+        }
+        .calibrate();
+
+        let bytes = calibration.to_bytes();
+        assert_eq!(Calibration::from_bytes(&bytes), Some(calibration));
+
+        let mut damaged = bytes;
+        damaged[40] ^= 1;
+        assert_eq!(Calibration::from_bytes(&damaged), None);
+
+        assert_eq!(Calibration::from_bytes(&[0xFF; STORED_LEN]), None);
+        assert_eq!(Calibration::from_bytes(&bytes[..STORED_LEN - 1]), None);
+    }
+
+    #[test]
+    fn rejects_bad_runs() {
+        let mut calibrator = Calibrator::new(POLE_PAIRS, STEPS_PER_CYCLE, 1);
+        assert_eq!(calibrator.finish(), Err(CalibrationError::Incomplete));
+
+        // the shaft never moved
+        while calibrator.command().is_some() {
+            calibrator.record(1.0);
+        }
+        assert!(calibrator.is_done());
+        assert_eq!(calibrator.finish(), Err(CalibrationError::NoMotion));
+
+        // a 100 pole pair motor calibrated as 50
+        let mut calibrator = Calibrator::new(POLE_PAIRS, STEPS_PER_CYCLE, 10);
+        let end = 10 * STEPS_PER_CYCLE;
+        for n in (0..=end).chain((0..=end).rev()) {
+            calibrator.record(n as f32 * TAU / STEPS_PER_CYCLE as f32 / 100.0);
+        }
+        assert_eq!(
+            calibrator.finish(),
+            Err(CalibrationError::PolePairs { measured: 100 })
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! Per coil current loop of the DRV8844 bridges.
+//!
+//! The open loop [`microstep`](cln17_motion::microstep) tables set a voltage,
+//! the coil current then drops with the supply and with the back EMF at
+//! speed. Here one [`Pi`] per coil turns the error between the microstep
+//! setpoint and the shunt current into the signed duty of the coil bridge.
+//!
+//! The shunts must be sampled at the same point of every PWM period, the
+//! firmware triggers the ADC from the PWM timer and runs [`CurrentLoop::update`]
+//! once per conversion.
+
+use cln17_motion::microstep::CoilCurrents;
+use libm::{cosf, sinf};
+
+use crate::pi::{Pi, PiGains};
+
+/// Shunt and amplifier of one coil, ADC counts to A.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Shunt {
+    /// Reading at zero current.
+    pub offset: f32,
+    pub amps_per_count: f32,
+}
+
+impl Shunt {
+    /// `shunt_mohm` through an amplifier with `gain` into an ADC of `bits`
+    /// and `vref_mv`, zero current at mid scale.
+    pub fn new(shunt_mohm: f32, gain: f32, vref_mv: f32, bits: u8) -> Self {
+        let full_scale = (1u32 << bits) as f32;
+
+        Self {
+            offset: full_scale / 2.0,
+            amps_per_count: vref_mv / full_scale / gain / shunt_mohm,
+        }
+    }
+
+    /// Takes `counts` as the zero current reading, read with the bridges off.
+    pub fn set_offset(&mut self, counts: f32) {
+        self.offset = counts;
+    }
+
+    pub fn current(&self, counts: u16) -> f32 {
+        (counts as f32 - self.offset) * self.amps_per_count
+    }
+}
+
+/// Signed coil currents in A.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PhaseCurrents {
+    pub a: f32,
+    pub b: f32,
+}
+
+impl PhaseCurrents {
+    /// Microstep setpoint at the electrical `angle` in radians, cosine on
+    /// coil A and sine on coil B like the microstep tables.
+    pub fn at_angle(angle: f32, amplitude: f32) -> Self {
+        Self {
+            a: amplitude * cosf(angle),
+            b: amplitude * sinf(angle),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CurrentLoop {
+    a: Pi,
+    b: Pi,
+    /// Loop period in s.
+    dt: f32,
+    max_duty: u16,
+}
+
+impl CurrentLoop {
+    /// Loop running at `loop_hz` with the output in duty counts up to
+    /// `max_duty`, the gains are in duty fractions per A.
+    pub fn new(gains: PiGains, loop_hz: f32, max_duty: u16) -> Self {
+        let pi = Pi::new(gains, -1.0, 1.0);
+
+        Self {
+            a: pi,
+            b: pi,
+            dt: 1.0 / loop_hz,
+            max_duty,
+        }
+    }
+
+    pub fn set_gains(&mut self, gains: PiGains) {
+        self.a.set_gains(gains);
+        self.b.set_gains(gains);
+    }
+
+    /// Limits the duty to `fraction` of the period, 0 to 1.
+    pub fn set_duty_limit(&mut self, fraction: f32) {
+        let fraction = fraction.clamp(0.0, 1.0);
+
+        self.a.set_limits(-fraction, fraction);
+        self.b.set_limits(-fraction, fraction);
+    }
+
+    /// Clears the integrators, call before the bridges are enabled again.
+    pub fn reset(&mut self) {
+        self.a.reset();
+        self.b.reset();
+    }
+
+    /// Coil duties for the next PWM period.
+    pub fn update(&mut self, setpoint: &PhaseCurrents, measured: &PhaseCurrents) -> CoilCurrents {
+        let a = self.a.update(setpoint.a - measured.a, self.dt);
+        let b = self.b.update(setpoint.b - measured.b, self.dt);
+
+        CoilCurrents {
+            a: self.duty(a),
+            b: self.duty(b),
+        }
+    }
+
+    fn duty(&self, fraction: f32) -> i32 {
+        libm::roundf(fraction * self.max_duty as f32) as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const R: f32 = 2.0;
+    const L: f32 = 3e-3;
+    const VBUS: f32 = 24.0;
+    const LOOP_HZ: f32 = 20_000.0;
+    const MAX_DUTY: u16 = 1000;
+
+    // one coil with the held duty of a PWM period
+    struct Coil {
+        current: f32,
+    }
+
+    impl Coil {
+        fn step(&mut self, duty: i32) {
+            let steady = duty as f32 / MAX_DUTY as f32 * VBUS / R;
+            let decay = libm::expf(-R / L / LOOP_HZ);
+            self.current = steady + (self.current - steady) * decay;
+        }
+    }
+
+    #[test]
+    fn settles_within_the_designed_bandwidth() {
+        let bandwidth = 1000.0;
+        let mut current =
+            CurrentLoop::new(PiGains::for_rl(R, L, bandwidth, VBUS), LOOP_HZ, MAX_DUTY);
+        let (mut a, mut b) = (Coil { current: 0.0 }, Coil { current: 0.0 });
+        let setpoint = PhaseCurrents { a: 1.0, b: -0.5 };
+
+        let mut rise = None;
+        for n in 0..2000 {
+            let measured = PhaseCurrents {
+                a: a.current,
+                b: b.current,
+            };
+            let duty = current.update(&setpoint, &measured);
+            a.step(duty.a);
+            b.step(duty.b);
+
+            if rise.is_none() && a.current > 1.0 - libm::expf(-1.0) {
+                rise = Some(n + 1);
+            }
+        }
+
+        assert!((a.current - 1.0).abs() < 0.01, "{}", a.current);
+        assert!((b.current + 0.5).abs() < 0.01, "{}", b.current);
+
+        // first order closed loop, 63 % after one time constant plus the
+        // delay of the sampled loop
+        let tau = 1.0 / (core::f32::consts::TAU * bandwidth);
+        let rise = rise.unwrap() as f32 / LOOP_HZ;
+        assert!(rise < tau + 2.0 / LOOP_HZ, "{} s", rise);
+        assert!(rise > tau / 2.0, "{} s", rise);
+    }
+
+    #[test]
+    fn duty_limit_holds() {
+        let mut current = CurrentLoop::new(PiGains::for_rl(R, L, 1000.0, VBUS), LOOP_HZ, MAX_DUTY);
+        current.set_duty_limit(0.25);
+
+        let setpoint = PhaseCurrents { a: 10.0, b: -10.0 };
+        for _ in 0..1000 {
+            let duty = current.update(&setpoint, &PhaseCurrents::default());
+            assert_eq!((duty.a, duty.b), (250, -250));
+        }
+
+        // no wound up integrator after the limit is lifted
+        current.set_duty_limit(1.0);
+        let duty = current.update(&PhaseCurrents::default(), &PhaseCurrents::default());
+        assert!(duty.a.abs() <= 250 && duty.b.abs() <= 250);
+    }
+
+    #[test]
+    fn shunt_counts_to_amps() {
+        // 100 mΩ, gain 10, 3.3 V 12 bit: 1241 counts per A
+        let mut shunt = Shunt::new(100.0, 10.0, 3300.0, 12);
+        assert_eq!(shunt.current(2048), 0.0);
+        assert!((shunt.current(2048 + 1241) - 1.0).abs() < 1e-3);
+        assert!((shunt.current(2048 - 1241) + 1.0).abs() < 1e-3);
+
+        shunt.set_offset(2010.0);
+        assert_eq!(shunt.current(2010), 0.0);
+    }
+
+    #[test]
+    fn setpoint_follows_the_angle() {
+        let setpoint = PhaseCurrents::at_angle(core::f32::consts::FRAC_PI_2, 2.0);
+        assert!(setpoint.a.abs() < 1e-5);
+        assert!((setpoint.b - 2.0).abs() < 1e-6);
+    }
+}
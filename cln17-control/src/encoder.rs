@@ -0,0 +1,280 @@
+//! Multi-turn position and velocity from a single-turn encoder.
+//!
+//! [`MultiTurn`] unwraps the readings across the zero crossing, assuming
+//! the shaft moves less than half a revolution between two samples.
+//! [`Pll`] tracks the unwrapped position with a second order loop, the
+//! velocity is its integrator state instead of a difference of two
+//! readings, so one count of quantisation does not show up as a jump of
+//! `sample_hz` counts/s.
+//!
+//! Positions are in encoder counts and velocities in counts/s.
+
+/// Unwrapped position of a single-turn encoder.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MultiTurn {
+    counts_per_rev: u32,
+    last: Option<u32>,
+    position: i64,
+}
+
+impl MultiTurn {
+    pub fn new(counts_per_rev: u32) -> Self {
+        debug_assert!(counts_per_rev >= 2);
+
+        Self {
+            counts_per_rev,
+            last: None,
+            position: 0,
+        }
+    }
+
+    pub fn counts_per_rev(&self) -> u32 {
+        self.counts_per_rev
+    }
+
+    /// Takes the next reading, 0 to `counts_per_rev - 1`, and returns the
+    /// position. The first reading sets the position within turn 0.
+    pub fn update(&mut self, counts: u32) -> i64 {
+        let counts = counts % self.counts_per_rev;
+
+        self.position = match self.last {
+            Some(last) => self.position + self.wrapped_delta(last, counts),
+            None => counts as i64,
+        };
+        self.last = Some(counts);
+
+        self.position
+    }
+
+    pub fn position(&self) -> i64 {
+        self.position
+    }
+
+    /// Whole revolutions from turn 0, rounded towards minus infinity.
+    pub fn turns(&self) -> i64 {
+        self.position.div_euclid(self.counts_per_rev as i64)
+    }
+
+    /// Moves the zero, the next reading continues from `position`.
+    pub fn set_position(&mut self, position: i64) {
+        self.position = position;
+    }
+
+    /// Shortest way from `from` to `to`, -cpr/2 to cpr/2.
+    fn wrapped_delta(&self, from: u32, to: u32) -> i64 {
+        let cpr = self.counts_per_rev as i64;
+        let delta = (to as i64 - from as i64).rem_euclid(cpr);
+
+        if delta > cpr / 2 {
+            delta - cpr
+        } else {
+            delta
+        }
+    }
+}
+
+/// Position and velocity tracking loop.
+///
+/// A PI on the position error drives a model that integrates the velocity,
+/// with the gains for a critically damped loop at `bandwidth_hz`. Higher
+/// bandwidth follows acceleration faster, lower smooths the quantisation
+/// more.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pll {
+    kp: f32,
+    ki: f32,
+    /// Sample period in s.
+    dt: f32,
+    /// Whole counts of the estimated position.
+    position: i64,
+    /// Fraction of the estimated position, 0 to 1.
+    fraction: f32,
+    velocity: f32,
+    started: bool,
+}
+
+impl Pll {
+    pub fn new(bandwidth_hz: f32, sample_hz: f32) -> Self {
+        let omega = core::f32::consts::TAU * bandwidth_hz;
+
+        Self {
+            kp: 2.0 * omega,
+            ki: omega * omega,
+            dt: 1.0 / sample_hz,
+            position: 0,
+            fraction: 0.0,
+            velocity: 0.0,
+            started: false,
+        }
+    }
+
+    /// Takes the next unwrapped position and returns the velocity. The first
+    /// sample sets the position at standstill.
+    pub fn update(&mut self, measured: i64) -> f32 {
+        if !self.started {
+            self.reset(measured);
+            return 0.0;
+        }
+
+        self.advance(self.velocity * self.dt);
+
+        let error = (measured - self.position) as f32 - self.fraction;
+        self.advance(self.kp * self.dt * error);
+        self.velocity += self.ki * self.dt * error;
+
+        self.velocity
+    }
+
+    /// Restarts at standstill at `position`.
+    pub fn reset(&mut self, position: i64) {
+        self.position = position;
+        self.fraction = 0.0;
+        self.velocity = 0.0;
+        self.started = true;
+    }
+
+    /// Estimated position rounded to counts.
+    pub fn position(&self) -> i64 {
+        self.position + libm::roundf(self.fraction) as i64
+    }
+
+    /// Estimated position with the fraction of a count.
+    pub fn position_f32(&self) -> f32 {
+        self.position as f32 + self.fraction
+    }
+
+    pub fn velocity(&self) -> f32 {
+        self.velocity
+    }
+
+    fn advance(&mut self, counts: f32) {
+        let fraction = self.fraction + counts;
+        let whole = libm::floorf(fraction);
+
+        self.position += whole as i64;
+        self.fraction = fraction - whole;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CPR: u32 = 1 << 15;
+    const SAMPLE_HZ: f32 = 10_000.0;
+
+    // uniform noise of ±0.5 from a linear congruential generator
+    struct Noise(u32);
+
+    impl Noise {
+        fn next(&mut self) -> f64 {
+            self.0 = self.0.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (self.0 >> 16) as f64 / 65536.0 - 0.5
+        }
+    }
+
+    fn reading(position: i64) -> u32 {
+        position.rem_euclid(CPR as i64) as u32
+    }
+
+    #[test]
+    fn unwraps_across_zero() {
+        let mut turns = MultiTurn::new(CPR);
+
+        assert_eq!(turns.update(32_000), 32_000);
+        assert_eq!(turns.update(100), 32_868);
+        assert_eq!(turns.turns(), 1);
+        assert_eq!(turns.update(32_700), 32_700);
+        assert_eq!(turns.turns(), 0);
+        assert_eq!(turns.update(500), 33_268);
+    }
+
+    #[test]
+    fn unwraps_many_turns_backwards() {
+        let mut turns = MultiTurn::new(CPR);
+        let mut position = 500;
+        turns.update(reading(position));
+
+        for _ in 0..100_000 {
+            position -= 7;
+            turns.update(reading(position));
+        }
+        assert_eq!(turns.position(), position);
+        assert_eq!(turns.turns(), position.div_euclid(CPR as i64));
+        assert!(turns.turns() < 0);
+
+        turns.set_position(0);
+        turns.update(reading(position + 10));
+        assert_eq!(turns.position(), 10);
+    }
+
+    #[test]
+    fn pll_tracks_a_noisy_ramp_and_its_reversal() {
+        let mut turns = MultiTurn::new(CPR);
+        let mut pll = Pll::new(50.0, SAMPLE_HZ);
+        let mut noise = Noise(1);
+
+        // 3.3 rev/s, 11 counts per sample with ±1 count of noise
+        let speed = 3.3 * CPR as f32;
+        let sample = |n: u32, noise: &mut Noise| {
+            let t = n as f64 / SAMPLE_HZ as f64;
+            let position = 1000.0 + speed as f64 * t + 2.0 * noise.next();
+            libm::floor(position) as i64
+        };
+
+        for n in 0..20_000 {
+            let velocity = pll.update(turns.update(reading(sample(n, &mut noise))));
+
+            // a difference of two readings jumps by SAMPLE_HZ per count
+            if n > 5000 {
+                assert!(
+                    (velocity - speed).abs() < 0.01 * speed,
+                    "{} at {}",
+                    velocity,
+                    n
+                );
+            }
+        }
+        assert!((pll.position() - turns.position()).abs() <= 2);
+
+        // back the other way through zero
+        let start = turns.position();
+        for n in 0..25_000 {
+            let t = n as f64 / SAMPLE_HZ as f64;
+            let position = start as f64 - speed as f64 * t + 2.0 * noise.next();
+            pll.update(turns.update(reading(libm::floor(position) as i64)));
+        }
+        assert!(turns.position() < 0);
+        assert!(
+            (pll.velocity() + speed).abs() < 0.01 * speed,
+            "{}",
+            pll.velocity()
+        );
+        assert!((pll.position() - turns.position()).abs() <= 2);
+
+        // standstill
+        let end = turns.position();
+        for _ in 0..20_000 {
+            pll.update(end);
+        }
+        assert!(pll.velocity().abs() < 1.0);
+        assert_eq!(pll.position(), end);
+    }
+
+    #[test]
+    fn pll_keeps_precision_far_from_zero() {
+        let mut pll = Pll::new(50.0, SAMPLE_HZ);
+        let far = 1i64 << 40;
+
+        assert_eq!(pll.update(far), 0.0);
+        for n in 1..1000 {
+            pll.update(far + n);
+        }
+        assert!(
+            (pll.velocity() - SAMPLE_HZ).abs() < 100.0,
+            "{}",
+            pll.velocity()
+        );
+        assert!((pll.position() - (far + 999)).abs() <= 2);
+    }
+}
@@ -0,0 +1,391 @@
+//! Driver fault classification, shutdown and recovery.
+//!
+//! The firmware reports what it sees to [`FaultManager`]: the DRV8844 nFAULT
+//! pin from its EXTI interrupt, polled [`Conditions`] such as the TMC2209
+//! DRV_STATUS flags or the supply state, and the time. The manager answers
+//! with the [`Action`] to take on the outputs. The first fault disables
+//! them and is latched with its timestamp, faults reported while the outputs
+//! are off do not replace it.
+//!
+//! Recovery depends on the kind:
+//!
+//! - overtemperature, undervoltage and overvoltage retry once their
+//!   condition is gone and the retry delay passed
+//! - overcurrent retries after the delay up to `max_retries` times, then
+//!   stays latched
+//! - open load stays latched, a coil is off or broken
+//!
+//! A latched fault only ends with [`FaultManager::clear`], which also
+//! restores the retries.
+//!
+//! Timestamps are in ms from any time base of the firmware.
+
+/// Fault classes, most severe first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultKind {
+    Overcurrent,
+    Overtemperature,
+    Overvoltage,
+    Undervoltage,
+    OpenLoad,
+}
+
+/// Fault conditions of one status poll.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Conditions {
+    pub overcurrent: bool,
+    pub overtemperature: bool,
+    pub overvoltage: bool,
+    pub undervoltage: bool,
+    pub open_load: bool,
+}
+
+impl Conditions {
+    /// Most severe condition reported.
+    pub fn kind(&self) -> Option<FaultKind> {
+        if self.overcurrent {
+            Some(FaultKind::Overcurrent)
+        } else if self.overtemperature {
+            Some(FaultKind::Overtemperature)
+        } else if self.overvoltage {
+            Some(FaultKind::Overvoltage)
+        } else if self.undervoltage {
+            Some(FaultKind::Undervoltage)
+        } else if self.open_load {
+            Some(FaultKind::OpenLoad)
+        } else {
+            None
+        }
+    }
+
+    pub fn contains(&self, kind: FaultKind) -> bool {
+        match kind {
+            FaultKind::Overcurrent => self.overcurrent,
+            FaultKind::Overtemperature => self.overtemperature,
+            FaultKind::Overvoltage => self.overvoltage,
+            FaultKind::Undervoltage => self.undervoltage,
+            FaultKind::OpenLoad => self.open_load,
+        }
+    }
+}
+
+/// A fault and the time it tripped in ms.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fault {
+    pub kind: FaultKind,
+    pub timestamp_ms: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultState {
+    /// Outputs on.
+    Running,
+    /// Outputs off until the automatic retry.
+    Tripped,
+    /// Outputs off until [`FaultManager::clear`].
+    Latched,
+}
+
+/// What the firmware does with the outputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    None,
+    Disable,
+    Enable,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FaultPolicy {
+    /// Time from the trip until an automatic retry in ms.
+    pub retry_delay_ms: u32,
+    /// Overcurrent retries before the fault latches.
+    pub max_retries: u8,
+}
+
+/// Fault state machine of one driver.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FaultManager {
+    policy: FaultPolicy,
+    state: FaultState,
+    fault: Option<Fault>,
+    retries: u8,
+    conditions: Conditions,
+}
+
+impl FaultManager {
+    /// Starts running, the outputs are assumed on.
+    pub fn new(policy: FaultPolicy) -> Self {
+        Self {
+            policy,
+            state: FaultState::Running,
+            fault: None,
+            retries: 0,
+            conditions: Conditions::default(),
+        }
+    }
+
+    pub fn policy(&self) -> &FaultPolicy {
+        &self.policy
+    }
+
+    pub fn state(&self) -> FaultState {
+        self.state
+    }
+
+    /// Fault of the last trip, kept after the recovery.
+    pub fn fault(&self) -> Option<Fault> {
+        self.fault
+    }
+
+    /// Automatic retries since the last clear.
+    pub fn retries(&self) -> u8 {
+        self.retries
+    }
+
+    /// Conditions of the last status poll.
+    pub fn conditions(&self) -> Conditions {
+        self.conditions
+    }
+
+    /// nFAULT went active. The DRV8844 pin does not tell overcurrent,
+    /// overtemperature and undervoltage apart, the last polled conditions
+    /// pick one, overcurrent when none of them is reported.
+    pub fn on_fault_pin(&mut self, now_ms: u64) -> Action {
+        let kind = match self.conditions.kind() {
+            Some(FaultKind::OpenLoad) | None => FaultKind::Overcurrent,
+            Some(kind) => kind,
+        };
+
+        self.trip(kind, now_ms)
+    }
+
+    /// Takes the conditions of a status poll, trips on any of them and
+    /// retries when they are gone.
+    pub fn on_status(&mut self, conditions: Conditions, now_ms: u64) -> Action {
+        self.conditions = conditions;
+
+        match conditions.kind() {
+            Some(kind) => self.trip(kind, now_ms),
+            None => self.poll(now_ms),
+        }
+    }
+
+    /// Retries a tripped fault once the delay passed and its condition is
+    /// gone, call it periodically when nothing else is polled.
+    pub fn poll(&mut self, now_ms: u64) -> Action {
+        let Some(fault) = self.fault else {
+            return Action::None;
+        };
+        if self.state != FaultState::Tripped || !self.is_recovered(fault, now_ms) {
+            return Action::None;
+        }
+
+        if fault.kind == FaultKind::Overcurrent {
+            self.retries += 1;
+        }
+        self.state = FaultState::Running;
+
+        Action::Enable
+    }
+
+    /// Ends a tripped or latched fault and restores the retries, refused
+    /// while its condition is still reported.
+    pub fn clear(&mut self) -> Action {
+        let Some(fault) = self.fault else {
+            return Action::None;
+        };
+        if self.state == FaultState::Running || self.conditions.contains(fault.kind) {
+            return Action::None;
+        }
+
+        self.retries = 0;
+        self.state = FaultState::Running;
+
+        Action::Enable
+    }
+
+    fn trip(&mut self, kind: FaultKind, now_ms: u64) -> Action {
+        if self.state != FaultState::Running {
+            return Action::None;
+        }
+
+        self.fault = Some(Fault {
+            kind,
+            timestamp_ms: now_ms,
+        });
+        self.state = match kind {
+            FaultKind::Overcurrent if self.retries < self.policy.max_retries => FaultState::Tripped,
+            FaultKind::Overcurrent | FaultKind::OpenLoad => FaultState::Latched,
+            _ => FaultState::Tripped,
+        };
+
+        Action::Disable
+    }
+
+    fn is_recovered(&self, fault: Fault, now_ms: u64) -> bool {
+        let elapsed = now_ms.saturating_sub(fault.timestamp_ms);
+
+        elapsed >= self.policy.retry_delay_ms as u64 && !self.conditions.contains(fault.kind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: FaultPolicy = FaultPolicy {
+        retry_delay_ms: 100,
+        max_retries: 2,
+    };
+
+    const UNDERVOLTAGE: Conditions = Conditions {
+        overcurrent: false,
+        overtemperature: false,
+        overvoltage: false,
+        undervoltage: true,
+        open_load: false,
+    };
+
+    #[test]
+    fn overcurrent_retries_then_latches() {
+        let mut manager = FaultManager::new(POLICY);
+
+        assert_eq!(manager.on_fault_pin(10), Action::Disable);
+        assert_eq!(
+            manager.fault(),
+            Some(Fault {
+                kind: FaultKind::Overcurrent,
+                timestamp_ms: 10
+            })
+        );
+        assert_eq!(manager.state(), FaultState::Tripped);
+
+        // the retry waits out the delay from the trip
+        assert_eq!(manager.poll(109), Action::None);
+        assert_eq!(manager.poll(110), Action::Enable);
+        assert_eq!(manager.retries(), 1);
+        assert_eq!(manager.state(), FaultState::Running);
+
+        assert_eq!(manager.on_fault_pin(200), Action::Disable);
+        assert_eq!(manager.poll(299), Action::None);
+        assert_eq!(manager.poll(300), Action::Enable);
+        assert_eq!(manager.retries(), 2);
+
+        // out of retries
+        assert_eq!(manager.on_fault_pin(400), Action::Disable);
+        assert_eq!(manager.state(), FaultState::Latched);
+        assert_eq!(manager.poll(10_000), Action::None);
+
+        // clear restores the retries
+        assert_eq!(manager.clear(), Action::Enable);
+        assert_eq!(manager.retries(), 0);
+        assert_eq!(manager.clear(), Action::None);
+        assert_eq!(manager.on_fault_pin(20_000), Action::Disable);
+        assert_eq!(manager.state(), FaultState::Tripped);
+    }
+
+    #[test]
+    fn fault_during_a_retry_wait_keeps_the_first() {
+        let mut manager = FaultManager::new(POLICY);
+        assert_eq!(manager.on_fault_pin(10), Action::Disable);
+
+        // nFAULT bounces and a hot driver is polled while the outputs are off
+        assert_eq!(manager.on_fault_pin(20), Action::None);
+        let hot = Conditions {
+            overtemperature: true,
+            ..Default::default()
+        };
+        assert_eq!(manager.on_status(hot, 50), Action::None);
+        assert_eq!(manager.fault().unwrap().timestamp_ms, 10);
+        assert_eq!(manager.fault().unwrap().kind, FaultKind::Overcurrent);
+
+        // the overcurrent retry still comes after its delay
+        assert_eq!(
+            manager.on_status(Conditions::default(), 110),
+            Action::Enable
+        );
+        assert_eq!(manager.retries(), 1);
+    }
+
+    #[test]
+    fn fault_right_after_a_retry_trips_again() {
+        let mut manager = FaultManager::new(POLICY);
+        manager.on_fault_pin(0);
+        assert_eq!(manager.poll(100), Action::Enable);
+
+        // the short is still there, the next trip times its own delay
+        assert_eq!(manager.on_fault_pin(101), Action::Disable);
+        assert_eq!(manager.poll(200), Action::None);
+        assert_eq!(manager.poll(201), Action::Enable);
+        assert_eq!(manager.retries(), 2);
+    }
+
+    #[test]
+    fn conditions_classify_and_gate_the_recovery() {
+        let mut manager = FaultManager::new(POLICY);
+
+        let hot = Conditions {
+            overtemperature: true,
+            open_load: true,
+            ..Default::default()
+        };
+        assert_eq!(manager.on_status(hot, 0), Action::Disable);
+        assert_eq!(manager.fault().unwrap().kind, FaultKind::Overtemperature);
+
+        // no retry or clear while it is still hot
+        assert_eq!(manager.on_status(hot, 500), Action::None);
+        assert_eq!(manager.clear(), Action::None);
+        assert_eq!(
+            manager.on_status(Conditions::default(), 600),
+            Action::Enable
+        );
+        assert_eq!(manager.retries(), 0);
+    }
+
+    #[test]
+    fn supply_lockout_clears_itself() {
+        let mut manager = FaultManager::new(POLICY);
+
+        assert_eq!(manager.on_status(UNDERVOLTAGE, 700), Action::Disable);
+        // nFAULT of the DRV8844 UVLO, the polled condition names it
+        assert_eq!(manager.on_fault_pin(701), Action::None);
+        assert_eq!(
+            manager.fault(),
+            Some(Fault {
+                kind: FaultKind::Undervoltage,
+                timestamp_ms: 700
+            })
+        );
+
+        // back inside the limits before the delay, the retry waits for it
+        assert_eq!(manager.on_status(Conditions::default(), 750), Action::None);
+        assert_eq!(manager.poll(800), Action::Enable);
+        assert_eq!(manager.retries(), 0);
+
+        // supply faults retry any number of times
+        for n in 1..10 {
+            let now = 1000 * n;
+            assert_eq!(manager.on_status(UNDERVOLTAGE, now), Action::Disable);
+            assert_eq!(
+                manager.on_status(Conditions::default(), now + 100),
+                Action::Enable
+            );
+        }
+        assert_eq!(manager.state(), FaultState::Running);
+    }
+
+    #[test]
+    fn open_load_latches() {
+        let mut manager = FaultManager::new(POLICY);
+        let open = Conditions {
+            open_load: true,
+            ..Default::default()
+        };
+
+        assert_eq!(manager.on_status(open, 0), Action::Disable);
+        assert_eq!(manager.state(), FaultState::Latched);
+        assert_eq!(manager.on_status(Conditions::default(), 1000), Action::None);
+        assert_eq!(manager.clear(), Action::Enable);
+        assert_eq!(manager.state(), FaultState::Running);
+    }
+}
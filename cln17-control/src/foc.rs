@@ -0,0 +1,427 @@
+//! Field oriented control of the two phase hybrid stepper.
+//!
+//! The coil currents are turned into the rotor frame with the electrical
+//! angle from the encoder: `d` along the rotor flux, `q` across it, only `q`
+//! makes torque. A PI loop per axis sets the coil voltages in that frame,
+//! they are turned back and modulated onto the two H bridges.
+//!
+//! ```text
+//! setpoint d/q --> PI d/q --> inverse Park --> modulate --> IN1..IN4
+//!                    ^                                          |
+//!                    '--- Park <-- Clarke <-- shunts <-- coils <'
+//! ```
+//!
+//! The coils of a two phase motor are 90° apart already, so the Clarke
+//! transform only renames coil A to α and coil B to β. A hybrid stepper has
+//! 50 pole pairs, 200 full steps per revolution, so the electrical angle runs
+//! 50 times faster than the shaft.
+
+use cln17_motion::microstep::CoilCurrents;
+use libm::{cosf, roundf, sinf, sqrtf};
+
+use crate::current::PhaseCurrents;
+use crate::pi::{Pi, PiGains};
+
+/// Pole pairs of a 1.8° hybrid stepper.
+pub const HYBRID_STEPPER_POLE_PAIRS: u16 = 50;
+
+/// Stator frame, α on coil A and β on coil B.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AlphaBeta {
+    pub alpha: f32,
+    pub beta: f32,
+}
+
+/// Rotor frame, `d` along the rotor flux, `q` 90° ahead of it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Dq {
+    pub d: f32,
+    pub q: f32,
+}
+
+/// Stator frame of the coil currents, the coils are 90° apart.
+pub fn clarke(phases: &PhaseCurrents) -> AlphaBeta {
+    AlphaBeta {
+        alpha: phases.a,
+        beta: phases.b,
+    }
+}
+
+/// Stator to rotor frame at the electrical `angle` in radians.
+pub fn park(ab: &AlphaBeta, angle: f32) -> Dq {
+    let (sin, cos) = (sinf(angle), cosf(angle));
+
+    Dq {
+        d: ab.alpha * cos + ab.beta * sin,
+        q: ab.beta * cos - ab.alpha * sin,
+    }
+}
+
+/// Rotor to stator frame at the electrical `angle` in radians.
+pub fn inverse_park(dq: &Dq, angle: f32) -> AlphaBeta {
+    let (sin, cos) = (sinf(angle), cosf(angle));
+
+    AlphaBeta {
+        alpha: dq.d * cos - dq.q * sin,
+        beta: dq.d * sin + dq.q * cos,
+    }
+}
+
+/// Electrical angle from the mechanical shaft angle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ElectricalAngle {
+    pub pole_pairs: u16,
+    /// Electrical angle at mechanical 0, the d axis of coil A in radians.
+    pub offset: f32,
+    /// The encoder counts against the electrical rotation.
+    pub reversed: bool,
+}
+
+impl ElectricalAngle {
+    pub fn new(pole_pairs: u16) -> Self {
+        Self {
+            pole_pairs,
+            offset: 0.0,
+            reversed: false,
+        }
+    }
+
+    /// Electrical angle in radians, 0 to 2π, of the shaft at `mechanical` radians.
+    pub fn from_mechanical(&self, mechanical: f32) -> f32 {
+        let mechanical = if self.reversed {
+            -mechanical
+        } else {
+            mechanical
+        };
+
+        wrap(mechanical * self.pole_pairs as f32 - self.offset)
+    }
+}
+
+/// Wraps `angle` into 0 to 2π.
+pub fn wrap(angle: f32) -> f32 {
+    let angle = angle % core::f32::consts::TAU;
+
+    if angle < 0.0 {
+        angle + core::f32::consts::TAU
+    } else {
+        angle
+    }
+}
+
+/// How the voltage vector is limited to what the bridges can drive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Modulation {
+    /// Sine coil voltages, the vector stays on the circle of radius VBUS.
+    Sine,
+    /// Every bridge up to VBUS on its own, the vector reaches the corners of
+    /// the square, √2 VBUS at 45°, with flattened coil voltages.
+    SpaceVector,
+}
+
+impl Modulation {
+    /// Limits `v` to `vbus`.
+    pub fn limit(self, v: &AlphaBeta, vbus: f32) -> AlphaBeta {
+        match self {
+            Modulation::Sine => {
+                let magnitude = sqrtf(v.alpha * v.alpha + v.beta * v.beta);
+                let scale = if magnitude > vbus {
+                    vbus / magnitude
+                } else {
+                    1.0
+                };
+
+                AlphaBeta {
+                    alpha: v.alpha * scale,
+                    beta: v.beta * scale,
+                }
+            }
+            Modulation::SpaceVector => AlphaBeta {
+                alpha: v.alpha.clamp(-vbus, vbus),
+                beta: v.beta.clamp(-vbus, vbus),
+            },
+        }
+    }
+
+    /// Coil duties of `v` at `vbus`, in counts up to `max_duty`.
+    pub fn duty(self, v: &AlphaBeta, vbus: f32, max_duty: u16) -> CoilCurrents {
+        let v = self.limit(v, vbus);
+        let scale = max_duty as f32 / vbus;
+
+        CoilCurrents {
+            a: roundf(v.alpha * scale) as i32,
+            b: roundf(v.beta * scale) as i32,
+        }
+    }
+}
+
+/// State of one [`Foc::update`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FocOutput {
+    pub current: Dq,
+    pub voltage: Dq,
+    pub duty: CoilCurrents,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Foc {
+    d: Pi,
+    q: Pi,
+    /// Loop period in s.
+    dt: f32,
+    modulation: Modulation,
+    max_duty: u16,
+}
+
+impl Foc {
+    /// Loop running at `loop_hz`, `gains` in V per A, the duty in counts up
+    /// to `max_duty`.
+    pub fn new(gains: PiGains, loop_hz: f32, max_duty: u16, modulation: Modulation) -> Self {
+        let pi = Pi::new(gains, 0.0, 0.0);
+
+        Self {
+            d: pi,
+            q: pi,
+            dt: 1.0 / loop_hz,
+            modulation,
+            max_duty,
+        }
+    }
+
+    pub fn set_gains(&mut self, gains: PiGains) {
+        self.d.set_gains(gains);
+        self.q.set_gains(gains);
+    }
+
+    pub fn modulation(&self) -> Modulation {
+        self.modulation
+    }
+
+    pub fn set_modulation(&mut self, modulation: Modulation) {
+        self.modulation = modulation;
+    }
+
+    /// Clears the integrators, call before the bridges are enabled again.
+    pub fn reset(&mut self) {
+        self.d.reset();
+        self.q.reset();
+    }
+
+    /// Coil duties for the next PWM period from the coil currents measured
+    /// at the electrical `angle` and the supply `vbus` in V.
+    pub fn update(
+        &mut self,
+        setpoint: &Dq,
+        measured: &PhaseCurrents,
+        angle: f32,
+        vbus: f32,
+    ) -> FocOutput {
+        let current = park(&clarke(measured), angle);
+
+        // each axis may use the whole supply, the vector is limited below
+        let limit = match self.modulation {
+            Modulation::Sine => vbus,
+            Modulation::SpaceVector => core::f32::consts::SQRT_2 * vbus,
+        };
+        self.d.set_limits(-limit, limit);
+        self.q.set_limits(-limit, limit);
+
+        let voltage = Dq {
+            d: self.d.update(setpoint.d - current.d, self.dt),
+            q: self.q.update(setpoint.q - current.q, self.dt),
+        };
+        let duty = self
+            .modulation
+            .duty(&inverse_park(&voltage, angle), vbus, self.max_duty);
+
+        FocOutput {
+            current,
+            voltage,
+            duty,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::{FRAC_PI_2, TAU};
+
+    const R: f32 = 1.5;
+    const L: f32 = 2.5e-3;
+    const VBUS: f32 = 24.0;
+    const LOOP_HZ: f32 = 20_000.0;
+    const MAX_DUTY: u16 = 1000;
+
+    // two phase hybrid stepper, the back EMF constant in V s/rad is the
+    // torque constant in Nm/A
+    struct Motor {
+        current: PhaseCurrents,
+        speed: f32,
+        angle: f32,
+        locked: bool,
+    }
+
+    impl Motor {
+        const KE: f32 = 0.03;
+        const INERTIA: f32 = 5e-6;
+        const FRICTION: f32 = 1e-5;
+
+        fn new(angle: f32, locked: bool) -> Self {
+            Self {
+                current: PhaseCurrents::default(),
+                speed: 0.0,
+                angle,
+                locked,
+            }
+        }
+
+        // one PWM period with the coil duties held, in 20 Euler steps
+        fn step(&mut self, duty: &CoilCurrents, load: f32) {
+            let h = 1.0 / LOOP_HZ / 20.0;
+            let (va, vb) = (
+                duty.a as f32 / MAX_DUTY as f32 * VBUS,
+                duty.b as f32 / MAX_DUTY as f32 * VBUS,
+            );
+
+            for _ in 0..20 {
+                let electrical = self.angle * HYBRID_STEPPER_POLE_PAIRS as f32;
+                let (sin, cos) = (sinf(electrical), cosf(electrical));
+                let (ea, eb) = (-Self::KE * self.speed * sin, Self::KE * self.speed * cos);
+
+                self.current.a += (va - R * self.current.a - ea) / L * h;
+                self.current.b += (vb - R * self.current.b - eb) / L * h;
+
+                if !self.locked {
+                    let torque = Self::KE * (self.current.b * cos - self.current.a * sin);
+                    self.speed += (torque - Self::FRICTION * self.speed - load) / Self::INERTIA * h;
+                    self.angle += self.speed * h;
+                }
+            }
+        }
+    }
+
+    fn foc(modulation: Modulation) -> Foc {
+        Foc::new(
+            PiGains::for_rl(R, L, 1000.0, 1.0),
+            LOOP_HZ,
+            MAX_DUTY,
+            modulation,
+        )
+    }
+
+    // runs `periods` PWM periods, returns the last output
+    fn run(foc: &mut Foc, motor: &mut Motor, setpoint: &Dq, periods: u32) -> FocOutput {
+        let electrical = ElectricalAngle::new(HYBRID_STEPPER_POLE_PAIRS);
+        let mut output = FocOutput::default();
+
+        for _ in 0..periods {
+            let angle = electrical.from_mechanical(motor.angle);
+            output = foc.update(setpoint, &motor.current, angle, VBUS);
+            motor.step(&output.duty, 0.0);
+        }
+
+        output
+    }
+
+    #[test]
+    fn park_round_trips() {
+        let ab = AlphaBeta {
+            alpha: 0.3,
+            beta: -0.7,
+        };
+        for n in 0..16 {
+            let angle = n as f32 * 0.5;
+            let back = inverse_park(&park(&ab, angle), angle);
+            assert!((back.alpha - ab.alpha).abs() < 1e-5);
+            assert!((back.beta - ab.beta).abs() < 1e-5);
+        }
+
+        // a microstep setpoint at the rotor angle is pure d current
+        let dq = park(&clarke(&PhaseCurrents::at_angle(1.0, 2.0)), 1.0);
+        assert!((dq.d - 2.0).abs() < 1e-5 && dq.q.abs() < 1e-5);
+    }
+
+    #[test]
+    fn electrical_angle_turns_with_the_pole_pairs() {
+        let mut electrical = ElectricalAngle::new(HYBRID_STEPPER_POLE_PAIRS);
+
+        // one full step is a quarter of an electrical revolution
+        let full_step = TAU / 200.0;
+        assert!((electrical.from_mechanical(full_step) - FRAC_PI_2).abs() < 1e-4);
+        assert!((electrical.from_mechanical(TAU / 50.0 + 0.001) - 0.05).abs() < 1e-3);
+
+        electrical.offset = 0.5;
+        assert!((electrical.from_mechanical(0.0) - (TAU - 0.5)).abs() < 1e-5);
+
+        electrical.reversed = true;
+        electrical.offset = 0.0;
+        assert!((electrical.from_mechanical(full_step) - 3.0 * FRAC_PI_2).abs() < 1e-4);
+
+        assert!((wrap(-0.1) - (TAU - 0.1)).abs() < 1e-5);
+        assert!((wrap(TAU + 0.1) - 0.1).abs() < 1e-5);
+    }
+
+    #[test]
+    fn modulation_limits_the_vector() {
+        let v = AlphaBeta {
+            alpha: 30.0,
+            beta: 40.0,
+        };
+        let limited = Modulation::Sine.limit(&v, 10.0);
+        assert!((limited.alpha - 6.0).abs() < 1e-4 && (limited.beta - 8.0).abs() < 1e-4);
+
+        let limited = Modulation::SpaceVector.limit(&v, 10.0);
+        assert_eq!((limited.alpha, limited.beta), (10.0, 10.0));
+
+        let v = AlphaBeta {
+            alpha: 30.0,
+            beta: -5.0,
+        };
+        let duty = Modulation::SpaceVector.duty(&v, 10.0, 1000);
+        assert_eq!((duty.a, duty.b), (1000, -500));
+    }
+
+    #[test]
+    fn locked_rotor_settles_on_the_setpoint() {
+        for modulation in [Modulation::Sine, Modulation::SpaceVector] {
+            let mut foc = foc(modulation);
+            let mut motor = Motor::new(0.3, true);
+            let setpoint = Dq { d: 0.2, q: 0.5 };
+
+            let output = run(&mut foc, &mut motor, &setpoint, 4000);
+            assert!((output.current.d - 0.2).abs() < 0.02, "{:?}", output);
+            assert!((output.current.q - 0.5).abs() < 0.02, "{:?}", output);
+        }
+    }
+
+    #[test]
+    fn q_current_spins_the_rotor() {
+        let mut foc = foc(Modulation::Sine);
+        let mut motor = Motor::new(0.0, false);
+
+        // 5 ms of torque current, slow enough for the loop to follow the
+        // electrical angle
+        let output = run(&mut foc, &mut motor, &Dq { d: 0.0, q: 1.0 }, 100);
+        assert!(motor.speed > 10.0, "{} rad/s", motor.speed);
+        assert!((output.current.q - 1.0).abs() < 0.1, "{:?}", output);
+        assert!(output.current.d.abs() < 0.1, "{:?}", output);
+
+        // negative torque spins it the other way
+        let mut motor = Motor::new(0.0, false);
+        foc.reset();
+        run(&mut foc, &mut motor, &Dq { d: 0.0, q: -1.0 }, 100);
+        assert!(motor.speed < -10.0, "{} rad/s", motor.speed);
+    }
+
+    #[test]
+    fn d_current_makes_no_torque() {
+        let mut foc = foc(Modulation::Sine);
+        let mut motor = Motor::new(0.0, false);
+
+        let output = run(&mut foc, &mut motor, &Dq { d: 1.0, q: 0.0 }, 4000);
+        assert!((output.current.d - 1.0).abs() < 0.05, "{:?}", output);
+        assert!(motor.speed.abs() < 0.1, "{} rad/s", motor.speed);
+        assert!(motor.angle.abs() < 1e-3, "{} rad", motor.angle);
+    }
+}
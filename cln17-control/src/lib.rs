@@ -0,0 +1,28 @@
+//! Closed loop control for the CLN17 DRV8844 bridges.
+//!
+//! Plain `no_std` math without hal types, the firmware feeds it ADC and encoder
+//! readings and writes the returned duties:
+//!
+//! - [`analog`] scan readings in volts, °C and amps
+//! - [`pi`] controller with output limits and anti-windup
+//! - per coil [`current`] loop on the shunt readings
+//! - [`foc`] field oriented control with the encoder angle
+//! - encoder to electrical angle [`calibration`]
+//! - multi-turn [`encoder`] position and velocity tracking
+//! - [`supply`] voltage feed-forward and lockout
+//! - [`thermal`] current derating and shutdown
+//! - driver [`fault`] classification, shutdown and recovery
+//! - [`steploss`] detection against the commanded step position
+
+#![no_std]
+
+pub mod analog;
+pub mod calibration;
+pub mod current;
+pub mod encoder;
+pub mod fault;
+pub mod foc;
+pub mod pi;
+pub mod steploss;
+pub mod supply;
+pub mod thermal;
@@ -0,0 +1,166 @@
+//! PI controller with output limits and conditional integration.
+//!
+//! While the output is saturated the integrator only moves back towards the
+//! range, so it does not wind up while the plant cannot follow, for example
+//! when the supply is too low for the current setpoint.
+
+/// Gains of `output = kp * error + ki * ∫ error dt`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PiGains {
+    pub kp: f32,
+    /// Integral gain per s.
+    pub ki: f32,
+}
+
+impl PiGains {
+    /// Gains of a current loop on an RL load with `bandwidth_hz`, output as
+    /// duty of `vbus`.
+    ///
+    /// The zero of the controller cancels the pole of the load at R / L, the
+    /// closed loop is first order with the time constant 1 / (2π bandwidth).
+    pub fn for_rl(r_ohm: f32, l_h: f32, bandwidth_hz: f32, vbus: f32) -> Self {
+        let omega = core::f32::consts::TAU * bandwidth_hz;
+
+        Self {
+            kp: omega * l_h / vbus,
+            ki: omega * r_ohm / vbus,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pi {
+    gains: PiGains,
+    min: f32,
+    max: f32,
+    integral: f32,
+}
+
+impl Pi {
+    /// Controller with the output limited to `min..=max`.
+    pub fn new(gains: PiGains, min: f32, max: f32) -> Self {
+        debug_assert!(min <= max);
+
+        Self {
+            gains,
+            min,
+            max,
+            integral: 0.0,
+        }
+    }
+
+    pub fn gains(&self) -> PiGains {
+        self.gains
+    }
+
+    /// New gains, the integral output is kept so the output does not jump.
+    pub fn set_gains(&mut self, gains: PiGains) {
+        self.gains = gains;
+    }
+
+    pub fn limits(&self) -> (f32, f32) {
+        (self.min, self.max)
+    }
+
+    pub fn set_limits(&mut self, min: f32, max: f32) {
+        debug_assert!(min <= max);
+
+        self.min = min;
+        self.max = max;
+        self.integral = self.integral.clamp(min, max);
+    }
+
+    /// Output share of the integrator.
+    pub fn integral(&self) -> f32 {
+        self.integral
+    }
+
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+    }
+
+    /// Output for `error = setpoint - measured`, `dt` s after the last update.
+    pub fn update(&mut self, error: f32, dt: f32) -> f32 {
+        let proportional = self.gains.kp * error;
+        let integral = (self.integral + self.gains.ki * error * dt).clamp(self.min, self.max);
+        let output = proportional + integral;
+
+        // integrate only when it does not push further into saturation
+        let winding_up = (output > self.max && integral > self.integral)
+            || (output < self.min && integral < self.integral);
+        if !winding_up {
+            self.integral = integral;
+        }
+
+        (proportional + self.integral).clamp(self.min, self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const R: f32 = 2.0;
+    const L: f32 = 3e-3;
+    const VBUS: f32 = 24.0;
+    const LOOP_HZ: f32 = 20_000.0;
+
+    // coil current after `dt` with `volts` applied, exact for a held voltage
+    fn rl_step(current: f32, volts: f32, dt: f32) -> f32 {
+        let steady = volts / R;
+        steady + (current - steady) * libm::expf(-dt * R / L)
+    }
+
+    #[test]
+    fn rl_gains_cancel_the_pole() {
+        let gains = PiGains::for_rl(R, L, 1000.0, VBUS);
+
+        assert!((gains.ki / gains.kp - R / L).abs() < 1e-2);
+        assert!((gains.kp * VBUS / L - core::f32::consts::TAU * 1000.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn output_stays_in_the_limits() {
+        let mut pi = Pi::new(PiGains { kp: 1.0, ki: 100.0 }, -0.5, 0.5);
+
+        assert_eq!(pi.update(10.0, 0.01), 0.5);
+        assert_eq!(pi.update(-10.0, 0.01), -0.5);
+
+        pi.set_limits(-0.1, 0.1);
+        assert!(pi.integral().abs() <= 0.1);
+        pi.reset();
+        assert_eq!(pi.integral(), 0.0);
+        assert_eq!(pi.update(0.0, 0.01), 0.0);
+    }
+
+    #[test]
+    fn integrator_does_not_wind_up_at_the_duty_limit() {
+        let dt = 1.0 / LOOP_HZ;
+        let mut pi = Pi::new(PiGains::for_rl(R, L, 1000.0, VBUS), -1.0, 1.0);
+        let mut current = 0.0;
+
+        // 20 A needs 40 V, the output sits at full duty for 1 s
+        for _ in 0..20_000 {
+            let duty = pi.update(20.0 - current, dt);
+            assert!(duty <= 1.0);
+            current = rl_step(current, duty * VBUS, dt);
+        }
+        assert!(pi.integral() <= 1.0);
+        assert!((current - VBUS / R).abs() < 0.01);
+
+        // a reachable setpoint settles within a few time constants, without a
+        // long overshoot from a wound up integrator
+        for n in 0..400 {
+            let duty = pi.update(2.0 - current, dt);
+            current = rl_step(current, duty * VBUS, dt);
+            if n > 100 {
+                assert!(
+                    (current - 2.0).abs() < 0.05,
+                    "{} A after {} periods",
+                    current,
+                    n
+                );
+            }
+        }
+    }
+}
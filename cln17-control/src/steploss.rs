@@ -0,0 +1,329 @@
+//! Step-loss detection for the open loop step/dir and microstep drives.
+//!
+//! [`StepLossMonitor`] compares the commanded position of the step
+//! generator with the encoder position converted to steps. Both are taken
+//! relative to the pair of positions given to [`StepLossMonitor::sync`],
+//! usually right after homing.
+//!
+//! A following error above [`StepLossConfig::max_error`] for
+//! [`StepLossConfig::fault_samples`] samples in a row raises a
+//! [`FollowingFault`] that stays latched until it is cleared. With
+//! [`StepLossConfig::correct_above`] set, a smaller error found at
+//! standstill asks for the commanded position to be moved to the measured
+//! one, so the next absolute move ends where it was meant to.
+//!
+//! Positions are in steps of the step generator, microsteps for a step/dir
+//! driver, and encoder counts.
+
+/// Monitor settings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StepLossConfig {
+    /// Steps of one motor revolution, full steps times microsteps.
+    pub steps_per_rev: u32,
+    pub counts_per_rev: u32,
+    /// The encoder counts down while the step position counts up.
+    pub reversed: bool,
+    /// Following error in steps that raises the fault. Keep it above the
+    /// steps the firmware commands ahead of the motor, a buffered step train
+    /// counts its steps when they are queued.
+    pub max_error: u32,
+    /// Samples in a row above `max_error` before the fault, 1 faults on the
+    /// first one, more ride out single bad readings.
+    pub fault_samples: u16,
+    /// Error in steps that is corrected at standstill, `None` only reports.
+    pub correct_above: Option<u32>,
+}
+
+/// Latched following error, positions in steps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FollowingFault {
+    pub commanded: i64,
+    pub measured: i64,
+}
+
+impl FollowingFault {
+    /// Commanded minus measured, positive when the motor lags behind a move
+    /// in the positive direction.
+    pub fn error(&self) -> i64 {
+        self.commanded - self.measured
+    }
+}
+
+/// Result of one comparison.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepLoss {
+    /// Within the limits, or not synced yet.
+    InSync,
+    /// The motor stands off by more than `correct_above` steps, set the
+    /// commanded position to `position`.
+    Correct { position: i64 },
+    /// The following error is or was above `max_error`, stop the motor.
+    Fault(FollowingFault),
+}
+
+/// Commanded against measured position comparator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StepLossMonitor {
+    config: StepLossConfig,
+    /// Commanded and encoder position of the sync.
+    zero: Option<(i64, i64)>,
+    /// Samples in a row above `max_error`.
+    over: u16,
+    error: i64,
+    fault: Option<FollowingFault>,
+}
+
+impl StepLossMonitor {
+    pub fn new(config: StepLossConfig) -> Self {
+        debug_assert!(config.steps_per_rev > 0 && config.counts_per_rev > 0);
+
+        Self {
+            config,
+            zero: None,
+            over: 0,
+            error: 0,
+            fault: None,
+        }
+    }
+
+    pub fn config(&self) -> &StepLossConfig {
+        &self.config
+    }
+
+    /// Commanded step position `commanded` is where the encoder reads
+    /// `encoder` counts, clears a latched fault.
+    pub fn sync(&mut self, commanded: i64, encoder: i64) {
+        self.zero = Some((commanded, encoder));
+        self.over = 0;
+        self.error = 0;
+        self.fault = None;
+    }
+
+    /// Forgets the sync and a latched fault, nothing is compared until the
+    /// next sync.
+    pub fn reset(&mut self) {
+        self.zero = None;
+        self.over = 0;
+        self.error = 0;
+        self.fault = None;
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.zero.is_some()
+    }
+
+    /// Encoder position `encoder` in steps, `None` before the first sync.
+    pub fn measured(&self, encoder: i64) -> Option<i64> {
+        let (commanded, zero) = self.zero?;
+
+        let counts = (encoder - zero) as i128;
+        let counts = if self.config.reversed {
+            -counts
+        } else {
+            counts
+        };
+        let steps = div_round(
+            counts * self.config.steps_per_rev as i128,
+            self.config.counts_per_rev as i128,
+        );
+
+        Some(commanded + steps as i64)
+    }
+
+    /// Compares the commanded position with the encoder position, `moving`
+    /// while the step generator runs. Corrections are only asked for at
+    /// standstill, during a move the steps queued ahead look like an error.
+    pub fn update(&mut self, commanded: i64, encoder: i64, moving: bool) -> StepLoss {
+        if let Some(fault) = self.fault {
+            return StepLoss::Fault(fault);
+        }
+
+        let Some(measured) = self.measured(encoder) else {
+            return StepLoss::InSync;
+        };
+
+        self.error = commanded - measured;
+        let error = self.error.unsigned_abs();
+
+        if error > self.config.max_error as u64 {
+            self.over = self.over.saturating_add(1);
+            if self.over >= self.config.fault_samples.max(1) {
+                let fault = FollowingFault {
+                    commanded,
+                    measured,
+                };
+                self.fault = Some(fault);
+                return StepLoss::Fault(fault);
+            }
+            return StepLoss::InSync;
+        }
+        self.over = 0;
+
+        match self.config.correct_above {
+            Some(limit) if !moving && error > limit as u64 => {
+                StepLoss::Correct { position: measured }
+            }
+            _ => StepLoss::InSync,
+        }
+    }
+
+    /// Commanded minus measured position of the last update.
+    pub fn error(&self) -> i64 {
+        self.error
+    }
+
+    pub fn fault(&self) -> Option<FollowingFault> {
+        self.fault
+    }
+
+    /// Clears a latched fault, the sync stays. Re-sync instead when the
+    /// commanded position was not set to the measured one.
+    pub fn clear(&mut self) {
+        self.over = 0;
+        self.fault = None;
+    }
+}
+
+/// `num / den` rounded to the nearest, halves away from zero.
+fn div_round(num: i128, den: i128) -> i128 {
+    if num >= 0 {
+        (num + den / 2) / den
+    } else {
+        (num - den / 2) / den
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 200 full steps with 8 microsteps against the 15 bit TLE5012
+    const STEPS_PER_REV: u32 = 1600;
+    const COUNTS_PER_REV: u32 = 1 << 15;
+
+    fn config(correct_above: Option<u32>) -> StepLossConfig {
+        StepLossConfig {
+            steps_per_rev: STEPS_PER_REV,
+            counts_per_rev: COUNTS_PER_REV,
+            reversed: false,
+            max_error: 80,
+            fault_samples: 3,
+            correct_above,
+        }
+    }
+
+    fn counts(steps: i64) -> i64 {
+        steps * COUNTS_PER_REV as i64 / STEPS_PER_REV as i64
+    }
+
+    #[test]
+    fn quiet_until_synced() {
+        let mut monitor = StepLossMonitor::new(config(Some(4)));
+
+        assert_eq!(monitor.update(1000, 0, false), StepLoss::InSync);
+        assert_eq!(monitor.measured(0), None);
+        assert!(!monitor.is_synced());
+    }
+
+    #[test]
+    fn stall_raises_a_latched_fault() {
+        let mut monitor = StepLossMonitor::new(config(None));
+        monitor.sync(100, 5000);
+
+        // the motor follows for 1000 steps
+        for step in 0..1000 {
+            let state = monitor.update(100 + step, 5000 + counts(step), true);
+            assert_eq!(state, StepLoss::InSync);
+        }
+
+        // then stalls while the commanded position runs on, the fault comes
+        // on the third sample over 80 steps
+        let script = (1000..1200).map(|step| (100 + step, 5000 + counts(1000)));
+        let (samples, state) = script
+            .enumerate()
+            .map(|(n, (commanded, encoder))| (n + 1, monitor.update(commanded, encoder, true)))
+            .find(|(_, state)| *state != StepLoss::InSync)
+            .unwrap();
+        assert_eq!(samples, 84);
+
+        let StepLoss::Fault(fault) = state else {
+            panic!("{:?}", state)
+        };
+        assert_eq!(fault.measured, 1100);
+        assert_eq!(fault.error(), 83);
+        assert_eq!(monitor.fault(), Some(fault));
+
+        // latched until cleared, whatever the positions
+        assert_eq!(monitor.update(1100, 5000 + counts(1000), false), state);
+        monitor.clear();
+        assert_eq!(monitor.fault(), None);
+        assert_eq!(
+            monitor.update(1100, 5000 + counts(1000), false),
+            StepLoss::InSync
+        );
+    }
+
+    #[test]
+    fn single_bad_readings_are_ridden_out() {
+        let mut monitor = StepLossMonitor::new(config(None));
+        monitor.sync(0, 0);
+
+        let script = [100_000, 0, 100_000, 100_000];
+        for encoder in script {
+            assert_eq!(monitor.update(0, encoder, true), StepLoss::InSync);
+        }
+        assert!(matches!(
+            monitor.update(0, 100_000, true),
+            StepLoss::Fault(_)
+        ));
+
+        // a re-sync clears the fault
+        monitor.sync(0, 100_000);
+        assert_eq!(monitor.update(0, 100_000, true), StepLoss::InSync);
+    }
+
+    #[test]
+    fn corrects_at_standstill_only() {
+        let mut config = config(Some(4));
+        config.reversed = true;
+        let mut monitor = StepLossMonitor::new(config);
+        monitor.sync(0, 0);
+
+        // 10 steps lost on the way to 500, the encoder counts down
+        assert_eq!(monitor.update(500, -counts(490), true), StepLoss::InSync);
+        assert_eq!(
+            monitor.update(500, -counts(490), false),
+            StepLoss::Correct { position: 490 }
+        );
+        assert_eq!(monitor.error(), 10);
+
+        // the firmware moves the commanded position, then stays in sync
+        // within the correction band
+        let mut commanded = 500;
+        if let StepLoss::Correct { position } = monitor.update(commanded, -counts(490), false) {
+            commanded = position;
+        }
+        assert_eq!(commanded, 490);
+        assert_eq!(
+            monitor.update(commanded, -counts(490), false),
+            StepLoss::InSync
+        );
+        assert_eq!(
+            monitor.update(commanded, -counts(487), false),
+            StepLoss::InSync
+        );
+        assert_eq!(monitor.measured(-counts(-20)), Some(-20));
+    }
+
+    #[test]
+    fn measured_rounds_to_the_nearest_step() {
+        let mut monitor = StepLossMonitor::new(config(None));
+        monitor.sync(0, 0);
+
+        // 20.48 counts per step
+        assert_eq!(monitor.measured(10), Some(0));
+        assert_eq!(monitor.measured(11), Some(1));
+        assert_eq!(monitor.measured(-11), Some(-1));
+        assert_eq!(monitor.measured(counts(1600)), Some(1600));
+    }
+}
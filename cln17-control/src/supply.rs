@@ -0,0 +1,185 @@
+//! Supply voltage feed-forward and lockout.
+//!
+//! The open loop microstep tables set a duty, so the coil voltage and with
+//! it the current follow VBUS. [`FeedForward`] returns the duty amplitude
+//! that puts a fixed voltage on the coils at the measured VBUS, for a fixed
+//! current that is the current times the coil resistance.
+//!
+//! [`SupplyMonitor`] locks the driver out below and above the VBUS limits
+//! and releases it once VBUS is back inside by the hysteresis.
+
+/// Duty amplitude for a fixed coil voltage.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FeedForward {
+    volts: f32,
+    max_duty: u16,
+}
+
+impl FeedForward {
+    /// `volts` peak coil voltage out of `max_duty` counts full scale.
+    pub fn new(volts: f32, max_duty: u16) -> Self {
+        Self { volts, max_duty }
+    }
+
+    /// Peak coil current `amps` at standstill through `r_ohm`.
+    pub fn for_current(amps: f32, r_ohm: f32, max_duty: u16) -> Self {
+        Self::new(amps * r_ohm, max_duty)
+    }
+
+    pub fn volts(&self) -> f32 {
+        self.volts
+    }
+
+    pub fn set_volts(&mut self, volts: f32) {
+        self.volts = volts;
+    }
+
+    /// Duty counts at `vbus` V, full scale when VBUS is too low for the
+    /// voltage.
+    pub fn amplitude(&self, vbus: f32) -> u16 {
+        if vbus <= self.volts {
+            return self.max_duty;
+        }
+
+        libm::roundf(self.max_duty as f32 * self.volts / vbus) as u16
+    }
+
+    /// VBUS is too low to reach the voltage.
+    pub fn is_saturated(&self, vbus: f32) -> bool {
+        vbus <= self.volts
+    }
+}
+
+/// VBUS lockout thresholds in V.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SupplyLimits {
+    pub undervoltage: f32,
+    pub overvoltage: f32,
+    /// Distance inside the limits VBUS must be back by before the release.
+    pub hysteresis: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SupplyState {
+    Ok,
+    Undervoltage,
+    Overvoltage,
+}
+
+impl SupplyState {
+    pub fn is_ok(&self) -> bool {
+        *self == SupplyState::Ok
+    }
+}
+
+/// Undervoltage and overvoltage lockout with hysteresis.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SupplyMonitor {
+    limits: SupplyLimits,
+    state: SupplyState,
+}
+
+impl SupplyMonitor {
+    /// Starts locked out until the first reading inside the limits.
+    pub fn new(limits: SupplyLimits) -> Self {
+        debug_assert!(limits.undervoltage + 2.0 * limits.hysteresis < limits.overvoltage);
+
+        Self {
+            limits,
+            state: SupplyState::Undervoltage,
+        }
+    }
+
+    pub fn limits(&self) -> &SupplyLimits {
+        &self.limits
+    }
+
+    pub fn state(&self) -> SupplyState {
+        self.state
+    }
+
+    /// Takes the next VBUS reading in V and returns the state.
+    pub fn update(&mut self, vbus: f32) -> SupplyState {
+        let SupplyLimits {
+            undervoltage,
+            overvoltage,
+            hysteresis,
+        } = self.limits;
+
+        self.state = if vbus > overvoltage {
+            SupplyState::Overvoltage
+        } else if vbus < undervoltage {
+            SupplyState::Undervoltage
+        } else {
+            match self.state {
+                SupplyState::Undervoltage if vbus < undervoltage + hysteresis => {
+                    SupplyState::Undervoltage
+                }
+                SupplyState::Overvoltage if vbus > overvoltage - hysteresis => {
+                    SupplyState::Overvoltage
+                }
+                _ => SupplyState::Ok,
+            }
+        };
+
+        self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: SupplyLimits = SupplyLimits {
+        undervoltage: 10.0,
+        overvoltage: 28.0,
+        hysteresis: 0.5,
+    };
+
+    #[test]
+    fn feed_forward_keeps_the_coil_voltage() {
+        // 1 A through 2 Ω
+        let feed_forward = FeedForward::for_current(1.0, 2.0, 1000);
+        assert_eq!(feed_forward.volts(), 2.0);
+
+        assert_eq!(feed_forward.amplitude(24.0), 83);
+        assert_eq!(feed_forward.amplitude(12.0), 167);
+        assert!(!feed_forward.is_saturated(12.0));
+
+        assert_eq!(feed_forward.amplitude(2.0), 1000);
+        assert_eq!(feed_forward.amplitude(1.0), 1000);
+        assert!(feed_forward.is_saturated(2.0));
+    }
+
+    #[test]
+    fn locked_out_until_the_supply_is_up() {
+        let mut monitor = SupplyMonitor::new(LIMITS);
+        assert_eq!(monitor.state(), SupplyState::Undervoltage);
+
+        // inside the limit but not by the hysteresis yet
+        assert_eq!(monitor.update(10.2), SupplyState::Undervoltage);
+        assert_eq!(monitor.update(10.6), SupplyState::Ok);
+        assert!(monitor.state().is_ok());
+    }
+
+    #[test]
+    fn hysteresis_at_both_limits() {
+        let mut monitor = SupplyMonitor::new(LIMITS);
+        monitor.update(24.0);
+
+        let script = [
+            (10.2, SupplyState::Ok),
+            (9.9, SupplyState::Undervoltage),
+            (10.4, SupplyState::Undervoltage),
+            (24.0, SupplyState::Ok),
+            (28.1, SupplyState::Overvoltage),
+            (27.8, SupplyState::Overvoltage),
+            (27.4, SupplyState::Ok),
+            (5.0, SupplyState::Undervoltage),
+            (30.0, SupplyState::Overvoltage),
+        ];
+        for (vbus, state) in script {
+            assert_eq!(monitor.update(vbus), state, "{} V", vbus);
+        }
+    }
+}
@@ -0,0 +1,350 @@
+//! Temperature tracking, current derating and thermal shutdown.
+//!
+//! Two sources feed [`Thermal`]: the G431 die temperature, close to the
+//! board temperature, from [`analog`](crate::analog), and the TMC2209
+//! temperature comparators in DRV_STATUS, which only tell which thresholds
+//! the driver passed. The die reading is low pass filtered, the driver
+//! flags count as the lowest temperature they prove. The hotter of the two
+//! picks the current scale from a [`DeratingPoint`] curve and trips the
+//! shutdown, which holds until the temperature fell below the restart
+//! threshold.
+//!
+//! Temperatures are in °C, scales from 0 to 1 of the configured current.
+
+/// One corner of the derating curve.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeratingPoint {
+    pub temperature: f32,
+    pub scale: f32,
+}
+
+/// Current scale at `temperature` on `curve`, ordered by temperature.
+/// The first scale below the first point, the last one above the last
+/// point and linear in between, full current for an empty curve. Two
+/// points at the same temperature make a step.
+pub fn derate(curve: &[DeratingPoint], temperature: f32) -> f32 {
+    let Some(first) = curve.first() else {
+        return 1.0;
+    };
+    if temperature <= first.temperature {
+        return first.scale;
+    }
+
+    for pair in curve.windows(2) {
+        let (low, high) = (pair[0], pair[1]);
+        // a step, no slope to take
+        if high.temperature <= low.temperature {
+            continue;
+        }
+        if temperature <= high.temperature {
+            let t = (temperature - low.temperature) / (high.temperature - low.temperature);
+            return low.scale + (high.scale - low.scale) * t;
+        }
+    }
+
+    curve[curve.len() - 1].scale
+}
+
+/// TMC2209 DRV_STATUS temperature flags.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DriverFlags {
+    /// Prewarning, 120 °C.
+    pub otpw: bool,
+    /// Shutdown, the driver switched its outputs off.
+    pub ot: bool,
+    pub t120: bool,
+    pub t143: bool,
+    pub t150: bool,
+    pub t157: bool,
+}
+
+impl DriverFlags {
+    /// Lowest temperature the flags prove, `None` below 120 °C.
+    pub fn temperature(&self) -> Option<f32> {
+        if self.t157 {
+            Some(157.0)
+        } else if self.t150 {
+            Some(150.0)
+        } else if self.t143 || self.ot {
+            Some(143.0)
+        } else if self.t120 || self.otpw {
+            Some(120.0)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ThermalConfig {
+    pub curve: &'static [DeratingPoint],
+    /// Outputs off at and above this temperature.
+    pub shutdown: f32,
+    /// Outputs back on below this temperature.
+    pub restart: f32,
+    /// Time constant of the die temperature filter in s.
+    pub filter_s: f32,
+}
+
+/// Derating of one update.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Derating {
+    /// Temperature the scale was taken at.
+    pub temperature: f32,
+    pub scale: f32,
+    pub shutdown: bool,
+}
+
+/// Filtered temperature, derating and shutdown state.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Thermal {
+    config: ThermalConfig,
+    filtered: Option<f32>,
+    shutdown: bool,
+}
+
+impl Thermal {
+    pub fn new(config: ThermalConfig) -> Self {
+        debug_assert!(config.restart < config.shutdown);
+        debug_assert!(config
+            .curve
+            .windows(2)
+            .all(|pair| pair[0].temperature <= pair[1].temperature));
+
+        Self {
+            config,
+            filtered: None,
+            shutdown: false,
+        }
+    }
+
+    pub fn config(&self) -> &ThermalConfig {
+        &self.config
+    }
+
+    /// Takes the die temperature `mcu`, if read, and the driver flags,
+    /// `dt` s after the last update. The first reading starts the filter.
+    pub fn update(&mut self, mcu: Option<f32>, driver: DriverFlags, dt: f32) -> Derating {
+        if let Some(mcu) = mcu {
+            self.filtered = Some(match self.filtered {
+                Some(filtered) => {
+                    let alpha = dt / (self.config.filter_s + dt);
+                    filtered + alpha * (mcu - filtered)
+                }
+                None => mcu,
+            });
+        }
+
+        let temperature = match (self.filtered, driver.temperature()) {
+            (Some(mcu), Some(driver)) => mcu.max(driver),
+            (Some(temperature), None) | (None, Some(temperature)) => temperature,
+            // nothing read yet and the driver is below its first threshold
+            (None, None) => f32::MIN,
+        };
+
+        if temperature >= self.config.shutdown || driver.ot {
+            self.shutdown = true;
+        } else if temperature < self.config.restart {
+            self.shutdown = false;
+        }
+
+        Derating {
+            temperature,
+            scale: derate(self.config.curve, temperature),
+            shutdown: self.shutdown,
+        }
+    }
+
+    /// Filtered die temperature.
+    pub fn mcu_temperature(&self) -> Option<f32> {
+        self.filtered
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURVE: &[DeratingPoint] = &[
+        DeratingPoint {
+            temperature: 70.0,
+            scale: 1.0,
+        },
+        DeratingPoint {
+            temperature: 90.0,
+            scale: 0.5,
+        },
+        DeratingPoint {
+            temperature: 100.0,
+            scale: 0.25,
+        },
+    ];
+
+    const CONFIG: ThermalConfig = ThermalConfig {
+        curve: CURVE,
+        shutdown: 110.0,
+        restart: 95.0,
+        filter_s: 1.0,
+    };
+
+    const COOL: DriverFlags = DriverFlags {
+        otpw: false,
+        ot: false,
+        t120: false,
+        t143: false,
+        t150: false,
+        t157: false,
+    };
+
+    /// Feeds `mcu` for `seconds` in 100 ms steps.
+    fn settle(thermal: &mut Thermal, mcu: f32, seconds: u32) -> Derating {
+        let mut derating = thermal.update(Some(mcu), COOL, 0.1);
+        for _ in 1..seconds * 10 {
+            derating = thermal.update(Some(mcu), COOL, 0.1);
+        }
+        derating
+    }
+
+    #[test]
+    fn curve_endpoints() {
+        assert_eq!(derate(CURVE, 20.0), 1.0);
+        assert_eq!(derate(CURVE, 70.0), 1.0);
+        assert!((derate(CURVE, 80.0) - 0.75).abs() < 1e-6);
+        assert!((derate(CURVE, 95.0) - 0.375).abs() < 1e-6);
+        assert_eq!(derate(CURVE, 100.0), 0.25);
+        assert_eq!(derate(CURVE, 150.0), 0.25);
+
+        assert_eq!(derate(&[], 150.0), 1.0);
+        assert_eq!(derate(&CURVE[..1], 150.0), 1.0);
+    }
+
+    #[test]
+    fn steps_in_the_curve() {
+        const STEP: &[DeratingPoint] = &[
+            DeratingPoint {
+                temperature: 80.0,
+                scale: 1.0,
+            },
+            DeratingPoint {
+                temperature: 80.0,
+                scale: 0.5,
+            },
+            DeratingPoint {
+                temperature: 80.0,
+                scale: 0.4,
+            },
+            DeratingPoint {
+                temperature: 90.0,
+                scale: 0.2,
+            },
+        ];
+
+        assert_eq!(derate(STEP, 80.0), 1.0);
+        assert!((derate(STEP, 80.5) - 0.39).abs() < 1e-6);
+        assert_eq!(derate(STEP, 95.0), 0.2);
+        for tenth in 700..1000 {
+            assert!(derate(STEP, tenth as f32 / 10.0).is_finite());
+        }
+    }
+
+    #[test]
+    fn derating_is_monotonic() {
+        let mut last = derate(CURVE, -40.0);
+        for tenth in -400..2000 {
+            let scale = derate(CURVE, tenth as f32 / 10.0);
+            assert!(scale <= last, "{} at {}", scale, tenth);
+            assert!((0.25..=1.0).contains(&scale));
+            last = scale;
+        }
+    }
+
+    #[test]
+    fn driver_flags() {
+        assert_eq!(COOL.temperature(), None);
+        let otpw = DriverFlags { otpw: true, ..COOL };
+        assert_eq!(otpw.temperature(), Some(120.0));
+        let ot = DriverFlags { ot: true, ..COOL };
+        assert_eq!(ot.temperature(), Some(143.0));
+        let hot = DriverFlags {
+            t120: true,
+            t143: true,
+            t150: true,
+            ..COOL
+        };
+        assert_eq!(hot.temperature(), Some(150.0));
+    }
+
+    #[test]
+    fn spikes_are_filtered() {
+        let mut thermal = Thermal::new(CONFIG);
+        let derating = thermal.update(Some(25.0), COOL, 0.1);
+        assert_eq!(derating.scale, 1.0);
+        assert!(!derating.shutdown);
+
+        let derating = thermal.update(Some(125.0), COOL, 0.1);
+        assert!(derating.temperature < 40.0, "{:?}", derating);
+        assert!(!derating.shutdown);
+    }
+
+    #[test]
+    fn shutdown_hysteresis() {
+        let mut thermal = Thermal::new(CONFIG);
+        let derating = settle(&mut thermal, 125.0, 20);
+        assert!(derating.shutdown);
+        assert_eq!(derating.scale, 0.25);
+
+        // between restart and shutdown the outputs stay off
+        let derating = settle(&mut thermal, 100.0, 20);
+        assert!(derating.temperature < CONFIG.shutdown);
+        assert!(derating.shutdown);
+
+        let derating = settle(&mut thermal, 50.0, 20);
+        assert!(!derating.shutdown);
+        assert_eq!(derating.scale, 1.0);
+
+        // and stay on when warming up to just below shutdown
+        let derating = settle(&mut thermal, 105.0, 20);
+        assert!(derating.temperature > CONFIG.restart);
+        assert!(!derating.shutdown);
+    }
+
+    #[test]
+    fn hard_shutdown_on_driver_overtemperature() {
+        let mut thermal = Thermal::new(CONFIG);
+        settle(&mut thermal, 40.0, 5);
+
+        // the driver is hotter than the die and dominates
+        let derating = thermal.update(None, DriverFlags { t120: true, ..COOL }, 0.1);
+        assert_eq!(derating.temperature, 120.0);
+        assert!(derating.shutdown);
+
+        // OT shuts down whatever the restart threshold says
+        let config = ThermalConfig {
+            shutdown: 200.0,
+            restart: 180.0,
+            ..CONFIG
+        };
+        let mut thermal = Thermal::new(config);
+        settle(&mut thermal, 40.0, 5);
+        let derating = thermal.update(Some(40.0), DriverFlags { ot: true, ..COOL }, 0.1);
+        assert!(derating.shutdown);
+        assert_eq!(derating.temperature, 143.0);
+        assert_eq!(derating.scale, 0.25);
+        // the flag is gone and the die is below restart
+        let derating = thermal.update(Some(40.0), COOL, 0.1);
+        assert!(!derating.shutdown);
+    }
+
+    #[test]
+    fn nothing_read_runs_at_full_current() {
+        let mut thermal = Thermal::new(CONFIG);
+        let derating = thermal.update(None, COOL, 0.1);
+        assert_eq!(derating.scale, 1.0);
+        assert!(!derating.shutdown);
+        assert_eq!(thermal.mcu_temperature(), None);
+    }
+}
@@ -0,0 +1,70 @@
+use core::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    /// Not a word, a number that does not parse or an open comment.
+    Syntax,
+    /// The `*` checksum does not match the line.
+    Checksum,
+    /// The `N` word is not the next line number.
+    LineNumber { expected: u32 },
+    /// A G or M code outside the dialect.
+    UnknownCode { letter: char, number: f32 },
+    /// Words without a G or M code.
+    MissingCode,
+    /// More than one G or M code on the line.
+    TooManyCodes,
+    /// More words than a block holds.
+    TooManyWords,
+    /// A word the code does not take or a word given twice.
+    UnexpectedWord(char),
+    /// A word without the value it needs.
+    MissingValue(char),
+    /// A value out of range, e.g. a feed rate of 0.
+    OutOfRange(char),
+    /// A move while the motor is disabled by `M18`.
+    Disabled,
+}
+
+impl ErrorKind {
+    /// The host has to send the line again, it was garbled or lost.
+    pub fn needs_resend(&self) -> bool {
+        matches!(self, ErrorKind::Checksum | ErrorKind::LineNumber { .. })
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::Syntax => f.write_str("syntax error"),
+            ErrorKind::Checksum => f.write_str("checksum mismatch"),
+            ErrorKind::LineNumber { expected } => {
+                write!(f, "line number is not the expected {}", expected)
+            }
+            ErrorKind::UnknownCode { letter, number } => {
+                write!(f, "unknown command {}{}", letter, number)
+            }
+            ErrorKind::MissingCode => f.write_str("no G or M code"),
+            ErrorKind::TooManyCodes => f.write_str("more than one G or M code"),
+            ErrorKind::TooManyWords => f.write_str("too many words"),
+            ErrorKind::UnexpectedWord(letter) => write!(f, "unexpected word {}", letter),
+            ErrorKind::MissingValue(letter) => write!(f, "no value for {}", letter),
+            ErrorKind::OutOfRange(letter) => write!(f, "{} out of range", letter),
+            ErrorKind::Disabled => f.write_str("motor disabled, M17 enables it"),
+        }
+    }
+}
+
+/// An error and the line it happened on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Error {
+    /// The `N` word of the line or, without one, the count of lines run.
+    pub line: u32,
+    pub kind: ErrorKind,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
@@ -0,0 +1,647 @@
+//! Modal state of the axis and the lines run on it.
+//!
+//! The [`Interpreter`] keeps what a line leaves behind for the next ones: the
+//! distance mode of `G90`/`G91`, the feed rate of the last `F`, the planned
+//! position of the last move, whether the motor is enabled and the line
+//! numbering `M110` resets. [`Interpreter::execute`] parses one line, checks
+//! its `N` word against that numbering and turns it into a [`Command`]. The
+//! firmware runs the command and answers `ok` once it is done, a line that
+//! fails returns an [`Error`] with its line number instead.
+
+use core::fmt;
+
+use crate::{parse, Block, Code, Error, ErrorKind};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Config {
+    /// Letter of the axis, the other axes are unexpected words.
+    pub axis: char,
+    pub steps_per_mm: f32,
+    /// `G0` feed rate and the limit of `F` in mm/min.
+    pub max_feed: f32,
+    /// `G1` feed rate until the first `F` in mm/min.
+    pub default_feed: f32,
+}
+
+/// Distance mode of the axis words.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Absolute,
+    Relative,
+}
+
+/// What the firmware runs for a line, it answers `ok` once it is done.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    /// Nothing to run, the line only changed the modal state.
+    None,
+    /// Move to `target` steps at up to `speed` steps/s.
+    Move {
+        target: i64,
+        speed: f32,
+    },
+    Home,
+    Enable,
+    Disable,
+    /// Report the position, see [`Interpreter::write_position`].
+    Position,
+    /// Report the run current or set it in mA.
+    Current(Option<u16>),
+}
+
+/// Modal state of the axis and the line numbering.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Interpreter {
+    config: Config,
+    mode: Mode,
+    /// Feed rate in mm/min.
+    feed: f32,
+    /// Planned position in steps, the target of the last move.
+    position: i64,
+    enabled: bool,
+    /// Lines run, the line of an error without `N`.
+    lines: u32,
+    /// Line number of the last line run.
+    line: u32,
+    /// Line number the next `N` word must have.
+    next_line: u32,
+}
+
+impl Interpreter {
+    /// Starts absolute at position 0 with the motor enabled.
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            mode: Mode::Absolute,
+            feed: config.default_feed,
+            position: 0,
+            enabled: true,
+            lines: 0,
+            line: 0,
+            next_line: 0,
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Feed rate of `G1` in mm/min.
+    pub fn feed(&self) -> f32 {
+        self.feed
+    }
+
+    /// Planned position in steps.
+    pub fn position(&self) -> i64 {
+        self.position
+    }
+
+    /// Takes the position of the motor after homing or a move that stopped
+    /// early, relative moves start from there.
+    pub fn set_position(&mut self, steps: i64) {
+        self.position = steps;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Line number of the last line run, its `N` word or the count of lines
+    /// run, to report errors of its command.
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    /// Line number the host sends next.
+    pub fn next_line(&self) -> u32 {
+        self.next_line
+    }
+
+    /// Runs one line without its line ending.
+    pub fn execute(&mut self, line: &str) -> Result<Command, Error> {
+        self.lines = self.lines.wrapping_add(1);
+
+        let block = match parse::parse(line) {
+            Ok(block) => block,
+            Err(kind) => {
+                self.line = parse::line_number(line).unwrap_or(self.lines);
+                return Err(Error {
+                    line: self.line,
+                    kind,
+                });
+            }
+        };
+
+        self.line = block.line.unwrap_or(self.lines);
+        self.run(&block).map_err(|kind| Error {
+            line: self.line,
+            kind,
+        })
+    }
+
+    /// Writes `X:10.00 Count X:400`, the position in mm and steps, for the
+    /// `M114` reply.
+    pub fn write_position(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        let axis = self.config.axis;
+        let mm = self.position as f32 / self.config.steps_per_mm;
+
+        write!(out, "{}:{:.2} Count {}:{}", axis, mm, axis, self.position)
+    }
+
+    fn run(&mut self, block: &Block) -> Result<Command, ErrorKind> {
+        if let Some(line) = block.line {
+            // M110 sets the numbering, its own N does not have to follow it
+            if block.code != Some(Code::M110) && line != self.next_line {
+                return Err(ErrorKind::LineNumber {
+                    expected: self.next_line,
+                });
+            }
+            self.next_line = line.wrapping_add(1);
+        }
+
+        let Some(code) = block.code else {
+            return match block.words() {
+                [] => Ok(Command::None),
+                _ => Err(ErrorKind::MissingCode),
+            };
+        };
+
+        let axis = self.config.axis;
+        match code {
+            Code::G0 | Code::G1 => {
+                expect(block, &[axis, 'F'])?;
+                self.linear(block, code == Code::G0)
+            }
+            Code::G28 => {
+                expect(block, &[axis])?;
+                if !self.enabled {
+                    return Err(ErrorKind::Disabled);
+                }
+                Ok(Command::Home)
+            }
+            Code::G90 | Code::G91 => {
+                expect(block, &[])?;
+                self.mode = match code {
+                    Code::G90 => Mode::Absolute,
+                    _ => Mode::Relative,
+                };
+                Ok(Command::None)
+            }
+            Code::M17 | Code::M18 => {
+                expect(block, &[axis])?;
+                self.enabled = code == Code::M17;
+                if self.enabled {
+                    Ok(Command::Enable)
+                } else {
+                    Ok(Command::Disable)
+                }
+            }
+            Code::M110 => {
+                expect(block, &['N'])?;
+                let line = match block.get('N') {
+                    Some(word) => {
+                        let value = word.value.ok_or(ErrorKind::MissingValue('N'))?;
+                        if value < 0. || value > u32::MAX as f32 {
+                            return Err(ErrorKind::OutOfRange('N'));
+                        }
+                        value as u32
+                    }
+                    // N110 M110 starts counting from its own number
+                    None => block.line.unwrap_or(0),
+                };
+                self.next_line = line.wrapping_add(1);
+                Ok(Command::None)
+            }
+            Code::M114 => {
+                expect(block, &[])?;
+                Ok(Command::Position)
+            }
+            Code::M906 => {
+                expect(block, &[axis])?;
+                let Some(word) = block.get(axis) else {
+                    return Ok(Command::Current(None));
+                };
+
+                let ma = word.value.ok_or(ErrorKind::MissingValue(axis))?;
+                if ma < 1. || ma > u16::MAX as f32 {
+                    return Err(ErrorKind::OutOfRange(axis));
+                }
+                Ok(Command::Current(Some(libm::roundf(ma) as u16)))
+            }
+        }
+    }
+
+    fn linear(&mut self, block: &Block, rapid: bool) -> Result<Command, ErrorKind> {
+        let axis = self.config.axis;
+
+        if let Some(word) = block.get('F') {
+            let feed = word.value.ok_or(ErrorKind::MissingValue('F'))?;
+            if feed <= 0. {
+                return Err(ErrorKind::OutOfRange('F'));
+            }
+            self.feed = feed;
+        }
+
+        // G1 F3000 only sets the feed rate
+        let Some(word) = block.get(axis) else {
+            return Ok(Command::None);
+        };
+        let distance = word.value.ok_or(ErrorKind::MissingValue(axis))?;
+        if !self.enabled {
+            return Err(ErrorKind::Disabled);
+        }
+
+        let steps = libm::roundf(distance * self.config.steps_per_mm);
+        // well inside the i64 range and exact in f32
+        if steps.is_nan() || steps.abs() >= (1 << 24) as f32 {
+            return Err(ErrorKind::OutOfRange(axis));
+        }
+        let target = match self.mode {
+            Mode::Absolute => steps as i64,
+            Mode::Relative => self.position + steps as i64,
+        };
+        if target == self.position {
+            return Ok(Command::None);
+        }
+
+        let feed = if rapid {
+            self.config.max_feed
+        } else {
+            self.feed.min(self.config.max_feed)
+        };
+        self.position = target;
+
+        Ok(Command::Move {
+            target,
+            speed: feed / 60. * self.config.steps_per_mm,
+        })
+    }
+}
+
+// fails on words outside `allowed`
+fn expect(block: &Block, allowed: &[char]) -> Result<(), ErrorKind> {
+    match block
+        .words()
+        .iter()
+        .find(|word| !allowed.contains(&word.letter))
+    {
+        Some(word) => Err(ErrorKind::UnexpectedWord(word.letter)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{
+        format,
+        string::{String, ToString},
+        vec::Vec,
+    };
+
+    use super::*;
+
+    // 40 steps/mm, 1 mm/min is 2/3 steps/s
+    const CONFIG: Config = Config {
+        axis: 'X',
+        steps_per_mm: 40.,
+        max_feed: 12_000.,
+        default_feed: 3000.,
+    };
+
+    fn checksum(body: &str) -> String {
+        format!(
+            "{}*{}",
+            body,
+            body.bytes().fold(0u8, |sum, byte| sum ^ byte)
+        )
+    }
+
+    fn run(program: &str) -> (Interpreter, Vec<Result<Command, Error>>) {
+        let mut interpreter = Interpreter::new(CONFIG);
+        let results = program
+            .lines()
+            .map(|line| interpreter.execute(line))
+            .collect();
+        (interpreter, results)
+    }
+
+    #[test]
+    fn sample_program() {
+        let program = "\
+; home and run back and forth
+G28
+G90
+G1 X10 F600
+G1 X5
+G91
+G1 X-2.5
+G0 X1
+M114
+G90
+G1 F6000
+G1 X0
+M906 X800
+M906
+M18
+M17
+";
+        let (interpreter, results) = run(program);
+        let commands: Vec<_> = results.into_iter().map(Result::unwrap).collect();
+        assert_eq!(
+            commands,
+            [
+                Command::None,
+                Command::Home,
+                Command::None,
+                Command::Move {
+                    target: 400,
+                    speed: 400.
+                },
+                Command::Move {
+                    target: 200,
+                    speed: 400.
+                },
+                Command::None,
+                Command::Move {
+                    target: 100,
+                    speed: 400.
+                },
+                // G0 runs at max_feed
+                Command::Move {
+                    target: 140,
+                    speed: 8000.
+                },
+                Command::Position,
+                Command::None,
+                // F alone only sets the feed
+                Command::None,
+                Command::Move {
+                    target: 0,
+                    speed: 4000.
+                },
+                Command::Current(Some(800)),
+                Command::Current(None),
+                Command::Disable,
+                Command::Enable,
+            ]
+        );
+        assert_eq!(interpreter.mode(), Mode::Absolute);
+        assert_eq!(interpreter.feed(), 6000.);
+        assert!(interpreter.is_enabled());
+    }
+
+    #[test]
+    fn feed_and_position() {
+        let (interpreter, results) =
+            run("G1 X12.345\nG1 X12.345\nG1 X1 F100000\nG1 X\nG1 F0\nG1 F-5");
+        assert_eq!(
+            results[0],
+            Ok(Command::Move {
+                target: 494,
+                speed: 2000.
+            })
+        );
+        // already there
+        assert_eq!(results[1], Ok(Command::None));
+        // the feed is capped at max_feed
+        assert_eq!(
+            results[2],
+            Ok(Command::Move {
+                target: 40,
+                speed: 8000.
+            })
+        );
+        assert_eq!(
+            results[3],
+            Err(Error {
+                line: 4,
+                kind: ErrorKind::MissingValue('X')
+            })
+        );
+        assert_eq!(
+            results[4],
+            Err(Error {
+                line: 5,
+                kind: ErrorKind::OutOfRange('F')
+            })
+        );
+        assert_eq!(results[5].unwrap_err().kind, ErrorKind::OutOfRange('F'));
+        // the bad feeds did not change it
+        assert_eq!(interpreter.feed(), 100_000.);
+
+        let mut reply = String::new();
+        interpreter.write_position(&mut reply).unwrap();
+        assert_eq!(reply, "X:1.00 Count X:40");
+    }
+
+    #[test]
+    fn moves_up_to_2_24_steps() {
+        let mut interpreter = Interpreter::new(CONFIG);
+        // 16 777 200 steps
+        assert_eq!(
+            interpreter.execute("G0 X419430"),
+            Ok(Command::Move {
+                target: 16_777_200,
+                speed: 8000.
+            })
+        );
+        assert_eq!(
+            interpreter.execute("G0 X-419430.4"),
+            Err(Error {
+                line: 2,
+                kind: ErrorKind::OutOfRange('X')
+            })
+        );
+        assert_eq!(
+            interpreter.execute("G1 X1000000").unwrap_err().kind,
+            ErrorKind::OutOfRange('X')
+        );
+        assert_eq!(interpreter.position(), 16_777_200);
+
+        // relative moves are limited by their distance, not the position
+        interpreter.execute("G91").unwrap();
+        assert_eq!(
+            interpreter.execute("G0 X419430"),
+            Ok(Command::Move {
+                target: 33_554_400,
+                speed: 8000.
+            })
+        );
+    }
+
+    #[test]
+    fn relative_after_set_position() {
+        let mut interpreter = Interpreter::new(CONFIG);
+        interpreter.execute("G91").unwrap();
+        assert_eq!(
+            interpreter.execute("G1 X10"),
+            Ok(Command::Move {
+                target: 400,
+                speed: 2000.
+            })
+        );
+
+        // the move stopped early at -13, the next one starts from there
+        interpreter.set_position(-13);
+        assert_eq!(
+            interpreter.execute("G1 X1"),
+            Ok(Command::Move {
+                target: 27,
+                speed: 2000.
+            })
+        );
+        assert_eq!(interpreter.position(), 27);
+
+        // absolute moves do not depend on it
+        interpreter.execute("G90").unwrap();
+        interpreter.set_position(5);
+        assert_eq!(
+            interpreter.execute("G1 X1"),
+            Ok(Command::Move {
+                target: 40,
+                speed: 2000.
+            })
+        );
+    }
+
+    #[test]
+    fn errors_carry_line_numbers() {
+        let (_, results) = run("G1 Y10\nM114 X1\nX10\nG2\nM906 X0\nM906 X\nM110 X1\nG1 X1 garbage");
+        let errors: Vec<_> = results
+            .iter()
+            .map(|result| {
+                let error = result.unwrap_err();
+                (error.line, error.kind)
+            })
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                (1, ErrorKind::UnexpectedWord('Y')),
+                (2, ErrorKind::UnexpectedWord('X')),
+                (3, ErrorKind::MissingCode),
+                (
+                    4,
+                    ErrorKind::UnknownCode {
+                        letter: 'G',
+                        number: 2.
+                    }
+                ),
+                (5, ErrorKind::OutOfRange('X')),
+                (6, ErrorKind::MissingValue('X')),
+                (7, ErrorKind::UnexpectedWord('X')),
+                (8, ErrorKind::TooManyCodes),
+            ]
+        );
+        assert_eq!(
+            results[3].unwrap_err().to_string(),
+            "line 4: unknown command G2"
+        );
+    }
+
+    #[test]
+    fn disabled_motor() {
+        let (interpreter, results) = run("M18 X\nG1 X10\nG28\nG1 F100\nM17\nG1 X10");
+        assert_eq!(results[0], Ok(Command::Disable));
+        assert_eq!(
+            results[1],
+            Err(Error {
+                line: 2,
+                kind: ErrorKind::Disabled
+            })
+        );
+        assert_eq!(results[2].unwrap_err().kind, ErrorKind::Disabled);
+        // the feed still changes while disabled
+        assert_eq!(results[3], Ok(Command::None));
+        assert_eq!(results[4], Ok(Command::Enable));
+        assert_eq!(
+            results[5],
+            Ok(Command::Move {
+                target: 400,
+                speed: 100. / 60. * 40.
+            })
+        );
+        assert!(interpreter.is_enabled());
+    }
+
+    #[test]
+    fn host_line_numbering() {
+        let mut interpreter = Interpreter::new(CONFIG);
+        assert_eq!(
+            interpreter.execute(&checksum("N0 M110 N0")),
+            Ok(Command::None)
+        );
+        assert_eq!(interpreter.next_line(), 1);
+        assert_eq!(interpreter.execute(&checksum("N1 G28")), Ok(Command::Home));
+
+        // a garbled line keeps the count, the host sends it again
+        let error = interpreter.execute("N2 G1 X10*0").unwrap_err();
+        assert_eq!(
+            error,
+            Error {
+                line: 2,
+                kind: ErrorKind::Checksum
+            }
+        );
+        assert!(error.kind.needs_resend());
+        assert_eq!(interpreter.next_line(), 2);
+
+        // a lost line
+        let error = interpreter.execute(&checksum("N3 G1 X10")).unwrap_err();
+        assert_eq!(
+            error,
+            Error {
+                line: 3,
+                kind: ErrorKind::LineNumber { expected: 2 }
+            }
+        );
+        assert!(error.kind.needs_resend());
+        assert_eq!(
+            interpreter.execute(&checksum("N2 G1 X10")),
+            Ok(Command::Move {
+                target: 400,
+                speed: 2000.
+            })
+        );
+        assert_eq!(
+            interpreter.execute(&checksum("N3 G1 X20")),
+            Ok(Command::Move {
+                target: 800,
+                speed: 2000.
+            })
+        );
+
+        // lines without N are taken as they come, errors count them
+        assert_eq!(interpreter.execute("M114"), Ok(Command::Position));
+        assert_eq!(interpreter.execute("G5").unwrap_err().line, 8);
+        assert!(!ErrorKind::Syntax.needs_resend());
+
+        // M110 without N counts from its own line number
+        assert_eq!(
+            interpreter.execute(&checksum("N100 M110")),
+            Ok(Command::None)
+        );
+        assert_eq!(interpreter.next_line(), 101);
+        assert_eq!(interpreter.execute("M110"), Ok(Command::None));
+        assert_eq!(interpreter.next_line(), 1);
+    }
+
+    #[test]
+    fn last_line() {
+        let mut interpreter = Interpreter::new(CONFIG);
+        interpreter.execute("G28").unwrap();
+        assert_eq!(interpreter.line(), 1);
+        interpreter.execute(&checksum("N9 M110")).unwrap();
+        assert_eq!(interpreter.line(), 9);
+        interpreter.execute("N10 G1 X1*0").unwrap_err();
+        assert_eq!(interpreter.line(), 10);
+        interpreter.execute("G1 X2").unwrap();
+        assert_eq!(interpreter.line(), 4);
+    }
+}
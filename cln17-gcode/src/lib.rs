@@ -0,0 +1,33 @@
+//! G-code for a single CLN17 axis, driven from printer hosts.
+//!
+//! Plain `no_std` code without allocations or hardware access, it runs and
+//! tests on the host as well:
+//!
+//! - [`parse`] one line into a [`Block`]: line number, checksum, comments
+//!   and the words of one G or M code
+//! - the [`Interpreter`] keeps the modal state, distance mode, feed rate and
+//!   planned position, and turns every block into a [`Command`] for the
+//!   firmware to run
+//!
+//! The dialect is a small Marlin subset:
+//!
+//! - `G0`, `G1` move the axis, `F` sets the feed rate in mm/min, `G0` runs
+//!   at the top feed rate
+//! - `G28` homes
+//! - `G90`, `G91` select absolute or relative distances
+//! - `M17`, `M18` enable and disable the motor
+//! - `M110 N` sets the line number
+//! - `M114` reports the position
+//! - `M906` reports the run current, with a value in mA it sets it
+//!
+//! Errors carry the line number, the `N` word or the count of lines run.
+
+#![no_std]
+
+mod error;
+mod interpreter;
+pub mod parse;
+
+pub use error::{Error, ErrorKind};
+pub use interpreter::{Command, Config, Interpreter, Mode};
+pub use parse::{Block, Code, Word};
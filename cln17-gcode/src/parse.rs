@@ -0,0 +1,286 @@
+//! One line of G-code.
+//!
+//! `N12 G1 X10.5 F3000*85` is an optional line number, one G or M code, its
+//! parameter words and an optional checksum, the XOR of all bytes before the
+//! `*`. Words may be written without spaces, `G1X10`, and in lower case.
+//! Comments in parentheses and after `;` are skipped.
+
+use crate::ErrorKind;
+
+/// Parameter words a block holds.
+pub const MAX_WORDS: usize = 8;
+
+/// G and M codes of the dialect.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Code {
+    G0,
+    G1,
+    G28,
+    G90,
+    G91,
+    M17,
+    M18,
+    M110,
+    M114,
+    M906,
+}
+
+impl Code {
+    fn new(letter: char, number: f32) -> Option<Self> {
+        let code = match (letter, number as u16) {
+            _ if number < 0. || libm::truncf(number) != number => return None,
+            ('G', 0) => Code::G0,
+            ('G', 1) => Code::G1,
+            ('G', 28) => Code::G28,
+            ('G', 90) => Code::G90,
+            ('G', 91) => Code::G91,
+            ('M', 17) => Code::M17,
+            ('M', 18) => Code::M18,
+            ('M', 110) => Code::M110,
+            ('M', 114) => Code::M114,
+            ('M', 906) => Code::M906,
+            _ => return None,
+        };
+
+        Some(code)
+    }
+}
+
+/// A parameter, `X10` or a bare `X` as in `G28 X`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Word {
+    pub letter: char,
+    pub value: Option<f32>,
+}
+
+/// Line number, code and parameters of one line.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Block {
+    /// `N` word in front of the code.
+    pub line: Option<u32>,
+    pub code: Option<Code>,
+    words: [Word; MAX_WORDS],
+    len: usize,
+}
+
+impl Block {
+    pub fn words(&self) -> &[Word] {
+        &self.words[..self.len]
+    }
+
+    pub fn get(&self, letter: char) -> Option<Word> {
+        self.words()
+            .iter()
+            .find(|word| word.letter == letter)
+            .copied()
+    }
+
+    /// Value of the word `letter`, `None` when it is missing or bare.
+    pub fn value(&self, letter: char) -> Option<f32> {
+        self.get(letter)?.value
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.line.is_none() && self.code.is_none() && self.len == 0
+    }
+
+    fn add(&mut self, letter: char, value: Option<f32>) -> Result<(), ErrorKind> {
+        match letter {
+            // a line number only comes first, M110 takes N as its parameter
+            'N' if self.line.is_none() && self.code.is_none() && self.len == 0 => {
+                self.line = Some(integer(value).ok_or(ErrorKind::Syntax)?);
+            }
+            'G' | 'M' => {
+                if self.code.is_some() {
+                    return Err(ErrorKind::TooManyCodes);
+                }
+
+                let number = value.ok_or(ErrorKind::Syntax)?;
+                let code = Code::new(letter, number);
+                self.code = Some(code.ok_or(ErrorKind::UnknownCode { letter, number })?);
+            }
+            _ => {
+                if self.get(letter).is_some() {
+                    return Err(ErrorKind::UnexpectedWord(letter));
+                }
+                if self.len == MAX_WORDS {
+                    return Err(ErrorKind::TooManyWords);
+                }
+
+                self.words[self.len] = Word { letter, value };
+                self.len += 1;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses one line without its line ending.
+pub fn parse(line: &str) -> Result<Block, ErrorKind> {
+    let line = line.split(';').next().unwrap_or("");
+
+    let body = match line.split_once('*') {
+        Some((body, checksum)) => {
+            let checksum: u8 = checksum.trim().parse().map_err(|_| ErrorKind::Syntax)?;
+            if body.bytes().fold(0, |sum, byte| sum ^ byte) != checksum {
+                return Err(ErrorKind::Checksum);
+            }
+            body
+        }
+        None => line,
+    };
+
+    let bytes = body.as_bytes();
+    let mut block = Block::default();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b' ' | b'\t' | b'\r' | b'\n' => i += 1,
+            b'(' => match bytes[i..].iter().position(|&byte| byte == b')') {
+                Some(end) => i += end + 1,
+                None => return Err(ErrorKind::Syntax),
+            },
+            byte if byte.is_ascii_alphabetic() => {
+                let start = i + 1;
+                i = start;
+                while i < bytes.len() && matches!(bytes[i], b'0'..=b'9' | b'.' | b'-' | b'+') {
+                    i += 1;
+                }
+
+                let value = match &body[start..i] {
+                    "" => None,
+                    number => Some(number.parse().map_err(|_| ErrorKind::Syntax)?),
+                };
+                block.add(byte.to_ascii_uppercase() as char, value)?;
+            }
+            _ => return Err(ErrorKind::Syntax),
+        }
+    }
+
+    Ok(block)
+}
+
+/// Line number of a line that may not parse, to report its error.
+pub fn line_number(line: &str) -> Option<u32> {
+    let rest = line.trim_start().strip_prefix(['N', 'n'])?;
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+
+    rest[..end].parse().ok()
+}
+
+fn integer(value: Option<f32>) -> Option<u32> {
+    let value = value?;
+    if value < 0. || libm::truncf(value) != value || value > u32::MAX as f32 {
+        return None;
+    }
+
+    Some(value as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{format, string::String};
+
+    use super::*;
+
+    // the line with the checksum a host appends
+    fn checksum(body: &str) -> String {
+        format!(
+            "{}*{}",
+            body,
+            body.bytes().fold(0u8, |sum, byte| sum ^ byte)
+        )
+    }
+
+    #[test]
+    fn words() {
+        let block = parse("n7 g1x-10.5F3000 (comment) ; rest").unwrap();
+        assert_eq!(block.line, Some(7));
+        assert_eq!(block.code, Some(Code::G1));
+        assert_eq!(block.value('X'), Some(-10.5));
+        assert_eq!(block.value('F'), Some(3000.));
+        assert_eq!(block.words().len(), 2);
+
+        let block = parse("G28 X").unwrap();
+        assert_eq!(block.get('X').unwrap().value, None);
+        assert_eq!(block.value('X'), None);
+    }
+
+    #[test]
+    fn empty_lines() {
+        assert!(parse("").unwrap().is_empty());
+        assert!(parse("  ; only a comment").unwrap().is_empty());
+        assert!(parse("(only a comment)").unwrap().is_empty());
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert_eq!(parse("G1 X1 (open"), Err(ErrorKind::Syntax));
+        assert_eq!(parse("G1 X1..2"), Err(ErrorKind::Syntax));
+        assert_eq!(parse("G1 #"), Err(ErrorKind::Syntax));
+        assert_eq!(parse("G"), Err(ErrorKind::Syntax));
+        assert_eq!(parse("N-1 G1"), Err(ErrorKind::Syntax));
+        assert_eq!(parse("N1.5 G1"), Err(ErrorKind::Syntax));
+    }
+
+    #[test]
+    fn codes_and_words() {
+        assert_eq!(parse("G1 G0"), Err(ErrorKind::TooManyCodes));
+        assert_eq!(parse("G1 X1 X2"), Err(ErrorKind::UnexpectedWord('X')));
+        assert_eq!(
+            parse("G2 X1"),
+            Err(ErrorKind::UnknownCode {
+                letter: 'G',
+                number: 2.
+            })
+        );
+        assert_eq!(
+            parse("G38.2"),
+            Err(ErrorKind::UnknownCode {
+                letter: 'G',
+                number: 38.2
+            })
+        );
+        assert_eq!(
+            parse("M-17"),
+            Err(ErrorKind::UnknownCode {
+                letter: 'M',
+                number: -17.
+            })
+        );
+        assert_eq!(
+            parse("G1 A1 B1 C1 D1 E1 F1 H1 I1 J1"),
+            Err(ErrorKind::TooManyWords)
+        );
+    }
+
+    #[test]
+    fn checksums() {
+        let line = checksum("N3 G1 X10");
+        assert_eq!(parse(&line).unwrap().line, Some(3));
+        assert_eq!(parse("N3 G1 X10*1"), Err(ErrorKind::Checksum));
+        assert_eq!(parse("N3 G1 X10*x"), Err(ErrorKind::Syntax));
+
+        // the comment after ; is not part of the sum
+        let line = format!("{} ; comment", checksum("N4 G28"));
+        assert_eq!(parse(&line).unwrap().code, Some(Code::G28));
+    }
+
+    #[test]
+    fn line_numbers() {
+        assert_eq!(line_number(" N42G1"), Some(42));
+        assert_eq!(line_number("n7 G1 X1*0"), Some(7));
+        assert_eq!(line_number("G1 N4"), None);
+
+        // N after the code is the M110 parameter
+        let block = parse("N0 M110 N5").unwrap();
+        assert_eq!(block.line, Some(0));
+        assert_eq!(block.value('N'), Some(5.));
+    }
+}
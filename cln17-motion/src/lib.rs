@@ -0,0 +1,22 @@
+//! Motion planning for the CLN17 steppers.
+//!
+//! Plain `no_std` math without any hardware access, the firmware feeds the
+//! results into its timers. Runs and tests on the host as well:
+//!
+//! - [`trapezoid`] speed profiles from rest to rest with separate
+//!   acceleration and deceleration
+//! - jerk limited [`scurve`] profiles, sampled per step or per control tick
+//! - a [`stepgen`] step generator that counts the emitted pulses and moves to
+//!   absolute targets
+//! - [`steptrain`] buffers of timer periods streamed to the step output by DMA
+//! - sine/cosine [`microstep`] tables for the DRV8844 bridge inputs
+//! - [`waveform`] speed, direction and stop control of the streamed table
+
+#![no_std]
+
+pub mod microstep;
+pub mod scurve;
+pub mod stepgen;
+pub mod steptrain;
+pub mod trapezoid;
+pub mod waveform;
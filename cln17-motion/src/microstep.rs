@@ -0,0 +1,249 @@
+//! Sine/cosine microstep tables for the DRV8844 bridges.
+//!
+//! One electrical revolution is four full steps. Coil A (IN1/IN2) carries
+//! `amplitude * cos(angle)`, coil B (IN3/IN4) `amplitude * sin(angle)`. A
+//! positive coil current puts the duty on IN1 (IN3) and holds IN2 (IN4) low,
+//! a negative one the other way round.
+//!
+//! A table holds one frame per microstep of an electrical revolution, each
+//! frame the duty of the four bridge inputs in the order the timer channels
+//! are written by the DMA burst, CCR1 to CCR4.
+
+use libm::{cosf, roundf, sinf};
+
+pub const MIN_MICROSTEPS: u16 = 2;
+pub const MAX_MICROSTEPS: u16 = 256;
+
+/// Words per microstep frame, one per bridge input.
+pub const FRAME_LEN: usize = 4;
+
+/// Full steps per electrical revolution.
+pub const FULL_STEPS: u32 = 4;
+
+/// Frame position of IN1 to IN4.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChannelMap {
+    slots: [usize; FRAME_LEN],
+}
+
+impl ChannelMap {
+    /// IN1 to IN4 on timer channels 1 to 4.
+    pub const IN_ORDER: ChannelMap = ChannelMap {
+        slots: [0, 1, 2, 3],
+    };
+
+    /// Map from the 1 based timer channel of IN1 to IN4.
+    pub const fn from_channels(channels: [u8; FRAME_LEN]) -> Self {
+        Self {
+            slots: [
+                channels[0] as usize - 1,
+                channels[1] as usize - 1,
+                channels[2] as usize - 1,
+                channels[3] as usize - 1,
+            ],
+        }
+    }
+
+    /// Frame position of IN`n`, `n` in 1..=4.
+    pub fn slot(&self, n: usize) -> usize {
+        self.slots[n - 1]
+    }
+
+    /// Frame in timer channel order from the duty of IN1 to IN4.
+    pub fn frame(&self, duty: [u16; FRAME_LEN]) -> [u16; FRAME_LEN] {
+        let mut frame = [0; FRAME_LEN];
+        for (n, duty) in duty.into_iter().enumerate() {
+            frame[self.slots[n]] = duty;
+        }
+
+        frame
+    }
+}
+
+/// Signed coil currents in timer duty counts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CoilCurrents {
+    pub a: i32,
+    pub b: i32,
+}
+
+impl CoilCurrents {
+    /// Duty of IN1 to IN4.
+    pub fn bridge_duty(&self) -> [u16; FRAME_LEN] {
+        let (in1, in2) = split(self.a);
+        let (in3, in4) = split(self.b);
+
+        [in1, in2, in3, in4]
+    }
+}
+
+/// Duty of the high and the low input of one bridge pair.
+fn split(current: i32) -> (u16, u16) {
+    let duty = current.unsigned_abs().min(u16::MAX as u32) as u16;
+
+    if current >= 0 {
+        (duty, 0)
+    } else {
+        (0, duty)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableError {
+    /// Microsteps must be a power of two from 2 to 256.
+    Microsteps,
+    BufferTooSmall {
+        needed: usize,
+    },
+}
+
+pub fn is_valid_microsteps(microsteps: u16) -> bool {
+    microsteps.is_power_of_two() && (MIN_MICROSTEPS..=MAX_MICROSTEPS).contains(&microsteps)
+}
+
+/// Microsteps per electrical revolution.
+pub const fn steps_per_cycle(microsteps: u16) -> u32 {
+    FULL_STEPS * microsteps as u32
+}
+
+/// Table length in words for `microsteps`.
+pub const fn table_len(microsteps: u16) -> usize {
+    steps_per_cycle(microsteps) as usize * FRAME_LEN
+}
+
+/// Coil currents of microstep `index` of an electrical revolution.
+///
+/// Every quadrant is built from the first one, so the table is exactly
+/// symmetric whatever the rounding.
+pub fn coil_currents(index: u32, microsteps: u16, amplitude: u16) -> CoilCurrents {
+    let microsteps = microsteps as u32;
+    let index = index % (FULL_STEPS * microsteps);
+    let quadrant = index / microsteps;
+
+    let angle = (index % microsteps) as f32 * core::f32::consts::FRAC_PI_2 / microsteps as f32;
+    let cos = roundf(amplitude as f32 * cosf(angle)) as i32;
+    let sin = roundf(amplitude as f32 * sinf(angle)) as i32;
+
+    let (a, b) = match quadrant {
+        0 => (cos, sin),
+        1 => (-sin, cos),
+        2 => (-cos, -sin),
+        _ => (sin, -cos),
+    };
+
+    CoilCurrents { a, b }
+}
+
+/// Fills `buf` with one electrical revolution of `microsteps` frames per
+/// full step at `amplitude` duty counts. Returns the number of words used.
+pub fn fill_table(
+    buf: &mut [u16],
+    microsteps: u16,
+    amplitude: u16,
+    map: &ChannelMap,
+) -> Result<usize, TableError> {
+    if !is_valid_microsteps(microsteps) {
+        return Err(TableError::Microsteps);
+    }
+
+    let needed = table_len(microsteps);
+    if buf.len() < needed {
+        return Err(TableError::BufferTooSmall { needed });
+    }
+
+    for (index, frame) in buf[..needed].chunks_exact_mut(FRAME_LEN).enumerate() {
+        let duty = coil_currents(index as u32, microsteps, amplitude).bridge_duty();
+        frame.copy_from_slice(&map.frame(duty));
+    }
+
+    Ok(needed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_map_permutes_the_frame() {
+        let map = ChannelMap::from_channels([2, 1, 4, 3]);
+        assert_eq!(map.slot(1), 1);
+        assert_eq!(map.slot(4), 2);
+        assert_eq!(map.frame([10, 20, 30, 40]), [20, 10, 40, 30]);
+
+        let map = ChannelMap::from_channels([3, 4, 1, 2]);
+        assert_eq!(map.frame([10, 20, 30, 40]), [30, 40, 10, 20]);
+
+        assert_eq!(ChannelMap::IN_ORDER.frame([1, 2, 3, 4]), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn coil_currents_are_in_quadrature() {
+        assert_eq!(coil_currents(0, 16, 500), CoilCurrents { a: 500, b: 0 });
+        assert_eq!(coil_currents(16, 16, 500), CoilCurrents { a: 0, b: 500 });
+        assert_eq!(coil_currents(32, 16, 500), CoilCurrents { a: -500, b: 0 });
+        assert_eq!(coil_currents(48, 16, 500), CoilCurrents { a: 0, b: -500 });
+        assert_eq!(coil_currents(64, 16, 500), coil_currents(0, 16, 500));
+
+        // half way between two full steps both coils carry cos 45°
+        let c = coil_currents(8, 16, 500);
+        assert_eq!((c.a, c.b), (354, 354));
+
+        assert_eq!(coil_currents(32, 16, 500).bridge_duty(), [0, 500, 0, 0]);
+        assert_eq!(coil_currents(16, 16, 500).bridge_duty(), [0, 0, 500, 0]);
+    }
+
+    #[test]
+    fn table_holds_one_revolution() {
+        let map = ChannelMap::from_channels([2, 1, 4, 3]);
+        let mut buf = [0; table_len(MAX_MICROSTEPS)];
+
+        for microsteps in [2, 4, 16, 256] {
+            let len = fill_table(&mut buf, microsteps, 1000, &map).unwrap();
+            assert_eq!(len, 16 * microsteps as usize);
+
+            for (index, frame) in buf[..len].chunks_exact(FRAME_LEN).enumerate() {
+                let (in1, in2, in3, in4) = (frame[1], frame[0], frame[3], frame[2]);
+                // one input of a bridge is always held low
+                assert!(in1 == 0 || in2 == 0);
+                assert!(in3 == 0 || in4 == 0);
+
+                let angle =
+                    index as f32 * core::f32::consts::TAU / steps_per_cycle(microsteps) as f32;
+                let (a, b) = (in1 as f32 - in2 as f32, in3 as f32 - in4 as f32);
+                assert!(
+                    (a - 1000. * cosf(angle)).abs() <= 0.501,
+                    "{} {}",
+                    microsteps,
+                    index
+                );
+                assert!(
+                    (b - 1000. * sinf(angle)).abs() <= 0.501,
+                    "{} {}",
+                    microsteps,
+                    index
+                );
+                // the current vector keeps the amplitude
+                let length = libm::sqrtf(a * a + b * b);
+                assert!((length - 1000.).abs() <= 1., "{} {}", microsteps, index);
+            }
+        }
+    }
+
+    #[test]
+    fn table_rejects_bad_sizes() {
+        let map = ChannelMap::IN_ORDER;
+        let mut buf = [0; 64];
+
+        assert!(!is_valid_microsteps(1));
+        assert!(!is_valid_microsteps(512));
+        assert_eq!(
+            fill_table(&mut buf, 3, 100, &map),
+            Err(TableError::Microsteps)
+        );
+        assert_eq!(
+            fill_table(&mut buf[..10], 4, 100, &map),
+            Err(TableError::BufferTooSmall { needed: 64 })
+        );
+        assert_eq!(fill_table(&mut buf, 4, 100, &map), Ok(64));
+    }
+}
@@ -0,0 +1,423 @@
+//! Jerk limited 7 segment S-curve profile of a move from rest to rest.
+//!
+//! The acceleration ramps up and down with `max_jerk` instead of jumping, so
+//! the motor sees no force steps and belt axes ring less:
+//!
+//! ```text
+//! segment   1     2     3     4      5     6     7
+//! jerk     +j     0    -j     0     -j     0    +j
+//! accel    ramp  a_pk  ramp   0     ramp -a_pk ramp
+//!          '---- accelerate ----' cruise '--- decelerate ---'
+//! ```
+//!
+//! Short moves drop the constant acceleration segments 2 and 6 when
+//! `max_accel` is not reached, and the cruise segment 4 when `max_speed` is
+//! not reached. The profile is symmetric, deceleration mirrors acceleration.
+//!
+//! Units are free as long as they match, steps or mm and s.
+
+use libm::{cbrtf, sqrtf};
+
+/// Limits in distance units per s, s² and s³, all must be positive.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct JerkLimits {
+    pub max_speed: f32,
+    pub max_accel: f32,
+    pub max_jerk: f32,
+}
+
+/// Position, velocity and acceleration at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Sample {
+    pub position: f32,
+    pub velocity: f32,
+    pub accel: f32,
+}
+
+const SEGMENTS: usize = 7;
+
+/// Jerk of each segment in units of `max_jerk`.
+const JERK_SIGN: [f32; SEGMENTS] = [1.0, 0.0, -1.0, 0.0, -1.0, 0.0, 1.0];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SCurve {
+    distance: f32,
+    jerk: f32,
+    peak_speed: f32,
+    peak_accel: f32,
+    /// Duration of each segment.
+    durations: [f32; SEGMENTS],
+    /// State at the start of each segment.
+    starts: [Sample; SEGMENTS],
+}
+
+impl SCurve {
+    /// Plans a move over `distance`, negative distances are planned as their
+    /// absolute value.
+    pub fn new(distance: f32, limits: &JerkLimits) -> Self {
+        debug_assert!(limits.max_speed > 0.0 && limits.max_accel > 0.0 && limits.max_jerk > 0.0);
+
+        let distance = distance.abs();
+        let (accel, jerk) = (limits.max_accel, limits.max_jerk);
+
+        // speed at which a_max is just reached at the end of the jerk segment
+        let full_accel_speed = accel * accel / jerk;
+
+        let mut peak_speed = limits.max_speed;
+        if 2.0 * accel_distance(peak_speed, accel, jerk) > distance {
+            // no cruise, find the speed where both ramps meet
+            peak_speed = cbrtf(distance * distance * jerk / 4.0);
+            if peak_speed > full_accel_speed {
+                let t = accel / jerk;
+                peak_speed = accel * (sqrtf(t * t + 4.0 * distance / accel) - t) / 2.0;
+            }
+            peak_speed = peak_speed.min(limits.max_speed);
+        }
+
+        let (jerk_time, accel_time) = accel_times(peak_speed, accel, jerk);
+        let cruise = (distance - 2.0 * accel_distance(peak_speed, accel, jerk)) / peak_speed;
+        let cruise_time = if cruise.is_finite() {
+            cruise.max(0.0)
+        } else {
+            0.0
+        };
+
+        let durations = [
+            jerk_time,
+            accel_time,
+            jerk_time,
+            cruise_time,
+            jerk_time,
+            accel_time,
+            jerk_time,
+        ];
+
+        let mut starts = [Sample::default(); SEGMENTS];
+        let mut state = Sample::default();
+        for (i, start) in starts.iter_mut().enumerate() {
+            *start = state;
+            state = advance(&state, JERK_SIGN[i] * jerk, durations[i]);
+        }
+
+        Self {
+            distance,
+            jerk,
+            peak_speed,
+            peak_accel: jerk * jerk_time,
+            durations,
+            starts,
+        }
+    }
+
+    pub fn distance(&self) -> f32 {
+        self.distance
+    }
+
+    /// Highest speed of the move, below `max_speed` for short moves.
+    pub fn peak_speed(&self) -> f32 {
+        self.peak_speed
+    }
+
+    /// Highest acceleration of the move, below `max_accel` for short moves.
+    pub fn peak_accel(&self) -> f32 {
+        self.peak_accel
+    }
+
+    /// Duration of the 7 segments, 0 for collapsed ones.
+    pub fn durations(&self) -> &[f32; SEGMENTS] {
+        &self.durations
+    }
+
+    /// Time from start to standstill.
+    pub fn duration(&self) -> f32 {
+        self.durations.iter().sum()
+    }
+
+    /// State at time `t` after the start, clamped to the move.
+    pub fn sample(&self, t: f32) -> Sample {
+        let mut t = t.max(0.0);
+
+        for (seg, &duration) in self.durations.iter().enumerate() {
+            if t < duration {
+                let sample = advance(&self.starts[seg], JERK_SIGN[seg] * self.jerk, t);
+
+                return Sample {
+                    position: sample.position.min(self.distance),
+                    ..sample
+                };
+            }
+            t -= duration;
+        }
+
+        Sample {
+            position: self.distance,
+            ..Sample::default()
+        }
+    }
+
+    /// Time between step `n - 1` and step `n`, `n` in `1..=distance`.
+    ///
+    /// The interval of step 1 is counted from the start of the move.
+    pub fn interval(&self, n: u32) -> f32 {
+        let (from, to) = ((n - 1) as f32, n as f32);
+
+        // near the final standstill the position is too flat in f32 to solve
+        // for the time, take the mirrored span of the ramp up instead
+        if from + to > self.distance {
+            self.span(self.distance - to, self.distance - from)
+        } else {
+            self.span(from, to)
+        }
+    }
+
+    /// Time at which `position` is passed.
+    pub fn time_at(&self, position: f32) -> f32 {
+        if 2.0 * position > self.distance {
+            return self.duration() - self.forward_time_at(self.distance - position);
+        }
+        self.forward_time_at(position)
+    }
+
+    fn forward_time_at(&self, position: f32) -> f32 {
+        let (seg, t) = self.locate(position);
+        self.durations[..seg].iter().sum::<f32>() + t
+    }
+
+    /// Time to travel from `from` to `to`.
+    fn span(&self, from: f32, to: f32) -> f32 {
+        let (seg0, t0) = self.locate(from);
+        let (seg1, t1) = self.locate(to);
+
+        if seg0 == seg1 {
+            // the local time of a long cruise is too coarse for a difference
+            if JERK_SIGN[seg0] == 0.0 && self.starts[seg0].accel == 0.0 {
+                return (to - from) / self.peak_speed;
+            }
+            return t1 - t0;
+        }
+
+        let between: f32 = self.durations[seg0 + 1..seg1].iter().sum();
+        self.durations[seg0] - t0 + between + t1
+    }
+
+    /// Segment and time within the segment at which `position` is passed.
+    fn locate(&self, position: f32) -> (usize, f32) {
+        let position = position.clamp(0.0, self.distance);
+
+        // last segment starting at or before the position, skipping empty ones
+        let seg = (0..SEGMENTS)
+            .rev()
+            .find(|&i| self.durations[i] > 0.0 && self.starts[i].position <= position)
+            .unwrap_or(0);
+
+        let start = &self.starts[seg];
+        let jerk = JERK_SIGN[seg] * self.jerk;
+        let duration = self.durations[seg];
+        let dist = position - start.position;
+
+        if dist <= 0.0 || duration <= 0.0 {
+            return (seg, 0.0);
+        }
+
+        // from standstill the position is j t³ / 6
+        if start.velocity <= 0.0 && start.accel <= 0.0 && jerk > 0.0 {
+            return (seg, cbrtf(6.0 * dist / jerk).min(duration));
+        }
+
+        // position grows monotonic in the segment, newton steps kept inside a bisection bracket
+        let (mut lo, mut hi) = (0.0, duration);
+        let mut t = if start.velocity > 0.0 {
+            (dist / start.velocity).min(duration)
+        } else {
+            duration / 2.0
+        };
+
+        for _ in 0..32 {
+            let s = advance(start, jerk, t);
+            let err = s.position - start.position - dist;
+
+            if err > 0.0 {
+                hi = t;
+            } else {
+                lo = t;
+            }
+
+            let next = if s.velocity > 0.0 {
+                t - err / s.velocity
+            } else {
+                f32::NAN
+            };
+            let next = if next > lo && next < hi {
+                next
+            } else {
+                (lo + hi) / 2.0
+            };
+
+            if (next - t).abs() <= f32::EPSILON * duration {
+                return (seg, next);
+            }
+            t = next;
+        }
+
+        (seg, t)
+    }
+}
+
+/// Durations of the jerk and the constant acceleration segment to reach `speed`.
+fn accel_times(speed: f32, accel: f32, jerk: f32) -> (f32, f32) {
+    if speed * jerk >= accel * accel {
+        (accel / jerk, speed / accel - accel / jerk)
+    } else {
+        (sqrtf(speed / jerk), 0.0)
+    }
+}
+
+/// Distance covered while accelerating from rest to `speed`.
+fn accel_distance(speed: f32, accel: f32, jerk: f32) -> f32 {
+    let (jerk_time, accel_time) = accel_times(speed, accel, jerk);
+
+    // the ramp is point symmetric, the mean speed is speed / 2
+    speed * (2.0 * jerk_time + accel_time) / 2.0
+}
+
+/// State after `t` with constant `jerk`.
+fn advance(start: &Sample, jerk: f32, t: f32) -> Sample {
+    Sample {
+        position: start.position
+            + start.velocity * t
+            + start.accel * t * t / 2.0
+            + jerk * t * t * t / 6.0,
+        velocity: start.velocity + start.accel * t + jerk * t * t / 2.0,
+        accel: start.accel + jerk * t,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: JerkLimits = JerkLimits {
+        max_speed: 4000.,
+        max_accel: 8000.,
+        max_jerk: 200_000.,
+    };
+
+    // samples the move densely and checks it against the limits
+    fn check_limits(profile: &SCurve, limits: &JerkLimits) {
+        let duration = profile.duration();
+        let dt = duration / 4000.;
+
+        let mut last = profile.sample(0.);
+        for i in 1..=4000 {
+            let sample = profile.sample(dt * i as f32);
+
+            assert!(sample.velocity >= -0.01 && sample.velocity <= limits.max_speed * 1.0001);
+            assert!(sample.accel.abs() <= limits.max_accel * 1.0001);
+            assert!(sample.position + 1e-3 >= last.position);
+            // a segment boundary in between can halve the step, never more
+            let jerk = (sample.accel - last.accel).abs() / dt;
+            assert!(jerk <= limits.max_jerk * 1.01, "jerk {} at {}", jerk, i);
+
+            last = sample;
+        }
+    }
+
+    #[test]
+    fn long_move_stays_in_the_limits() {
+        let profile = SCurve::new(2500., &LIMITS);
+
+        assert_eq!(profile.peak_speed(), 4000.);
+        assert_eq!(profile.peak_accel(), 8000.);
+        assert!(profile.durations()[3] > 0.);
+        check_limits(&profile, &LIMITS);
+
+        let end = profile.sample(profile.duration());
+        assert_eq!(end.position, 2500.);
+        assert_eq!(end.velocity, 0.);
+    }
+
+    #[test]
+    fn short_move_never_cruises() {
+        // a_max is not reached either, the ramps are two jerk segments each
+        let profile = SCurve::new(20., &LIMITS);
+        let peak = cbrtf(20. * 20. * 200_000. / 4.);
+
+        assert!((profile.peak_speed() - peak).abs() < 0.01);
+        assert!(profile.peak_speed() < LIMITS.max_speed);
+        assert!(profile.peak_accel() < LIMITS.max_accel);
+        let durations = profile.durations();
+        assert_eq!((durations[1], durations[3], durations[5]), (0., 0., 0.));
+        check_limits(&profile, &LIMITS);
+
+        // the peak is reached half way
+        let middle = profile.sample(profile.duration() / 2.);
+        assert!((middle.position - 10.).abs() < 0.01);
+        assert!((middle.velocity - profile.peak_speed()).abs() < 0.1);
+
+        // a_max is reached but not v_max
+        let profile = SCurve::new(1500., &LIMITS);
+        assert!(profile.peak_speed() < LIMITS.max_speed);
+        assert_eq!(profile.peak_accel(), 8000.);
+        assert_eq!(profile.durations()[3], 0.);
+        check_limits(&profile, &LIMITS);
+        let end = profile.sample(profile.duration());
+        assert!((end.position - 1500.).abs() < 0.01);
+    }
+
+    #[test]
+    fn intervals_add_up_to_the_duration() {
+        for distance in [1u32, 3, 100, 1500, 2500, 100_000] {
+            let profile = SCurve::new(distance as f32, &LIMITS);
+
+            let sum: f64 = (1..=distance).map(|n| profile.interval(n) as f64).sum();
+            let duration = profile.duration() as f64;
+            assert!(
+                (sum - duration).abs() < 1e-4 * duration,
+                "{}: {} {}",
+                distance,
+                sum,
+                duration
+            );
+            assert!((1..=distance).all(|n| profile.interval(n) > 0.));
+        }
+    }
+
+    #[test]
+    fn deceleration_mirrors_acceleration() {
+        let profile = SCurve::new(2500., &LIMITS);
+
+        for n in 1..=2500 {
+            assert_eq!(
+                profile.interval(n),
+                profile.interval(2501 - n),
+                "step {}",
+                n
+            );
+        }
+        assert!((profile.time_at(2500.) - profile.duration()).abs() < 1e-6);
+        assert!((profile.time_at(1250.) - profile.duration() / 2.).abs() < 1e-4);
+    }
+
+    #[test]
+    fn time_at_inverts_sample() {
+        let profile = SCurve::new(1500., &LIMITS);
+
+        for i in 1..30 {
+            let position = 1500. * i as f32 / 30.;
+            let sample = profile.sample(profile.time_at(position));
+            assert!(
+                (sample.position - position).abs() < 0.05,
+                "{} {}",
+                position,
+                sample.position
+            );
+        }
+    }
+
+    #[test]
+    fn empty_move() {
+        let profile = SCurve::new(0., &LIMITS);
+        assert_eq!(profile.duration(), 0.);
+        assert_eq!(profile.sample(1.), Sample::default());
+    }
+}
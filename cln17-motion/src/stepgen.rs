@@ -0,0 +1,366 @@
+//! Step generator moving to absolute positions along [`Trapezoid`] or
+//! [`SCurve`] profiles.
+//!
+//! The firmware takes the interval of the next step from
+//! [`StepGenerator::next_ticks`], programs it into the step timer and reports
+//! every emitted pulse with [`StepGenerator::on_step`]. The position counts
+//! emitted pulses only, so a move cut short by [`StepGenerator::stop`] leaves
+//! it where the motor is.
+
+use crate::scurve::{JerkLimits, SCurve};
+use crate::trapezoid::{Limits, Trapezoid};
+
+/// Longest single move, the profile math is exact up to here.
+pub const MAX_MOVE_STEPS: u32 = 1 << 24;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Position counts up.
+    Forward,
+    /// Position counts down.
+    Backward,
+}
+
+impl Direction {
+    fn sign(self) -> i64 {
+        match self {
+            Direction::Forward => 1,
+            Direction::Backward => -1,
+        }
+    }
+}
+
+/// Shape of the speed ramps of a move.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ramp {
+    /// Constant acceleration and deceleration.
+    Trapezoid,
+    /// Jerk limited with `jerk` in steps/s³, the lower of `accel` and `decel`
+    /// limits both ramps.
+    SCurve { jerk: f32 },
+}
+
+/// Speed profile of one move in steps.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Profile {
+    Trapezoid(Trapezoid),
+    SCurve(SCurve),
+}
+
+impl Profile {
+    pub fn new(steps: u32, limits: &Limits, ramp: Ramp) -> Self {
+        match ramp {
+            Ramp::Trapezoid => Profile::Trapezoid(Trapezoid::new(steps, limits)),
+            Ramp::SCurve { jerk } => Profile::SCurve(SCurve::new(
+                steps as f32,
+                &JerkLimits {
+                    max_speed: limits.max_speed,
+                    max_accel: limits.accel.min(limits.decel),
+                    max_jerk: jerk,
+                },
+            )),
+        }
+    }
+
+    pub fn steps(&self) -> u32 {
+        match self {
+            Profile::Trapezoid(profile) => profile.steps(),
+            Profile::SCurve(profile) => profile.distance() as u32,
+        }
+    }
+
+    /// Time between step `n - 1` and step `n` in s, `n` in `1..=steps`.
+    pub fn interval(&self, n: u32) -> f32 {
+        match self {
+            Profile::Trapezoid(profile) => profile.interval(n),
+            Profile::SCurve(profile) => profile.interval(n),
+        }
+    }
+
+    /// Time from start to standstill in s.
+    pub fn duration(&self) -> f32 {
+        match self {
+            Profile::Trapezoid(profile) => profile.duration(),
+            Profile::SCurve(profile) => profile.duration(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MoveError {
+    /// A move is still running.
+    Busy,
+    /// The move is longer than [`MAX_MOVE_STEPS`].
+    TooFar,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StepGenerator {
+    limits: Limits,
+    ramp: Ramp,
+    position: i64,
+    direction: Direction,
+    profile: Profile,
+    /// Steps of `profile` handed out by `next_interval`.
+    scheduled: u32,
+    /// Steps of `profile` reported by `on_step`.
+    emitted: u32,
+    /// Rounding left over from the last `next_ticks`, in ticks.
+    residue: f32,
+}
+
+impl StepGenerator {
+    /// Idle at position 0 with [`Ramp::Trapezoid`] ramps.
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            ramp: Ramp::Trapezoid,
+            position: 0,
+            direction: Direction::Forward,
+            profile: Profile::new(0, &limits, Ramp::Trapezoid),
+            scheduled: 0,
+            emitted: 0,
+            residue: 0.0,
+        }
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// New limits apply from the next move.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn ramp(&self) -> Ramp {
+        self.ramp
+    }
+
+    /// The new ramp shape applies from the next move.
+    pub fn set_ramp(&mut self, ramp: Ramp) {
+        self.ramp = ramp;
+    }
+
+    /// Emitted steps counted from the last [`StepGenerator::set_position`].
+    pub fn position(&self) -> i64 {
+        self.position
+    }
+
+    /// Redefines the current position, e.g. to 0 after homing.
+    pub fn set_position(&mut self, position: i64) -> Result<(), MoveError> {
+        if self.is_moving() {
+            return Err(MoveError::Busy);
+        }
+        self.position = position;
+
+        Ok(())
+    }
+
+    /// Where the running move ends, the position when idle.
+    pub fn target(&self) -> i64 {
+        self.position + self.direction.sign() * self.remaining() as i64
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.emitted < self.profile.steps()
+    }
+
+    /// Steps of the running move not emitted yet.
+    pub fn remaining(&self) -> u32 {
+        self.profile.steps() - self.emitted
+    }
+
+    /// Profile of the running or last move.
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    /// Plans a move to `target`. Returns the direction to set on the DIR pin
+    /// before the first step, `None` when already there.
+    pub fn move_to(&mut self, target: i64) -> Result<Option<Direction>, MoveError> {
+        let steps = target.checked_sub(self.position).ok_or(MoveError::TooFar)?;
+        self.move_by(steps)
+    }
+
+    /// Plans a move of `steps` steps relative to the current position.
+    pub fn move_by(&mut self, steps: i64) -> Result<Option<Direction>, MoveError> {
+        if self.is_moving() {
+            return Err(MoveError::Busy);
+        }
+        if steps.unsigned_abs() > MAX_MOVE_STEPS as u64 {
+            return Err(MoveError::TooFar);
+        }
+        if steps == 0 {
+            return Ok(None);
+        }
+
+        self.direction = if steps > 0 {
+            Direction::Forward
+        } else {
+            Direction::Backward
+        };
+        self.profile = Profile::new(steps.unsigned_abs() as u32, &self.limits, self.ramp);
+        self.scheduled = 0;
+        self.emitted = 0;
+        self.residue = 0.0;
+
+        Ok(Some(self.direction))
+    }
+
+    /// Interval in s before the next step, `None` when every step of the move
+    /// is scheduled.
+    pub fn next_interval(&mut self) -> Option<f32> {
+        if self.scheduled >= self.profile.steps() {
+            return None;
+        }
+        self.scheduled += 1;
+
+        Some(self.profile.interval(self.scheduled))
+    }
+
+    /// [`StepGenerator::next_interval`] in ticks of a `tick_hz` timer. The
+    /// rounding is carried over to the next step, so the step times do not
+    /// drift from the profile.
+    pub fn next_ticks(&mut self, tick_hz: f32) -> Option<u32> {
+        let exact = self.next_interval()? * tick_hz + self.residue;
+        let ticks = (exact + 0.5) as u32;
+        self.residue = exact - ticks as f32;
+
+        Some(ticks)
+    }
+
+    /// Counts a step pulse emitted by the hardware.
+    pub fn on_step(&mut self) {
+        if self.emitted < self.scheduled {
+            self.emitted += 1;
+            self.position += self.direction.sign();
+        }
+    }
+
+    /// Drops the rest of the move at once, e.g. on a stall or a fault. Steps
+    /// scheduled but not reported by [`StepGenerator::on_step`] are lost.
+    pub fn stop(&mut self) {
+        self.profile = Profile::new(0, &self.limits, Ramp::Trapezoid);
+        self.scheduled = 0;
+        self.emitted = 0;
+        self.residue = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: Limits = Limits {
+        max_speed: 4000.,
+        accel: 8000.,
+        decel: 16_000.,
+    };
+
+    // emits the whole move, returns the steps and the total time in ticks
+    fn run(generator: &mut StepGenerator, tick_hz: f32) -> (u32, u64) {
+        let (mut steps, mut ticks) = (0, 0);
+        while let Some(interval) = generator.next_ticks(tick_hz) {
+            generator.on_step();
+            steps += 1;
+            ticks += interval as u64;
+        }
+
+        (steps, ticks)
+    }
+
+    // runs a 2500 step move backward along `ramp`
+    fn check_move(ramp: Ramp) {
+        let mut generator = StepGenerator::new(LIMITS);
+        generator.set_ramp(ramp);
+
+        assert_eq!(generator.move_to(-2500), Ok(Some(Direction::Backward)));
+        assert_eq!(generator.target(), -2500);
+        assert_eq!(generator.move_to(5), Err(MoveError::Busy));
+
+        let (steps, ticks) = run(&mut generator, 1e6);
+        assert_eq!(steps, 2500);
+        assert_eq!(generator.position(), -2500);
+        assert!(!generator.is_moving());
+
+        // the rounding residue carries over, the tick sum does not drift
+        let duration = generator.profile().duration() as f64 * 1e6;
+        assert!(
+            (ticks as f64 - duration).abs() <= 2.,
+            "{} {}",
+            ticks,
+            duration
+        );
+    }
+
+    #[test]
+    fn trapezoid_move_emits_exactly_the_planned_steps() {
+        check_move(Ramp::Trapezoid);
+    }
+
+    #[test]
+    fn scurve_move_emits_exactly_the_planned_steps() {
+        check_move(Ramp::SCurve { jerk: 200_000. });
+    }
+
+    #[test]
+    fn moves_are_relative_to_the_position() {
+        let mut generator = StepGenerator::new(LIMITS);
+        generator.set_position(100).unwrap();
+
+        assert_eq!(generator.move_to(100), Ok(None));
+        assert_eq!(generator.move_by(-30), Ok(Some(Direction::Backward)));
+        run(&mut generator, 1e6);
+        assert_eq!(generator.position(), 70);
+
+        assert_eq!(
+            generator.move_by(MAX_MOVE_STEPS as i64 + 1),
+            Err(MoveError::TooFar)
+        );
+        assert_eq!(
+            generator.move_by(-(MAX_MOVE_STEPS as i64) - 1),
+            Err(MoveError::TooFar)
+        );
+        // the distance does not fit an i64
+        assert_eq!(generator.move_to(i64::MIN), Err(MoveError::TooFar));
+    }
+
+    #[test]
+    fn stop_keeps_only_the_emitted_steps() {
+        let mut generator = StepGenerator::new(LIMITS);
+        generator.move_to(1000).unwrap();
+
+        for _ in 0..10 {
+            generator.next_interval();
+            generator.on_step();
+        }
+        // scheduled but never emitted
+        generator.next_interval();
+        assert_eq!(generator.set_position(0), Err(MoveError::Busy));
+
+        generator.stop();
+        assert_eq!(generator.position(), 10);
+        assert_eq!(generator.target(), 10);
+        assert!(!generator.is_moving());
+        assert_eq!(generator.next_interval(), None);
+    }
+
+    #[test]
+    fn steps_are_counted_once_scheduled() {
+        let mut generator = StepGenerator::new(LIMITS);
+        generator.move_to(3).unwrap();
+
+        // a step report without a scheduled step is ignored
+        generator.on_step();
+        assert_eq!(generator.position(), 0);
+
+        generator.next_interval();
+        generator.on_step();
+        assert_eq!((generator.position(), generator.remaining()), (1, 2));
+    }
+}
@@ -0,0 +1,294 @@
+//! Step pulse trains streamed to the step timer by a circular DMA.
+//!
+//! The step timer runs in PWM mode 2 with the compare value `pulse_ticks`:
+//! every period starts low and the step edge rises at `pulse_ticks`. Each
+//! update event requests a DMA transfer of the next ARR value from the buffer
+//! straight into the not preloaded ARR, so every entry is the length of one
+//! period and with that the time from its step to the next one. Entries
+//! shorter than `pulse_ticks` end before the edge, they fill the buffer once
+//! the move is done.
+//!
+//! The DMA raises a half transfer and a transfer complete interrupt, the
+//! firmware hands the half just read to [`StepTrain::refill`]. A half must
+//! last longer than the refill takes, size the buffer for the top speed.
+//!
+//! ```text
+//! buffer   | first half            | second half           |
+//!            ^ DMA reads             ^ half transfer: refill the first half
+//! ```
+
+use crate::stepgen::StepGenerator;
+
+/// Half of the circular buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Half {
+    First,
+    Second,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StepTrain {
+    tick_hz: f32,
+    pulse_ticks: u16,
+    /// Steps of the move without a buffer entry yet.
+    pending: u32,
+    /// Entries before this index are counted as emitted.
+    counted: usize,
+}
+
+impl StepTrain {
+    /// `pulse_ticks` is the compare value of the step channel, at least 1.
+    pub fn new(tick_hz: f32, pulse_ticks: u16) -> Self {
+        debug_assert!(pulse_ticks >= 1);
+
+        Self {
+            tick_hz,
+            pulse_ticks,
+            pending: 0,
+            counted: 0,
+        }
+    }
+
+    pub fn pulse_ticks(&self) -> u16 {
+        self.pulse_ticks
+    }
+
+    /// ARR of a period without a step edge.
+    pub fn idle_arr(&self) -> u16 {
+        self.pulse_ticks - 1
+    }
+
+    /// Shortest period with a step edge, the edge needs a high phase too.
+    fn min_step_ticks(&self) -> u32 {
+        self.pulse_ticks as u32 + 2
+    }
+
+    /// Starts the move planned in `steps`: fills the whole buffer and counts
+    /// the first step. Returns the ARR to load before the timer is enabled,
+    /// its first period carries step 1, `None` when there is no move.
+    ///
+    /// The DMA must start at the beginning of `buf` with the first update.
+    pub fn start(&mut self, steps: &mut StepGenerator, buf: &mut [u16]) -> Option<u16> {
+        debug_assert!(buf.len() >= 2 && buf.len().is_multiple_of(2));
+
+        // the interval of step 1 is the wait from standstill, step 1 comes at once
+        steps.next_ticks(self.tick_hz)?;
+        steps.on_step();
+        self.pending = steps.remaining();
+
+        let first = self.next_arr(steps);
+
+        self.counted = 0;
+        self.fill(steps, buf);
+
+        Some(first)
+    }
+
+    /// Counts the steps of the `half` the DMA just finished and fills it with
+    /// the next periods. Returns the number of steps counted, the move is
+    /// complete once `steps` is no longer moving.
+    pub fn refill(&mut self, steps: &mut StepGenerator, buf: &mut [u16], half: Half) -> u32 {
+        let mid = buf.len() / 2;
+        let (start, end) = match half {
+            Half::First => (0, mid),
+            Half::Second => (mid, buf.len()),
+        };
+
+        let emitted = self.count(steps, &buf[self.counted.max(start)..end]);
+        self.counted = if end == buf.len() { 0 } else { end };
+
+        self.fill(steps, &mut buf[start..end]);
+
+        emitted
+    }
+
+    /// Counts the steps up to `read_pos`, the index of the entry the DMA
+    /// reads next, and drops the rest of the move. Call it with the timer
+    /// stopped and the pending DMA interrupts served. Returns the number of
+    /// steps counted.
+    pub fn stop(&mut self, steps: &mut StepGenerator, buf: &[u16], read_pos: usize) -> u32 {
+        let read_pos = read_pos.min(buf.len());
+        let emitted = if read_pos > self.counted {
+            self.count(steps, &buf[self.counted..read_pos])
+        } else {
+            0
+        };
+
+        self.pending = 0;
+        self.counted = 0;
+        steps.stop();
+
+        emitted
+    }
+
+    fn count(&self, steps: &mut StepGenerator, entries: &[u16]) -> u32 {
+        let mut emitted = 0;
+
+        for &arr in entries {
+            if arr >= self.pulse_ticks {
+                steps.on_step();
+                emitted += 1;
+            }
+        }
+
+        emitted
+    }
+
+    fn fill(&mut self, steps: &mut StepGenerator, entries: &mut [u16]) {
+        for entry in entries.iter_mut() {
+            *entry = if self.pending > 0 {
+                self.pending -= 1;
+                self.next_arr(steps)
+            } else {
+                self.idle_arr()
+            };
+        }
+    }
+
+    /// Period of the next scheduled step, the last step gets the shortest one.
+    fn next_arr(&self, steps: &mut StepGenerator) -> u16 {
+        let ticks = steps
+            .next_ticks(self.tick_hz)
+            .unwrap_or(0)
+            .clamp(self.min_step_ticks(), u16::MAX as u32 + 1);
+
+        (ticks - 1) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stepgen::Ramp;
+    use crate::trapezoid::Limits;
+
+    const LIMITS: Limits = Limits {
+        max_speed: 4000.,
+        accel: 8000.,
+        decel: 16_000.,
+    };
+
+    const PULSE: u16 = 2;
+
+    fn generator(steps: i64, ramp: Ramp) -> StepGenerator {
+        let mut generator = StepGenerator::new(LIMITS);
+        generator.set_ramp(ramp);
+        generator.move_by(steps).unwrap();
+
+        generator
+    }
+
+    // runs the timer and the DMA over the buffer of `len` entries, checks
+    // the time between the step edges against the generator intervals
+    fn check_stream(steps: i64, len: usize, ramp: Ramp) {
+        let mut reference = generator(steps, ramp);
+        reference.next_ticks(1e6);
+
+        let mut generator = generator(steps, ramp);
+        let mut train = StepTrain::new(1e6, PULSE);
+        let mut storage = [0; 64];
+        let buf = &mut storage[..len];
+
+        let mut arr = train.start(&mut generator, buf).unwrap();
+        let (mut time, mut last_edge) = (0u64, None);
+        let (mut edges, mut counted) = (0, 1);
+        let mut read_pos = 0;
+
+        while generator.is_moving() || arr >= PULSE {
+            if arr >= PULSE {
+                let edge = time + PULSE as u64;
+                if let Some(last) = last_edge {
+                    let expected = reference.next_ticks(1e6).unwrap().clamp(4, 65_536);
+                    assert_eq!(edge - last, expected as u64, "step {}", edges + 1);
+                }
+                last_edge = Some(edge);
+                edges += 1;
+            }
+            time += arr as u64 + 1;
+
+            // update event, the DMA loads the next ARR
+            arr = buf[read_pos];
+            read_pos += 1;
+            if read_pos == len / 2 {
+                counted += train.refill(&mut generator, buf, Half::First);
+            } else if read_pos == len {
+                read_pos = 0;
+                counted += train.refill(&mut generator, buf, Half::Second);
+            }
+        }
+
+        assert_eq!(
+            edges,
+            steps.unsigned_abs(),
+            "{} steps, {} entries",
+            steps,
+            len
+        );
+        assert_eq!(counted as u64, steps.unsigned_abs());
+        assert_eq!(generator.position(), steps);
+    }
+
+    #[test]
+    fn streams_every_step_with_its_interval() {
+        for steps in [1, 2, 3, 7, 8, 9, 100, -1000, 5000] {
+            for len in [2, 8, 64] {
+                check_stream(steps, len, Ramp::Trapezoid);
+                check_stream(steps, len, Ramp::SCurve { jerk: 100_000. });
+            }
+        }
+    }
+
+    #[test]
+    fn refill_counts_the_half_just_read() {
+        let mut generator = generator(10, Ramp::Trapezoid);
+        let mut train = StepTrain::new(1e6, PULSE);
+        let mut buf = [0; 8];
+
+        // the first period carries step 1, the buffer steps 2 to 9
+        assert!(train.start(&mut generator, &mut buf).is_some());
+        assert_eq!(generator.position(), 1);
+        assert!(buf.iter().all(|&arr| arr >= PULSE));
+
+        // half transfer: steps 2 to 5 are out, step 10 and idle periods follow
+        assert_eq!(train.refill(&mut generator, &mut buf, Half::First), 4);
+        assert_eq!(generator.position(), 5);
+        assert_eq!(buf[0], PULSE + 1);
+        assert!(buf[1..4].iter().all(|&arr| arr == train.idle_arr()));
+
+        // transfer complete: steps 6 to 9, nothing left to plan
+        assert_eq!(train.refill(&mut generator, &mut buf, Half::Second), 4);
+        assert_eq!(generator.position(), 9);
+        assert!(buf[4..].iter().all(|&arr| arr == train.idle_arr()));
+        assert!(generator.is_moving());
+
+        assert_eq!(train.refill(&mut generator, &mut buf, Half::First), 1);
+        assert_eq!(generator.position(), 10);
+        assert!(!generator.is_moving());
+
+        // idle periods count nothing
+        assert_eq!(train.refill(&mut generator, &mut buf, Half::Second), 0);
+    }
+
+    #[test]
+    fn stop_counts_up_to_the_read_position() {
+        let mut generator = generator(1000, Ramp::Trapezoid);
+        let mut train = StepTrain::new(1e6, PULSE);
+        let mut buf = [0; 16];
+
+        train.start(&mut generator, &mut buf).unwrap();
+        assert_eq!(train.refill(&mut generator, &mut buf, Half::First), 8);
+
+        // 3 entries of the second half read before the stop
+        assert_eq!(train.stop(&mut generator, &buf, 11), 3);
+        assert_eq!(generator.position(), 1 + 8 + 3);
+        assert!(!generator.is_moving());
+    }
+
+    #[test]
+    fn no_move_no_start() {
+        let mut generator = StepGenerator::new(LIMITS);
+        let mut train = StepTrain::new(1e6, PULSE);
+
+        assert_eq!(train.start(&mut generator, &mut [0; 4]), None);
+    }
+}
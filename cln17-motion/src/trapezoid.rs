@@ -0,0 +1,263 @@
+//! Trapezoidal speed profile of a move from rest to rest.
+//!
+//! The motor accelerates with `accel` up to `max_speed`, cruises and slows
+//! down with `decel` to standstill at the last step. Moves too short to reach
+//! `max_speed` turn into a triangle.
+//!
+//! Step `n` is emitted when the ideal position reaches `n`, the time at
+//! position `s` of a move of `steps` steps is
+//!
+//! ```text
+//! accelerating:  t(s) = sqrt(2 s / accel)
+//! cruising:      t(s) = t_accel + (s - s_accel) / v_peak
+//! decelerating:  t(s) = t_total - sqrt(2 (steps - s) / decel)
+//! ```
+
+use libm::sqrtf;
+
+/// Speed limits in steps/s and steps/s², all must be positive.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    pub max_speed: f32,
+    pub accel: f32,
+    pub decel: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Trapezoid {
+    steps: u32,
+    accel: f32,
+    decel: f32,
+    peak_speed: f32,
+    /// Distance covered while accelerating, in steps.
+    accel_dist: f32,
+    /// Distance covered while decelerating, in steps.
+    decel_dist: f32,
+}
+
+/// `sqrt(a) - sqrt(b)` without the cancellation of two close square roots.
+fn sqrt_diff(a: f32, b: f32) -> f32 {
+    let sum = sqrtf(a) + sqrtf(b);
+    if sum > 0.0 {
+        (a - b) / sum
+    } else {
+        0.0
+    }
+}
+
+impl Trapezoid {
+    /// Plans a move of `steps` steps, exact up to 2^24 steps.
+    pub fn new(steps: u32, limits: &Limits) -> Self {
+        debug_assert!(limits.max_speed > 0.0 && limits.accel > 0.0 && limits.decel > 0.0);
+
+        let dist = steps as f32;
+        let (accel, decel) = (limits.accel, limits.decel);
+
+        // peak speed of the triangle, v²/2a + v²/2d = dist
+        let triangle = sqrtf(2.0 * dist * accel * decel / (accel + decel));
+        let peak_speed = triangle.min(limits.max_speed);
+
+        let accel_dist = (peak_speed * peak_speed / (2.0 * accel)).min(dist);
+        let decel_dist = (peak_speed * peak_speed / (2.0 * decel)).min(dist - accel_dist);
+
+        Self {
+            steps,
+            accel,
+            decel,
+            peak_speed,
+            accel_dist,
+            decel_dist,
+        }
+    }
+
+    pub fn steps(&self) -> u32 {
+        self.steps
+    }
+
+    /// Highest speed of the move, below `max_speed` for a triangle.
+    pub fn peak_speed(&self) -> f32 {
+        self.peak_speed
+    }
+
+    pub fn accel_steps(&self) -> f32 {
+        self.accel_dist
+    }
+
+    pub fn decel_steps(&self) -> f32 {
+        self.decel_dist
+    }
+
+    /// Time from start to standstill in s.
+    pub fn duration(&self) -> f32 {
+        self.time_between(0.0, self.steps as f32)
+    }
+
+    /// Time between step `n - 1` and step `n` in s, `n` in `1..=steps`.
+    ///
+    /// The interval of step 1 is counted from the start of the move.
+    pub fn interval(&self, n: u32) -> f32 {
+        debug_assert!(n >= 1 && n <= self.steps);
+
+        self.time_between((n - 1) as f32, n as f32)
+    }
+
+    /// Ideal speed at position `s` in steps/s.
+    pub fn speed_at(&self, s: f32) -> f32 {
+        let dist = self.steps as f32;
+
+        if s <= 0.0 || s >= dist {
+            0.0
+        } else if s < self.accel_dist {
+            sqrtf(2.0 * self.accel * s)
+        } else if s > dist - self.decel_dist {
+            sqrtf(2.0 * self.decel * (dist - s))
+        } else {
+            self.peak_speed
+        }
+    }
+
+    /// Travel time from position `from` to `to` within the move.
+    fn time_between(&self, from: f32, to: f32) -> f32 {
+        let dist = self.steps as f32;
+        let cruise_end = dist - self.decel_dist;
+        let mut t = 0.0;
+
+        if from < self.accel_dist {
+            let end = to.min(self.accel_dist);
+            t += sqrt_diff(2.0 * end / self.accel, 2.0 * from / self.accel);
+        }
+
+        let (start, end) = (from.max(self.accel_dist), to.min(cruise_end));
+        if end > start {
+            t += (end - start) / self.peak_speed;
+        }
+
+        if to > cruise_end {
+            let start = from.max(cruise_end);
+            t += sqrt_diff(
+                2.0 * (dist - start) / self.decel,
+                2.0 * (dist - to) / self.decel,
+            );
+        }
+
+        t
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: Limits = Limits {
+        max_speed: 4000.,
+        accel: 8000.,
+        decel: 16_000.,
+    };
+
+    fn close(a: f32, b: f32, tolerance: f32) -> bool {
+        (a - b).abs() <= tolerance
+    }
+
+    #[test]
+    fn long_move_cruises_at_max_speed() {
+        let profile = Trapezoid::new(10_000, &LIMITS);
+
+        assert_eq!(profile.peak_speed(), 4000.);
+        // v² / 2a
+        assert_eq!(profile.accel_steps(), 1000.);
+        assert_eq!(profile.decel_steps(), 500.);
+
+        let cruise = (10_000. - 1000. - 500.) / 4000.;
+        let expected = 4000. / 8000. + 4000. / 16_000. + cruise;
+        assert!(close(profile.duration(), expected, 1e-4));
+
+        // the intervals add up to the duration
+        let sum: f64 = (1..=10_000).map(|n| profile.interval(n) as f64).sum();
+        assert!((sum - expected as f64).abs() < 1e-3, "{}", sum);
+
+        assert!(close(profile.interval(1), sqrtf(2. / 8000.), 1e-6));
+        assert!(close(profile.interval(5000), 1. / 4000., 1e-7));
+    }
+
+    #[test]
+    fn accel_and_decel_are_symmetric() {
+        let limits = Limits {
+            decel: LIMITS.accel,
+            ..LIMITS
+        };
+        let profile = Trapezoid::new(3000, &limits);
+
+        for n in 1..=3000 {
+            let mirrored = profile.interval(3001 - n);
+            assert!(
+                close(profile.interval(n), mirrored, 1e-6),
+                "step {}: {} {}",
+                n,
+                profile.interval(n),
+                mirrored
+            );
+        }
+    }
+
+    #[test]
+    fn intervals_shrink_then_grow() {
+        let profile = Trapezoid::new(2000, &LIMITS);
+
+        let intervals: [f32; 2000] = core::array::from_fn(|i| profile.interval(i as u32 + 1));
+        let fastest = intervals
+            .iter()
+            .position(|&interval| interval <= 1. / 4000. + 1e-7)
+            .unwrap();
+
+        assert!(intervals[..fastest].windows(2).all(|w| w[1] <= w[0]));
+        assert!(intervals[fastest..].windows(2).all(|w| w[1] + 1e-7 >= w[0]));
+    }
+
+    #[test]
+    fn short_move_peaks_below_max_speed() {
+        let profile = Trapezoid::new(300, &LIMITS);
+
+        // v²/2a + v²/2d = 300
+        let peak = sqrtf(2. * 300. * 8000. * 16_000. / (8000. + 16_000.));
+        assert!(close(profile.peak_speed(), peak, 0.01));
+        assert!(profile.peak_speed() < LIMITS.max_speed);
+        assert!(close(
+            profile.accel_steps() + profile.decel_steps(),
+            300.,
+            1e-3
+        ));
+        assert!(close(
+            profile.accel_steps(),
+            2. * profile.decel_steps(),
+            1e-3
+        ));
+
+        // no step is faster than the peak
+        assert!((1..=300).all(|n| profile.interval(n) * profile.peak_speed() >= 1. - 1e-4));
+
+        let sum: f32 = (1..=300).map(|n| profile.interval(n)).sum();
+        assert!(close(sum, peak / 8000. + peak / 16_000., 1e-4));
+    }
+
+    #[test]
+    fn speed_at_follows_the_ramps() {
+        let profile = Trapezoid::new(10_000, &LIMITS);
+
+        assert_eq!(profile.speed_at(0.), 0.);
+        assert!(close(profile.speed_at(250.), 2000., 0.01));
+        assert_eq!(profile.speed_at(5000.), 4000.);
+        assert!(close(profile.speed_at(10_000. - 125.), 2000., 0.01));
+        assert_eq!(profile.speed_at(10_000.), 0.);
+    }
+
+    #[test]
+    fn single_step_and_empty_moves() {
+        let profile = Trapezoid::new(1, &LIMITS);
+        assert!(profile.interval(1) > 0.);
+        assert!(close(profile.duration(), profile.interval(1), 1e-7));
+
+        let profile = Trapezoid::new(0, &LIMITS);
+        assert_eq!(profile.duration(), 0.);
+        assert_eq!(profile.peak_speed(), 0.);
+    }
+}
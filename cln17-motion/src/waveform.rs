@@ -0,0 +1,395 @@
+//! Speed and direction control of a streamed microstep waveform.
+//!
+//! The PWM timer of the bridges requests one DMA burst per period, each
+//! burst writes one frame of a circular buffer into the compare registers.
+//! TIM2 has no repetition counter and a new period would rescale every duty
+//! of the table, so the PWM rate stays fixed and the frames carry the speed:
+//! a phase accumulator advances by `speed / frame_hz` microsteps per frame
+//! and picks the frame of the [`microstep`](crate::microstep) table at the
+//! accumulated position.
+//!
+//! Speed changes ramp with `accel`, with a jerk set the acceleration itself
+//! ramps up and down as in an [`SCurve`](crate::scurve::SCurve). Reversing
+//! ramps through zero, a stop ramps down and keeps repeating the last
+//! microstep, so the motor holds with the table current.
+//!
+//! Like [`steptrain`](crate::steptrain) the buffer is refilled half by half
+//! from the DMA half transfer and transfer complete interrupts.
+
+use crate::microstep::{self, FRAME_LEN};
+use crate::steptrain::Half;
+
+/// Fraction bits of the phase accumulator.
+const PHASE_BITS: u32 = 16;
+const PHASE_ONE: f32 = (1u32 << PHASE_BITS) as f32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Waveform {
+    microsteps: u16,
+    frame_hz: f32,
+    /// Ramp rate in microsteps/s².
+    accel: f32,
+    /// Rate of change of the ramp in microsteps/s³, 0 ramps with `accel` at once.
+    jerk: f32,
+    speed: f32,
+    /// Acceleration of the last generated frame in microsteps/s².
+    ramp: f32,
+    target: f32,
+    /// `target - speed`, integrated by the jerk limited ramp. It goes to 0 at
+    /// the end of the ramp and stays exact where `speed` would round.
+    error: f32,
+    /// Position of the last generated frame in 1/2^16 microsteps.
+    phase: i64,
+    /// `phase` at the end of each buffer half when it was filled.
+    half_end: [i64; 2],
+    /// `phase` at the end of the last half the DMA finished.
+    streamed: i64,
+}
+
+impl Waveform {
+    /// Standstill at position 0. `frame_hz` is the PWM rate, `accel` the ramp
+    /// rate in microsteps/s².
+    pub fn new(microsteps: u16, frame_hz: f32, accel: f32) -> Self {
+        debug_assert!(microstep::is_valid_microsteps(microsteps));
+
+        Self {
+            microsteps,
+            frame_hz,
+            accel,
+            jerk: 0.0,
+            speed: 0.0,
+            ramp: 0.0,
+            target: 0.0,
+            error: 0.0,
+            phase: 0,
+            half_end: [0; 2],
+            streamed: 0,
+        }
+    }
+
+    pub fn microsteps(&self) -> u16 {
+        self.microsteps
+    }
+
+    /// Highest speed in microsteps/s, one microstep per frame, so no
+    /// microstep of the table is skipped.
+    pub fn max_speed(&self) -> f32 {
+        self.frame_hz
+    }
+
+    /// Speed of the last generated frame in microsteps/s, negative runs
+    /// backwards through the table.
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    pub fn target_speed(&self) -> f32 {
+        self.target
+    }
+
+    /// Ramps to `speed` microsteps/s, clamped to [`Waveform::max_speed`].
+    pub fn set_speed(&mut self, speed: f32) {
+        self.set_target(speed.clamp(-self.max_speed(), self.max_speed()));
+    }
+
+    pub fn set_accel(&mut self, accel: f32) {
+        self.accel = accel;
+    }
+
+    /// Jerk limit of the speed ramps in microsteps/s³, 0 switches it off.
+    pub fn set_jerk(&mut self, jerk: f32) {
+        self.jerk = jerk;
+    }
+
+    /// Ramps through zero to the same speed the other way.
+    pub fn reverse(&mut self) {
+        self.set_target(-self.target);
+    }
+
+    /// Ramps to zero and holds the microstep reached.
+    pub fn stop(&mut self) {
+        self.set_target(0.0);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.speed == 0.0 && self.target == 0.0
+    }
+
+    /// Microsteps streamed, updated per finished buffer half.
+    pub fn position(&self) -> i64 {
+        self.streamed >> PHASE_BITS
+    }
+
+    /// Microstep of the electrical revolution, index of the table frame.
+    pub fn microstep(&self) -> u32 {
+        self.position()
+            .rem_euclid(microstep::steps_per_cycle(self.microsteps) as i64) as u32
+    }
+
+    /// Electrical angle in radians, 0 to 2π, of the streamed position.
+    pub fn electrical_angle(&self) -> f32 {
+        let cycle = microstep::steps_per_cycle(self.microsteps) as f32 * PHASE_ONE;
+        let phase = self.streamed.rem_euclid(cycle as i64) as f32;
+
+        phase / cycle * core::f32::consts::TAU
+    }
+
+    fn set_target(&mut self, target: f32) {
+        self.target = target;
+        self.error = target - self.speed;
+    }
+
+    /// Fills the whole buffer, the DMA must start at its beginning.
+    /// `table` is the [`microstep::fill_table`] table of `microsteps`.
+    pub fn start(&mut self, table: &[u16], buf: &mut [u16]) {
+        let mid = buf.len() / 2;
+        let (first, second) = buf.split_at_mut(mid);
+
+        self.fill(table, first);
+        self.half_end[0] = self.phase;
+        self.fill(table, second);
+        self.half_end[1] = self.phase;
+    }
+
+    /// Fills the `half` the DMA just finished with the next frames.
+    pub fn refill(&mut self, table: &[u16], buf: &mut [u16], half: Half) {
+        let mid = buf.len() / 2;
+        let (slot, frames) = match half {
+            Half::First => (0, &mut buf[..mid]),
+            Half::Second => (1, &mut buf[mid..]),
+        };
+
+        self.streamed = self.half_end[slot];
+        self.fill(table, frames);
+        self.half_end[slot] = self.phase;
+    }
+
+    fn fill(&mut self, table: &[u16], frames: &mut [u16]) {
+        debug_assert!(table.len() >= microstep::table_len(self.microsteps));
+
+        let cycle = microstep::steps_per_cycle(self.microsteps) as i64;
+
+        for frame in frames.chunks_exact_mut(FRAME_LEN) {
+            if self.jerk > 0.0 {
+                self.jerk_step();
+            } else {
+                let ramp_step = self.accel / self.frame_hz;
+                self.speed = if self.speed < self.target {
+                    (self.speed + ramp_step).min(self.target)
+                } else {
+                    (self.speed - ramp_step).max(self.target)
+                };
+                self.error = self.target - self.speed;
+            }
+            self.phase += (self.speed / self.frame_hz * PHASE_ONE) as i64;
+
+            let index = (self.phase >> PHASE_BITS).rem_euclid(cycle) as usize;
+            frame.copy_from_slice(&table[index * FRAME_LEN..(index + 1) * FRAME_LEN]);
+        }
+    }
+
+    // one frame of a jerk limited ramp to the target speed
+    fn jerk_step(&mut self) {
+        if self.error == 0.0 && self.ramp == 0.0 {
+            return;
+        }
+        let dt = 1.0 / self.frame_hz;
+        let jerk_step = self.jerk * dt;
+        let direction = if self.error < 0.0 { -1.0 } else { 1.0 };
+
+        // the most acceleration that still ramps down to 0 at the target,
+        // on that curve it falls by exactly one jerk step per frame
+        let error = direction * self.error;
+        let stop =
+            libm::sqrtf(jerk_step * jerk_step / 4.0 + 2.0 * self.jerk * error) - jerk_step / 2.0;
+        let ramp = (direction * self.ramp + jerk_step)
+            .min(self.accel)
+            .min(stop);
+
+        self.ramp = direction * ramp;
+        self.error -= self.ramp * dt;
+        self.speed = self.target - self.error;
+
+        // reached or passed, the speed holds there and the acceleration
+        // left drops to 0 in the next frame
+        if direction * self.error <= 0.0 {
+            self.error = 0.0;
+            self.speed = self.target;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::microstep::ChannelMap;
+
+    const MICROSTEPS: u16 = 16;
+
+    struct Stream {
+        table: [u16; microstep::table_len(MICROSTEPS)],
+        buf: [u16; 512],
+        half: Half,
+    }
+
+    impl Stream {
+        fn new(waveform: &mut Waveform) -> Self {
+            let mut stream = Self {
+                table: [0; microstep::table_len(MICROSTEPS)],
+                buf: [0; 512],
+                half: Half::First,
+            };
+            microstep::fill_table(&mut stream.table, MICROSTEPS, 1000, &ChannelMap::IN_ORDER)
+                .unwrap();
+            waveform.start(&stream.table, &mut stream.buf);
+
+            stream
+        }
+
+        // the DMA finishes `halves` buffer halves of 64 frames each
+        fn run(&mut self, waveform: &mut Waveform, halves: u32) {
+            for _ in 0..halves {
+                waveform.refill(&self.table, &mut self.buf, self.half);
+                self.half = match self.half {
+                    Half::First => Half::Second,
+                    Half::Second => Half::First,
+                };
+            }
+        }
+    }
+
+    #[test]
+    fn ramps_runs_and_holds() {
+        let mut waveform = Waveform::new(MICROSTEPS, 20_000., 6400.);
+        let mut stream = Stream::new(&mut waveform);
+        assert_eq!(&stream.buf[..4], &stream.table[..4]);
+        assert!(waveform.is_stopped());
+
+        // 0.5 s to 3200 microsteps/s
+        waveform.set_speed(3200.);
+        stream.run(&mut waveform, 200);
+        assert_eq!(waveform.speed(), 3200.);
+
+        // 625 halves are 2 s
+        let start = waveform.position();
+        stream.run(&mut waveform, 625);
+        assert!((waveform.position() - start - 6400).abs() <= 2);
+
+        waveform.reverse();
+        stream.run(&mut waveform, 400);
+        assert_eq!(waveform.speed(), -3200.);
+
+        waveform.stop();
+        stream.run(&mut waveform, 400);
+        assert!(waveform.is_stopped());
+
+        // every frame repeats the microstep reached
+        let index = waveform.microstep() as usize;
+        let frame = &stream.table[index * FRAME_LEN..(index + 1) * FRAME_LEN];
+        assert!(stream.buf.chunks_exact(FRAME_LEN).all(|f| f == frame));
+
+        let angle = waveform.electrical_angle();
+        let expected = index as f32 / 64. * core::f32::consts::TAU;
+        assert!((0. ..core::f32::consts::TAU).contains(&angle));
+        assert!((angle - expected).abs() < 0.1, "{} {}", angle, expected);
+    }
+
+    #[test]
+    fn speed_is_limited_to_one_microstep_per_frame() {
+        let mut waveform = Waveform::new(MICROSTEPS, 20_000., 6400.);
+
+        waveform.set_speed(1e9);
+        assert_eq!(waveform.target_speed(), 20_000.);
+        waveform.set_speed(-1e9);
+        assert_eq!(waveform.target_speed(), -20_000.);
+    }
+
+    #[test]
+    fn backwards_positions_wrap_the_table() {
+        let mut waveform = Waveform::new(MICROSTEPS, 20_000., 1e9);
+        let mut stream = Stream::new(&mut waveform);
+
+        waveform.set_speed(-2000.);
+        stream.run(&mut waveform, 10);
+        assert!(waveform.position() < 0);
+        assert!(waveform.microstep() < 64);
+        assert_eq!(
+            waveform.microstep() as i64,
+            waveform.position().rem_euclid(64)
+        );
+    }
+
+    // frames until the target speed, checks the acceleration and jerk limits
+    fn jerk_ramp(waveform: &mut Waveform, jerk: f32) -> (u32, f32) {
+        let dt = 1.0 / waveform.frame_hz;
+        let (mut frames, mut peak) = (0, 0.0f32);
+        let mut last = (waveform.speed, waveform.ramp);
+
+        while waveform.speed != waveform.target || waveform.ramp != 0.0 {
+            waveform.jerk_step();
+            frames += 1;
+            assert!(frames < 1_000_000);
+
+            let (speed, ramp) = (waveform.speed, waveform.ramp);
+            assert!(ramp.abs() <= waveform.accel);
+            // no frame changes the acceleration by more than one jerk step
+            assert!(
+                (ramp - last.1).abs() <= jerk * dt * 1.001 + 1e-3,
+                "{} {}",
+                ramp,
+                last.1
+            );
+            // monotonic to the target
+            assert!((speed - last.0) * (waveform.target - last.0) >= 0.0);
+            peak = peak.max(ramp.abs());
+            last = (speed, ramp);
+        }
+
+        (frames, peak)
+    }
+
+    #[test]
+    fn jerk_limited_ramps() {
+        let mut waveform = Waveform::new(MICROSTEPS, 20_000., 6400.);
+        waveform.set_jerk(64_000.);
+
+        // 0.1 s up to the full accel and 0.1 s down again, 0.6 s in total
+        waveform.set_speed(3200.);
+        let (frames, peak) = jerk_ramp(&mut waveform, 64_000.);
+        assert_eq!(peak, 6400.);
+        assert!(frames.abs_diff(12_000) < 40, "{}", frames);
+        assert_eq!(waveform.speed(), 3200.);
+
+        // short change, the accel never reaches its limit: peak sqrt(v j)
+        waveform.set_speed(3100.);
+        let (frames, peak) = jerk_ramp(&mut waveform, 64_000.);
+        assert!((peak - 2530.).abs() < 30., "{}", peak);
+        // 2 sqrt(v / j) = 79 ms
+        assert!(frames.abs_diff(1580) < 20, "{}", frames);
+
+        // reversing ramps through zero, 0.2 s of jerk and 5560 / 6400 s at
+        // the full accel
+        waveform.reverse();
+        let (frames, _) = jerk_ramp(&mut waveform, 64_000.);
+        assert_eq!(waveform.speed(), -3100.);
+        assert!(frames.abs_diff(21_375) < 40, "{}", frames);
+    }
+
+    #[test]
+    fn jerk_limited_stop_holds() {
+        let mut waveform = Waveform::new(MICROSTEPS, 20_000., 6400.);
+        waveform.set_jerk(64_000.);
+        let mut stream = Stream::new(&mut waveform);
+
+        waveform.set_speed(3200.);
+        stream.run(&mut waveform, 100);
+        // a stop half way up the ramp
+        waveform.stop();
+        stream.run(&mut waveform, 400);
+        assert!(waveform.is_stopped());
+
+        let index = waveform.microstep() as usize;
+        let frame = &stream.table[index * FRAME_LEN..(index + 1) * FRAME_LEN];
+        assert!(stream.buf.chunks_exact(FRAME_LEN).all(|f| f == frame));
+    }
+}
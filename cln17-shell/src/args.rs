@@ -0,0 +1,47 @@
+use core::str::{FromStr, SplitWhitespace};
+
+use crate::Error;
+
+/// Whitespace separated arguments after the command name.
+#[derive(Clone, Debug)]
+pub struct Args<'a> {
+    tokens: SplitWhitespace<'a>,
+}
+
+impl<'a> Args<'a> {
+    pub fn new(args: &'a str) -> Self {
+        Self {
+            tokens: args.split_whitespace(),
+        }
+    }
+
+    /// Next argument parsed as `T`, `name` tells the user which one is
+    /// missing or invalid.
+    pub fn required<T: FromStr>(&mut self, name: &'static str) -> Result<T, Error> {
+        self.optional(name)?.ok_or(Error::Missing(name))
+    }
+
+    /// Next argument parsed as `T`, `None` at the end of the line.
+    pub fn optional<T: FromStr>(&mut self, name: &'static str) -> Result<Option<T>, Error> {
+        match self.tokens.next() {
+            Some(token) => token.parse().map(Some).map_err(|_| Error::Invalid(name)),
+            None => Ok(None),
+        }
+    }
+
+    /// Fails on arguments left over.
+    pub fn end(&mut self) -> Result<(), Error> {
+        match self.tokens.next() {
+            Some(_) => Err(Error::TooMany),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a> Iterator for Args<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        self.tokens.next()
+    }
+}
@@ -0,0 +1,113 @@
+//! The last `D` command lines.
+//!
+//! Lines are numbered from 1 in the order they were entered, the numbers
+//! keep counting once the oldest lines drop out, like the shell `history`.
+
+use crate::line::LineBuffer;
+
+/// Ring of the last `D` lines of up to `N` bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct History<const D: usize, const N: usize> {
+    lines: [LineBuffer<N>; D],
+    /// Lines entered so far, the newest one has this number.
+    count: usize,
+}
+
+impl<const D: usize, const N: usize> History<D, N> {
+    pub const fn new() -> Self {
+        Self {
+            lines: [LineBuffer::new(); D],
+            count: 0,
+        }
+    }
+
+    /// Adds a line, empty lines and repeats of the newest one are skipped.
+    pub fn push(&mut self, line: &str) {
+        if D == 0 || line.is_empty() || self.newest() == Some(line) {
+            return;
+        }
+
+        self.lines[self.count % D].set(line);
+        self.count += 1;
+    }
+
+    /// Lines kept.
+    pub fn len(&self) -> usize {
+        self.count.min(D)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Line `number`, `None` once it dropped out or before it was entered.
+    pub fn get(&self, number: usize) -> Option<&str> {
+        if number == 0 || number > self.count || number + D <= self.count {
+            return None;
+        }
+
+        Some(self.lines[(number - 1) % D].as_str())
+    }
+
+    pub fn newest(&self) -> Option<&str> {
+        self.get(self.count)
+    }
+
+    /// `back` lines before the next one, 1 is the newest.
+    pub fn recall(&self, back: usize) -> Option<&str> {
+        self.get((self.count + 1).checked_sub(back)?)
+    }
+
+    /// Numbers and lines, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &str)> + '_ {
+        let first = self.count - self.len() + 1;
+        (first..=self.count).filter_map(|number| Some((number, self.get(number)?)))
+    }
+}
+
+impl<const D: usize, const N: usize> Default for History<D, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_keep_counting() {
+        let mut history = History::<3, 8>::new();
+        assert!(history.is_empty());
+        assert_eq!(history.newest(), None);
+
+        for line in ["a", "b", "b", "", "c", "d"] {
+            history.push(line);
+        }
+        assert_eq!(history.len(), 3);
+        let mut lines = history.iter();
+        assert_eq!(lines.next(), Some((2, "b")));
+        assert_eq!(lines.next(), Some((3, "c")));
+        assert_eq!(lines.next(), Some((4, "d")));
+        assert_eq!(lines.next(), None);
+
+        assert_eq!(history.get(0), None);
+        assert_eq!(history.get(1), None);
+        assert_eq!(history.get(5), None);
+    }
+
+    #[test]
+    fn recall() {
+        let mut history = History::<3, 8>::new();
+        for line in ["a", "b", "c", "d"] {
+            history.push(line);
+        }
+        assert_eq!(history.recall(0), None);
+        assert_eq!(history.recall(1), Some("d"));
+        assert_eq!(history.recall(3), Some("b"));
+        assert_eq!(history.recall(4), None);
+
+        history.push("abcdefghijk");
+        assert_eq!(history.newest(), Some("abcdefgh"));
+    }
+}
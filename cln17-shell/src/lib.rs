@@ -0,0 +1,23 @@
+//! Line based command shell for a serial console.
+//!
+//! Plain `no_std` code without any hardware access, the firmware feeds the
+//! received bytes into [`Shell::feed`] and hands it a `core::fmt::Write` for
+//! the replies. Runs and tests on the host as well:
+//!
+//! - [`line`] editing: backspace, Ctrl-C and the cursor keys of a terminal
+//! - [`history`] of the last lines, recalled with the cursor keys, `!!` and
+//!   `!<number>`
+//! - [`Args`] of a command line, parsed into numbers or any other `FromStr`
+//! - the [`Command`] dispatch table with its usage and help text
+//!
+//! There is no tab completion, `help` lists the commands.
+
+#![no_std]
+
+mod args;
+pub mod history;
+pub mod line;
+mod shell;
+
+pub use args::Args;
+pub use shell::{Command, Error, Handler, Shell};
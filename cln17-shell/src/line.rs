@@ -0,0 +1,241 @@
+//! Terminal keys and the line being edited.
+//!
+//! [`Decoder`] turns the bytes of a terminal into [`Key`]s: CR, LF and CR LF
+//! end a line, backspace and DEL erase, Ctrl-C drops the line and the cursor
+//! up/down escape sequences browse the history. Other control bytes and
+//! escape sequences are dropped.
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+const CTRL_C: u8 = 0x03;
+const ESC: u8 = 0x1b;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Key {
+    /// A printable ASCII character.
+    Char(u8),
+    Erase,
+    Enter,
+    Cancel,
+    Up,
+    Down,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Idle,
+    /// CR ended the line, a LF right after it belongs to the same enter.
+    AfterCr,
+    /// ESC received.
+    Escape,
+    /// Inside a CSI `ESC [` or SS3 `ESC O` sequence.
+    Sequence,
+}
+
+/// Byte to key decoder.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Decoder {
+    state: State,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self { state: State::Idle }
+    }
+
+    /// Takes the next byte, `None` while a sequence is incomplete or the
+    /// byte means nothing to the editor.
+    pub fn key(&mut self, byte: u8) -> Option<Key> {
+        match self.state {
+            State::Escape => {
+                self.state = match byte {
+                    b'[' | b'O' => State::Sequence,
+                    _ => State::Idle,
+                };
+                return None;
+            }
+            State::Sequence => {
+                // parameters and intermediates up to the final byte
+                if !(0x40..=0x7e).contains(&byte) {
+                    return None;
+                }
+                self.state = State::Idle;
+                return match byte {
+                    b'A' => Some(Key::Up),
+                    b'B' => Some(Key::Down),
+                    _ => None,
+                };
+            }
+            State::AfterCr => {
+                self.state = State::Idle;
+                if byte == b'\n' {
+                    return None;
+                }
+            }
+            State::Idle => {}
+        }
+
+        match byte {
+            b'\r' => {
+                self.state = State::AfterCr;
+                Some(Key::Enter)
+            }
+            b'\n' => Some(Key::Enter),
+            BACKSPACE | DELETE => Some(Key::Erase),
+            CTRL_C => Some(Key::Cancel),
+            ESC => {
+                self.state = State::Escape;
+                None
+            }
+            b' '..=b'~' => Some(Key::Char(byte)),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Printable ASCII line of up to `N` bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineBuffer<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> LineBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    /// Appends a printable character, false when the line is full or the
+    /// byte is not printable.
+    pub fn push(&mut self, byte: u8) -> bool {
+        if self.len == N || !(b' '..=b'~').contains(&byte) {
+            return false;
+        }
+
+        self.buf[self.len] = byte;
+        self.len += 1;
+        true
+    }
+
+    /// Removes the last character, false on an empty line.
+    pub fn pop(&mut self) -> bool {
+        if self.len == 0 {
+            return false;
+        }
+
+        self.len -= 1;
+        true
+    }
+
+    /// Replaces the line, a longer `line` is cut at `N` bytes.
+    pub fn set(&mut self, line: &str) {
+        self.clear();
+        for byte in line.bytes() {
+            if !self.push(byte) {
+                break;
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only printable ASCII gets in
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl<const N: usize> Default for LineBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(bytes: &[u8]) -> ([Option<Key>; 32], usize) {
+        let mut decoder = Decoder::new();
+        let mut keys = [None; 32];
+        let mut len = 0;
+        for &byte in bytes {
+            if let Some(key) = decoder.key(byte) {
+                keys[len] = Some(key);
+                len += 1;
+            }
+        }
+        (keys, len)
+    }
+
+    fn assert_keys(bytes: &[u8], expected: &[Key]) {
+        let (keys, len) = keys(bytes);
+        assert_eq!(len, expected.len(), "{:?}", &keys[..len]);
+        for (key, expected) in keys.iter().zip(expected) {
+            assert_eq!(*key, Some(*expected));
+        }
+    }
+
+    #[test]
+    fn line_endings() {
+        assert_keys(b"a\r\n", &[Key::Char(b'a'), Key::Enter]);
+        assert_keys(b"\r\r", &[Key::Enter, Key::Enter]);
+        assert_keys(b"\n\n", &[Key::Enter, Key::Enter]);
+        assert_keys(b"\r\n\n", &[Key::Enter, Key::Enter]);
+    }
+
+    #[test]
+    fn escape_sequences() {
+        assert_keys(b"\x1b[A\x1bOB", &[Key::Up, Key::Down]);
+        // parameters are skipped, other sequences dropped
+        assert_keys(b"\x1b[1;5C\x1b[3~x", &[Key::Char(b'x')]);
+        assert_keys(b"\x1b[1;2A", &[Key::Up]);
+        // a lone ESC swallows the next byte only
+        assert_keys(b"\x1bxy", &[Key::Char(b'y')]);
+    }
+
+    #[test]
+    fn control_bytes() {
+        assert_keys(
+            b"\x08\x7f\x03\t\x00",
+            &[Key::Erase, Key::Erase, Key::Cancel],
+        );
+    }
+
+    #[test]
+    fn line_buffer() {
+        let mut line = LineBuffer::<4>::new();
+        assert!(!line.pop());
+        assert!(line.push(b'a'));
+        assert!(!line.push(b'\t'));
+        line.set("bcdefg");
+        assert_eq!(line.as_str(), "bcde");
+        assert!(line.is_full());
+        assert!(!line.push(b'x'));
+        assert!(line.pop());
+        assert_eq!(line.as_str(), "bcd");
+    }
+}
@@ -0,0 +1,528 @@
+use core::fmt::{self, Write};
+
+use crate::{
+    history::History,
+    line::{Decoder, Key, LineBuffer},
+    Args,
+};
+
+// name, usage and help of the built in commands
+const HELP: (&str, &str, &str) = ("help", "[command]", "list the commands or show one");
+const HISTORY: (&str, &str, &str) = ("history", "", "last lines, !! or !<number> runs one again");
+
+/// Runs a command on the firmware context `C` and writes its reply.
+pub type Handler<C> = fn(&mut C, &mut Args, &mut dyn Write) -> Result<(), Error>;
+
+/// One entry of the dispatch table.
+pub struct Command<C> {
+    pub name: &'static str,
+    /// Arguments as shown by `help`, e.g. `<steps> [speed]`.
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub run: Handler<C>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// No command of that name.
+    Unknown,
+    /// The named argument is missing.
+    Missing(&'static str),
+    /// The named argument does not parse.
+    Invalid(&'static str),
+    /// More arguments than the command takes.
+    TooMany,
+    /// The line did not fit the buffer, it was dropped.
+    TooLong,
+    /// `!<number>` is not in the history.
+    NoHistory,
+    /// The command could not be done, with the reason.
+    Failed(&'static str),
+    /// Writing the reply failed.
+    Output,
+}
+
+impl Error {
+    /// The usage line helps with this error.
+    fn shows_usage(&self) -> bool {
+        matches!(self, Error::Missing(_) | Error::Invalid(_) | Error::TooMany)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Unknown => f.write_str("unknown command, try help"),
+            Error::Missing(name) => write!(f, "missing {}", name),
+            Error::Invalid(name) => write!(f, "invalid {}", name),
+            Error::TooMany => f.write_str("too many arguments"),
+            Error::TooLong => f.write_str("line too long"),
+            Error::NoHistory => f.write_str("not in history"),
+            Error::Failed(reason) => f.write_str(reason),
+            Error::Output => f.write_str("output failed"),
+        }
+    }
+}
+
+impl From<fmt::Error> for Error {
+    fn from(_: fmt::Error) -> Self {
+        Error::Output
+    }
+}
+
+/// Line editor, history and dispatch of the `commands` table, lines of up
+/// to `N` bytes and `D` lines of history.
+///
+/// `help` and `history` are built in, a table entry of the same name is
+/// never reached.
+pub struct Shell<C: 'static, const N: usize, const D: usize> {
+    commands: &'static [Command<C>],
+    prompt: &'static str,
+    decoder: Decoder,
+    line: LineBuffer<N>,
+    /// Characters were dropped from the line being edited.
+    overflow: bool,
+    history: History<D, N>,
+    /// History line shown by the cursor keys, 0 while editing a new one.
+    browse: usize,
+}
+
+impl<C: 'static, const N: usize, const D: usize> Shell<C, N, D> {
+    pub fn new(commands: &'static [Command<C>], prompt: &'static str) -> Self {
+        Self {
+            commands,
+            prompt,
+            decoder: Decoder::new(),
+            line: LineBuffer::new(),
+            overflow: false,
+            history: History::new(),
+            browse: 0,
+        }
+    }
+
+    pub fn history(&self) -> &History<D, N> {
+        &self.history
+    }
+
+    /// Writes the prompt, at start up and after every line `feed` ran.
+    pub fn prompt(&self, out: &mut dyn Write) -> fmt::Result {
+        out.write_str(self.prompt)
+    }
+
+    /// Takes the next received byte, echoes it and runs the line on enter.
+    ///
+    /// Returns true once a line ended, the firmware finishes the reply of
+    /// work the command left in `ctx` and writes the [`prompt`](Self::prompt).
+    /// Command errors are reported to `out`, only a failed write returns one.
+    pub fn feed(&mut self, byte: u8, ctx: &mut C, out: &mut dyn Write) -> Result<bool, fmt::Error> {
+        let Some(key) = self.decoder.key(byte) else {
+            return Ok(false);
+        };
+
+        match key {
+            Key::Char(c) => {
+                if self.line.push(c) {
+                    out.write_char(c as char)?;
+                } else {
+                    self.overflow = true;
+                }
+            }
+            Key::Erase => {
+                if self.line.pop() {
+                    out.write_str("\x08 \x08")?;
+                }
+            }
+            Key::Cancel => {
+                self.reset();
+                out.write_str("^C\r\n")?;
+                out.write_str(self.prompt)?;
+            }
+            Key::Up | Key::Down => {
+                let browse = match key {
+                    Key::Up if self.browse < self.history.len() => self.browse + 1,
+                    Key::Down => self.browse.saturating_sub(1),
+                    _ => self.browse,
+                };
+                self.browse = browse;
+                self.line.set(self.history.recall(browse).unwrap_or(""));
+                self.overflow = false;
+
+                // back to the line start, clear it and draw it again
+                out.write_str("\r\x1b[K")?;
+                out.write_str(self.prompt)?;
+                out.write_str(self.line.as_str())?;
+            }
+            Key::Enter => {
+                out.write_str("\r\n")?;
+
+                let line = self.line;
+                let overflow = self.overflow;
+                self.reset();
+
+                if overflow {
+                    writeln!(out, "error: {}\r", Error::TooLong)?;
+                } else if let Err(Error::Output) = self.execute(line.as_str(), ctx, out) {
+                    return Err(fmt::Error);
+                }
+
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Runs one line as if it was typed, adds it to the history and
+    /// returns the command result. Errors are reported to `out` as well,
+    /// argument errors with the usage of the command.
+    pub fn execute(&mut self, line: &str, ctx: &mut C, out: &mut dyn Write) -> Result<(), Error> {
+        let mut command = None;
+        let result = self.dispatch(line, ctx, out, &mut command);
+
+        if let Err(error) = result {
+            writeln!(out, "error: {}\r", error)?;
+            if let (true, Some((name, usage))) = (error.shows_usage(), command) {
+                writeln!(out, "usage: {} {}\r", name, usage)?;
+            }
+        }
+
+        result
+    }
+
+    // sets `command` to the name and usage of the command found
+    fn dispatch(
+        &mut self,
+        line: &str,
+        ctx: &mut C,
+        out: &mut dyn Write,
+        command: &mut Option<(&'static str, &'static str)>,
+    ) -> Result<(), Error> {
+        let line = line.trim();
+
+        // !! and !<number> repeat a history line, shown before it runs
+        let mut expanded = LineBuffer::<N>::new();
+        let line = match line.strip_prefix('!') {
+            Some(number) => {
+                let recalled = match number {
+                    "!" => self.history.newest(),
+                    _ => number.parse().ok().and_then(|n| self.history.get(n)),
+                };
+                expanded.set(recalled.ok_or(Error::NoHistory)?);
+                writeln!(out, "{}\r", expanded.as_str())?;
+                expanded.as_str()
+            }
+            None => line,
+        };
+
+        if line.is_empty() {
+            return Ok(());
+        }
+        self.history.push(line);
+
+        let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let mut args = Args::new(rest);
+
+        match name {
+            "help" => {
+                *command = Some((HELP.0, HELP.1));
+                self.help(&mut args, out)
+            }
+            "history" => {
+                *command = Some((HISTORY.0, HISTORY.1));
+                args.end()?;
+                for (number, line) in self.history.iter() {
+                    writeln!(out, "{:>4}  {}\r", number, line)?;
+                }
+                Ok(())
+            }
+            _ => {
+                let found = self.find(name).ok_or(Error::Unknown)?;
+                *command = Some((found.name, found.usage));
+                (found.run)(ctx, &mut args, out)
+            }
+        }
+    }
+
+    fn help(&self, args: &mut Args, out: &mut dyn Write) -> Result<(), Error> {
+        let builtins = [HELP, HISTORY];
+
+        let Some(name) = args.next() else {
+            for command in self.commands {
+                writeln!(out, "{:<10} {}\r", command.name, command.help)?;
+            }
+            for (name, _, help) in builtins {
+                writeln!(out, "{:<10} {}\r", name, help)?;
+            }
+            return Ok(());
+        };
+        args.end()?;
+
+        let (name, usage, help) = match self.find(name) {
+            Some(command) => (command.name, command.usage, command.help),
+            None => builtins
+                .into_iter()
+                .find(|builtin| builtin.0 == name)
+                .ok_or(Error::Unknown)?,
+        };
+        writeln!(out, "usage: {} {}\r", name, usage)?;
+        writeln!(out, "{}\r", help)?;
+
+        Ok(())
+    }
+
+    fn find(&self, name: &str) -> Option<&'static Command<C>> {
+        self.commands.iter().find(|command| command.name == name)
+    }
+
+    fn reset(&mut self) {
+        self.line.clear();
+        self.overflow = false;
+        self.browse = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::string::String;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Context {
+        target: Option<i64>,
+        speed: Option<f32>,
+    }
+
+    fn move_to(ctx: &mut Context, args: &mut Args, _out: &mut dyn Write) -> Result<(), Error> {
+        let target = args.required("steps")?;
+        args.end()?;
+        ctx.target = Some(target);
+        Ok(())
+    }
+
+    fn speed(ctx: &mut Context, args: &mut Args, out: &mut dyn Write) -> Result<(), Error> {
+        match args.optional("steps/s")? {
+            Some(speed) => {
+                args.end()?;
+                ctx.speed = Some(speed);
+            }
+            None => writeln!(out, "speed {:?}\r", ctx.speed)?,
+        }
+        Ok(())
+    }
+
+    fn fail(_ctx: &mut Context, _args: &mut Args, _out: &mut dyn Write) -> Result<(), Error> {
+        Err(Error::Failed("driver off"))
+    }
+
+    static COMMANDS: &[Command<Context>] = &[
+        Command {
+            name: "move",
+            usage: "<steps>",
+            help: "move to",
+            run: move_to,
+        },
+        Command {
+            name: "speed",
+            usage: "[steps/s]",
+            help: "max speed",
+            run: speed,
+        },
+        Command {
+            name: "fail",
+            usage: "",
+            help: "fails",
+            run: fail,
+        },
+    ];
+
+    type TestShell = Shell<Context, 16, 3>;
+
+    fn shell() -> (TestShell, Context) {
+        (Shell::new(COMMANDS, "> "), Context::default())
+    }
+
+    /// Feeds `bytes` as the firmware does and returns what went out.
+    fn feed(shell: &mut TestShell, ctx: &mut Context, bytes: &[u8]) -> String {
+        let mut out = String::new();
+        for &byte in bytes {
+            if shell.feed(byte, ctx, &mut out).unwrap() {
+                shell.prompt(&mut out).unwrap();
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn runs_commands() {
+        let (mut shell, mut ctx) = shell();
+        let out = feed(&mut shell, &mut ctx, b"  speed   300.5 \r");
+        assert_eq!(ctx.speed, Some(300.5));
+        assert_eq!(out, "  speed   300.5 \r\n> ");
+
+        let out = feed(&mut shell, &mut ctx, b"speed\r");
+        assert_eq!(out, "speed\r\nspeed Some(300.5)\r\n> ");
+
+        let out = feed(&mut shell, &mut ctx, b"\r\r");
+        assert_eq!(out, "\r\n> \r\n> ");
+    }
+
+    #[test]
+    fn errors() {
+        let (mut shell, mut ctx) = shell();
+        let out = feed(&mut shell, &mut ctx, b"move\r");
+        assert_eq!(
+            out,
+            "move\r\nerror: missing steps\r\nusage: move <steps>\r\n> "
+        );
+
+        let out = feed(&mut shell, &mut ctx, b"move x\n");
+        assert_eq!(
+            out,
+            "move x\r\nerror: invalid steps\r\nusage: move <steps>\r\n> "
+        );
+
+        let out = feed(&mut shell, &mut ctx, b"move 1 2\r\n");
+        assert!(out.contains("error: too many arguments\r\n"), "{out:?}");
+        assert_eq!(ctx.target, None);
+
+        let out = feed(&mut shell, &mut ctx, b"jump\r");
+        assert_eq!(out, "jump\r\nerror: unknown command, try help\r\n> ");
+
+        // a failed command does not show the usage
+        let out = feed(&mut shell, &mut ctx, b"fail\r");
+        assert_eq!(out, "fail\r\nerror: driver off\r\n> ");
+
+        let mut out = String::new();
+        assert_eq!(shell.execute("move -5", &mut ctx, &mut out), Ok(()));
+        assert_eq!(ctx.target, Some(-5));
+        assert_eq!(
+            shell.execute("move", &mut ctx, &mut out),
+            Err(Error::Missing("steps"))
+        );
+    }
+
+    #[test]
+    fn backspace() {
+        let (mut shell, mut ctx) = shell();
+        let out = feed(&mut shell, &mut ctx, b"move 1x\x08\x7f200\r\n");
+        assert_eq!(ctx.target, Some(200));
+        assert_eq!(out, "move 1x\x08 \x08\x08 \x08200\r\n> ");
+
+        // nothing to erase, nothing echoed
+        let out = feed(&mut shell, &mut ctx, b"\x7f\x7f\r");
+        assert_eq!(out, "\r\n> ");
+    }
+
+    #[test]
+    fn ctrl_c_drops_the_line() {
+        let (mut shell, mut ctx) = shell();
+        let out = feed(&mut shell, &mut ctx, b"move 9\x03");
+        assert_eq!(out, "move 9^C\r\n> ");
+        assert_eq!(ctx.target, None);
+        assert!(shell.history().is_empty());
+
+        feed(&mut shell, &mut ctx, b"move 1\r");
+        assert_eq!(ctx.target, Some(1));
+    }
+
+    #[test]
+    fn escape_sequences_browse_the_history() {
+        let (mut shell, mut ctx) = shell();
+        feed(&mut shell, &mut ctx, b"move 3\rmove 7\r");
+
+        // up twice, down once
+        ctx.target = None;
+        let out = feed(&mut shell, &mut ctx, b"\x1b[A\x1b[A\x1b[B\r");
+        assert_eq!(
+            out,
+            "\r\x1b[K> move 7\r\x1b[K> move 3\r\x1b[K> move 7\r\n> "
+        );
+        assert_eq!(ctx.target, Some(7));
+
+        // other sequences are dropped, up stops at the oldest line
+        let out = feed(&mut shell, &mut ctx, b"\x1b[C\x1b[A\x1b[A\x1b[A\x03");
+        assert_eq!(
+            out,
+            "\r\x1b[K> move 7\r\x1b[K> move 3\r\x1b[K> move 3^C\r\n> "
+        );
+    }
+
+    #[test]
+    fn overflow_drops_the_line() {
+        let (mut shell, mut ctx) = shell();
+        let out = feed(&mut shell, &mut ctx, b"move 123456789012345\r");
+        // the first 16 bytes are echoed, the rest dropped
+        assert_eq!(out, "move 12345678901\r\nerror: line too long\r\n> ");
+        assert_eq!(ctx.target, None);
+        assert!(shell.history().is_empty());
+
+        // exactly 16 fit
+        let out = feed(&mut shell, &mut ctx, b"move 00000000042\r");
+        assert_eq!(out, "move 00000000042\r\n> ");
+        assert_eq!(ctx.target, Some(42));
+    }
+
+    #[test]
+    fn history_and_recall() {
+        let (mut shell, mut ctx) = shell();
+        feed(&mut shell, &mut ctx, b"move 1\rmove 2\rmove 3\rmove 4\r");
+        let out = feed(&mut shell, &mut ctx, b"history\r");
+        assert_eq!(
+            out,
+            "history\r\n   3  move 3\r\n   4  move 4\r\n   5  history\r\n> "
+        );
+
+        let out = feed(&mut shell, &mut ctx, b"!3\r");
+        assert_eq!(ctx.target, Some(3));
+        assert_eq!(out, "!3\r\nmove 3\r\n> ");
+
+        let out = feed(&mut shell, &mut ctx, b"!1\r");
+        assert_eq!(out, "!1\r\nerror: not in history\r\n> ");
+        let out = feed(&mut shell, &mut ctx, b"!x\r");
+        assert_eq!(out, "!x\r\nerror: not in history\r\n> ");
+
+        feed(&mut shell, &mut ctx, b"move 7\r");
+        ctx.target = None;
+        let out = feed(&mut shell, &mut ctx, b"!!\r");
+        assert_eq!(out, "!!\r\nmove 7\r\n> ");
+        assert_eq!(ctx.target, Some(7));
+        assert_eq!(shell.history().newest(), Some("move 7"));
+
+        let out = feed(&mut shell, &mut ctx, b"history 2\r");
+        assert!(out.contains("error: too many arguments\r\nusage: history \r\n"));
+    }
+
+    #[test]
+    fn help() {
+        let (mut shell, mut ctx) = shell();
+        let out = feed(&mut shell, &mut ctx, b"help\r");
+        assert_eq!(
+            out,
+            "help\r\n\
+             move       move to\r\n\
+             speed      max speed\r\n\
+             fail       fails\r\n\
+             help       list the commands or show one\r\n\
+             history    last lines, !! or !<number> runs one again\r\n\
+             > "
+        );
+
+        let out = feed(&mut shell, &mut ctx, b"help speed\r");
+        assert_eq!(
+            out,
+            "help speed\r\nusage: speed [steps/s]\r\nmax speed\r\n> "
+        );
+
+        let out = feed(&mut shell, &mut ctx, b"help history\r");
+        assert!(out.contains("usage: history \r\n"));
+
+        let out = feed(&mut shell, &mut ctx, b"help nope\r");
+        assert_eq!(out, "help nope\r\nerror: unknown command, try help\r\n> ");
+
+        let out = feed(&mut shell, &mut ctx, b"help move speed\r");
+        assert!(out.contains("error: too many arguments\r\nusage: help [command]\r\n"));
+    }
+}
@@ -4,20 +4,54 @@
 use defmt_rtt as _;
 use panic_probe as _;
 
+use cln17_control::{
+    analog::{Divider, FactoryCal, RawScan, Scaling, Snapshot},
+    current::Shunt,
+};
 use hal::{
     self,
-    adc::{Adc, AdcDevice, AdcInterrupt, Align, InputType, SampleTime},
+    adc::{Adc, AdcDevice, SampleTime},
     clocks::Clocks,
     dma,
-    dma::{Dma, DmaChannel, DmaInput, DmaInterrupt, DmaPeriph},
+    dma::{ChannelCfg, Circular, DmaChannel, DmaInput, DmaInterrupt, DmaPeriph},
     pac,
-    pac::TIM3,
-    pac::{ADC1, DMA1},
+    pac::TIM4,
     timer::Timer,
     timer::TimerInterrupt,
 };
 
-static mut ADC_READ_BUF: [u16; 1] = [0; 1];
+// scans per s, TIM3 TRGO starts each one
+const SCAN_HZ: f32 = 1000.;
+
+// ADC1 inputs, set the external ones to your board wiring
+const VBUS_CHANNEL: u8 = 2;
+const TEMP_CHANNEL: u8 = 16;
+const VREFINT_CHANNEL: u8 = 18;
+const SHUNT_A_CHANNEL: u8 = 3;
+const SHUNT_B_CHANNEL: u8 = 4;
+
+// regular sequence, the order of the words in SCAN_BUF
+const SEQUENCE: [u8; 5] = [
+    VBUS_CHANNEL,
+    TEMP_CHANNEL,
+    VREFINT_CHANNEL,
+    SHUNT_A_CHANNEL,
+    SHUNT_B_CHANNEL,
+];
+
+// VBUS divider resistors in Ohm
+const VBUS_R_TOP: f32 = 100_000.;
+const VBUS_R_BOTTOM: f32 = 4_700.;
+
+const SHUNT_MOHM: f32 = 100.0;
+const SHUNT_GAIN: f32 = 10.0;
+const VREF_MV: f32 = 3300.0;
+
+// EXTSEL of tim3_trgo for ADC1/2 regular conversions
+const ADC_TRIGGER_TIM3_TRGO: u8 = 4;
+
+// written by DMA1 ch1 once per scan, circular
+static mut SCAN_BUF: [u16; SEQUENCE.len()] = [0; SEQUENCE.len()];
 
 #[rtic::app(device = pac, peripherals = true)]
 mod app {
@@ -25,13 +59,13 @@ mod app {
 
     #[shared]
     struct Shared {
-        adc1: Adc<ADC1>,
+        snapshot: Snapshot,
     }
 
     #[local]
     struct Local {
-        timer: Timer<TIM3>,
-        dma1: Dma<DMA1>,
+        scaling: Scaling,
+        report_timer: Timer<TIM4>,
     }
 
     #[init]
@@ -41,6 +75,11 @@ mod app {
         let clock_cfg = Clocks::default();
         clock_cfg.setup().unwrap();
 
+        // temperature sensor and VREFINT on ADC1 ch16 and ch18
+        dp.ADC12_COMMON
+            .ccr
+            .modify(|_, w| w.vsensesel().set_bit().vrefen().set_bit());
+
         let mut adc = Adc::new_adc1(
             dp.ADC1,
             AdcDevice::One,
@@ -48,58 +87,113 @@ mod app {
             clock_cfg.systick(),
         );
 
-        adc.set_sequence(1, 2);
-        adc.set_sequence_len(2);
-        adc.set_input_type(2, InputType::Differential);
-        adc.set_sample_time(2, SampleTime::T2);
-        adc.set_align(Align::Left);
-        adc.enable_interrupt(AdcInterrupt::EndOfSequence);
+        for (position, channel) in SEQUENCE.iter().enumerate() {
+            adc.set_sequence(position as u8 + 1, *channel);
+        }
+        adc.set_sequence_len(SEQUENCE.len() as u8);
+
+        adc.set_sample_time(VBUS_CHANNEL, SampleTime::T92);
+        // the temperature sensor needs at least 5 us
+        adc.set_sample_time(TEMP_CHANNEL, SampleTime::T640);
+        adc.set_sample_time(VREFINT_CHANNEL, SampleTime::T640);
+        adc.set_sample_time(SHUNT_A_CHANNEL, SampleTime::T25);
+        adc.set_sample_time(SHUNT_B_CHANNEL, SampleTime::T25);
+
+        // conversions start on the rising edge of TIM3 TRGO instead of software
+        adc.regs
+            .cfgr
+            .modify(|_, w| unsafe { w.exten().bits(0b01).extsel().bits(ADC_TRIGGER_TIM3_TRGO) });
 
-        let dma = Dma::new(dp.DMA1);
         dma::enable_mux1();
         dma::mux(DmaPeriph::Dma1, DmaChannel::C1, DmaInput::Adc1);
 
-        let mut timer = Timer::new_tim3(dp.TIM3, 1., Default::default(), &clock_cfg);
-        timer.enable_interrupt(TimerInterrupt::Update);
-        timer.enable();
-
-        (Shared { adc1: adc }, Local { timer, dma1: dma })
+        // circular, the DMA wraps after each scan and interrupts on its completion
+        unsafe {
+            adc.read_dma(
+                &mut SCAN_BUF,
+                &SEQUENCE,
+                DmaChannel::C1,
+                ChannelCfg {
+                    circular: Circular::Enabled,
+                    ..Default::default()
+                },
+                DmaPeriph::Dma1,
+            );
+        }
+
+        let shunt = Shunt::new(SHUNT_MOHM, SHUNT_GAIN, VREF_MV, 12);
+        let scaling = Scaling {
+            cal: unsafe { FactoryCal::read() },
+            vbus: Divider::new(VBUS_R_TOP, VBUS_R_BOTTOM),
+            shunts: [shunt; 2],
+        };
+
+        // the update event is TRGO, only configured here, no interrupt needed
+        let mut scan_timer = Timer::new_tim3(dp.TIM3, SCAN_HZ, Default::default(), &clock_cfg);
+        scan_timer
+            .regs
+            .cr2
+            .modify(|_, w| unsafe { w.mms().bits(0b010) });
+        scan_timer.enable();
+
+        let mut report_timer = Timer::new_tim4(dp.TIM4, 1., Default::default(), &clock_cfg);
+        report_timer.enable_interrupt(TimerInterrupt::Update);
+        report_timer.enable();
+
+        (
+            Shared {
+                snapshot: Snapshot::default(),
+            },
+            Local {
+                scaling,
+                report_timer,
+            },
+        )
     }
 
-    #[task(binds = DMA1_CH1, local=[dma1], shared=[adc1], priority = 1)]
-    fn on_adc_dma_read(mut cx: on_adc_dma_read::Context) {
+    // DMA1 ch1 transfer complete - one scan is in SCAN_BUF
+    #[task(binds = DMA1_CH1, local=[scaling, scans: u64 = 0], shared=[snapshot], priority = 2)]
+    fn on_scan(mut cx: on_scan::Context) {
         dma::clear_interrupt(
             DmaPeriph::Dma1,
             DmaChannel::C1,
             DmaInterrupt::TransferComplete,
         );
 
-        cx.local.dma1.stop(DmaChannel::C1);
-
-        defmt::println!("ADC DMA read complete");
-
-        let buf = unsafe { &mut ADC_READ_BUF };
-
-        let voltage = cx.shared.adc1.lock(|adc| adc.reading_to_voltage(buf[0]));
-
-        defmt::println!("voltage: {:?}", voltage);
+        // the next scan is only written one trigger period later
+        let [vbus, temperature, vrefint, shunt_a, shunt_b] = unsafe { SCAN_BUF };
+        let raw = RawScan {
+            vbus,
+            temperature,
+            vrefint,
+            shunt_a,
+            shunt_b,
+        };
+
+        *cx.local.scans += 1;
+        let timestamp_us = *cx.local.scans * 1_000_000 / SCAN_HZ as u64;
+        let snapshot = cx.local.scaling.snapshot(&raw, timestamp_us);
+
+        cx.shared.snapshot.lock(|s| *s = snapshot);
     }
 
-    #[task(binds = TIM3, local=[timer], shared=[adc1], priority = 2)]
-    fn on_timer(mut cx: on_timer::Context) {
-        cx.local.timer.clear_interrupt(TimerInterrupt::Update);
-
-        cx.shared.adc1.lock(|adc| {
-            unsafe {
-                adc.read_dma(
-                    &mut ADC_READ_BUF,
-                    &[2],
-                    DmaChannel::C1,
-                    Default::default(),
-                    DmaPeriph::Dma1,
-                )
-            };
-        });
+    #[task(binds = TIM4, local=[report_timer], shared=[snapshot], priority = 1)]
+    fn on_report(mut cx: on_report::Context) {
+        cx.local
+            .report_timer
+            .clear_interrupt(TimerInterrupt::Update);
+
+        let snapshot = cx.shared.snapshot.lock(|s| *s);
+
+        defmt::println!(
+            "{:?} us: vdda {:?} V, vbus {:?} V, temperature {:?} C, current a {:?} b {:?} A",
+            snapshot.timestamp_us,
+            snapshot.vdda,
+            snapshot.vbus,
+            snapshot.temperature,
+            snapshot.current.a,
+            snapshot.current.b
+        );
     }
 }
 
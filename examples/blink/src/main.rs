@@ -8,12 +8,8 @@
 use cortex_m::delay::Delay;
 use cortex_m_rt::entry; // The runtime
 
-use hal::{
-    self,
-    clocks::Clocks,
-    gpio::{Edge, Pin, PinMode, Port, Pull},
-    pac,
-};
+use cln17_bsp::board::{Leds, Sw1};
+use hal::{self, clocks::Clocks, gpio::Edge, pac};
 
 use defmt_rtt as _;
 // global logger
@@ -38,32 +34,26 @@ fn main() -> ! {
     // Setup a delay, based on the Cortex-m systick.
     let mut delay = Delay::new(cp.SYST, clock_cfg.systick());
 
-    let mut led_red = Pin::new(Port::B, 13, PinMode::Output);
-    let mut led_green = Pin::new(Port::B, 14, PinMode::Output);
-    let mut led_blue = Pin::new(Port::B, 15, PinMode::Output);
+    // all leds are off after init
+    let mut leds = Leds::new();
 
-    let mut button = Pin::new(Port::A, 15, PinMode::Input);
-    button.pull(Pull::Up);
+    let mut button = Sw1::new();
     button.enable_interrupt(Edge::Rising);
 
-    led_green.set_high();
-    led_blue.set_high();
-    led_red.set_high();
-
     loop {
-        led_green.set_low();
+        leds.green.set_low();
         delay.delay_ms(1_000);
-        led_green.set_high();
+        leds.green.set_high();
 
-        led_blue.set_low();
+        leds.blue.set_low();
         delay.delay_ms(1_000);
-        led_blue.set_high();
+        leds.blue.set_high();
 
-        led_red.set_low();
+        leds.red.set_low();
         delay.delay_ms(1_000);
-        led_red.set_high();
+        leds.red.set_high();
 
-        if button.is_low() {
+        if button.is_pressed() {
             defmt::println!("low");
         } else {
             defmt::println!("hight");
@@ -0,0 +1,263 @@
+#![no_main]
+#![no_std]
+
+use defmt_rtt as _;
+use panic_probe as _;
+
+use cln17_bsp::{
+    board::{Bridge, DriverControl},
+    pins,
+};
+use cln17_control::{
+    current::{CurrentLoop, PhaseCurrents, Shunt},
+    pi::PiGains,
+};
+use cln17_motion::microstep::{ChannelMap, FRAME_LEN};
+use hal::{
+    self,
+    adc::{Adc, AdcDevice, SampleTime},
+    clocks::Clocks,
+    dma,
+    dma::{ChannelCfg, Circular, DmaChannel, DmaInput, DmaInterrupt, DmaPeriph},
+    pac,
+    pac::{ADC1, TIM2, TIM3},
+    timer::{
+        Alignment, CaptureCompareDma, CountDir, OutputCompare, TimChannel, Timer, TimerConfig,
+        TimerInterrupt, UpdateReqSrc,
+    },
+};
+
+// PWM rate of the bridges, the current loop runs once per period
+const PWM_HZ: f32 = 20_000.0;
+
+// ADC1 inputs of the coil A and coil B shunt amplifiers, set them to your board wiring
+const SHUNT_A_CHANNEL: u8 = 3;
+const SHUNT_B_CHANNEL: u8 = 4;
+const SHUNT_MOHM: f32 = 100.0;
+const SHUNT_GAIN: f32 = 10.0;
+const VREF_MV: f32 = 3300.0;
+// conversions averaged for the zero current reading of each shunt
+const OFFSET_SAMPLES: u32 = 64;
+
+// motor and supply, the loop gains are derived from them
+const COIL_R_OHM: f32 = 2.0;
+const COIL_L_H: f32 = 3e-3;
+const VBUS: f32 = 24.0;
+const BANDWIDTH_HZ: f32 = 1000.0;
+
+// peak coil current in A
+const CURRENT: f32 = 0.5;
+// electrical revolutions per s, a full step is a quarter of it
+const ELECTRICAL_HZ: f32 = 1.0;
+
+// regular sequence SHUNT_A_CHANNEL, SHUNT_B_CHANNEL, written by DMA1 ch1 on every TIM2 update
+static mut SHUNT_READINGS: [u16; 2] = [0; 2];
+
+// EXTSEL of tim2_trgo for ADC1/2 regular conversions
+const ADC_TRIGGER_TIM2_TRGO: u8 = 11;
+
+// frame slot of IN1 to IN4
+const BRIDGE: ChannelMap = ChannelMap::from_channels(pins::DRV_IN_CHANNELS);
+
+const CHANNELS: [TimChannel; FRAME_LEN] = [
+    TimChannel::C1,
+    TimChannel::C2,
+    TimChannel::C3,
+    TimChannel::C4,
+];
+
+#[rtic::app(device = pac, peripherals = true)]
+mod app {
+    use super::*;
+
+    #[shared]
+    struct Shared {
+        setpoint: PhaseCurrents,
+        measured: PhaseCurrents,
+    }
+
+    #[local]
+    struct Local {
+        timer_pwd: Timer<TIM2>,
+        current_loop: CurrentLoop,
+        shunts: [Shunt; 2],
+        timer: Timer<TIM3>,
+    }
+
+    // the driver stays disabled until the shunt offsets are read
+    fn init_pins() -> DriverControl {
+        let driver = DriverControl::new();
+
+        // driver inputs for motor pwd control, TIM2 ch1-ch4
+        Bridge::new();
+
+        driver
+    }
+
+    // mean reading of `channel` with no coil current
+    fn read_offset(adc: &mut Adc<ADC1>, channel: u8) -> f32 {
+        let sum: u32 = (0..OFFSET_SAMPLES).map(|_| adc.read(channel) as u32).sum();
+
+        sum as f32 / OFFSET_SAMPLES as f32
+    }
+
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local) {
+        let dp = ctx.device;
+
+        let clock_cfg = Clocks::default();
+        clock_cfg.setup().unwrap();
+
+        let mut driver = init_pins();
+
+        let mut timer_pwd = Timer::new_tim2(
+            dp.TIM2,
+            PWM_HZ,
+            TimerConfig {
+                one_pulse_mode: false,
+                update_request_source: UpdateReqSrc::Any,
+                auto_reload_preload: true,
+                alignment: Alignment::Edge,
+                capture_compare_dma: CaptureCompareDma::Ccx,
+                direction: CountDir::Up,
+            },
+            &clock_cfg,
+        );
+
+        for channel in CHANNELS {
+            timer_pwd.enable_pwm_output(channel, OutputCompare::Pwm1, 0.0);
+        }
+
+        // the update event is TRGO, every PWM period starts one ADC sequence
+        timer_pwd
+            .regs
+            .cr2
+            .modify(|_, w| unsafe { w.mms().bits(0b010) });
+
+        let mut adc = Adc::new_adc1(
+            dp.ADC1,
+            AdcDevice::One,
+            Default::default(),
+            clock_cfg.systick(),
+        );
+
+        adc.set_sequence(1, SHUNT_A_CHANNEL);
+        adc.set_sequence(2, SHUNT_B_CHANNEL);
+        adc.set_sequence_len(2);
+        adc.set_sample_time(SHUNT_A_CHANNEL, SampleTime::T25);
+        adc.set_sample_time(SHUNT_B_CHANNEL, SampleTime::T25);
+
+        // bridges off, the amplifiers read their zero current level
+        let mut shunts = [Shunt::new(SHUNT_MOHM, SHUNT_GAIN, VREF_MV, 12); 2];
+        shunts[0].set_offset(read_offset(&mut adc, SHUNT_A_CHANNEL));
+        shunts[1].set_offset(read_offset(&mut adc, SHUNT_B_CHANNEL));
+        defmt::println!(
+            "shunt offsets: a {:?} b {:?}",
+            shunts[0].offset,
+            shunts[1].offset
+        );
+
+        // conversions start on the rising edge of TIM2 TRGO instead of software
+        adc.regs
+            .cfgr
+            .modify(|_, w| unsafe { w.exten().bits(0b01).extsel().bits(ADC_TRIGGER_TIM2_TRGO) });
+
+        dma::enable_mux1();
+        dma::mux(DmaPeriph::Dma1, DmaChannel::C1, DmaInput::Adc1);
+
+        // circular, the DMA wraps after each sequence and interrupts on its completion
+        unsafe {
+            adc.read_dma(
+                &mut SHUNT_READINGS,
+                &[SHUNT_A_CHANNEL, SHUNT_B_CHANNEL],
+                DmaChannel::C1,
+                ChannelCfg {
+                    circular: Circular::Enabled,
+                    ..Default::default()
+                },
+                DmaPeriph::Dma1,
+            );
+        }
+
+        let gains = PiGains::for_rl(COIL_R_OHM, COIL_L_H, BANDWIDTH_HZ, VBUS);
+        let current_loop = CurrentLoop::new(gains, PWM_HZ, timer_pwd.get_max_duty() as u16);
+
+        driver.enable();
+        timer_pwd.enable();
+
+        let mut timer = Timer::new_tim3(dp.TIM3, 1.0, Default::default(), &clock_cfg);
+        timer.enable_interrupt(TimerInterrupt::Update);
+        timer.enable();
+
+        (
+            Shared {
+                setpoint: PhaseCurrents::default(),
+                measured: PhaseCurrents::default(),
+            },
+            Local {
+                timer_pwd,
+                current_loop,
+                shunts,
+                timer,
+            },
+        )
+    }
+
+    // DMA1 ch1 transfer complete - both shunts of this PWM period were converted
+    #[task(binds = DMA1_CH1, shared=[setpoint, measured], local=[timer_pwd, current_loop, shunts, angle: f32 = 0.0], priority = 2)]
+    fn on_shunt_dma(cx: on_shunt_dma::Context) {
+        dma::clear_interrupt(
+            DmaPeriph::Dma1,
+            DmaChannel::C1,
+            DmaInterrupt::TransferComplete,
+        );
+
+        // the next sequence is only written one PWM period later
+        let readings = unsafe { SHUNT_READINGS };
+        let [shunt_a, shunt_b] = cx.local.shunts;
+        let measured = PhaseCurrents {
+            a: shunt_a.current(readings[0]),
+            b: shunt_b.current(readings[1]),
+        };
+
+        let angle = cx.local.angle;
+        *angle =
+            (*angle + core::f32::consts::TAU * ELECTRICAL_HZ / PWM_HZ) % core::f32::consts::TAU;
+        let setpoint = PhaseCurrents::at_angle(*angle, CURRENT);
+
+        let duty = cx
+            .local
+            .current_loop
+            .update(&setpoint, &measured)
+            .bridge_duty();
+
+        for (channel, duty) in CHANNELS.into_iter().zip(BRIDGE.frame(duty)) {
+            cx.local.timer_pwd.set_duty(channel, duty as u32);
+        }
+
+        (cx.shared.setpoint, cx.shared.measured).lock(|s, m| {
+            *s = setpoint;
+            *m = measured;
+        });
+    }
+
+    #[task(binds = TIM3, shared=[setpoint, measured], local=[timer], priority = 1)]
+    fn on_status(cx: on_status::Context) {
+        cx.local.timer.clear_interrupt(TimerInterrupt::Update);
+
+        let (setpoint, measured) = (cx.shared.setpoint, cx.shared.measured).lock(|s, m| (*s, *m));
+
+        defmt::println!(
+            "setpoint: a {:?} b {:?} A, measured: a {:?} b {:?} A",
+            setpoint.a,
+            setpoint.b,
+            measured.a,
+            measured.b
+        );
+    }
+}
+
+#[defmt::panic_handler]
+fn panic() -> ! {
+    cortex_m::asm::udf()
+}
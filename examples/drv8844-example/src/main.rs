@@ -4,52 +4,157 @@
 use defmt_rtt as _;
 use panic_probe as _;
 
+use cln17_bsp::{
+    board::{Bridge, DriverControl, DriverFault, Sw1},
+    pins,
+};
+use cln17_control::{
+    analog::{Divider, FactoryCal},
+    fault::{Action, Conditions, FaultManager, FaultPolicy, FaultState},
+    supply::{FeedForward, SupplyLimits, SupplyMonitor, SupplyState},
+};
+use cln17_motion::{
+    microstep::{self, ChannelMap, FRAME_LEN},
+    steptrain::Half,
+    waveform::Waveform,
+};
 use hal::{
     self,
+    adc::{Adc, AdcDevice, SampleTime},
     clocks::Clocks,
     dma,
-    dma::{ChannelCfg, Circular, Dma, DmaChannel, DmaInput, DmaPeriph, IncrMode, Priority},
-    gpio::{Edge, Pin, PinMode, Port, Pull},
+    dma::{ChannelCfg, Circular, Dma, DmaChannel, DmaInput, DmaInterrupt, DmaPeriph},
+    gpio::Edge,
     pac,
-    pac::{TIM2, TIM3},
+    pac::{DMA1, TIM2, TIM3},
     timer::{
         Alignment, CaptureCompareDma, CountDir, OutputCompare, TimChannel, Timer, TimerConfig,
         TimerInterrupt, UpdateReqSrc,
     },
 };
 
-const STEPS: usize = 128;
-static mut DUTY_CYCLES: [u16; STEPS * 4] = [0; STEPS * 4];
+// microsteps per full step, the table holds one electrical revolution (4 full steps)
+const MICROSTEPS: u16 = 16;
+const TABLE_LEN: usize = microstep::table_len(MICROSTEPS);
+static mut MICROSTEP_TABLE: [u16; TABLE_LEN] = [0; TABLE_LEN];
+
+// PWM rate of the bridges, one frame of DUTY_CYCLES per period
+const PWM_HZ: f32 = 20_000.0;
+// frames streamed by DMA1 ch1, refilled half by half by the Waveform
+const FRAMES: usize = 128;
+const DUTY_CYCLES_LEN: usize = FRAMES * FRAME_LEN;
+static mut DUTY_CYCLES: [u16; DUTY_CYCLES_LEN] = [0; DUTY_CYCLES_LEN];
+
+// SW1 speed in microsteps/s, 1 rev/s of a 200 step motor
+const SPEED: f32 = 200.0 * MICROSTEPS as f32;
+// microsteps/s², reaches SPEED in 0.5 s
+const ACCEL: f32 = 2.0 * SPEED;
+// microsteps/s³, the acceleration builds up in 0.1 s
+const JERK: f32 = 10.0 * ACCEL;
+
+// peak coil current at standstill, the duty follows VBUS to keep it
+const CURRENT: f32 = 0.5;
+const COIL_R_OHM: f32 = 2.0;
+
+// the driver stays off outside of these, 12 V and 24 V supplies pass
+const SUPPLY_LIMITS: SupplyLimits = SupplyLimits {
+    undervoltage: 10.0,
+    overvoltage: 28.0,
+    hysteresis: 0.5,
+};
+// automatic restarts after a fault, overcurrent latches after the retries
+const FAULT_POLICY: FaultPolicy = FaultPolicy {
+    retry_delay_ms: 500,
+    max_retries: 3,
+};
+
+// VBUS the table is built for until the first reading
+const VBUS_NOMINAL: f32 = 24.0;
+
+// VBUS readings per s, TIM4 TRGO starts each scan, the scans are the ms time base
+const SUPPLY_HZ: f32 = 1000.0;
+
+// ADC1 input of the VBUS divider, set it to your board wiring
+const VBUS_CHANNEL: u8 = 2;
+const VREFINT_CHANNEL: u8 = 18;
+const VBUS_R_TOP: f32 = 100_000.;
+const VBUS_R_BOTTOM: f32 = 4_700.;
+
+// EXTSEL of tim4_trgo for ADC1/2 regular conversions
+const ADC_TRIGGER_TIM4_TRGO: u8 = 12;
+
+// VBUS_CHANNEL, VREFINT_CHANNEL, written by DMA1 ch2 once per scan
+static mut SUPPLY_READINGS: [u16; 2] = [0; 2];
+
+// frame slot of IN1 to IN4
+const BRIDGE: ChannelMap = ChannelMap::from_channels(pins::DRV_IN_CHANNELS);
+
 #[rtic::app(device = pac, peripherals = true)]
 mod app {
     use super::*;
 
+    // table is shared by the two priority 2 tasks, so the lock costs nothing
     #[shared]
-    struct Shared {}
+    struct Shared {
+        waveform: Waveform,
+        table: &'static mut [u16],
+        driver: DriverControl,
+        faults: FaultManager,
+        millis: u64,
+    }
 
     #[local]
     struct Local {
+        sw1: Sw1,
+        nfault: DriverFault,
+        dma: Dma<DMA1>,
+        supply: SupplyMonitor,
+        feed_forward: FeedForward,
+        amplitude: u16,
+        vbus: Divider,
+        cal: FactoryCal,
         timer_pwd: Timer<TIM2>,
         timer: Timer<TIM3>,
     }
 
-    fn init_pins() {
+    fn init_pins() -> (Sw1, DriverControl, DriverFault) {
         // setup pins
-        let mut sw1_button = Pin::new(Port::A, 10, PinMode::Input);
-        sw1_button.pull(Pull::Up);
+        let mut sw1_button = Sw1::new();
         sw1_button.enable_interrupt(Edge::Rising); // and enable interrupt
 
-        let mut dr_reset = Pin::new(Port::B, 2, PinMode::Output);
-        dr_reset.set_high();
+        // disabled until the first VBUS reading inside SUPPLY_LIMITS
+        let driver = DriverControl::new();
 
-        let mut dr_en = Pin::new(Port::A, 4, PinMode::Output);
-        dr_en.set_high();
+        let mut nfault = DriverFault::new();
+        nfault.enable_interrupt();
 
-        // driver step pin for motor pwd control
-        Pin::new(Port::A, 1, PinMode::Alt(1)); //   in1 - a2 -- ch2 PA1
-        Pin::new(Port::A, 0, PinMode::Alt(1)); //   in2 - a1 -- ch1 PA0
-        Pin::new(Port::B, 11, PinMode::Alt(1)); //  in3 - b1 -- ch4 PB11
-        Pin::new(Port::B, 10, PinMode::Alt(1)); //  in4 - b2 -- ch3 PB10
+        // driver inputs for motor pwd control, TIM2 ch1-ch4
+        Bridge::new();
+
+        (sw1_button, driver, nfault)
+    }
+
+    // switches the bridges as the fault manager says, the rotor is lost while they are off
+    fn apply(action: Action, driver: &mut DriverControl, waveform: &mut Waveform) {
+        match action {
+            Action::None => {}
+            Action::Disable => {
+                driver.disable();
+                waveform.stop();
+            }
+            Action::Enable => driver.enable(),
+        }
+    }
+
+    fn report_fault(faults: &FaultManager) {
+        if let Some(fault) = faults.fault() {
+            defmt::println!(
+                "{:?} fault at {:?} ms, {:?}",
+                defmt::Debug2Format(&fault.kind),
+                fault.timestamp_ms,
+                defmt::Debug2Format(&faults.state())
+            );
+        }
     }
 
     #[init]
@@ -59,11 +164,12 @@ mod app {
         let clock_cfg = Clocks::default();
         clock_cfg.setup().unwrap();
 
-        init_pins();
+        let (sw1, driver, nfault) = init_pins();
 
+        // the PWM rate stays fixed, the speed comes from the frames the Waveform streams
         let mut timer_pwd = Timer::new_tim2(
             dp.TIM2,
-            500.0,
+            PWM_HZ,
             TimerConfig {
                 one_pulse_mode: false,
                 update_request_source: UpdateReqSrc::Any,
@@ -81,80 +187,298 @@ mod app {
         timer_pwd.enable_pwm_output(TimChannel::C3, OutputCompare::Pwm1, 0.0);
         timer_pwd.enable_pwm_output(TimChannel::C4, OutputCompare::Pwm1, 0.0);
 
-        let _dma = Dma::new(dp.DMA1);
+        let table = unsafe { &mut *core::ptr::addr_of_mut!(MICROSTEP_TABLE) };
+
+        // coil current amplitude for CURRENT, rebuilt as VBUS changes
+        let feed_forward =
+            FeedForward::for_current(CURRENT, COIL_R_OHM, timer_pwd.get_max_duty() as u16);
+        let amplitude = feed_forward.amplitude(VBUS_NOMINAL);
+
+        // cosine on coil A (IN1/IN2) and sine on coil B (IN3/IN4), frames in CCR1..CCR4 order
+        microstep::fill_table(table, MICROSTEPS, amplitude, &BRIDGE)
+            .expect("microstep table does not fit");
+
+        // standstill at microstep 0 until SW1 starts the motor
+        let mut waveform = Waveform::new(MICROSTEPS, PWM_HZ, ACCEL);
+        waveform.set_jerk(JERK);
+        let buf = unsafe { &mut *core::ptr::addr_of_mut!(DUTY_CYCLES) };
+        waveform.start(table, buf);
+
+        // TIM2 update requests DMA1 ch1, it bursts one frame into CCR1..CCR4
+        // same register level setup as dma_pwm_pac, with half transfer and complete interrupts
+        let dma = Dma::new(dp.DMA1);
         dma::enable_mux1();
         dma::mux(DmaPeriph::Dma1, DmaChannel::C1, DmaInput::Tim2Up);
 
+        unsafe {
+            let ma = buf.as_ptr() as usize as u32;
+            let pa = timer_pwd.regs.dmar.as_ptr() as usize as u32;
+            dma.regs.cmar1.write(|w| w.ma().bits(ma));
+            dma.regs.cpar1.write(|w| w.pa().bits(pa));
+            dma.regs
+                .cndtr1
+                .write(|w| w.ndt().bits(DUTY_CYCLES_LEN as u16));
+            dma.regs.ccr1.write(|w| {
+                w.mem2mem()
+                    .clear_bit()
+                    .pl()
+                    .bits(1) // set dma priority
+                    .msize()
+                    .bits(1) // memory word size is 16 bits
+                    .psize()
+                    .bits(2) // peripheral word size is 32 bits
+                    .minc()
+                    .set_bit()
+                    .pinc()
+                    .clear_bit()
+                    .circ()
+                    .set_bit() // dma mode is circular
+                    .dir()
+                    .set_bit() // read from memory
+                    .htie()
+                    .set_bit()
+                    .tcie()
+                    .set_bit()
+            });
+
+            // burst of 4 registers from CCR1
+            timer_pwd
+                .regs
+                .dcr
+                .modify(|_, w| w.dbl().bits(3).dba().bits(0xD));
+            dma.regs.ccr1.modify(|_, w| w.en().set_bit());
+        }
+
         timer_pwd.enable_interrupt(TimerInterrupt::UpdateDma);
+        timer_pwd.enable();
 
-        let pwm_steps = unsafe { &mut DUTY_CYCLES };
+        // VREFINT on ADC1 ch18, VDDA scales the VBUS reading
+        dp.ADC12_COMMON.ccr.modify(|_, w| w.vrefen().set_bit());
 
-        let max_pwm = (timer_pwd.get_max_duty() / 100 * 10) as u16;
+        let mut adc = Adc::new_adc1(
+            dp.ADC1,
+            AdcDevice::One,
+            Default::default(),
+            clock_cfg.systick(),
+        );
 
-        let quadrature_wave: [[u16; 4]; 4] = [
-            [1, 0, 1, 0], // Шаг 1: IN1 = High, IN2 = Low, IN3 = High, IN4 = Low
-            [0, 1, 1, 0], // Шаг 2: IN1 = Low, IN2 = High, IN3 = High, IN4 = Low
-            [0, 1, 0, 1], // Шаг 3: IN1 = Low, IN2 = High, IN3 = Low, IN4 = High
-            [1, 0, 0, 1], // Шаг 4: IN1 = High, IN2 = Low, IN3 = Low, IN4 = High
-        ];
+        adc.set_sequence(1, VBUS_CHANNEL);
+        adc.set_sequence(2, VREFINT_CHANNEL);
+        adc.set_sequence_len(2);
+        adc.set_sample_time(VBUS_CHANNEL, SampleTime::T92);
+        adc.set_sample_time(VREFINT_CHANNEL, SampleTime::T640);
 
-        for step in 0..STEPS {
-            let base_index = step * 4;
-            let phase = step % 4;
+        // conversions start on the rising edge of TIM4 TRGO instead of software
+        adc.regs
+            .cfgr
+            .modify(|_, w| unsafe { w.exten().bits(0b01).extsel().bits(ADC_TRIGGER_TIM4_TRGO) });
 
-            // Заполняем значения IN1, IN2, IN3, IN4 для данного шага
-            pwm_steps[base_index] = quadrature_wave[phase][0] * max_pwm; // IN1
-            pwm_steps[base_index + 1] = quadrature_wave[phase][1] * max_pwm; // IN2
-            pwm_steps[base_index + 2] = quadrature_wave[phase][2] * max_pwm; // IN3
-            pwm_steps[base_index + 3] = quadrature_wave[phase][3] * max_pwm; // IN4
-        }
+        dma::mux(DmaPeriph::Dma1, DmaChannel::C2, DmaInput::Adc1);
 
+        // circular, the DMA wraps after each scan and interrupts on its completion
         unsafe {
-            timer_pwd.write_dma_burst(
-                &DUTY_CYCLES,
-                13,
-                4,
-                DmaChannel::C1,
+            adc.read_dma(
+                &mut SUPPLY_READINGS,
+                &[VBUS_CHANNEL, VREFINT_CHANNEL],
+                DmaChannel::C2,
                 ChannelCfg {
-                    priority: Priority::Medium,
                     circular: Circular::Enabled,
-                    periph_incr: IncrMode::Disabled,
-                    mem_incr: IncrMode::Enabled,
+                    ..Default::default()
                 },
-                true,
                 DmaPeriph::Dma1,
             );
         }
 
+        // the update event is TRGO, only configured here, no interrupt needed
+        let mut supply_timer = Timer::new_tim4(dp.TIM4, SUPPLY_HZ, Default::default(), &clock_cfg);
+        supply_timer
+            .regs
+            .cr2
+            .modify(|_, w| unsafe { w.mms().bits(0b010) });
+        supply_timer.enable();
+
         let mut timer = Timer::new_tim3(dp.TIM3, 1.0, Default::default(), &clock_cfg);
         timer.enable_interrupt(TimerInterrupt::Update);
         timer.enable();
 
-        (Shared {}, Local { timer_pwd, timer })
+        // held off like an undervoltage until the first VBUS reading inside SUPPLY_LIMITS
+        let mut faults = FaultManager::new(FAULT_POLICY);
+        let undervoltage = Conditions {
+            undervoltage: true,
+            ..Default::default()
+        };
+        faults.on_status(undervoltage, 0);
+
+        (
+            Shared {
+                waveform,
+                table,
+                driver,
+                faults,
+                millis: 0,
+            },
+            Local {
+                sw1,
+                nfault,
+                dma,
+                supply: SupplyMonitor::new(SUPPLY_LIMITS),
+                feed_forward,
+                amplitude,
+                vbus: Divider::new(VBUS_R_TOP, VBUS_R_BOTTOM),
+                cal: unsafe { FactoryCal::read() },
+                timer_pwd,
+                timer,
+            },
+        )
     }
 
-    #[task(binds = TIM3, local=[timer_pwd, timer, a: i32 = 0], priority = 1)]
-    fn on_dma1_complete(cx: on_dma1_complete::Context) {
-        cx.local.timer.clear_interrupt(TimerInterrupt::Update);
+    // DMA1 ch1 half transfer or transfer complete - half of the frames were streamed
+    #[task(binds = DMA1_CH1, shared=[waveform, table], local=[dma], priority = 2)]
+    fn on_wave_dma(cx: on_wave_dma::Context) {
+        let dma = &cx.local.dma.regs;
+        let isr = dma.isr.read();
 
-        defmt::println!("psc: {:?}", cx.local.timer_pwd.regs.psc.read().bits());
-        defmt::println!("arr: {:?}", cx.local.timer_pwd.regs.arr.read().bits());
-        defmt::println!("rcr: {:?}", cx.local.timer_pwd.regs.rcr.read().bits());
+        // only the DMA task touches the buffer while the DMA reads the other half
+        let buf = unsafe { &mut *core::ptr::addr_of_mut!(DUTY_CYCLES) };
 
-        defmt::println!(
-            "cx.local.timer_pwd1: {:?}",
-            cx.local.timer_pwd.get_duty(TimChannel::C1)
+        (cx.shared.waveform, cx.shared.table).lock(|waveform, table| {
+            if isr.htif1().bit_is_set() {
+                dma.ifcr.write(|w| w.chtif1().set_bit());
+                waveform.refill(table, buf, Half::First);
+            }
+            if isr.tcif1().bit_is_set() {
+                dma.ifcr.write(|w| w.ctcif1().set_bit());
+                waveform.refill(table, buf, Half::Second);
+            }
+        });
+    }
+
+    // DMA1 ch2 transfer complete - VBUS and VREFINT of this scan were converted
+    #[task(binds = DMA1_CH2, shared=[waveform, table, driver, faults, millis], local=[supply, feed_forward, amplitude, vbus, cal], priority = 2)]
+    fn on_supply_dma(mut cx: on_supply_dma::Context) {
+        dma::clear_interrupt(
+            DmaPeriph::Dma1,
+            DmaChannel::C2,
+            DmaInterrupt::TransferComplete,
         );
-        defmt::println!(
-            "cx.local.timer_pwd2: {:?}",
-            cx.local.timer_pwd.get_duty(TimChannel::C2)
+
+        // the next scan is only written one TIM4 period later
+        let [vbus, vrefint] = unsafe { SUPPLY_READINGS };
+        let vbus = cx.local.vbus.volts(vbus, cx.local.cal.vdda(vrefint));
+
+        let state = cx.local.supply.update(vbus);
+        let conditions = Conditions {
+            undervoltage: state == SupplyState::Undervoltage,
+            overvoltage: state == SupplyState::Overvoltage,
+            ..Default::default()
+        };
+
+        // the nFAULT task shares these, keep the table rebuild out of the lock
+        (
+            cx.shared.waveform,
+            cx.shared.driver,
+            cx.shared.faults,
+            cx.shared.millis,
+        )
+            .lock(|waveform, driver, faults, millis| {
+                *millis += 1;
+
+                let action = faults.on_status(conditions, *millis);
+                apply(action, driver, waveform);
+                match action {
+                    Action::Disable => report_fault(faults),
+                    Action::Enable => defmt::println!("vbus {:?} V, driver on", vbus),
+                    Action::None => {}
+                }
+            });
+
+        if !state.is_ok() {
+            return;
+        }
+
+        // the ripple would rebuild the table on every scan, only follow changes above 1/64
+        let amplitude = cx.local.feed_forward.amplitude(vbus);
+        if amplitude.abs_diff(*cx.local.amplitude) > *cx.local.amplitude / 64 {
+            *cx.local.amplitude = amplitude;
+            cx.shared.table.lock(|table| {
+                microstep::fill_table(table, MICROSTEPS, amplitude, &BRIDGE).ok();
+            });
+        }
+    }
+
+    // EXTI4 - nFAULT went low, the driver shut its bridges down, keep them off at once
+    #[task(binds = EXTI4, local=[nfault], shared=[waveform, driver, faults, millis], priority = 3)]
+    fn on_driver_fault(cx: on_driver_fault::Context) {
+        cx.local.nfault.clear_interrupt();
+
+        (
+            cx.shared.waveform,
+            cx.shared.driver,
+            cx.shared.faults,
+            cx.shared.millis,
+        )
+            .lock(|waveform, driver, faults, millis| {
+                let action = faults.on_fault_pin(*millis);
+                apply(action, driver, waveform);
+                if action == Action::Disable {
+                    report_fault(faults);
+                }
+            });
+    }
+
+    // SW1 cycles forward, reverse and stop, the stop holds the last microstep
+    // after a latched fault the press clears it instead
+    #[task(binds = EXTI15_10, shared=[waveform, driver, faults], local=[sw1], priority = 1)]
+    fn on_button(cx: on_button::Context) {
+        cx.local.sw1.clear_interrupt();
+
+        (cx.shared.waveform, cx.shared.driver, cx.shared.faults).lock(
+            |waveform, driver, faults| {
+                if faults.state() == FaultState::Latched {
+                    let action = faults.clear();
+                    apply(action, driver, waveform);
+                    if action == Action::Enable {
+                        defmt::println!("fault cleared");
+                    }
+                    return;
+                }
+
+                if waveform.target_speed() == 0.0 {
+                    waveform.set_speed(SPEED);
+                } else if waveform.target_speed() > 0.0 {
+                    waveform.reverse();
+                } else {
+                    waveform.stop();
+                }
+
+                defmt::println!("target speed: {:?} microsteps/s", waveform.target_speed());
+            },
         );
+    }
+
+    #[task(binds = TIM3, shared=[waveform], local=[timer_pwd, timer], priority = 1)]
+    fn on_status(mut cx: on_status::Context) {
+        cx.local.timer.clear_interrupt(TimerInterrupt::Update);
+
+        let (speed, position, angle) = cx.shared.waveform.lock(|waveform| {
+            (
+                waveform.speed(),
+                waveform.position(),
+                waveform.electrical_angle(),
+            )
+        });
+
         defmt::println!(
-            "cx.local.timer_pwd3: {:?}",
-            cx.local.timer_pwd.get_duty(TimChannel::C3)
+            "speed: {:?} microsteps/s, position: {:?}, electrical angle: {:?} rad",
+            speed,
+            position,
+            angle
         );
         defmt::println!(
-            "cx.local.timer_pwd4: {:?}",
+            "duty: {:?} {:?} {:?} {:?}",
+            cx.local.timer_pwd.get_duty(TimChannel::C1),
+            cx.local.timer_pwd.get_duty(TimChannel::C2),
+            cx.local.timer_pwd.get_duty(TimChannel::C3),
             cx.local.timer_pwd.get_duty(TimChannel::C4)
         );
     }
@@ -0,0 +1,209 @@
+#![no_main]
+#![no_std]
+
+use defmt_rtt as _;
+use panic_probe as _;
+
+use cln17_bsp::{
+    board::{Bridge, DriverControl, EncoderSpi},
+    pins,
+};
+use cln17_control::{
+    calibration::{Calibration, Calibrator, STORED_LEN},
+    foc::HYBRID_STEPPER_POLE_PAIRS,
+};
+use cln17_motion::microstep::{self, ChannelMap, FRAME_LEN};
+use hal::{
+    self,
+    clocks::Clocks,
+    flash::{Bank, Flash},
+    gpio::Pin,
+    pac,
+    pac::{SPI1, TIM2, TIM3},
+    spi::{BaudRate, Spi, SpiConfig, SpiMode},
+    timer::{
+        Alignment, CaptureCompareDma, CountDir, OutputCompare, TimChannel, Timer, TimerConfig,
+        TimerInterrupt, UpdateReqSrc,
+    },
+};
+use tle5012::Tle5012;
+
+// microsteps per full step of the open loop drive
+const MICROSTEPS: u16 = 16;
+// one mechanical revolution each way fills the whole correction table
+const REVOLUTIONS: u16 = HYBRID_STEPPER_POLE_PAIRS;
+
+// microsteps per s, the encoder is read right before the next microstep
+const STEP_HZ: f32 = 500.0;
+// ticks the first microstep is held, the rotor settles from anywhere
+const SETTLE_TICKS: u32 = 500;
+
+// last 2 KB page of the 128 KB flash, memory.x keeps the firmware below it
+const CALIBRATION_PAGE: usize = 63;
+
+// frame slot of IN1 to IN4
+const BRIDGE: ChannelMap = ChannelMap::from_channels(pins::DRV_IN_CHANNELS);
+
+const CHANNELS: [TimChannel; FRAME_LEN] = [
+    TimChannel::C1,
+    TimChannel::C2,
+    TimChannel::C3,
+    TimChannel::C4,
+];
+
+#[rtic::app(device = pac, peripherals = true)]
+mod app {
+    use super::*;
+
+    #[shared]
+    struct Shared {}
+
+    #[local]
+    struct Local {
+        timer_pwd: Timer<TIM2>,
+        encoder: Tle5012<Spi<SPI1>, Pin>,
+        flash: Flash,
+        calibrator: Calibrator,
+        amplitude: u16,
+        timer: Timer<TIM3>,
+    }
+
+    fn init_pins() -> EncoderSpi {
+        let mut driver = DriverControl::new();
+        driver.enable();
+
+        // driver inputs for motor pwd control, TIM2 ch1-ch4
+        Bridge::new();
+
+        EncoderSpi::new()
+    }
+
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local) {
+        let dp = ctx.device;
+
+        let clock_cfg = Clocks::default();
+        clock_cfg.setup().unwrap();
+
+        let encoder_pins = init_pins();
+
+        let flash = Flash::new(dp.FLASH);
+        let mut stored = [0; STORED_LEN];
+        flash.read(Bank::B1, CALIBRATION_PAGE, 0, &mut stored);
+        match Calibration::from_bytes(&stored) {
+            Some(cal) => defmt::println!(
+                "stored calibration: offset {:?} rad, reversed {:?}, recalibrating",
+                cal.angle.offset,
+                cal.angle.reversed
+            ),
+            None => defmt::println!("no stored calibration"),
+        }
+
+        let mut timer_pwd = Timer::new_tim2(
+            dp.TIM2,
+            20_000.0,
+            TimerConfig {
+                one_pulse_mode: false,
+                update_request_source: UpdateReqSrc::Any,
+                auto_reload_preload: true,
+                alignment: Alignment::Edge,
+                capture_compare_dma: CaptureCompareDma::Ccx,
+                direction: CountDir::Up,
+            },
+            &clock_cfg,
+        );
+
+        for channel in CHANNELS {
+            timer_pwd.enable_pwm_output(channel, OutputCompare::Pwm1, 0.0);
+        }
+        timer_pwd.enable();
+
+        // coil current amplitude, 10% of max duty
+        let amplitude = (timer_pwd.get_max_duty() / 100 * 10) as u16;
+
+        let spi_cfg = SpiConfig {
+            mode: SpiMode::mode1(),
+            ..Default::default()
+        };
+        let spi1 = Spi::new(dp.SPI1, spi_cfg, BaudRate::Div32);
+        let encoder = Tle5012::new(spi1, encoder_pins.cs);
+
+        let calibrator = Calibrator::new(
+            HYBRID_STEPPER_POLE_PAIRS,
+            microstep::steps_per_cycle(MICROSTEPS),
+            REVOLUTIONS,
+        );
+
+        let mut timer = Timer::new_tim3(dp.TIM3, STEP_HZ, Default::default(), &clock_cfg);
+        timer.enable_interrupt(TimerInterrupt::Update);
+        timer.enable();
+
+        (
+            Shared {},
+            Local {
+                timer_pwd,
+                encoder,
+                flash,
+                calibrator,
+                amplitude,
+                timer,
+            },
+        )
+    }
+
+    // records the settled rotor and drives the next microstep
+    #[task(binds = TIM3, local=[timer_pwd, encoder, flash, calibrator, amplitude, timer, ticks: u32 = 0], priority = 1)]
+    fn on_step(cx: on_step::Context) {
+        cx.local.timer.clear_interrupt(TimerInterrupt::Update);
+
+        let calibrator = cx.local.calibrator;
+        let Some(index) = calibrator.command() else {
+            return;
+        };
+
+        *cx.local.ticks += 1;
+        if *cx.local.ticks > SETTLE_TICKS {
+            match cx.local.encoder.read_angle() {
+                Ok(angle) => calibrator.record(angle.radians()),
+                // stay on the microstep and read again on the next tick
+                Err(_) => defmt::println!("encoder read failed at microstep {:?}", index),
+            }
+        }
+
+        // hold the last microstep after the run
+        let index = calibrator.command().unwrap_or(index);
+        let duty = microstep::coil_currents(index, MICROSTEPS, *cx.local.amplitude).bridge_duty();
+        for (channel, duty) in CHANNELS.into_iter().zip(BRIDGE.frame(duty)) {
+            cx.local.timer_pwd.set_duty(channel, duty as u32);
+        }
+
+        if !calibrator.is_done() {
+            return;
+        }
+
+        match calibrator.finish() {
+            Ok(cal) => {
+                defmt::println!(
+                    "calibrated: offset {:?} rad, reversed {:?}",
+                    cal.angle.offset,
+                    cal.angle.reversed
+                );
+
+                match cx
+                    .local
+                    .flash
+                    .erase_write_page(Bank::B1, CALIBRATION_PAGE, &cal.to_bytes())
+                {
+                    Ok(()) => defmt::println!("stored in flash page {:?}", CALIBRATION_PAGE),
+                    Err(_) => defmt::println!("flash write failed"),
+                }
+            }
+            Err(err) => defmt::println!("calibration failed: {:?}", defmt::Debug2Format(&err)),
+        }
+    }
+}
+
+#[defmt::panic_handler]
+fn panic() -> ! {
+    cortex_m::asm::udf()
+}
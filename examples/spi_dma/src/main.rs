@@ -4,45 +4,55 @@
 use defmt_rtt as _;
 use panic_probe as _;
 
-use cortex_m::prelude::_embedded_hal_blocking_delay_DelayMs;
+use cln17_bsp::board::EncoderSpi;
+use cln17_control::encoder::{MultiTurn, Pll};
 use hal::dma;
-use hal::dma::DmaInterrupt;
 use hal::dma::{Dma, DmaChannel, DmaInput, DmaPeriph};
-use hal::gpio::{Pin, PinMode, Port};
-use hal::pac::SPI1;
+use hal::gpio::Pin;
+use hal::pac::{DMA1, TIM4};
 use hal::spi::{BaudRate, Spi, SpiConfig, SpiMode};
 use hal::timer::{Timer, TimerInterrupt};
 use hal::{self, clocks::Clocks, pac, pac::TIM3};
-
-static mut SPI_READ_BUF: [u8; 4] = [0; 4];
-static mut SPI_WRITE_BUF: [u8; 4] = [0x80, 0x20, 0x00, 0x00];
+use tle5012::{
+    frame::{self, FRAME_LEN},
+    reg::Address,
+    stream::{DoubleBuffer, BUFFER_LEN},
+    COUNTS_PER_REV,
+};
+
+// angle reads per s, the DMA transfer of one read takes about 10 us at SPI1 / 32
+const SAMPLE_HZ: f32 = 20_000.0;
+// rate of the loop reading the newest angle, it does not need to match SAMPLE_HZ
+const CONTROL_HZ: f32 = 1_000.0;
+// velocity tracking bandwidth, lower smooths the one count steps more
+const PLL_BANDWIDTH_HZ: f32 = 50.0;
+
+// one word read of the angle value register, the same frame is sent every time
+static SPI_WRITE_BUF: [u8; FRAME_LEN] = frame::read_request(Address::Aval as u8);
+
+// replies, written by the circular RX DMA one half per frame, read lock-free
+static ENCODER: DoubleBuffer = DoubleBuffer::new();
 
 #[rtic::app(device = pac, peripherals = true)]
 mod app {
     use super::*;
 
+    // only shared by the two priority 4 tasks, so the locks cost nothing
     #[shared]
     struct Shared {
-        spi1: Spi<SPI1>,
+        cs_pin: Pin,
+        dma: Dma<DMA1>,
     }
 
     #[local]
     struct Local {
-        cs_pin: Pin,
+        sample_timer: Timer<TIM4>,
 
+        multi_turn: MultiTurn,
+        pll: Pll,
         timer: Timer<TIM3>,
     }
 
-    fn init_pins() {
-        Pin::new(Port::A, 5, PinMode::Alt(5)); // PA5 SPI1_SCK
-        Pin::new(Port::A, 6, PinMode::Alt(5)); // PA6 SPI1_MISO
-        Pin::new(Port::A, 7, PinMode::Alt(5)); // PA7 SPI1_MOSI
-
-        // Configure CS pin on Port C, Pin 4 as output
-        let mut cs_pin = Pin::new(Port::C, 4, PinMode::Output);
-        cs_pin.set_high();
-    }
-
     #[init]
     fn init(ctx: init::Context) -> (Shared, Local) {
         let _cp = ctx.core;
@@ -51,83 +61,172 @@ mod app {
         let clock_cfg = Clocks::default();
         clock_cfg.setup().unwrap();
 
-        init_pins();
+        let encoder_pins = EncoderSpi::new();
 
         let spi_cfg = SpiConfig {
             mode: SpiMode::mode1(),
             ..Default::default()
         };
 
-        let mut spi1 = Spi::new(dp.SPI1, spi_cfg, BaudRate::Div32);
+        // only configured here, the DMA drives it from then on
+        let spi1 = Spi::new(dp.SPI1, spi_cfg, BaudRate::Div32);
 
-        let _dma = Dma::new(dp.DMA1);
+        // SPI1 TX on DMA1 ch1, one frame per sample, re-armed by the sample timer
+        // SPI1 RX on DMA1 ch2, circular over the two halves of ENCODER
+        let dma = Dma::new(dp.DMA1);
         dma::enable_mux1();
         dma::mux(DmaPeriph::Dma1, DmaChannel::C1, DmaInput::Spi1Tx);
         dma::mux(DmaPeriph::Dma1, DmaChannel::C2, DmaInput::Spi1Rx);
 
-        let mut timer = Timer::new_tim3(dp.TIM3, 1., Default::default(), &clock_cfg);
+        unsafe {
+            let ma = SPI_WRITE_BUF.as_ptr() as usize as u32; // mem address
+            let pa = spi1.regs.dr.as_ptr() as usize as u32; // spi1 data register address
+
+            dma.regs.cmar1.write(|w| w.ma().bits(ma));
+            dma.regs.cpar1.write(|w| w.pa().bits(pa));
+            dma.regs.ccr1.write(|w| {
+                w.mem2mem()
+                    .clear_bit() // source is memory, disable memory to memory transfer
+                    .pl()
+                    .bits(1) // set dma priority
+                    .msize()
+                    .bits(0) // memory word size is 8 bits
+                    .psize()
+                    .bits(0) // peripheral word size is 8 bits
+                    .minc()
+                    .set_bit() // increment memory address every transfer
+                    .pinc()
+                    .clear_bit() // not increment peripheral address every transfer
+                    .circ()
+                    .clear_bit() // stops after the frame, the sample timer starts the next one
+                    .dir()
+                    .set_bit() // set to read from memory
+            });
+
+            dma.regs.cmar2.write(|w| w.ma().bits(ENCODER.dma_address()));
+            dma.regs.cpar2.write(|w| w.pa().bits(pa));
+            dma.regs.cndtr2.write(|w| w.ndt().bits(BUFFER_LEN as u16));
+            dma.regs.ccr2.write(|w| {
+                w.mem2mem()
+                    .clear_bit()
+                    .pl()
+                    .bits(2) // above TX, the RX FIFO must not overflow
+                    .msize()
+                    .bits(0) // memory word size is 8 bits
+                    .psize()
+                    .bits(0) // peripheral word size is 8 bits
+                    .minc()
+                    .set_bit()
+                    .pinc()
+                    .clear_bit()
+                    .circ()
+                    .set_bit() // dma mode is circular
+                    .dir()
+                    .clear_bit() // set to read from peripheral
+                    .htie()
+                    .set_bit() // interrupt when the first frame is in
+                    .tcie()
+                    .set_bit() // interrupt when the second frame is in
+                    .en()
+                    .set_bit()
+            });
+
+            // RXNE for every byte, then the DMA requests, RX first so no byte is lost
+            spi1.regs
+                .cr2
+                .modify(|_, w| w.frxth().set_bit().rxdmaen().set_bit());
+            spi1.regs.cr2.modify(|_, w| w.txdmaen().set_bit());
+        }
+
+        // every update pulls CS low and re-arms the TX DMA, the frame itself runs in hardware
+        let mut sample_timer = Timer::new_tim4(dp.TIM4, SAMPLE_HZ, Default::default(), &clock_cfg);
+        sample_timer.enable_interrupt(TimerInterrupt::Update);
+        sample_timer.enable();
+
+        let mut timer = Timer::new_tim3(dp.TIM3, CONTROL_HZ, Default::default(), &clock_cfg);
         timer.enable_interrupt(TimerInterrupt::Update);
         timer.enable();
 
         (
-            Shared { spi1 },
+            Shared {
+                cs_pin: encoder_pins.cs,
+                dma,
+            },
             Local {
-                cs_pin: Pin::new(Port::C, 4, PinMode::Output),
+                sample_timer,
+                multi_turn: MultiTurn::new(COUNTS_PER_REV),
+                pll: Pll::new(PLL_BANDWIDTH_HZ, CONTROL_HZ),
                 timer,
             },
         )
     }
 
-    #[task(binds = DMA1_CH2, local=[cs_pin], shared = [spi1], priority = 1)]
-    fn on_encoder_rx(mut cx: on_encoder_rx::Context) {
-        dma::clear_interrupt(
-            DmaPeriph::Dma1,
-            DmaChannel::C2,
-            DmaInterrupt::TransferComplete,
-        );
+    // starts one frame, CS stays low until its reply is in
+    #[task(binds = TIM4, local=[sample_timer], shared = [cs_pin, dma], priority = 4)]
+    fn on_sample(cx: on_sample::Context) {
+        cx.local
+            .sample_timer
+            .clear_interrupt(TimerInterrupt::Update);
 
-        defmt::println!("SPI DMA read complete");
+        (cx.shared.cs_pin, cx.shared.dma).lock(|cs_pin, dma| {
+            cs_pin.set_low();
 
-        cx.shared.spi1.lock(|spi1| {
-            spi1.stop_dma(DmaChannel::C1, Some(DmaChannel::C2), DmaPeriph::Dma1);
-            spi1.cleanup_dma(DmaPeriph::Dma1, DmaChannel::C1, Some(DmaChannel::C2));
+            let dma = &dma.regs;
+            dma.ccr1.modify(|_, w| w.en().clear_bit());
+            dma.cndtr1.write(|w| w.ndt().bits(FRAME_LEN as u16));
+            dma.ccr1.modify(|_, w| w.en().set_bit());
         });
+    }
 
-        unsafe {
-            let buf = unsafe { &mut SPI_READ_BUF };
-
-            let respond = ((buf[2] as u16) << 8) | buf[3] as u16;
-            let res = respond << 1;
-
-            defmt::println!("Data read: {:?}", res);
-        }
-
-        cx.local.cs_pin.set_high();
+    // DMA1 ch2 half transfer or transfer complete - one reply is in ENCODER
+    #[task(binds = DMA1_CH2, shared = [cs_pin, dma], priority = 4)]
+    fn on_encoder_rx(cx: on_encoder_rx::Context) {
+        (cx.shared.cs_pin, cx.shared.dma).lock(|cs_pin, dma| {
+            cs_pin.set_high();
+
+            let dma = &dma.regs;
+            let isr = dma.isr.read();
+            if isr.htif2().bit_is_set() {
+                dma.ifcr.write(|w| w.chtif2().set_bit());
+                ENCODER.complete(0);
+            }
+            if isr.tcif2().bit_is_set() {
+                dma.ifcr.write(|w| w.ctcif2().set_bit());
+                ENCODER.complete(1);
+            }
+        });
     }
 
-    #[task(binds = TIM3, local=[timer], shared = [spi1], priority = 2)]
-    fn on_timer(mut cx: on_timer::Context) {
+    // control loop side, takes the newest angle without locking out the sampling,
+    // it must stay below the priority of on_encoder_rx for ENCODER.latest()
+    #[task(binds = TIM3, local=[timer, multi_turn, pll, ticks: u32 = 0, errors: u32 = 0], priority = 1)]
+    fn on_control(cx: on_control::Context) {
         cx.local.timer.clear_interrupt(TimerInterrupt::Update);
-        req_spi_transfer::spawn().expect("can not spawn on_spi_transfer");
-    }
 
-    #[task(priority = 0, shared = [spi1])]
-    async fn req_spi_transfer(mut cx: req_spi_transfer::Context) {
-        defmt::println!("transfer_dma");
-
-        Pin::new(Port::C, 4, PinMode::Output).set_low();
-
-        cx.shared.spi1.lock(|spi1| unsafe {
-            spi1.transfer_dma(
-                &SPI_WRITE_BUF,
-                &mut SPI_READ_BUF,
-                DmaChannel::C1,
-                DmaChannel::C2,
-                Default::default(),
-                Default::default(),
-                DmaPeriph::Dma1,
-            );
-        });
+        // a rejected frame is skipped and counted, the next good one catches up
+        match ENCODER.latest().map(|sample| sample.angle()) {
+            Some(Ok(angle)) => {
+                let position = cx.local.multi_turn.update(angle.counts() as u32);
+                cx.local.pll.update(position);
+            }
+            Some(Err(_)) | None => *cx.local.errors += 1,
+        }
+
+        *cx.local.ticks += 1;
+        if *cx.local.ticks < CONTROL_HZ as u32 {
+            return;
+        }
+        *cx.local.ticks = 0;
+
+        let multi_turn = cx.local.multi_turn;
+        defmt::println!(
+            "frames: {:?}, position: {:?} counts, {:?} turns, velocity: {:?} rev/s, rejected: {:?}",
+            ENCODER.seq(),
+            multi_turn.position(),
+            multi_turn.turns(),
+            cx.local.pll.velocity() / COUNTS_PER_REV as f32,
+            *cx.local.errors
+        );
     }
 }
 
@@ -4,82 +4,1916 @@
 use defmt_rtt as _;
 use panic_probe as _;
 
+use cln17_bsp::board::{Console, Diag, EncoderSpi, StepDir, Sw1, Uart};
+use cln17_control::{
+    analog::FactoryCal,
+    encoder::MultiTurn,
+    fault::{Action, Conditions, FaultManager, FaultPolicy, FaultState},
+    steploss::{StepLoss, StepLossConfig, StepLossMonitor},
+    thermal::{DeratingPoint, DriverFlags, Thermal, ThermalConfig},
+};
+use cln17_gcode::{
+    Command as Gcode, Config as GcodeConfig, ErrorKind as GcodeErrorKind, Interpreter,
+};
+use cln17_motion::{
+    stepgen::{Direction, Profile, Ramp, StepGenerator},
+    steptrain::{Half, StepTrain},
+    trapezoid::Limits,
+};
+use cln17_shell::{line::LineBuffer, Args, Command, Error as ShellError, Shell};
+use core::{
+    cell::RefCell,
+    fmt::{self, Write},
+    future::poll_fn,
+    ptr::{addr_of, addr_of_mut},
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Poll, Waker},
+};
+use cortex_m::{
+    interrupt::{self, Mutex},
+    peripheral::DWT,
+};
 use hal::{
     self,
+    adc::{Adc, AdcDevice, SampleTime},
     clocks::Clocks,
-    gpio,
-    gpio::{Edge, Pin, PinMode, Port, Pull},
+    dma,
+    dma::{ChannelCfg, Circular, Dma, DmaChannel, DmaInput, DmaPeriph},
+    gpio::{Edge, Pin},
     pac,
-    timer::{OutputCompare, TimChannel, Timer, TimerConfig},
-    usart::{Usart, UsartConfig},
+    pac::{ADC1, DMA1, SPI1, TIM3, TIM4, USART1, USART3},
+    spi::{BaudRate, Spi, SpiConfig, SpiMode},
+    timer::{OutputCompare, TimChannel, Timer, TimerConfig, TimerInterrupt, UpdateReqSrc},
+    usart::{Usart, UsartConfig, UsartInterrupt},
+};
+use tle5012::{Tle5012, COUNTS_PER_REV};
+use tmc2209_uart::{
+    chopper::{ChopperMode, DEFAULT_TOFF},
+    current::{Current, CurrentConfig},
+    homing::{Homing, HomingConfig, HomingState},
+    reg::{self, Address},
+    stallguard::{StallGuard, Tuner},
+    units::{Mechanics, Velocity},
+    Bus, SlaveAddr, Tmc2209,
+};
+
+type TmcBus = Bus<Usart<USART3>>;
+type Driver<'a> = Tmc2209<'a, Usart<USART3>>;
+type Encoder = Tle5012<Spi<SPI1>, Pin>;
+
+// homing speed in steps/s, the stallguard tuning runs at this speed too
+const STEP_HZ: f32 = 1000.;
+// driver default with MS1/MS2 used as the uart address
+const MICROSTEPS: u16 = 8;
+
+// step timer tick, the period between two step pulses comes from STEP_BUFFER
+// TIM3 is a 16 bit timer, longer intervals are cut, at 1 MHz slower than ~15 steps/s
+const STEP_TICK_HZ: u32 = 1_000_000;
+// low phase before the rising step edge, TMC2209 needs 100 ns
+const STEP_PULSE_TICKS: u16 = 2;
+
+// one half lasts 4 ms at the top speed, enough for the refill
+const STEP_BUFFER_LEN: usize = 64;
+// ARR values written by DMA1 channel 2 on every TIM3 update, filled by StepTrain
+static mut STEP_BUFFER: [u16; STEP_BUFFER_LEN] = [0; STEP_BUFFER_LEN];
+
+const HOMING_LIMITS: Limits = Limits {
+    max_speed: STEP_HZ,
+    accel: 4000.,
+    decel: 40_000.,
+};
+
+const MOVE_LIMITS: Limits = Limits {
+    max_speed: 8000.,
+    accel: 16_000.,
+    decel: 16_000.,
+};
+// moves ramp the acceleration up within 50 ms, belts ring less than with a plain trapezoid
+const MOVE_RAMP: Ramp = Ramp::SCurve { jerk: 320_000. };
+
+// the button moves between home and this position once homed, 10 revolutions
+const TRAVEL_STEPS: i64 = 10 * 200 * MICROSTEPS as i64;
+
+// 1.8° motor on a GT2 belt with a 20 tooth pulley
+const MECHANICS: Mechanics = Mechanics {
+    full_steps: 200,
+    microsteps: MICROSTEPS,
+    mm_per_rev: 40.,
+};
+
+// typical TMC2209 module with 110 mOhm sense resistors
+const MOTOR_CURRENT: CurrentConfig = CurrentConfig {
+    run_ma: 800,
+    hold_ma: 400,
+    rsense_mohm: 110,
+    vsense: None,
+    standstill_ms: 500,
+    power_down_ms: 200,
+};
+
+// SG_RESULT reads while the motor runs free at the homing speed for the threshold tuning,
+// a read takes about 20 ms so the cruise of TUNE_STEPS fits them with room to spare
+const TUNE_SAMPLES: u32 = 64;
+const TUNE_MARGIN_PERCENT: u8 = 30;
+const TUNE_STEPS: i64 = 4000;
+
+// encoder reads per s for the step-loss check
+const MONITOR_HZ: f32 = 1000.;
+
+// the step train counts a step when it is queued, so the fault limit is a full buffer
+// ahead plus one electrical revolution, a skip loses four full steps at once
+const STEP_LOSS: StepLossConfig = StepLossConfig {
+    steps_per_rev: 200 * MICROSTEPS as u32,
+    counts_per_rev: COUNTS_PER_REV,
+    // flip when the error grows with every move
+    reversed: false,
+    max_error: STEP_BUFFER_LEN as u32 + 4 * MICROSTEPS as u32,
+    fault_samples: 3,
+    // more than a full step off at standstill moves the position to the encoder
+    correct_above: Some(MICROSTEPS as u32),
+};
+
+// DRV_STATUS is read this often, one read takes about 20 ms at 9600 baud
+const STATUS_POLL_MS: u32 = 100;
+
+// the driver shuts itself down on a short or overtemperature, TOFF 0 clears it before a retry
+const FAULT_POLICY: FaultPolicy = FaultPolicy {
+    retry_delay_ms: 1000,
+    max_retries: 3,
+};
+
+// run and hold current scale over the hotter of the MCU die and the driver
+const DERATING: &[DeratingPoint] = &[
+    DeratingPoint {
+        temperature: 70.,
+        scale: 1.,
+    },
+    DeratingPoint {
+        temperature: 100.,
+        scale: 0.6,
+    },
+    DeratingPoint {
+        temperature: 120.,
+        scale: 0.3,
+    },
+];
+
+// the die follows the board slowly, the filter keeps ADC noise out of the current
+const THERMAL: ThermalConfig = ThermalConfig {
+    curve: DERATING,
+    shutdown: 130.,
+    restart: 100.,
+    filter_s: 5.,
+};
+
+// the current is only rewritten when the scale moved this much
+const DERATING_STEP: f32 = 0.05;
+
+// ADC1 internal channels
+const TEMP_CHANNEL: u8 = 16;
+const VREFINT_CHANNEL: u8 = 18;
+
+const CONSOLE_BAUD: u32 = 115_200;
+// DMA1 ch3 writes the console input round this buffer, a command running longer
+// than the buffer takes to fill at the baud rate loses what was typed meanwhile
+// holds a full G-code line, the task only wakes up on the idle line after it
+const CONSOLE_BUFFER_LEN: usize = 128;
+static mut CONSOLE_BUFFER: [u8; CONSOLE_BUFFER_LEN] = [0; CONSOLE_BUFFER_LEN];
+// set on the idle line, the console task waits for it once it read everything
+static CONSOLE_RX: AtomicBool = AtomicBool::new(false);
+static CONSOLE_WAKER: Mutex<RefCell<Option<Waker>>> = Mutex::new(RefCell::new(None));
+
+// lines of up to 64 characters, the last 8 in the history
+type ConsoleShell = Shell<Option<Request>, 64, 8>;
+
+// printer hosts send up to about 80 characters with line number and checksum
+type GcodeLine = LineBuffer<96>;
+
+const STEPS_PER_MM: f32 = MECHANICS.full_steps as f32 * MICROSTEPS as f32 / MECHANICS.mm_per_rev;
+
+// G-code in mm on the belt axis, G0 runs at the move speed
+const GCODE: GcodeConfig = GcodeConfig {
+    axis: 'X',
+    steps_per_mm: STEPS_PER_MM,
+    max_feed: MOVE_LIMITS.max_speed / STEPS_PER_MM * 60.,
+    default_feed: 3000.,
 };
 
+// TMC2209 registers the regs command reads
+const REGISTERS: [(&str, Address); 10] = [
+    ("GCONF", Address::GCONF),
+    ("GSTAT", Address::GSTAT),
+    ("IFCNT", Address::IFCNT),
+    ("IOIN", Address::IOIN),
+    ("TSTEP", Address::TSTEP),
+    ("SG_RESULT", Address::SG_RESULT),
+    ("MSCNT", Address::MSCNT),
+    ("CHOPCONF", Address::CHOPCONF),
+    ("DRV_STATUS", Address::DRV_STATUS),
+    ("PWMCONF", Address::PWMCONF),
+];
+
+// write only registers, the regs command shows what the shadow last wrote
+const WRITE_ONLY: [(&str, Address); 8] = [
+    ("SLAVECONF", Address::SLAVECONF),
+    ("IHOLD_IRUN", Address::IHOLD_IRUN),
+    ("TPOWERDOWN", Address::TPOWERDOWN),
+    ("TPWMTHRS", Address::TPWMTHRS),
+    ("TCOOLTHRS", Address::TCOOLTHRS),
+    ("VACTUAL", Address::VACTUAL),
+    ("SGTHRS", Address::SGTHRS),
+    ("COOLCONF", Address::COOLCONF),
+];
+
+const COMMANDS: &[Command<Option<Request>>] = &[
+    Command {
+        name: "move",
+        usage: "<position>",
+        help: "move to a position in steps",
+        run: cmd_move,
+    },
+    Command {
+        name: "jog",
+        usage: "<steps>",
+        help: "move by steps, negative ones backwards",
+        run: cmd_jog,
+    },
+    Command {
+        name: "stop",
+        usage: "",
+        help: "stop at once, homing as well",
+        run: cmd_stop,
+    },
+    Command {
+        name: "speed",
+        usage: "[steps/s]",
+        help: "show or set the speed of the next moves",
+        run: cmd_speed,
+    },
+    Command {
+        name: "current",
+        usage: "[run mA] [hold mA]",
+        help: "show or set the motor current",
+        run: cmd_current,
+    },
+    Command {
+        name: "encoder",
+        usage: "",
+        help: "encoder position and following error",
+        run: cmd_encoder,
+    },
+    Command {
+        name: "regs",
+        usage: "",
+        help: "dump the driver registers",
+        run: cmd_regs,
+    },
+    Command {
+        name: "faults",
+        usage: "[clear]",
+        help: "show the faults, clear ends a latched driver fault",
+        run: cmd_faults,
+    },
+    Command {
+        name: "tune",
+        usage: "",
+        help: "run the free motor and set the stall threshold of homing",
+        run: cmd_tune,
+    },
+    Command {
+        name: "config",
+        usage: "[accel|decel|jerk <value>]",
+        help: "show or change the move settings, jerk 0 is a trapezoid",
+        run: cmd_config,
+    },
+    Command {
+        name: "gcode",
+        usage: "",
+        help: "take G-code from a printer host, the line exit returns",
+        run: cmd_gcode,
+    },
+];
+
+// settings the console changes at run time
+#[derive(Clone, Copy)]
+struct Config {
+    limits: Limits,
+    ramp: Ramp,
+    current: CurrentConfig,
+}
+
+#[derive(Clone, Copy)]
+enum Setting {
+    Accel,
+    Decel,
+    Jerk,
+}
+
+// a parsed console command, the console task runs it with the resources it needs
+#[derive(Clone, Copy)]
+enum Request {
+    Move(i64),
+    Jog(i64),
+    Stop,
+    Speed(Option<f32>),
+    Current(Option<(u16, Option<u16>)>),
+    Encoder,
+    Registers,
+    Faults { clear: bool },
+    Config(Option<(Setting, f32)>),
+    Tune,
+    Gcode,
+}
+
+fn cmd_move(
+    request: &mut Option<Request>,
+    args: &mut Args,
+    _: &mut dyn Write,
+) -> Result<(), ShellError> {
+    let target = args.required("position")?;
+    args.end()?;
+
+    *request = Some(Request::Move(target));
+    Ok(())
+}
+
+fn cmd_jog(
+    request: &mut Option<Request>,
+    args: &mut Args,
+    _: &mut dyn Write,
+) -> Result<(), ShellError> {
+    let steps = args.required("steps")?;
+    args.end()?;
+
+    *request = Some(Request::Jog(steps));
+    Ok(())
+}
+
+fn cmd_stop(
+    request: &mut Option<Request>,
+    args: &mut Args,
+    _: &mut dyn Write,
+) -> Result<(), ShellError> {
+    args.end()?;
+
+    *request = Some(Request::Stop);
+    Ok(())
+}
+
+fn cmd_speed(
+    request: &mut Option<Request>,
+    args: &mut Args,
+    _: &mut dyn Write,
+) -> Result<(), ShellError> {
+    let speed: Option<f32> = args.optional("speed")?;
+    args.end()?;
+    // inf and NaN parse as well, the step generator can not run them
+    if speed.is_some_and(|speed| !speed.is_finite() || speed <= 0.) {
+        return Err(ShellError::Invalid("speed"));
+    }
+
+    *request = Some(Request::Speed(speed));
+    Ok(())
+}
+
+fn cmd_current(
+    request: &mut Option<Request>,
+    args: &mut Args,
+    _: &mut dyn Write,
+) -> Result<(), ShellError> {
+    let current = match args.optional("run mA")? {
+        Some(run_ma) => Some((run_ma, args.optional("hold mA")?)),
+        None => None,
+    };
+    args.end()?;
+
+    *request = Some(Request::Current(current));
+    Ok(())
+}
+
+fn cmd_encoder(
+    request: &mut Option<Request>,
+    args: &mut Args,
+    _: &mut dyn Write,
+) -> Result<(), ShellError> {
+    args.end()?;
+
+    *request = Some(Request::Encoder);
+    Ok(())
+}
+
+fn cmd_regs(
+    request: &mut Option<Request>,
+    args: &mut Args,
+    _: &mut dyn Write,
+) -> Result<(), ShellError> {
+    args.end()?;
+
+    *request = Some(Request::Registers);
+    Ok(())
+}
+
+fn cmd_faults(
+    request: &mut Option<Request>,
+    args: &mut Args,
+    _: &mut dyn Write,
+) -> Result<(), ShellError> {
+    let clear = match args.next() {
+        Some("clear") => true,
+        Some(_) => return Err(ShellError::Invalid("argument")),
+        None => false,
+    };
+    args.end()?;
+
+    *request = Some(Request::Faults { clear });
+    Ok(())
+}
+
+fn cmd_config(
+    request: &mut Option<Request>,
+    args: &mut Args,
+    _: &mut dyn Write,
+) -> Result<(), ShellError> {
+    let setting = match args.next() {
+        Some("accel") => Setting::Accel,
+        Some("decel") => Setting::Decel,
+        Some("jerk") => Setting::Jerk,
+        Some(_) => return Err(ShellError::Invalid("setting")),
+        None => {
+            *request = Some(Request::Config(None));
+            return Ok(());
+        }
+    };
+    let value: f32 = args.required("value")?;
+    args.end()?;
+
+    // the limits must be positive and finite, jerk 0 switches the s-curve off
+    let valid = match setting {
+        Setting::Jerk => value >= 0.,
+        _ => value > 0.,
+    };
+    if !valid || !value.is_finite() {
+        return Err(ShellError::Invalid("value"));
+    }
+
+    *request = Some(Request::Config(Some((setting, value))));
+    Ok(())
+}
+
+fn cmd_tune(
+    request: &mut Option<Request>,
+    args: &mut Args,
+    _: &mut dyn Write,
+) -> Result<(), ShellError> {
+    args.end()?;
+
+    *request = Some(Request::Tune);
+    Ok(())
+}
+
+fn cmd_gcode(
+    request: &mut Option<Request>,
+    args: &mut Args,
+    _: &mut dyn Write,
+) -> Result<(), ShellError> {
+    args.end()?;
+
+    *request = Some(Request::Gcode);
+    Ok(())
+}
+
+// console in G-code mode, no echo or prompt, printer hosts wait for the ok of every line
+struct GcodeConsole {
+    interpreter: Interpreter,
+    line: GcodeLine,
+    overflow: bool,
+    active: bool,
+}
+
+impl GcodeConsole {
+    fn new() -> Self {
+        Self {
+            interpreter: Interpreter::new(GCODE),
+            line: LineBuffer::new(),
+            overflow: false,
+            active: false,
+        }
+    }
+
+    // collects a line, Err when it did not fit, a cut line could move somewhere else
+    fn feed(&mut self, byte: u8) -> Option<Result<GcodeLine, ()>> {
+        match byte {
+            b'\r' | b'\n' => {
+                let line = self.line;
+                let overflow = self.overflow;
+                self.line.clear();
+                self.overflow = false;
+
+                match (overflow, line.is_empty()) {
+                    (true, _) => Some(Err(())),
+                    // the LF of CR LF
+                    (false, true) => None,
+                    (false, false) => Some(Ok(line)),
+                }
+            }
+            b' '..=b'~' => {
+                if !self.line.push(byte) {
+                    self.overflow = true;
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+// returns to the executor once, the other priority 0 tasks run before the caller goes on
+async fn yield_now() {
+    let mut yielded = false;
+    poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await
+}
+
+// waits for the idle line interrupt, returns at once when input came in since the last wait
+async fn console_rx() {
+    poll_fn(|cx| {
+        // the interrupt sets the flag and takes the waker in one go, no input is missed
+        interrupt::free(|cs| {
+            if CONSOLE_RX.swap(false, Ordering::Relaxed) {
+                return Poll::Ready(());
+            }
+
+            *CONSOLE_WAKER.borrow(cs).borrow_mut() = Some(cx.waker().clone());
+            Poll::Pending
+        })
+    })
+    .await
+}
+
+// ms since boot from the free-running DWT cycle counter, a monitor tick delayed by a
+// long tmc_bus lock loses no time, it only has to run once per counter wrap (25 s at 170 MHz)
+struct Millis {
+    cycles_per_ms: u32,
+    last: u32,
+    cycles: u64,
+}
+
+impl Millis {
+    fn new(sysclk_hz: u32) -> Self {
+        Self {
+            cycles_per_ms: sysclk_hz / 1000,
+            last: DWT::cycle_count(),
+            cycles: 0,
+        }
+    }
+
+    fn now(&mut self) -> u64 {
+        let count = DWT::cycle_count();
+        self.cycles += count.wrapping_sub(self.last) as u64;
+        self.last = count;
+
+        self.cycles / self.cycles_per_ms as u64
+    }
+}
+
+// blocking writes, the console task runs at the lowest priority
+struct ConsoleUart(Usart<USART1>);
+
+impl fmt::Write for ConsoleUart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+// step/dir output, TIM3 ch4 rises once per period and DMA1 ch2 streams the periods
+struct Stepper {
+    timer: Timer<TIM3>,
+    dma: Dma<DMA1>,
+    dir: Pin,
+    steps: StepGenerator,
+    train: StepTrain,
+}
+
+impl Stepper {
+    fn buffer() -> &'static mut [u16; STEP_BUFFER_LEN] {
+        // only the stepper resource touches the buffer, the DMA only reads it
+        unsafe { &mut *addr_of_mut!(STEP_BUFFER) }
+    }
+
+    // starts a move to the absolute position `target`, false when there is nothing to do
+    fn start(&mut self, target: i64) -> bool {
+        match self.steps.move_to(target) {
+            Ok(Some(Direction::Forward)) => self.dir.set_low(),
+            Ok(Some(Direction::Backward)) => self.dir.set_high(),
+            Ok(None) | Err(_) => return false,
+        }
+
+        let Some(arr) = self.train.start(&mut self.steps, Self::buffer()) else {
+            return false;
+        };
+        self.timer.set_auto_reload(arr as u32);
+
+        // restart the DMA at the beginning of the buffer
+        let dma = &self.dma.regs;
+        dma.ccr2.modify(|_, w| w.en().clear_bit());
+        dma.ifcr.write(|w| w.cgif2().set_bit());
+        dma.cndtr2
+            .write(|w| unsafe { w.ndt().bits(STEP_BUFFER_LEN as u16) });
+        dma.ccr2.modify(|_, w| w.en().set_bit());
+
+        // restart the counter and load the prescaler, the first edge comes after STEP_PULSE_TICKS
+        self.timer.regs.egr.write(|w| w.ug().set_bit());
+        self.timer.enable();
+
+        true
+    }
+
+    fn start_relative(&mut self, steps: i64) -> bool {
+        match self.steps.position().checked_add(steps) {
+            Some(target) => self.start(target),
+            None => false,
+        }
+    }
+
+    fn is_moving(&self) -> bool {
+        self.steps.is_moving()
+    }
+
+    // refills the half the DMA finished, returns the steps emitted from it
+    fn on_dma(&mut self) -> u32 {
+        // the last step counted on the previous interrupt, its edge is out
+        if !self.steps.is_moving() {
+            self.halt();
+        }
+
+        let dma = &self.dma.regs;
+        let isr = dma.isr.read();
+        let mut emitted = 0;
+
+        if isr.htif2().bit_is_set() {
+            dma.ifcr.write(|w| w.chtif2().set_bit());
+            emitted += self
+                .train
+                .refill(&mut self.steps, Self::buffer(), Half::First);
+        }
+        if isr.tcif2().bit_is_set() {
+            dma.ifcr.write(|w| w.ctcif2().set_bit());
+            emitted += self
+                .train
+                .refill(&mut self.steps, Self::buffer(), Half::Second);
+        }
+
+        emitted
+    }
+
+    // stops at once, the position counts the steps streamed so far
+    fn stop(&mut self) {
+        self.halt();
+        self.on_dma();
+
+        let remaining = self.dma.regs.cndtr2.read().ndt().bits() as usize;
+        let read_pos = (STEP_BUFFER_LEN - remaining) % STEP_BUFFER_LEN;
+        self.train.stop(&mut self.steps, Self::buffer(), read_pos);
+    }
+
+    fn halt(&mut self) {
+        self.timer.disable();
+        self.dma.regs.ccr2.modify(|_, w| w.en().clear_bit());
+    }
+}
+
 #[rtic::app(device = pac, peripherals = true)]
 mod app {
     use super::*;
 
     #[shared]
-    struct Shared {}
+    struct Shared {
+        tmc_bus: TmcBus,
+        tmc_addr: SlaveAddr,
+        stepper: Stepper,
+        homing: Homing,
+        step_loss: StepLossMonitor,
+        faults: FaultManager,
+        config: Config,
+        encoder_position: Option<i64>,
+    }
 
     #[local]
     struct Local {
-        m_directory: Pin,
+        sw1_button: Sw1,
+        diag: Diag,
+        encoder: Encoder,
+        multi_turn: MultiTurn,
+        monitor_timer: Timer<TIM4>,
+        millis: Millis,
+        adc: Adc<ADC1>,
+        cal: FactoryCal,
+        thermal: Thermal,
+        console_uart: ConsoleUart,
+        shell: ConsoleShell,
+        gcode: GcodeConsole,
+    }
+
+    // fault classes of DRV_STATUS, the open load flags only mean something in SpreadCycle while moving
+    fn conditions(status: &reg::DRV_STATUS, moving: bool) -> Conditions {
+        Conditions {
+            overcurrent: status.s2ga() || status.s2gb() || status.s2vsa() || status.s2vsb(),
+            overtemperature: status.ot(),
+            open_load: moving && !status.stealth() && (status.ola() || status.olb()),
+            ..Default::default()
+        }
+    }
+
+    // stops the motor and switches the power stage as the fault manager says
+    fn apply(action: Action, driver: &mut Driver, stepper: &mut Stepper) {
+        let toff = match action {
+            Action::None => return,
+            Action::Disable => {
+                stepper.stop();
+                0
+            }
+            Action::Enable => DEFAULT_TOFF,
+        };
+
+        if driver.set_toff(toff).is_err() {
+            defmt::println!("{}: toff write failed", driver.addr().get());
+        }
+    }
+
+    fn driver_flags(status: &reg::DRV_STATUS) -> DriverFlags {
+        DriverFlags {
+            otpw: status.otpw(),
+            ot: status.ot(),
+            t120: status.t120(),
+            t143: status.t143(),
+            t150: status.t150(),
+            t157: status.t157(),
+        }
+    }
+
+    // die temperature, VREFINT gives the VDDA it was read at
+    fn read_temperature(adc: &mut Adc<ADC1>, cal: &FactoryCal) -> f32 {
+        let vdda = cal.vdda(adc.read(VREFINT_CHANNEL));
+        cal.temperature(adc.read(TEMP_CHANNEL), vdda)
+    }
+
+    fn report_driver(driver: &mut Driver) {
+        match driver.ifcnt() {
+            Ok(ifcnt) => defmt::println!("{}: ifcnt: {}", driver.addr().get(), ifcnt),
+            Err(_) => defmt::println!("tmc2209 does not answer"),
+        }
+
+        if let Ok(true) = driver.restore_after_reset() {
+            defmt::println!("tmc2209 was reset, configuration restored");
+        }
+
+        if let Ok(gconf) = driver.gconf() {
+            defmt::println!(
+                "gconf: pdn_disable {} en_spread_cycle {}",
+                gconf.pdn_disable(),
+                gconf.en_spread_cycle()
+            );
+        }
+
+        if let Ok(ioin) = driver.ioin() {
+            defmt::println!("ioin: version {:#x} enn {}", ioin.version(), ioin.enn());
+        }
+
+        if let Ok(status) = driver.drv_status() {
+            defmt::println!(
+                "drv_status: ot {} otpw {} s2ga {} s2gb {} ola {} olb {} stst {}",
+                status.ot(),
+                status.otpw(),
+                status.s2ga(),
+                status.s2gb(),
+                status.ola(),
+                status.olb(),
+                status.stst()
+            );
+        }
+    }
+
+    // ends a latched driver fault, the motor stopped at once so the position is lost
+    fn clear_fault(
+        driver: &mut Driver,
+        stepper: &mut Stepper,
+        faults: &mut FaultManager,
+        homing: &mut Homing,
+    ) -> bool {
+        let action = faults.clear();
+        apply(action, driver, stepper);
+        if action == Action::Enable {
+            *homing = Homing::new(*homing.config());
+        }
+
+        action == Action::Enable
+    }
+
+    // runs a console command and writes its reply
+    fn run_request(
+        request: Request,
+        shared: &mut console::SharedResources,
+        out: &mut ConsoleUart,
+    ) -> fmt::Result {
+        let addr = shared.tmc_addr.lock(|addr| *addr);
+
+        match request {
+            Request::Move(target) => {
+                let config = shared.config.lock(|config| *config);
+                match start_move(shared, target, config.limits, config.ramp) {
+                    Ok(()) => writeln!(out, "move to {}\r", target),
+                    Err(reason) => writeln!(out, "{}\r", reason),
+                }
+            }
+            Request::Jog(steps) => {
+                let position = shared.stepper.lock(|stepper| stepper.steps.position());
+                match position.checked_add(steps) {
+                    Some(target) => run_request(Request::Move(target), shared, out),
+                    None => writeln!(out, "error: {}\r", ShellError::Invalid("steps")),
+                }
+            }
+            Request::Stop => {
+                let position = (&mut shared.stepper, &mut shared.homing).lock(|stepper, homing| {
+                    stepper.stop();
+                    if homing.is_running() {
+                        *homing = Homing::new(*homing.config());
+                    }
+                    stepper.steps.position()
+                });
+
+                writeln!(out, "stopped at {}\r", position)
+            }
+            Request::Speed(speed) => {
+                let limits = shared.config.lock(|config| {
+                    if let Some(speed) = speed {
+                        config.limits.max_speed = speed;
+                    }
+                    config.limits
+                });
+
+                writeln!(out, "speed {} steps/s\r", limits.max_speed)
+            }
+            Request::Current(set) => {
+                let current = match set {
+                    Some((run_ma, hold_ma)) => match set_current(shared, run_ma, hold_ma) {
+                        Some(current) => current,
+                        None => return writeln!(out, "current out of range\r"),
+                    },
+                    None => shared.config.lock(|config| config.current),
+                };
+
+                writeln!(
+                    out,
+                    "run {} mA, hold {} mA\r",
+                    current.run_ma, current.hold_ma
+                )
+            }
+            Request::Encoder => {
+                let Some(encoder) = shared.encoder_position.lock(|position| *position) else {
+                    return writeln!(out, "no encoder reading\r");
+                };
+                let position = shared.stepper.lock(|stepper| stepper.steps.position());
+
+                write!(
+                    out,
+                    "encoder {} counts, position {} steps",
+                    encoder, position
+                )?;
+                match shared
+                    .step_loss
+                    .lock(|step_loss| step_loss.measured(encoder))
+                {
+                    Some(measured) => writeln!(
+                        out,
+                        ", measured {} steps, error {}\r",
+                        measured,
+                        position - measured
+                    ),
+                    None => writeln!(out, ", not synced until homed\r"),
+                }
+            }
+            Request::Registers => {
+                // one lock per read, a read takes about 20 ms
+                for (name, address) in REGISTERS {
+                    match shared
+                        .tmc_bus
+                        .lock(|bus| bus.driver(addr).read_raw(address as u8))
+                    {
+                        Ok(value) => writeln!(out, "{:<10} {:#010x}\r", name, value)?,
+                        Err(_) => writeln!(out, "{:<10} no answer\r", name)?,
+                    }
+                }
+
+                let shadow = shared.tmc_bus.lock(|bus| *bus.driver(addr).shadow());
+                for (name, address) in WRITE_ONLY {
+                    if let Some(value) = shadow.raw(address as u8) {
+                        writeln!(out, "{:<10} {:#010x} written\r", name, value)?;
+                    }
+                }
+
+                Ok(())
+            }
+            Request::Faults { clear } => {
+                if clear {
+                    let cleared = (
+                        &mut shared.tmc_bus,
+                        &mut shared.stepper,
+                        &mut shared.faults,
+                        &mut shared.homing,
+                    )
+                        .lock(|bus, stepper, faults, homing| {
+                            clear_fault(&mut bus.driver(addr), stepper, faults, homing)
+                        });
+                    if cleared {
+                        writeln!(out, "driver fault cleared, home again\r")?;
+                    } else {
+                        writeln!(out, "no fault to clear or still reported\r")?;
+                    }
+                }
+
+                let faults = shared.faults.lock(|faults| *faults);
+                writeln!(
+                    out,
+                    "driver {:?}, {} retries\r",
+                    faults.state(),
+                    faults.retries()
+                )?;
+                if let Some(fault) = faults.fault() {
+                    writeln!(
+                        out,
+                        "last fault {:?} at {} ms\r",
+                        fault.kind, fault.timestamp_ms
+                    )?;
+                }
+                writeln!(out, "{:?}\r", faults.conditions())?;
+
+                if let Some(fault) = shared.step_loss.lock(|step_loss| step_loss.fault()) {
+                    writeln!(
+                        out,
+                        "following error {} steps at {}, home again\r",
+                        fault.error(),
+                        fault.measured
+                    )?;
+                }
+
+                Ok(())
+            }
+            Request::Config(set) => {
+                let config = shared.config.lock(|config| {
+                    match set {
+                        Some((Setting::Accel, value)) => config.limits.accel = value,
+                        Some((Setting::Decel, value)) => config.limits.decel = value,
+                        Some((Setting::Jerk, value)) if value > 0. => {
+                            config.ramp = Ramp::SCurve { jerk: value }
+                        }
+                        Some((Setting::Jerk, _)) => config.ramp = Ramp::Trapezoid,
+                        None => {}
+                    }
+                    *config
+                });
+
+                let Limits {
+                    max_speed,
+                    accel,
+                    decel,
+                } = config.limits;
+                writeln!(
+                    out,
+                    "speed {} steps/s, accel {} decel {} steps/s2\r",
+                    max_speed, accel, decel
+                )?;
+                match config.ramp {
+                    Ramp::Trapezoid => writeln!(out, "ramp trapezoid\r")?,
+                    Ramp::SCurve { jerk } => {
+                        writeln!(out, "ramp s-curve, jerk {} steps/s3\r", jerk)?
+                    }
+                }
+                writeln!(
+                    out,
+                    "current run {} mA, hold {} mA, {} microsteps\r",
+                    config.current.run_ma, config.current.hold_ma, MICROSTEPS
+                )
+            }
+            Request::Tune => {
+                if tune_stallguard::spawn().is_err() {
+                    return writeln!(out, "tuning already runs\r");
+                }
+                writeln!(out, "tuning, the motor runs {} steps\r", TUNE_STEPS)
+            }
+            // the console task switches over, nothing to run here
+            Request::Gcode => Ok(()),
+        }
+    }
+
+    // starts a move unless the driver is off or the motor busy, the reason when not
+    fn start_move(
+        shared: &mut console::SharedResources,
+        target: i64,
+        limits: Limits,
+        ramp: Ramp,
+    ) -> Result<(), &'static str> {
+        if shared.faults.lock(|faults| faults.state()) != FaultState::Running {
+            return Err("driver off, see faults");
+        }
+        if shared.homing.lock(|homing| homing.is_running()) {
+            return Err("homing, stop it first");
+        }
+
+        shared.stepper.lock(|stepper| {
+            if stepper.is_moving() {
+                return Err("moving, stop first");
+            }
+
+            if target == stepper.steps.position() {
+                return Ok(());
+            }
+
+            stepper.steps.set_limits(limits);
+            stepper.steps.set_ramp(ramp);
+            // the step generator refuses moves longer than it can count
+            if !stepper.start(target) {
+                return Err("too far");
+            }
+            Ok(())
+        })
+    }
+
+    // home backwards, the homing state machine gives up after max_steps
+    fn start_homing(stepper: &mut Stepper, homing: &mut Homing, step_loss: &mut StepLossMonitor) {
+        step_loss.reset();
+        homing.start();
+        stepper.steps.set_limits(HOMING_LIMITS);
+        stepper.steps.set_ramp(Ramp::Trapezoid);
+        stepper.start_relative(-(homing.config().max_steps as i64));
+    }
+
+    // changes the configured current, the status poll writes it to the driver derated by the
+    // temperature, None when the driver can not run it
+    fn set_current(
+        shared: &mut console::SharedResources,
+        run_ma: u16,
+        hold_ma: Option<u16>,
+    ) -> Option<CurrentConfig> {
+        shared.config.lock(|config| {
+            let changed = CurrentConfig {
+                run_ma,
+                hold_ma: hold_ma.unwrap_or(config.current.hold_ma),
+                ..config.current
+            };
+            Current::new(&changed).ok()?;
+
+            config.current = changed;
+            Some(changed)
+        })
+    }
+
+    // waits for the move or homing run to end, the other priority 0 tasks go on meanwhile
+    async fn wait_for_motor(shared: &mut console::SharedResources<'_>) {
+        loop {
+            let moving = shared.stepper.lock(|stepper| stepper.is_moving());
+            if !moving && !shared.homing.lock(|homing| homing.is_running()) {
+                return;
+            }
+
+            yield_now().await;
+        }
+    }
+
+    // runs one G-code line and answers as Marlin does, printer hosts send the next line
+    // after the ok so it comes once the motion finished
+    async fn run_gcode(
+        line: &str,
+        interpreter: &mut Interpreter,
+        shared: &mut console::SharedResources<'_>,
+        out: &mut ConsoleUart,
+    ) -> fmt::Result {
+        let command = match interpreter.execute(line) {
+            Ok(command) => command,
+            Err(error) => {
+                match error.kind {
+                    GcodeErrorKind::UnknownCode { .. } => writeln!(out, "echo:{}\r", error)?,
+                    _ => writeln!(out, "Error:{}\r", error)?,
+                }
+                if error.kind.needs_resend() {
+                    writeln!(out, "Resend: {}\r", interpreter.next_line())?;
+                }
+                return writeln!(out, "ok\r");
+            }
+        };
+
+        let line = interpreter.line();
+        let result = match command {
+            Gcode::None => Ok(()),
+            Gcode::Move { target, speed } => {
+                let config = shared.config.lock(|config| *config);
+                let limits = Limits {
+                    max_speed: speed.min(config.limits.max_speed),
+                    ..config.limits
+                };
+
+                match start_move(shared, target, limits, config.ramp) {
+                    Ok(()) => {
+                        wait_for_motor(shared).await;
+
+                        // a stop or a following error ends the move early
+                        let position = shared.stepper.lock(|stepper| stepper.steps.position());
+                        interpreter.set_position(position);
+                        if position == target {
+                            Ok(())
+                        } else {
+                            Err("move stopped early")
+                        }
+                    }
+                    Err(reason) => {
+                        let position = shared.stepper.lock(|stepper| stepper.steps.position());
+                        interpreter.set_position(position);
+                        Err(reason)
+                    }
+                }
+            }
+            Gcode::Home => {
+                let homed = home(shared).await;
+                if homed.is_ok() {
+                    interpreter.set_position(0);
+                }
+                homed
+            }
+            Gcode::Enable | Gcode::Disable => {
+                let addr = shared.tmc_addr.lock(|addr| *addr);
+                let state = shared.faults.lock(|faults| faults.state());
+
+                if state != FaultState::Running {
+                    Err("driver off, see faults")
+                } else if command == Gcode::Enable {
+                    (&mut shared.tmc_bus, &mut shared.stepper)
+                        .lock(|bus, stepper| apply(Action::Enable, &mut bus.driver(addr), stepper));
+                    Ok(())
+                } else {
+                    // the motor can be turned by hand now, G28 finds the position again
+                    (&mut shared.tmc_bus, &mut shared.stepper, &mut shared.homing).lock(
+                        |bus, stepper, homing| {
+                            apply(Action::Disable, &mut bus.driver(addr), stepper);
+                            *homing = Homing::new(*homing.config());
+                        },
+                    );
+                    Ok(())
+                }
+            }
+            Gcode::Position => {
+                let position = shared.stepper.lock(|stepper| stepper.steps.position());
+                interpreter.set_position(position);
+                interpreter.write_position(out)?;
+                writeln!(out, "\r")?;
+                Ok(())
+            }
+            Gcode::Current(set) => {
+                let current = match set {
+                    Some(run_ma) => set_current(shared, run_ma, None).ok_or("current out of range"),
+                    None => Ok(shared.config.lock(|config| config.current)),
+                };
+                if let Ok(current) = current {
+                    writeln!(out, "{} driver current: {}\r", GCODE.axis, current.run_ma)?;
+                }
+                current.map(|_| ())
+            }
+        };
+
+        if let Err(reason) = result {
+            writeln!(out, "Error:line {}: {}\r", line, reason)?;
+        }
+        writeln!(out, "ok\r")
+    }
+
+    // homes as the button does and waits for the stall
+    async fn home(shared: &mut console::SharedResources<'_>) -> Result<(), &'static str> {
+        let addr = shared.tmc_addr.lock(|addr| *addr);
+
+        if shared.faults.lock(|faults| faults.state()) != FaultState::Running {
+            return Err("driver off, see faults");
+        }
+        if shared.stepper.lock(|stepper| stepper.is_moving()) {
+            return Err("moving, stop first");
+        }
+
+        let stallguard = shared.homing.lock(|homing| homing.config().stallguard);
+        if shared
+            .tmc_bus
+            .lock(|bus| bus.driver(addr).enable_stallguard(stallguard))
+            .is_err()
+        {
+            return Err("stallguard setup failed");
+        }
+
+        (
+            &mut shared.stepper,
+            &mut shared.homing,
+            &mut shared.step_loss,
+        )
+            .lock(start_homing);
+        wait_for_motor(shared).await;
+
+        match shared.homing.lock(|homing| homing.state()) {
+            HomingState::Homed { .. } => Ok(()),
+            _ => Err("homing failed, no stall found"),
+        }
     }
 
     #[init]
     fn init(ctx: init::Context) -> (Shared, Local) {
         let dp = ctx.device;
+        let mut cp = ctx.core;
 
         let clock_cfg = Clocks::default();
         clock_cfg.setup().unwrap();
 
+        // the cycle counter is the time base of the fault manager
+        cp.DCB.enable_trace();
+        cp.DWT.enable_cycle_counter();
+
         // setup pins
-        let mut sw1_button = Pin::new(Port::A, 10, PinMode::Input);
-        sw1_button.pull(Pull::Up);
+        let mut sw1_button = Sw1::new();
         sw1_button.enable_interrupt(Edge::Rising); // and enable interrupt
 
-        // Configure pins for UART, according to the user manual.
-        let _uart_tx = Pin::new(Port::B, 10, PinMode::Alt(7));
-        let _uart_rx = Pin::new(Port::B, 11, PinMode::Alt(7));
+        // stall output of the driver
+        let mut diag = Diag::new();
+        diag.enable_interrupt();
 
-        // control motor directory pin
-        let mut m_directory = Pin::new(Port::B, 0, PinMode::Output);
-        m_directory.set_low();
+        // Configure pins for UART, according to the user manual.
+        let _uart = Uart::new();
 
-        // driver step pin for motor pwd control
-        let _m_step = Pin::new(Port::B, 1, PinMode::Alt(2));
+        // motor directory pin is low, step pin is driven by TIM3 ch4
+        let StepDir {
+            step: _m_step,
+            dir: m_directory,
+        } = StepDir::new();
 
-        // set up pwd timer for pin PB1
+        // set up step timer for pin PB1, one step per period
         // stm32g431rb datasheet - Table 13. Alternate function
-        // freq in Hz
-        let mut timer_pwd = Timer::new_tim3(dp.TIM3, 1000., TimerConfig::default(), &clock_cfg);
-        timer_pwd.enable_pwm_output(TimChannel::C4, OutputCompare::Pwm1, 0.5); // duty 0.5 == 50%
-        timer_pwd.enable();
+        // the DMA writes the auto reload at the start of the period it sets, so it must not be preloaded
+        let mut step_timer = Timer::new_tim3(
+            dp.TIM3,
+            STEP_HZ,
+            TimerConfig {
+                update_request_source: UpdateReqSrc::OverUnderflow,
+                auto_reload_preload: false,
+                ..Default::default()
+            },
+            &clock_cfg,
+        );
+        // pwm mode 2: low while the counter is below ccr4, the step edge rises at ccr4
+        step_timer.enable_pwm_output(TimChannel::C4, OutputCompare::Pwm2, 0.);
+        step_timer.set_prescaler((clock_cfg.apb1_timer() / STEP_TICK_HZ - 1) as u16);
+        step_timer.set_auto_reload(u16::MAX as u32);
+        step_timer.set_duty(TimChannel::C4, STEP_PULSE_TICKS as _);
+
+        // TIM3 update requests DMA1 ch2, it copies the next period from STEP_BUFFER into ARR
+        // same register level setup as dma_pwm_pac, with half transfer and complete interrupts
+        let dma = Dma::new(dp.DMA1);
+        dma::enable_mux1();
+        dma::mux(DmaPeriph::Dma1, DmaChannel::C2, DmaInput::Tim3Up);
+
+        unsafe {
+            let ma = addr_of!(STEP_BUFFER) as usize as u32; // mem address
+            let pa = step_timer.regs.arr.as_ptr() as usize as u32; // tim3 arr register address
+
+            dma.regs.cmar2.write(|w| w.ma().bits(ma));
+            dma.regs.cpar2.write(|w| w.pa().bits(pa));
+            dma.regs
+                .cndtr2
+                .write(|w| w.ndt().bits(STEP_BUFFER_LEN as u16));
+            dma.regs.ccr2.write(|w| {
+                w.mem2mem()
+                    .clear_bit() // source is memory, disable memory to memory transfer
+                    .pl()
+                    .bits(2) // high priority, a late period delays the step
+                    .msize()
+                    .bits(1) // memory word size is 16 bits
+                    .psize()
+                    .bits(1) // peripheral word size is 16 bits
+                    .minc()
+                    .set_bit() // increment memory address every transfer
+                    .pinc()
+                    .clear_bit() // not increment peripheral address every transfer
+                    .circ()
+                    .set_bit() // dma mode is circular
+                    .dir()
+                    .set_bit() // set to read from memory
+                    .htie()
+                    .set_bit() // interrupt when the first half is read
+                    .tcie()
+                    .set_bit() // interrupt when the second half is read
+            });
+        }
+        step_timer.regs.dier.modify(|_, w| w.ude().set_bit()); // update DMA request enable
+
+        let stepper = Stepper {
+            timer: step_timer,
+            dma,
+            dir: m_directory,
+            steps: StepGenerator::new(HOMING_LIMITS),
+            train: StepTrain::new(STEP_TICK_HZ as f32, STEP_PULSE_TICKS),
+        };
 
         // set up uart for communicate with tmc2209 driver
-        let mut uart = Usart::new(dp.USART3, 9600, UsartConfig::default(), &clock_cfg);
+        let uart = Usart::new(dp.USART3, 9600, UsartConfig::default(), &clock_cfg);
+        let mut tmc_bus = Bus::new(uart, Default::default());
+
+        // up to four drivers share the bus, addressed by their MS1/MS2 pins
+        let present = tmc_bus.scan();
+        defmt::println!("tmc2209 drivers found: {}", present.count());
 
-        // set up default gconf and send it to driver
-        let mut gconf = tmc2209::reg::GCONF::default();
+        // set up default gconf and send it to every driver
+        let mut gconf = reg::GCONF::default();
         gconf.set_pdn_disable(true);
 
-        uart.write(tmc2209::write_request(0, gconf).bytes())
-            .unwrap();
+        let current = Current::new(&MOTOR_CURRENT).expect("motor current out of range");
+
+        for addr in present.iter() {
+            let mut driver = tmc_bus.driver(addr);
+            if driver.write(gconf).is_err() {
+                defmt::println!("{}: gconf write failed", addr.get());
+            }
+
+            // quiet StealthChop up to the homing speed and above, SpreadCycle for fast moves
+            let mode = ChopperMode::Hybrid {
+                switch_over: Velocity::Rpm(300.),
+            };
+            if driver.set_chopper_mode(mode, &MECHANICS).is_err() {
+                defmt::println!("{}: chopper setup failed", addr.get());
+            }
+
+            if driver.set_current(&current).is_err() {
+                defmt::println!("{}: current setup failed", addr.get());
+            }
+
+            report_driver(&mut driver);
+        }
+
+        // the button reports and homes the first driver found
+        let tmc_addr = present.iter().next().unwrap_or(SlaveAddr::ALL[0]);
+
+        // stall detection from 80% of the homing speed, sgthrs is replaced by the tuning
+        let homing = Homing::new(HomingConfig {
+            stallguard: StallGuard::new(60, STEP_HZ * 0.8, MICROSTEPS),
+            blank_steps: 50,
+            max_steps: 20_000,
+        });
 
-        (Shared {}, Local { m_directory })
+        // encoder on SPI1, read blocking by the step-loss check
+        let encoder_pins = EncoderSpi::new();
+        let spi_cfg = SpiConfig {
+            mode: SpiMode::mode1(),
+            ..Default::default()
+        };
+        let spi1 = Spi::new(dp.SPI1, spi_cfg, BaudRate::Div32);
+        let encoder = Tle5012::new(spi1, encoder_pins.cs);
+
+        let mut monitor_timer =
+            Timer::new_tim4(dp.TIM4, MONITOR_HZ, Default::default(), &clock_cfg);
+        monitor_timer.enable_interrupt(TimerInterrupt::Update);
+        monitor_timer.enable();
+        let millis = Millis::new(clock_cfg.sysclk());
+
+        // temperature sensor and VREFINT for the derating, read blocking with the status
+        dp.ADC12_COMMON
+            .ccr
+            .modify(|_, w| w.vsensesel().set_bit().vrefen().set_bit());
+        let mut adc = Adc::new_adc1(
+            dp.ADC1,
+            AdcDevice::One,
+            Default::default(),
+            clock_cfg.systick(),
+        );
+        // the temperature sensor needs at least 5 us
+        adc.set_sample_time(TEMP_CHANNEL, SampleTime::T640);
+        adc.set_sample_time(VREFINT_CHANNEL, SampleTime::T640);
+
+        // console on USART1, DMA1 ch3 writes every received byte round CONSOLE_BUFFER
+        let _console_pins = Console::new();
+        let mut console_uart =
+            Usart::new(dp.USART1, CONSOLE_BAUD, UsartConfig::default(), &clock_cfg);
+        dma::mux(DmaPeriph::Dma1, DmaChannel::C3, DmaInput::Usart1Rx);
+        unsafe {
+            console_uart.read_dma(
+                &mut *addr_of_mut!(CONSOLE_BUFFER),
+                DmaChannel::C3,
+                ChannelCfg {
+                    circular: Circular::Enabled,
+                    ..Default::default()
+                },
+                DmaPeriph::Dma1,
+            );
+        }
+        // the line goes idle after every burst of input, the console task reads it then
+        console_uart.enable_interrupt(UsartInterrupt::Idle);
+
+        let mut console_uart = ConsoleUart(console_uart);
+        let shell = ConsoleShell::new(COMMANDS, "> ");
+        writeln!(
+            console_uart,
+            "\r\ntmc2209-example, help lists the commands\r"
+        )
+        .ok();
+        shell.prompt(&mut console_uart).ok();
+        console::spawn().ok();
+
+        (
+            Shared {
+                tmc_bus,
+                tmc_addr,
+                stepper,
+                homing,
+                step_loss: StepLossMonitor::new(STEP_LOSS),
+                faults: FaultManager::new(FAULT_POLICY),
+                config: Config {
+                    limits: MOVE_LIMITS,
+                    ramp: MOVE_RAMP,
+                    current: MOTOR_CURRENT,
+                },
+                encoder_position: None,
+            },
+            Local {
+                sw1_button,
+                diag,
+                encoder,
+                multi_turn: MultiTurn::new(COUNTS_PER_REV),
+                monitor_timer,
+                millis,
+                adc,
+                cal: unsafe { FactoryCal::read() },
+                thermal: Thermal::new(THERMAL),
+                console_uart,
+                shell,
+                gcode: GcodeConsole::new(),
+            },
+        )
+    }
+
+    // the motor cruises at the speed the trapezoid reached, steps still in the DMA buffer
+    // are counted already so the accel phase ends on the shaft a buffer later
+    fn cruising(steps: &StepGenerator) -> bool {
+        let Profile::Trapezoid(profile) = steps.profile() else {
+            return false;
+        };
+        let done = profile.steps() - steps.remaining();
+
+        steps.is_moving()
+            && done as f32 >= profile.accel_steps() + STEP_BUFFER_LEN as f32
+            && steps.remaining() as f32 > profile.decel_steps()
+    }
+
+    // runs the motor free at the homing speed and sets sgthrs from the measured load,
+    // started from the console, the shaft must turn freely
+    #[task(priority = 0, shared = [tmc_bus, tmc_addr, stepper, homing, faults])]
+    async fn tune_stallguard(mut cx: tune_stallguard::Context) {
+        let addr = cx.shared.tmc_addr.lock(|addr| *addr);
+        let stallguard = cx.shared.homing.lock(|homing| homing.config().stallguard);
+
+        if cx.shared.faults.lock(|faults| faults.state()) != FaultState::Running {
+            defmt::println!("stallguard tuning failed, driver off");
+            return;
+        }
+        if cx.shared.homing.lock(|homing| homing.is_running())
+            || cx.shared.stepper.lock(|stepper| stepper.is_moving())
+        {
+            defmt::println!("stallguard tuning failed, the motor is busy");
+            return;
+        }
+
+        // SG_RESULT is measured in StealthChop mode, no stall reports while tuning
+        let prepared = cx.shared.tmc_bus.lock(|bus| {
+            bus.driver(addr).enable_stallguard(StallGuard {
+                sgthrs: 0,
+                ..stallguard
+            })
+        });
+        if prepared.is_err() {
+            defmt::println!("stallguard tuning failed, tmc2209 does not answer");
+            return;
+        }
+
+        let started = cx.shared.stepper.lock(|stepper| {
+            stepper.steps.set_limits(HOMING_LIMITS);
+            stepper.steps.set_ramp(Ramp::Trapezoid);
+            stepper.start_relative(TUNE_STEPS)
+        });
+        if !started {
+            defmt::println!("stallguard tuning failed, the motor did not start");
+            return;
+        }
+
+        // SG_RESULT only means something at a steady speed, the samples of the ramps are dropped
+        let mut tuner = Tuner::default();
+        while tuner.count() < TUNE_SAMPLES {
+            let (moving, cruising) = cx
+                .shared
+                .stepper
+                .lock(|stepper| (stepper.is_moving(), cruising(&stepper.steps)));
+            if !moving {
+                break;
+            }
+            if !cruising {
+                yield_now().await;
+                continue;
+            }
+
+            let sg_result = cx.shared.tmc_bus.lock(|bus| bus.driver(addr).sg_result());
+            // the read takes long, the decel may have started meanwhile
+            let cruised = cx.shared.stepper.lock(|stepper| cruising(&stepper.steps));
+            if let (Ok(sg_result), true) = (sg_result, cruised) {
+                tuner.add(sg_result);
+            }
+        }
+
+        cx.shared.stepper.lock(|stepper| stepper.stop());
+
+        if tuner.count() < TUNE_SAMPLES {
+            defmt::println!(
+                "stallguard tuning failed, {} samples at cruise speed, keep sgthrs {}",
+                tuner.count(),
+                stallguard.sgthrs
+            );
+            return;
+        }
+
+        match tuner.sgthrs(TUNE_MARGIN_PERCENT) {
+            Some(sgthrs) => {
+                defmt::println!(
+                    "sg_result min {} mean {} max {}, sgthrs: {}",
+                    tuner.min(),
+                    tuner.mean(),
+                    tuner.max(),
+                    sgthrs
+                );
+
+                cx.shared.homing.lock(|homing| {
+                    let config = *homing.config();
+                    *homing = Homing::new(HomingConfig {
+                        stallguard: StallGuard {
+                            sgthrs,
+                            ..config.stallguard
+                        },
+                        ..config
+                    });
+                });
+            }
+            None => defmt::println!(
+                "stallguard tuning failed, keep sgthrs {}",
+                stallguard.sgthrs
+            ),
+        }
     }
 
     // EXTI15_10 - interrupt line for pins with 10 - 15 pin numbers
-    #[task(binds = EXTI15_10, local=[m_directory], priority = 1)]
-    fn on_sw1_button(cx: on_sw1_button::Context) {
-        gpio::clear_exti_interrupt(10);
+    #[task(binds = EXTI15_10, local=[sw1_button], shared=[tmc_bus, tmc_addr, stepper, homing, step_loss, faults, config], priority = 1)]
+    fn on_sw1_button(mut cx: on_sw1_button::Context) {
+        cx.local.sw1_button.clear_interrupt();
+
+        if !cx.local.sw1_button.is_pressed() {
+            return;
+        }
+
+        let addr = cx.shared.tmc_addr.lock(|addr| *addr);
+
+        // a latched driver fault takes one press to clear, the next one homes
+        let fault_state = cx.shared.faults.lock(|faults| faults.state());
+        if fault_state == FaultState::Tripped {
+            defmt::println!("driver fault, waiting for the retry");
+            return;
+        }
+        if fault_state == FaultState::Latched {
+            (
+                cx.shared.tmc_bus,
+                cx.shared.stepper,
+                cx.shared.faults,
+                cx.shared.homing,
+            )
+                .lock(|bus, stepper, faults, homing| {
+                    if clear_fault(&mut bus.driver(addr), stepper, faults, homing) {
+                        defmt::println!("driver fault cleared, press to home");
+                    }
+                });
+            return;
+        }
+
+        if cx.shared.stepper.lock(|stepper| stepper.is_moving()) {
+            return;
+        }
+
+        // lost steps leave the position unknown, the press homes again
+        let faulted = cx
+            .shared
+            .step_loss
+            .lock(|step_loss| step_loss.fault().is_some());
+
+        // once homed move between home and the travel end on every press
+        let homed = matches!(
+            cx.shared.homing.lock(|homing| homing.state()),
+            HomingState::Homed { .. }
+        );
+        if homed && !faulted {
+            let config = cx.shared.config.lock(|config| *config);
+            cx.shared.stepper.lock(|stepper| {
+                let target = if stepper.steps.position() == 0 {
+                    TRAVEL_STEPS
+                } else {
+                    0
+                };
+
+                defmt::println!("move to {}", target);
+                stepper.steps.set_limits(config.limits);
+                stepper.steps.set_ramp(config.ramp);
+                stepper.start(target);
+            });
+            return;
+        }
+
+        let stallguard = cx.shared.homing.lock(|homing| homing.config().stallguard);
+
+        let configured = cx.shared.tmc_bus.lock(|bus| {
+            let mut driver = bus.driver(addr);
+            report_driver(&mut driver);
+            driver.enable_stallguard(stallguard)
+        });
+        if configured.is_err() {
+            defmt::println!("stallguard setup failed, homing not started");
+            return;
+        }
+
+        defmt::println!("homing, sgthrs {}", stallguard.sgthrs);
+        (cx.shared.stepper, cx.shared.homing, cx.shared.step_loss).lock(start_homing);
+    }
+
+    // DMA1 ch2 half transfer or transfer complete - half of the step buffer was streamed
+    #[task(binds = DMA1_CH2, shared=[stepper, homing], priority = 2)]
+    fn on_step_dma(cx: on_step_dma::Context) {
+        (cx.shared.stepper, cx.shared.homing).lock(|stepper, homing| {
+            let was_moving = stepper.is_moving();
+            let emitted = stepper.on_dma();
+
+            if homing.is_running() {
+                for _ in 0..emitted {
+                    if !homing.on_step() {
+                        stepper.stop();
+                        defmt::println!("homing failed, no stall found");
+                        break;
+                    }
+                }
+            } else if was_moving && !stepper.is_moving() {
+                defmt::println!("position {}", stepper.steps.position());
+            }
+        });
+    }
+
+    // EXTI9_5 - DIAG goes high when the driver detects a stall
+    #[task(binds = EXTI9_5, local=[diag], shared=[stepper, homing], priority = 3)]
+    fn on_diag(cx: on_diag::Context) {
+        cx.local.diag.clear_interrupt();
+
+        (cx.shared.stepper, cx.shared.homing).lock(|stepper, homing| {
+            if homing.on_stall() {
+                stepper.stop();
+                stepper.steps.set_position(0).ok();
+
+                if let HomingState::Homed { steps } = homing.state() {
+                    defmt::println!("home found after {} steps, position 0", steps);
+                }
+            }
+        });
+    }
+
+    // compares the step position with the encoder once homed
+    #[task(binds = TIM4, local=[encoder, multi_turn, monitor_timer, millis, next_poll_ms: u64 = 0], shared=[stepper, homing, step_loss, encoder_position], priority = 1)]
+    fn on_monitor(mut cx: on_monitor::Context) {
+        cx.local
+            .monitor_timer
+            .clear_interrupt(TimerInterrupt::Update);
+
+        // ticks are lost while a lower task holds tmc_bus, the time comes from the cycle counter
+        let now_ms = cx.local.millis.now();
+        if now_ms >= *cx.local.next_poll_ms {
+            *cx.local.next_poll_ms = now_ms + STATUS_POLL_MS as u64;
+            // still busy with the last poll when the bus is slow, skip this one
+            poll_drv_status::spawn(now_ms).ok();
+        }
+
+        // a failed read is skipped, the next one catches up within half a revolution
+        let Ok(angle) = cx.local.encoder.read_angle() else {
+            return;
+        };
+        let encoder = cx.local.multi_turn.update(angle.counts() as u32);
+        cx.shared
+            .encoder_position
+            .lock(|position| *position = Some(encoder));
+
+        (cx.shared.stepper, cx.shared.homing, cx.shared.step_loss).lock(
+            |stepper, homing, step_loss| {
+                if !matches!(homing.state(), HomingState::Homed { .. }) {
+                    return;
+                }
+
+                let commanded = stepper.steps.position();
+                let moving = stepper.is_moving();
+
+                // the home position is where the encoder stands right after homing
+                if !step_loss.is_synced() {
+                    if !moving {
+                        step_loss.sync(commanded, encoder);
+                    }
+                    return;
+                }
+
+                match step_loss.update(commanded, encoder, moving) {
+                    StepLoss::InSync => {}
+                    StepLoss::Correct { position } => {
+                        if stepper.steps.set_position(position).is_ok() {
+                            defmt::println!(
+                                "position corrected from {} to {}",
+                                commanded,
+                                position
+                            );
+                        }
+                    }
+                    StepLoss::Fault(fault) => {
+                        if moving {
+                            stepper.stop();
+                            defmt::println!(
+                                "following error {} steps at {}, stopped, press to home again",
+                                fault.error(),
+                                fault.measured
+                            );
+                        }
+                    }
+                }
+            },
+        );
+    }
+
+    // reads DRV_STATUS and the die temperature, follows the fault manager and derates the current
+    // the driver reports faults only here, a thermal shutdown counts as overtemperature
+    #[task(priority = 0, local = [adc, cal, thermal, scale: f32 = 1., current: CurrentConfig = MOTOR_CURRENT], shared = [tmc_bus, tmc_addr, stepper, faults, config])]
+    async fn poll_drv_status(mut cx: poll_drv_status::Context, now_ms: u64) {
+        let addr = cx.shared.tmc_addr.lock(|addr| *addr);
+        let moving = cx.shared.stepper.lock(|stepper| stepper.is_moving());
+
+        let Ok(status) = cx.shared.tmc_bus.lock(|bus| bus.driver(addr).drv_status()) else {
+            return;
+        };
+
+        let mcu = read_temperature(cx.local.adc, cx.local.cal);
+        let derating = cx.local.thermal.update(
+            Some(mcu),
+            driver_flags(&status),
+            STATUS_POLL_MS as f32 / 1000.,
+        );
+
+        let mut conditions = conditions(&status, moving);
+        conditions.overtemperature |= derating.shutdown;
+
+        (cx.shared.tmc_bus, cx.shared.stepper, cx.shared.faults).lock(|bus, stepper, faults| {
+            let action = faults.on_status(conditions, now_ms);
+            apply(action, &mut bus.driver(addr), stepper);
+
+            match (action, faults.fault()) {
+                (Action::Disable, Some(fault)) => defmt::println!(
+                    "driver fault {} at {} ms, {}, {} C",
+                    defmt::Debug2Format(&fault.kind),
+                    fault.timestamp_ms,
+                    defmt::Debug2Format(&faults.state()),
+                    derating.temperature
+                ),
+                (Action::Enable, _) => defmt::println!("driver fault gone, outputs on"),
+                _ => {}
+            }
+        });
+
+        // the console changes the configured current
+        let configured = cx.shared.config.lock(|config| config.current);
+        let (scale, current) = (cx.local.scale, cx.local.current);
+        if (derating.scale - *scale).abs() < DERATING_STEP && *current == configured {
+            return;
+        }
+
+        // clamped to the lowest step, a hot driver never keeps the full current
+        let derated = configured.scaled(derating.scale);
+        let current = match Current::new(&derated) {
+            Ok(current) => current,
+            Err(error) => {
+                defmt::println!("derated current refused: {}", defmt::Debug2Format(&error));
+                return;
+            }
+        };
+
+        if cx
+            .shared
+            .tmc_bus
+            .lock(|bus| bus.driver(addr).set_current(&current))
+            .is_ok()
+        {
+            *scale = derating.scale;
+            *current = configured;
+            defmt::println!(
+                "{} C, run current {} mA",
+                derating.temperature,
+                derated.run_ma
+            );
+        }
+    }
+
+    // USART1 idle line - a burst of console input is in CONSOLE_BUFFER
+    #[task(binds = USART1, priority = 1)]
+    fn on_console_idle(_: on_console_idle::Context) {
+        // only the flag is cleared here, the console task owns USART1
+        unsafe { (*pac::USART1::ptr()).icr.write(|w| w.idlecf().set_bit()) };
+
+        // wakes the console task, or makes its next wait return when it is still reading
+        interrupt::free(|cs| {
+            CONSOLE_RX.store(true, Ordering::Relaxed);
+            if let Some(waker) = CONSOLE_WAKER.borrow(cs).borrow_mut().take() {
+                waker.wake();
+            }
+        });
+    }
+
+    // feeds the console input to the shell and runs the commands at the lowest priority,
+    // driver register reads take long and must not hold up the motion tasks. Runs from init on
+    // and never returns, a spawn from the interrupt could fail while it is finishing
+    #[task(priority = 0, local = [console_uart, shell, gcode, read_pos: usize = 0], shared = [tmc_bus, tmc_addr, stepper, homing, step_loss, faults, config, encoder_position])]
+    async fn console(mut cx: console::Context) {
+        let out = cx.local.console_uart;
+        let read_pos = cx.local.read_pos;
+        let gcode = cx.local.gcode;
+
+        loop {
+            // DMA1 ch3 counts down to the end of the buffer and starts over
+            let remaining = unsafe { (*pac::DMA1::ptr()).cndtr3.read().ndt().bits() } as usize;
+            let write_pos = (CONSOLE_BUFFER_LEN - remaining) % CONSOLE_BUFFER_LEN;
+            if *read_pos == write_pos {
+                console_rx().await;
+                continue;
+            }
+
+            let byte = unsafe { (*addr_of!(CONSOLE_BUFFER))[*read_pos] };
+            *read_pos = (*read_pos + 1) % CONSOLE_BUFFER_LEN;
 
-        // a possible way to get a pin state, I think better to use local or share
-        let b = Pin::new(Port::A, 10, PinMode::Input);
+            if gcode.active {
+                match gcode.feed(byte) {
+                    Some(Ok(line)) if line.as_str().trim() == "exit" => {
+                        gcode.active = false;
+                        // M18 left the driver off, the shell commands expect it on
+                        let running =
+                            cx.shared.faults.lock(|faults| faults.state()) == FaultState::Running;
+                        if !gcode.interpreter.is_enabled() && running {
+                            let addr = cx.shared.tmc_addr.lock(|addr| *addr);
+                            (&mut cx.shared.tmc_bus, &mut cx.shared.stepper).lock(
+                                |bus, stepper| {
+                                    apply(Action::Enable, &mut bus.driver(addr), stepper)
+                                },
+                            );
+                        }
+                        cx.local.shell.prompt(out).ok();
+                    }
+                    Some(Ok(line)) => {
+                        run_gcode(line.as_str(), &mut gcode.interpreter, &mut cx.shared, out)
+                            .await
+                            .ok();
+                    }
+                    Some(Err(())) => {
+                        write!(out, "Error:line too long\r\nok\r\n").ok();
+                    }
+                    None => {}
+                }
+                continue;
+            }
 
-        if b.is_low() {
-            defmt::println!("m_directory.toggle");
-            cx.local.m_directory.toggle();
+            let mut request = None;
+            if let Ok(true) = cx.local.shell.feed(byte, &mut request, out) {
+                match request {
+                    Some(Request::Gcode) => {
+                        // absolute moves count from where the motor stands until G28 homes it
+                        let position = cx.shared.stepper.lock(|stepper| stepper.steps.position());
+                        gcode.interpreter = Interpreter::new(GCODE);
+                        gcode.interpreter.set_position(position);
+                        gcode.active = true;
+                        writeln!(out, "G-code mode, exit returns\r").ok();
+                        continue;
+                    }
+                    Some(request) => {
+                        run_request(request, &mut cx.shared, out).ok();
+                    }
+                    None => {}
+                }
+                cx.local.shell.prompt(out).ok();
+            }
         }
     }
 }
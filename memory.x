@@ -1,6 +1,7 @@
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 128K
+  /* the last 2K page of the 128K holds the encoder calibration */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 126K
   RAM : ORIGIN = 0x20000000, LENGTH = 32K
 }
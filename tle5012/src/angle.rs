@@ -0,0 +1,48 @@
+//! Angle values of the AVAL register.
+
+use crate::reg::{AVAL_MASK, AVAL_NEW};
+
+/// Angle counts per revolution.
+pub const COUNTS_PER_REV: u32 = 1 << 15;
+
+/// Shaft angle, 15 bits over one revolution.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Angle {
+    counts: u16,
+    new: bool,
+}
+
+impl Angle {
+    /// From the AVAL register value. Its signed ±180° value is taken as 0 to
+    /// 360°, negative angles land in the upper half.
+    pub fn from_aval(aval: u16) -> Self {
+        Self {
+            counts: aval & AVAL_MASK,
+            new: aval & AVAL_NEW != 0,
+        }
+    }
+
+    /// Counts 0 to 32767 over one revolution.
+    pub fn counts(self) -> u16 {
+        self.counts
+    }
+
+    /// Counts scaled to 16 bits, the value `spi_dma` printed.
+    pub fn counts_u16(self) -> u16 {
+        self.counts << 1
+    }
+
+    /// Angle in radians, 0 to 2π.
+    pub fn radians(self) -> f32 {
+        self.counts as f32 / COUNTS_PER_REV as f32 * core::f32::consts::TAU
+    }
+
+    pub fn degrees(self) -> f32 {
+        self.counts as f32 / COUNTS_PER_REV as f32 * 360.0
+    }
+
+    /// The angle was updated since the last read.
+    pub fn is_new(self) -> bool {
+        self.new
+    }
+}
@@ -0,0 +1,176 @@
+//! Blocking register reads with the chip select handled per frame.
+
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+
+use crate::angle::Angle;
+use crate::frame::{self, FrameError, FRAME_LEN};
+use crate::reg::{Address, Stat};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error<E> {
+    Spi(E),
+    /// The chip select pin could not be driven.
+    Pin,
+    Frame(FrameError),
+}
+
+impl<E> From<FrameError> for Error<E> {
+    fn from(err: FrameError) -> Self {
+        Error::Frame(err)
+    }
+}
+
+pub struct Tle5012<SPI, CS> {
+    spi: SPI,
+    cs: CS,
+}
+
+impl<SPI, CS, E> Tle5012<SPI, CS>
+where
+    SPI: Transfer<u8, Error = E>,
+    CS: OutputPin,
+{
+    /// The SPI must run in mode 1, the chip select is released here.
+    pub fn new(spi: SPI, mut cs: CS) -> Self {
+        let _ = cs.set_high();
+
+        Self { spi, cs }
+    }
+
+    pub fn release(self) -> (SPI, CS) {
+        (self.spi, self.cs)
+    }
+
+    /// Shaft angle, fails on a bad CRC and when the sensor flags the value,
+    /// [`Tle5012::read_status`] tells why.
+    pub fn read_angle(&mut self) -> Result<Angle, Error<E>> {
+        self.read(Address::Aval as u8).map(Angle::from_aval)
+    }
+
+    /// STAT register, clears its reset flag.
+    ///
+    /// Only the CRC is checked, the safety word flags a system error whose
+    /// cause is in here.
+    pub fn read_status(&mut self) -> Result<Stat, Error<E>> {
+        self.read_unchecked(Address::Stat as u8).map(Stat)
+    }
+
+    /// One register with the CRC and the safety word flags checked.
+    pub fn read(&mut self, addr: u8) -> Result<u16, Error<E>> {
+        let reply = self.transfer(addr)?;
+
+        Ok(frame::parse_reply(frame::read_command(addr), &reply)?)
+    }
+
+    /// One register with only the CRC checked.
+    fn read_unchecked(&mut self, addr: u8) -> Result<u16, Error<E>> {
+        let reply = self.transfer(addr)?;
+
+        match frame::parse_reply(frame::read_command(addr), &reply) {
+            Ok(data) => Ok(data),
+            Err(FrameError::Status(_)) => Ok(u16::from_be_bytes([reply[2], reply[3]])),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn transfer(&mut self, addr: u8) -> Result<[u8; FRAME_LEN], Error<E>> {
+        let mut buf = frame::read_request(addr);
+
+        self.cs.set_low().map_err(|_| Error::Pin)?;
+        let result = self.spi.transfer(&mut buf).map(|_| ());
+        self.cs.set_high().map_err(|_| Error::Pin)?;
+        result.map_err(Error::Spi)?;
+
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{reply, MockCs, MockSpi};
+    use crate::reg::AVAL_NEW;
+
+    fn sensor(replies: &[[u8; FRAME_LEN]]) -> Tle5012<MockSpi, MockCs> {
+        Tle5012::new(MockSpi::new(replies), MockCs::default())
+    }
+
+    #[test]
+    fn reads_the_angle() {
+        let mut sensor = sensor(&[reply(0x02, AVAL_NEW | 0x4000, 0x70)]);
+
+        let angle = sensor.read_angle().unwrap();
+        assert_eq!(angle.counts(), 0x4000);
+        assert!(angle.is_new());
+        assert!((angle.degrees() - 180.0).abs() < 1e-3);
+
+        // one frame inside one chip select
+        let (spi, cs) = sensor.release();
+        assert_eq!(spi.sent, [[0x80, 0x21, 0, 0, 0, 0]]);
+        assert_eq!(cs.levels, [true, false, true]);
+    }
+
+    #[test]
+    fn negative_angles_land_in_the_upper_half() {
+        // -90° in 15 bit two's complement
+        let mut sensor = sensor(&[reply(0x02, 0x6000, 0x70)]);
+
+        let angle = sensor.read_angle().unwrap();
+        assert!(!angle.is_new());
+        assert!((angle.degrees() - 270.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn rejects_a_corrupted_frame() {
+        let mut frame = reply(0x02, 0x1234, 0x70);
+        frame[2] ^= 0x80;
+        let mut sensor = sensor(&[frame]);
+
+        assert!(matches!(
+            sensor.read_angle(),
+            Err(Error::Frame(FrameError::Crc { .. }))
+        ));
+        // the chip select is released anyway
+        let (_, cs) = sensor.release();
+        assert_eq!(cs.levels.last(), Some(&true));
+    }
+
+    #[test]
+    fn flagged_angle_and_its_status() {
+        // S_MAGOL and S_OV, the safety word flags a system error and an invalid angle
+        let mut sensor = sensor(&[reply(0x02, 0x1234, 0x20), reply(0x00, 0x00A0, 0x20)]);
+
+        match sensor.read_angle() {
+            Err(Error::Frame(FrameError::Status(safety))) => {
+                assert!(safety.system_error() && safety.invalid_angle());
+            }
+            other => panic!("{:?}", other),
+        }
+
+        let stat = sensor.read_status().unwrap();
+        assert!(stat.s_magol() && stat.s_ov());
+        assert!(!stat.s_xyol() && !stat.is_field_ok());
+    }
+
+    #[test]
+    fn status_still_checks_the_crc() {
+        let mut frame = reply(0x00, 0x0001, 0x70);
+        frame[5] ^= 0x01;
+        let mut sensor = sensor(&[frame]);
+
+        assert!(matches!(
+            sensor.read_status(),
+            Err(Error::Frame(FrameError::Crc { .. }))
+        ));
+    }
+
+    #[test]
+    fn spi_errors_pass_through() {
+        let mut sensor = sensor(&[]);
+
+        assert_eq!(sensor.read_angle(), Err(Error::Spi(())));
+        let (_, cs) = sensor.release();
+        assert_eq!(cs.levels, [true, false, true]);
+    }
+}
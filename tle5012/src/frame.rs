@@ -0,0 +1,207 @@
+//! SSC frames of a single register read, see the datasheet chapter 6 SSC
+//! interface.
+//!
+//! ```text
+//! command   rw | lock[3:0] | upd | addr[5:0] | nd[3:0]
+//! reply     data[15:0] | safety[15:0]
+//! safety    stat[3:0] | resp[3:0] | crc[7:0]
+//! ```
+//!
+//! The bus is full duplex on the MCU side: the first two bytes clock the
+//! command out, the next four clock the data word and the safety word in.
+//! With `nd = 0` the sensor leaves out the safety word, reads here always
+//! ask for one data word so every value comes with a CRC.
+
+/// Command bit of a read.
+pub const READ: u16 = 0x8000;
+
+/// Command bit reading the snapshot taken by the last update instead of the
+/// current value.
+pub const UPDATE: u16 = 0x0400;
+
+/// Bytes of a one word read, command, data and safety word.
+pub const FRAME_LEN: usize = 6;
+
+/// Offset of the data word in a frame.
+pub const DATA_OFFSET: usize = 2;
+
+const CRC_POLYNOMIAL: u8 = 0x1D;
+const CRC_SEED: u8 = 0xFF;
+
+/// Reasons a reply frame is rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    Crc {
+        expected: u8,
+        got: u8,
+    },
+    /// The sensor flagged the access or the value, see [`Safety`].
+    Status(Safety),
+}
+
+/// Safety word sent after the data words.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Safety(pub u16);
+
+impl Safety {
+    /// The chip was reset or its watchdog overflowed since STAT was read.
+    pub fn reset(self) -> bool {
+        self.0 & 0x8000 != 0
+    }
+
+    /// One of the S_VR, S_DSPU, S_OV, S_XYOL, S_MAGOL, S_FUSE, S_ROM or
+    /// S_ADCT flags of [`Stat`](crate::Stat) is set, low active.
+    pub fn system_error(self) -> bool {
+        self.0 & 0x4000 == 0
+    }
+
+    /// Wrong address or lock of the command, low active.
+    pub fn interface_error(self) -> bool {
+        self.0 & 0x2000 == 0
+    }
+
+    /// S_XYOL or S_OV, the angle value is not valid, low active.
+    pub fn invalid_angle(self) -> bool {
+        self.0 & 0x1000 == 0
+    }
+
+    /// Sensor number of the response, for sensors sharing the bus.
+    pub fn resp(self) -> u8 {
+        (self.0 >> 8) as u8 & 0x0F
+    }
+
+    pub fn crc(self) -> u8 {
+        self.0 as u8
+    }
+
+    pub fn is_ok(self) -> bool {
+        !(self.system_error() || self.interface_error() || self.invalid_angle())
+    }
+}
+
+/// CRC8 over the command and the data words, polynomial
+/// x^8 + x^4 + x^3 + x^2 + 1, seed 0xFF, inverted, bytes MSB first.
+pub fn crc(data: &[u8]) -> u8 {
+    let mut crc = CRC_SEED;
+
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            if crc & 0x80 != 0 {
+                crc = (crc << 1) ^ CRC_POLYNOMIAL;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+
+    !crc
+}
+
+/// Command word reading one word at `addr`.
+pub const fn read_command(addr: u8) -> u16 {
+    READ | (addr as u16 & 0x3F) << 4 | 1
+}
+
+/// Transmit frame of a one word read of `addr`, the data part is zero.
+pub const fn read_request(addr: u8) -> [u8; FRAME_LEN] {
+    let cmd = read_command(addr).to_be_bytes();
+    [cmd[0], cmd[1], 0, 0, 0, 0]
+}
+
+/// Checks the received frame of a read with `command` and returns the data
+/// word. A failed CRC wins over the safety word flags.
+pub fn parse_reply(command: u16, reply: &[u8; FRAME_LEN]) -> Result<u16, FrameError> {
+    let cmd = command.to_be_bytes();
+    let data = u16::from_be_bytes([reply[2], reply[3]]);
+    let safety = Safety(u16::from_be_bytes([reply[4], reply[5]]));
+
+    let expected = crc(&[cmd[0], cmd[1], reply[2], reply[3]]);
+    if safety.crc() != expected {
+        return Err(FrameError::Crc {
+            expected,
+            got: safety.crc(),
+        });
+    }
+
+    if !safety.is_ok() {
+        return Err(FrameError::Status(safety));
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::reply;
+
+    #[test]
+    fn crc_matches_sae_j1850() {
+        // check value of the CRC-8/SAE-J1850 catalogue entry
+        assert_eq!(crc(b"123456789"), 0x4B);
+        assert_eq!(crc(&[]), 0x00);
+    }
+
+    #[test]
+    fn read_command_layout() {
+        assert_eq!(read_command(0x02), 0x8021);
+        assert_eq!(read_command(0x00), 0x8001);
+        assert_eq!(read_request(0x02), [0x80, 0x21, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn good_reply_gives_the_data() {
+        let frame = reply(0x02, 0x9234, 0x70);
+        assert_eq!(parse_reply(read_command(0x02), &frame), Ok(0x9234));
+    }
+
+    #[test]
+    fn crc_covers_command_and_data() {
+        let mut frame = reply(0x02, 0x1234, 0x70);
+        frame[3] ^= 0x01;
+        assert!(matches!(
+            parse_reply(read_command(0x02), &frame),
+            Err(FrameError::Crc { .. })
+        ));
+
+        // the reply of another register does not pass
+        let frame = reply(0x03, 0x1234, 0x70);
+        assert!(matches!(
+            parse_reply(read_command(0x02), &frame),
+            Err(FrameError::Crc { .. })
+        ));
+    }
+
+    #[test]
+    fn crc_failure_wins_over_the_flags() {
+        let mut frame = reply(0x02, 0x1234, 0x00);
+        frame[5] ^= 0xFF;
+        assert!(matches!(
+            parse_reply(read_command(0x02), &frame),
+            Err(FrameError::Crc { .. })
+        ));
+    }
+
+    #[test]
+    fn safety_flags_are_low_active() {
+        let ok = Safety(0x7000);
+        assert!(ok.is_ok() && !ok.reset());
+
+        let flags = Safety(0x8300);
+        assert!(flags.reset());
+        assert!(flags.system_error());
+        assert!(flags.interface_error());
+        assert!(flags.invalid_angle());
+        assert_eq!(flags.resp(), 3);
+
+        let frame = reply(0x02, 0x1234, 0x60);
+        match parse_reply(read_command(0x02), &frame) {
+            Err(FrameError::Status(safety)) => {
+                assert!(safety.invalid_angle());
+                assert!(!safety.system_error() && !safety.interface_error());
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+}
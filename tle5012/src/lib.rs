@@ -0,0 +1,27 @@
+//! Infineon TLE5012B magnetic angle sensor over its SSC (SPI) interface.
+//!
+//! - [`frame`] encoding: command words, the safety word and its CRC
+//! - [`reg`] addresses and the [`Stat`] status flags, among them the magnet
+//!   field strength (S_MAGOL) and the DSPU overflow (S_OV)
+//! - [`Angle`] in counts, radians and degrees
+//! - a [`stream`] double buffer for continuous DMA reads
+//!
+//! The [`Tle5012`] driver is generic over the `embedded-hal` blocking SPI and
+//! output pin traits, so it runs on `hal::spi::Spi` as well as on a host side
+//! mock fed with recorded frames. DMA based firmware uses [`frame`] directly
+//! on its buffers.
+
+#![no_std]
+
+mod angle;
+mod driver;
+pub mod frame;
+#[cfg(test)]
+mod mock;
+pub mod reg;
+pub mod stream;
+
+pub use angle::{Angle, COUNTS_PER_REV};
+pub use driver::{Error, Tle5012};
+pub use frame::{FrameError, Safety};
+pub use reg::Stat;
@@ -0,0 +1,71 @@
+//! Recorded sensor replies on a mock SPI for the host tests.
+
+extern crate std;
+
+use std::collections::VecDeque;
+use std::vec::Vec;
+
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+
+use crate::frame::{self, FRAME_LEN};
+
+/// Answers every transfer with the next queued frame.
+#[derive(Default)]
+pub struct MockSpi {
+    pub replies: VecDeque<[u8; FRAME_LEN]>,
+    /// Frames clocked out, in order.
+    pub sent: Vec<[u8; FRAME_LEN]>,
+}
+
+impl MockSpi {
+    pub fn new(replies: &[[u8; FRAME_LEN]]) -> Self {
+        Self {
+            replies: replies.iter().copied().collect(),
+            sent: Vec::new(),
+        }
+    }
+}
+
+/// Reply of a read at `addr`: the sensor keeps the bus released during the
+/// command, then sends the data and the safety word with `stat` in its upper
+/// byte and the CRC over command and data.
+pub fn reply(addr: u8, data: u16, stat: u8) -> [u8; FRAME_LEN] {
+    let cmd = frame::read_command(addr).to_be_bytes();
+    let data = data.to_be_bytes();
+    let crc = frame::crc(&[cmd[0], cmd[1], data[0], data[1]]);
+
+    [0xFF, 0xFF, data[0], data[1], stat, crc]
+}
+
+impl Transfer<u8> for MockSpi {
+    type Error = ();
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], ()> {
+        self.sent.push(words.try_into().map_err(|_| ())?);
+        let reply = self.replies.pop_front().ok_or(())?;
+        words.copy_from_slice(&reply);
+
+        Ok(words)
+    }
+}
+
+/// Chip select levels, `true` is high.
+#[derive(Default)]
+pub struct MockCs {
+    pub levels: Vec<bool>,
+}
+
+impl OutputPin for MockCs {
+    type Error = core::convert::Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.levels.push(false);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.levels.push(true);
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+//! Register addresses and status flags, datasheet chapter 7.
+
+/// Register addresses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Address {
+    Stat = 0x00,
+    Acstat = 0x01,
+    Aval = 0x02,
+    Aspd = 0x03,
+    Arev = 0x04,
+    Fsync = 0x05,
+    Mod1 = 0x06,
+    Sil = 0x07,
+    Mod2 = 0x08,
+    Mod3 = 0x09,
+    Offx = 0x0A,
+    Offy = 0x0B,
+    Synch = 0x0C,
+    Ifab = 0x0D,
+    Mod4 = 0x0E,
+    Tcoy = 0x0F,
+    Adc = 0x10,
+    Adcy = 0x11,
+    Dmag = 0x14,
+    Traw = 0x15,
+    Iifcnt = 0x20,
+    T25o = 0x30,
+}
+
+/// AVAL bit set when the angle changed since the last read.
+pub const AVAL_NEW: u16 = 0x8000;
+
+/// AVAL angle value bits, 15 bit two's complement over ±180°.
+pub const AVAL_MASK: u16 = 0x7FFF;
+
+/// STAT register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stat(pub u16);
+
+impl Stat {
+    /// Reset occurred, cleared by the read.
+    pub fn s_rst(self) -> bool {
+        self.bit(0)
+    }
+
+    /// Watchdog overflow.
+    pub fn s_wd(self) -> bool {
+        self.bit(1)
+    }
+
+    /// Supply voltage out of range.
+    pub fn s_vr(self) -> bool {
+        self.bit(2)
+    }
+
+    /// Fuse CRC error.
+    pub fn s_fuse(self) -> bool {
+        self.bit(3)
+    }
+
+    /// DSPU self test failed.
+    pub fn s_dspu(self) -> bool {
+        self.bit(4)
+    }
+
+    /// DSPU overflow, the angle changes faster than the sensor can track.
+    pub fn s_ov(self) -> bool {
+        self.bit(5)
+    }
+
+    /// X/Y values out of limit.
+    pub fn s_xyol(self) -> bool {
+        self.bit(6)
+    }
+
+    /// GMR magnitude out of limit, the magnet is too weak, too strong or
+    /// missing.
+    pub fn s_magol(self) -> bool {
+        self.bit(7)
+    }
+
+    /// ADC test vectors failed.
+    pub fn s_adct(self) -> bool {
+        self.bit(9)
+    }
+
+    /// ROM CRC error.
+    pub fn s_rom(self) -> bool {
+        self.bit(10)
+    }
+
+    /// No valid GMR X/Y values.
+    pub fn no_gmr_xy(self) -> bool {
+        self.bit(11)
+    }
+
+    /// No valid GMR angle value.
+    pub fn no_gmr_a(self) -> bool {
+        self.bit(12)
+    }
+
+    /// Slave number of the sensor.
+    pub fn s_nr(self) -> u8 {
+        (self.0 >> 13) as u8 & 0x03
+    }
+
+    /// Status values are fresh since the last read.
+    pub fn rd_st(self) -> bool {
+        self.bit(15)
+    }
+
+    /// The magnet field is in range and the angle can be tracked.
+    pub fn is_field_ok(self) -> bool {
+        !(self.s_magol() || self.s_xyol() || self.s_ov())
+    }
+
+    fn bit(self, n: u8) -> bool {
+        self.0 & 1 << n != 0
+    }
+}
@@ -0,0 +1,220 @@
+//! Lock-free hand over of continuously streamed frames.
+//!
+//! The RX DMA runs circular over two frames, so each half of the buffer
+//! holds one reply and the DMA fills one while the other is read. The
+//! interrupt of each finished half only calls [`DoubleBuffer::complete`],
+//! readers take the newest frame with [`DoubleBuffer::latest`] without a
+//! lock. It works like a seqlock: the sequence count is read before and
+//! after the copy, a change means the DMA came round to the frame during the
+//! copy and the copy is retried.
+//!
+//! The count is bumped by the interrupt, not by the DMA, so readers must run
+//! below the priority of the DMA interrupt. A reader above it could copy a
+//! half the DMA already writes again while the interrupt is still pending,
+//! and see no change of the count.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{fence, AtomicU32, Ordering};
+
+use crate::angle::Angle;
+use crate::frame::{self, FrameError, FRAME_LEN};
+use crate::reg::Address;
+
+/// Bytes of the RX DMA buffer.
+pub const BUFFER_LEN: usize = 2 * FRAME_LEN;
+
+/// Copies tried before [`DoubleBuffer::latest`] gives up.
+const RETRIES: usize = 4;
+
+/// Newest frame with its sequence number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sample {
+    /// Frames completed so far, counts missed interrupts too.
+    pub seq: u32,
+    pub frame: [u8; FRAME_LEN],
+}
+
+impl Sample {
+    /// Angle of an AVAL read.
+    pub fn angle(&self) -> Result<Angle, FrameError> {
+        frame::parse_reply(frame::read_command(Address::Aval as u8), &self.frame)
+            .map(Angle::from_aval)
+    }
+}
+
+pub struct DoubleBuffer {
+    frames: UnsafeCell<[u8; BUFFER_LEN]>,
+    /// Frames completed, frame `n` is in half `(n - 1) % 2`.
+    seq: AtomicU32,
+}
+
+// the DMA writes the frames, readers check `seq` around their copy
+unsafe impl Sync for DoubleBuffer {}
+
+impl DoubleBuffer {
+    pub const fn new() -> Self {
+        Self {
+            frames: UnsafeCell::new([0; BUFFER_LEN]),
+            seq: AtomicU32::new(0),
+        }
+    }
+
+    /// Start of the circular RX DMA buffer, [`BUFFER_LEN`] bytes.
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.frames.get() as *mut u8
+    }
+
+    /// Memory address of the circular RX DMA for CMAR.
+    pub fn dma_address(&self) -> u32 {
+        self.as_mut_ptr() as usize as u32
+    }
+
+    /// Marks `half`, 0 or 1, as written, from the half transfer (0) and
+    /// transfer complete (1) interrupt.
+    pub fn complete(&self, half: usize) {
+        let seq = self.seq.load(Ordering::Relaxed);
+
+        // a missed interrupt skips one more frame, so the half stays in step
+        let next = if seq as usize % 2 == half {
+            seq.wrapping_add(1)
+        } else {
+            seq.wrapping_add(2)
+        };
+        self.seq.store(next, Ordering::Release);
+    }
+
+    /// Frames completed so far.
+    pub fn seq(&self) -> u32 {
+        self.seq.load(Ordering::Acquire)
+    }
+
+    /// Newest complete frame, `None` before the first one or when the DMA
+    /// kept overwriting it during the copy. Call it below the priority of the
+    /// DMA interrupt.
+    pub fn latest(&self) -> Option<Sample> {
+        for _ in 0..RETRIES {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq == 0 {
+                return None;
+            }
+
+            let half = (seq.wrapping_sub(1) % 2) as usize;
+            let mut frame = [0; FRAME_LEN];
+            for (n, byte) in frame.iter_mut().enumerate() {
+                // the DMA writes behind the compiler's back
+                *byte = unsafe {
+                    core::ptr::read_volatile(
+                        (self.frames.get() as *const u8).add(half * FRAME_LEN + n),
+                    )
+                };
+            }
+
+            // the DMA starts on this half again only after the next frame completed
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Acquire) == seq {
+                return Some(Sample { seq, frame });
+            }
+        }
+
+        None
+    }
+}
+
+impl Default for DoubleBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::mock::reply;
+
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    // writes `frame` into `half` as the DMA does
+    fn dma_write(buffer: &DoubleBuffer, half: usize, frame: &[u8; FRAME_LEN]) {
+        for (n, &byte) in frame.iter().enumerate() {
+            unsafe {
+                core::ptr::write_volatile(buffer.as_mut_ptr().add(half * FRAME_LEN + n), byte)
+            };
+        }
+    }
+
+    fn aval(counts: u16) -> [u8; FRAME_LEN] {
+        reply(Address::Aval as u8, counts, 0x70)
+    }
+
+    #[test]
+    fn hands_over_each_half() {
+        let buffer = DoubleBuffer::new();
+        assert_eq!(buffer.latest(), None);
+
+        dma_write(&buffer, 0, &aval(100));
+        buffer.complete(0);
+        let sample = buffer.latest().unwrap();
+        assert_eq!(sample.seq, 1);
+        assert_eq!(sample.angle().unwrap().counts(), 100);
+
+        dma_write(&buffer, 1, &aval(200));
+        buffer.complete(1);
+        assert_eq!(buffer.latest().unwrap().angle().unwrap().counts(), 200);
+        assert_eq!(buffer.seq(), 2);
+    }
+
+    #[test]
+    fn missed_interrupt_keeps_the_halves_in_step() {
+        let buffer = DoubleBuffer::new();
+        dma_write(&buffer, 0, &aval(100));
+        buffer.complete(0);
+
+        dma_write(&buffer, 1, &aval(200));
+        buffer.complete(1);
+
+        // the half transfer interrupt of frame 3 is lost
+        dma_write(&buffer, 0, &aval(300));
+        dma_write(&buffer, 1, &aval(400));
+        buffer.complete(1);
+        let sample = buffer.latest().unwrap();
+        assert_eq!(sample.seq, 4);
+        assert_eq!(sample.angle().unwrap().counts(), 400);
+
+        dma_write(&buffer, 0, &aval(500));
+        buffer.complete(0);
+        assert_eq!(buffer.latest().unwrap().angle().unwrap().counts(), 500);
+        assert_eq!(buffer.seq(), 5);
+    }
+
+    #[test]
+    fn reader_never_sees_a_torn_frame() {
+        let buffer = DoubleBuffer::new();
+        let done = AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            // the DMA and its interrupt, the interrupt is never late
+            scope.spawn(|| {
+                for n in 1..200_000u32 {
+                    let half = (n as usize - 1) % 2;
+                    dma_write(&buffer, half, &aval(n as u16 & 0x7FFF));
+                    buffer.complete(half);
+                }
+                done.store(true, Ordering::Release);
+            });
+
+            let mut last = 0;
+            while !done.load(Ordering::Acquire) {
+                if let Some(sample) = buffer.latest() {
+                    // a mix of two frames fails the CRC
+                    let angle = sample.angle().unwrap();
+                    assert_eq!(angle.counts() as u32, sample.seq & 0x7FFF);
+                    assert!(sample.seq >= last);
+                    last = sample.seq;
+                }
+            }
+        });
+    }
+}
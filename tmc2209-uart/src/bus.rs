@@ -0,0 +1,405 @@
+//! UART bus shared by up to four TMC2209s, addressed with their MS1/MS2 pins.
+
+use embedded_hal::serial::{Read, Write};
+use tmc2209::reg;
+
+use crate::datagram::{self, ReplyError, MAX_SLAVE_ADDR, REPLY_LEN};
+use crate::driver::Tmc2209;
+use crate::shadow::Shadow;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// TX and RX are joined into a single wire, every sent byte comes back on RX.
+    pub single_wire: bool,
+    /// Serial port polls before an expected byte is considered missing.
+    pub timeout_polls: u32,
+    /// Extra attempts after a failed read or a write the driver did not count.
+    pub retries: u8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            single_wire: true,
+            timeout_polls: 200_000,
+            retries: 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error<E> {
+    Serial(E),
+    /// The driver did not answer, check the slave address and wiring.
+    Timeout,
+    /// The echo of a sent byte differs from the byte, another node talks on the bus.
+    Echo {
+        sent: u8,
+        got: u8,
+    },
+    Reply(ReplyError),
+    /// IFCNT did not advance after a write, the driver dropped the datagram.
+    NotCounted {
+        before: u8,
+        after: u8,
+    },
+}
+
+impl<E> From<ReplyError> for Error<E> {
+    fn from(err: ReplyError) -> Self {
+        Error::Reply(err)
+    }
+}
+
+/// Slave address 0-3 set by the MS1/MS2 pins of a driver.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SlaveAddr(u8);
+
+impl SlaveAddr {
+    pub const ALL: [SlaveAddr; 4] = [SlaveAddr(0), SlaveAddr(1), SlaveAddr(2), SlaveAddr(3)];
+
+    pub const fn new(addr: u8) -> Option<Self> {
+        if addr <= MAX_SLAVE_ADDR {
+            Some(Self(addr))
+        } else {
+            None
+        }
+    }
+
+    pub const fn get(self) -> u8 {
+        self.0
+    }
+}
+
+/// Drivers that answered a [`Bus::scan`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Present(u8);
+
+impl Present {
+    pub fn contains(&self, addr: SlaveAddr) -> bool {
+        self.0 & (1 << addr.get()) != 0
+    }
+
+    pub fn count(&self) -> u32 {
+        self.0.count_ones()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = SlaveAddr> + '_ {
+        SlaveAddr::ALL
+            .into_iter()
+            .filter(|&addr| self.contains(addr))
+    }
+}
+
+/// Owns the UART and a register [`Shadow`] per slave address.
+///
+/// Keep the bus in an RTIC `#[shared]` resource and keep [`SlaveAddr`]s in the
+/// tasks, the resource lock serialises the datagrams of different tasks:
+///
+/// ```ignore
+/// cx.shared.tmc_bus.lock(|bus| bus.driver(addr).drv_status())
+/// ```
+pub struct Bus<S> {
+    serial: S,
+    config: Config,
+    shadows: [Shadow; SlaveAddr::ALL.len()],
+}
+
+impl<S, E> Bus<S>
+where
+    S: Read<u8, Error = E> + Write<u8, Error = E>,
+{
+    pub fn new(serial: S, config: Config) -> Self {
+        Self {
+            serial,
+            config,
+            shadows: Default::default(),
+        }
+    }
+
+    pub fn free(self) -> S {
+        self.serial
+    }
+
+    /// Register access to the driver at `addr`.
+    pub fn driver(&mut self, addr: SlaveAddr) -> Tmc2209<'_, S> {
+        Tmc2209::new(self, addr)
+    }
+
+    /// Reads IFCNT of every slave address and reports who answered.
+    pub fn scan(&mut self) -> Present {
+        let mut present = Present::default();
+
+        for addr in SlaveAddr::ALL {
+            if self.read_raw(addr, reg::Address::IFCNT as u8).is_ok() {
+                present.0 |= 1 << addr.get();
+            }
+        }
+
+        present
+    }
+
+    pub(crate) fn shadow(&self, addr: SlaveAddr) -> &Shadow {
+        &self.shadows[addr.get() as usize]
+    }
+
+    /// Reads register `reg`, retries `config.retries` times on failure.
+    pub(crate) fn read_raw(&mut self, addr: SlaveAddr, reg: u8) -> Result<u32, Error<E>> {
+        let mut attempt = 0;
+
+        loop {
+            match self.try_read(addr, reg) {
+                Err(_) if attempt < self.config.retries => attempt += 1,
+                res => return res,
+            }
+        }
+    }
+
+    /// Writes register `reg` and checks that IFCNT advanced by one, retries
+    /// `config.retries` times when it did not.
+    pub(crate) fn write_raw(
+        &mut self,
+        addr: SlaveAddr,
+        reg: u8,
+        data: u32,
+    ) -> Result<(), Error<E>> {
+        let mut attempt = 0;
+
+        loop {
+            match self.try_write(addr, reg, data) {
+                Err(_) if attempt < self.config.retries => attempt += 1,
+                Err(err) => return Err(err),
+                Ok(()) => {
+                    self.shadows[addr.get() as usize].record(reg, data);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn ifcnt(&mut self, addr: SlaveAddr) -> Result<u8, Error<E>> {
+        self.read_raw(addr, reg::Address::IFCNT as u8)
+            .map(|data| data as u8)
+    }
+
+    fn try_read(&mut self, addr: SlaveAddr, reg: u8) -> Result<u32, Error<E>> {
+        self.drain();
+        self.send(&datagram::read_request(addr.get(), reg))?;
+
+        let mut reply = [0; REPLY_LEN];
+        for byte in reply.iter_mut() {
+            *byte = self.read_byte()?;
+        }
+
+        Ok(datagram::parse_reply(&reply, reg)?)
+    }
+
+    fn try_write(&mut self, addr: SlaveAddr, reg: u8, data: u32) -> Result<(), Error<E>> {
+        let before = self.ifcnt(addr)?;
+
+        self.drain();
+        self.send(&datagram::write_request(addr.get(), reg, data))?;
+
+        let after = self.ifcnt(addr)?;
+        if after != before.wrapping_add(1) {
+            return Err(Error::NotCounted { before, after });
+        }
+
+        Ok(())
+    }
+
+    fn send(&mut self, bytes: &[u8]) -> Result<(), Error<E>> {
+        for &sent in bytes {
+            nb::block!(self.serial.write(sent)).map_err(Error::Serial)?;
+
+            // read the echo byte by byte, the port may have no rx fifo
+            if self.config.single_wire {
+                let got = self.read_byte()?;
+                if got != sent {
+                    return Err(Error::Echo { sent, got });
+                }
+            }
+        }
+
+        nb::block!(self.serial.flush()).map_err(Error::Serial)
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error<E>> {
+        for _ in 0..self.config.timeout_polls {
+            match self.serial.read() {
+                Ok(byte) => return Ok(byte),
+                Err(nb::Error::WouldBlock) => continue,
+                Err(nb::Error::Other(err)) => return Err(Error::Serial(err)),
+            }
+        }
+
+        Err(Error::Timeout)
+    }
+
+    /// Drops stale bytes left from a previous failed transfer.
+    fn drain(&mut self) {
+        for _ in 0..REPLY_LEN * 2 {
+            if let Err(nb::Error::WouldBlock) = self.serial.read() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockUart;
+
+    const CONFIG: Config = Config {
+        single_wire: true,
+        timeout_polls: 10,
+        retries: 2,
+    };
+
+    const DRV_STATUS: u8 = reg::Address::DRV_STATUS as u8;
+
+    fn bus(present: &[u8]) -> Bus<MockUart> {
+        Bus::new(MockUart::new(present), CONFIG)
+    }
+
+    #[test]
+    fn read_strips_the_echo() {
+        let mut bus = bus(&[0]);
+        bus.serial.regs[0][DRV_STATUS as usize] = 0x8000_0003;
+
+        assert_eq!(bus.read_raw(SlaveAddr::ALL[0], DRV_STATUS), Ok(0x8000_0003));
+        assert_eq!(bus.serial.reads, 1);
+        // the 4 echo bytes and the 8 reply bytes were all consumed
+        assert_eq!(bus.serial.pending(), 0);
+    }
+
+    #[test]
+    fn bad_crc_is_retried() {
+        let mut bus = bus(&[0]);
+        bus.serial.regs[0][DRV_STATUS as usize] = 42;
+        bus.serial.bad_crc = 2;
+
+        assert_eq!(bus.read_raw(SlaveAddr::ALL[0], DRV_STATUS), Ok(42));
+        assert_eq!(bus.serial.reads, 3);
+    }
+
+    #[test]
+    fn bad_crc_uses_up_the_retries() {
+        let mut bus = bus(&[0]);
+        bus.serial.bad_crc = 3;
+
+        let res = bus.read_raw(SlaveAddr::ALL[0], DRV_STATUS);
+        assert!(
+            matches!(res, Err(Error::Reply(ReplyError::Crc { .. }))),
+            "{:?}",
+            res
+        );
+        assert_eq!(bus.serial.reads, 1 + CONFIG.retries as u32);
+    }
+
+    #[test]
+    fn missing_reply_times_out() {
+        let mut bus = bus(&[0]);
+        bus.serial.silent = 1;
+
+        // one lost reply is covered by a retry
+        assert_eq!(bus.read_raw(SlaveAddr::ALL[0], DRV_STATUS), Ok(0));
+
+        let absent = SlaveAddr::new(1).unwrap();
+        assert_eq!(bus.read_raw(absent, DRV_STATUS), Err(Error::Timeout));
+        assert_eq!(bus.serial.reads, 2 + 1 + CONFIG.retries as u32);
+    }
+
+    #[test]
+    fn echo_mismatch_is_reported() {
+        let mut bus = Bus::new(
+            MockUart::new(&[0]),
+            Config {
+                retries: 0,
+                ..CONFIG
+            },
+        );
+        bus.serial.bad_echo = 1;
+
+        assert_eq!(
+            bus.read_raw(SlaveAddr::ALL[0], DRV_STATUS),
+            Err(Error::Echo {
+                sent: datagram::SYNC,
+                got: !datagram::SYNC
+            })
+        );
+    }
+
+    #[test]
+    fn stale_bytes_are_drained() {
+        let mut bus = bus(&[0]);
+        bus.serial.regs[0][DRV_STATUS as usize] = 7;
+        bus.serial.inject(&[0xFF, 0x05, 0x12]);
+
+        assert_eq!(bus.read_raw(SlaveAddr::ALL[0], DRV_STATUS), Ok(7));
+        assert_eq!(bus.serial.reads, 1);
+    }
+
+    #[test]
+    fn write_is_verified_with_ifcnt() {
+        let mut bus = bus(&[2]);
+        let addr = SlaveAddr::new(2).unwrap();
+        let gconf = reg::Address::GCONF as u8;
+
+        assert_eq!(bus.write_raw(addr, gconf, 0x1C0), Ok(()));
+        assert_eq!(bus.serial.regs[2][gconf as usize], 0x1C0);
+        assert_eq!(bus.serial.regs[2][0x02], 1);
+        assert_eq!(bus.shadow(addr).raw(gconf), Some(0x1C0));
+    }
+
+    #[test]
+    fn slave_addresses() {
+        assert!(SlaveAddr::ALL
+            .iter()
+            .map(|addr| addr.get())
+            .eq(0..=MAX_SLAVE_ADDR));
+        assert_eq!(SlaveAddr::new(3), Some(SlaveAddr::ALL[3]));
+        assert!(SlaveAddr::new(4).is_none());
+    }
+
+    #[test]
+    fn scan_finds_the_present_drivers() {
+        let mut bus = bus(&[1, 3]);
+
+        let present = bus.scan();
+        assert_eq!(present.count(), 2);
+        assert!(present.contains(SlaveAddr::ALL[1]));
+        assert!(!present.contains(SlaveAddr::ALL[0]));
+        assert!(present.iter().map(SlaveAddr::get).eq([1, 3]));
+
+        // nobody on the wire
+        assert_eq!(Bus::new(MockUart::new(&[]), CONFIG).scan().count(), 0);
+    }
+
+    #[test]
+    fn drivers_share_the_bus() {
+        let mut bus = bus(&[0, 2]);
+        let (first, third) = (SlaveAddr::ALL[0], SlaveAddr::ALL[2]);
+        let gconf = reg::Address::GCONF as u8;
+
+        bus.driver(first).write_raw(gconf, 0x40).unwrap();
+        bus.driver(third).write_raw(gconf, 0x1C0).unwrap();
+        bus.serial.regs[2][DRV_STATUS as usize] = 5;
+
+        // every handle talks to its own address
+        assert_eq!(bus.driver(first).read_raw(gconf), Ok(0x40));
+        assert_eq!(bus.driver(third).read_raw(DRV_STATUS), Ok(5));
+        assert_eq!(bus.driver(first).read_raw(DRV_STATUS), Ok(0));
+
+        // and keeps its own shadow
+        assert_eq!(bus.shadow(first).raw(gconf), Some(0x40));
+        assert_eq!(bus.shadow(third).raw(gconf), Some(0x1C0));
+        assert_eq!(bus.shadow(SlaveAddr::ALL[1]).raw(gconf), None);
+
+        let mock = bus.free();
+        assert_eq!(mock.regs[0][gconf as usize], 0x40);
+        assert_eq!(mock.regs[2][gconf as usize], 0x1C0);
+        assert_eq!(mock.regs[1][gconf as usize], 0);
+    }
+}
@@ -0,0 +1,287 @@
+//! Chopper mode and CoolStep configuration.
+//!
+//! StealthChop is the quiet voltage mode chopper, SpreadCycle the current mode
+//! chopper for high speed and load. In hybrid mode the driver runs StealthChop
+//! below the TPWMTHRS velocity and switches to SpreadCycle above it.
+//!
+//! CoolStep adapts the motor current to the load measured by StallGuard4: the
+//! current goes up when SG_RESULT falls below `SEMIN * 32` and down when it
+//! rises above `(SEMIN + SEMAX + 1) * 32`. It works above the TCOOLTHRS
+//! velocity, the same threshold enables the StallGuard output on DIAG.
+//!
+//! TOFF 0 switches the power stage off, that is also how a short or
+//! overtemperature shutdown is cleared.
+
+use embedded_hal::serial::{Read, Write};
+use tmc2209::reg::{self, Address};
+
+use crate::bus::Error;
+use crate::driver::Tmc2209;
+use crate::units::{Mechanics, Velocity};
+
+/// TOFF after reset.
+pub const DEFAULT_TOFF: u8 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChopperMode {
+    StealthChop,
+    SpreadCycle,
+    /// StealthChop up to `switch_over`, SpreadCycle above.
+    Hybrid {
+        switch_over: Velocity,
+    },
+}
+
+/// Motor coil and supply, used for the StealthChop start values.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Coil {
+    pub resistance_ohm: f32,
+    /// RMS run current.
+    pub current_ma: u16,
+    pub supply_mv: u32,
+}
+
+/// PWM_OFS start value for the StealthChop automatic tuning, from the
+/// datasheet `PWM_OFS = 374 * R_COIL * I_COIL / V_M`.
+pub fn pwm_ofs(coil: &Coil) -> u8 {
+    if coil.supply_mv == 0 {
+        return u8::MAX;
+    }
+
+    let ofs = 374.0 * coil.resistance_ohm * coil.current_ma as f32 / coil.supply_mv as f32;
+    if ofs >= u8::MAX as f32 {
+        u8::MAX
+    } else {
+        ofs as u8
+    }
+}
+
+/// Current increment per SG_RESULT measurement below the lower threshold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CurrentUp {
+    Step1 = 0,
+    Step2 = 1,
+    Step4 = 2,
+    Step8 = 3,
+}
+
+/// SG_RESULT measurements above the upper threshold per current decrement.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CurrentDown {
+    Per32 = 0,
+    Per8 = 1,
+    Per2 = 2,
+    Per1 = 3,
+}
+
+/// Lowest current CoolStep goes down to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MinCurrent {
+    HalfIrun,
+    QuarterIrun,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CoolStep {
+    /// Lower threshold in units of 32 SG_RESULT, 1-15, 0 turns CoolStep off.
+    pub semin: u8,
+    /// Hysteresis above the lower threshold in units of 32 SG_RESULT, 0-15.
+    pub semax: u8,
+    pub up: CurrentUp,
+    pub down: CurrentDown,
+    pub min_current: MinCurrent,
+    /// CoolStep works from this velocity up.
+    pub min_velocity: Velocity,
+}
+
+impl CoolStep {
+    /// Keeps SG_RESULT between `lower` and `upper`, rounded to the 32 step
+    /// register resolution.
+    pub fn from_sg_band(lower: u16, upper: u16, min_velocity: Velocity) -> Self {
+        let semin = (lower / 32).clamp(1, 15) as u8;
+        let semax = (upper / 32).saturating_sub(semin as u16 + 1).min(15) as u8;
+
+        Self {
+            semin,
+            semax,
+            up: CurrentUp::Step1,
+            down: CurrentDown::Per32,
+            min_current: MinCurrent::HalfIrun,
+            min_velocity,
+        }
+    }
+
+    /// SG_RESULT below which the current goes up.
+    pub fn lower(&self) -> u16 {
+        self.semin as u16 * 32
+    }
+
+    /// SG_RESULT from which the current goes down.
+    pub fn upper(&self) -> u16 {
+        (self.semin as u16 + self.semax as u16 + 1) * 32
+    }
+
+    /// COOLCONF register value.
+    pub fn coolconf(&self) -> u32 {
+        let seimin = match self.min_current {
+            MinCurrent::HalfIrun => 0,
+            MinCurrent::QuarterIrun => 1,
+        };
+
+        (self.semin as u32 & 0xF)
+            | (self.up as u32) << 5
+            | (self.semax as u32 & 0xF) << 8
+            | (self.down as u32) << 13
+            | seimin << 15
+    }
+}
+
+impl<'a, S, E> Tmc2209<'a, S>
+where
+    S: Read<u8, Error = E> + Write<u8, Error = E>,
+{
+    pub fn set_chopper_mode(
+        &mut self,
+        mode: ChopperMode,
+        mech: &Mechanics,
+    ) -> Result<(), Error<E>> {
+        let (spread_cycle, tpwmthrs) = match mode {
+            ChopperMode::StealthChop => (false, 0),
+            ChopperMode::SpreadCycle => (true, 0),
+            ChopperMode::Hybrid { switch_over } => (false, mech.tstep(switch_over)),
+        };
+
+        let mut gconf = self.gconf()?;
+        gconf.set_en_spread_cycle(spread_cycle);
+        self.write(gconf)?;
+
+        self.write_raw(Address::TPWMTHRS as u8, tpwmthrs)
+    }
+
+    /// StealthChop amplitude start value for `coil` with automatic scaling.
+    pub fn set_stealthchop_pwm(&mut self, coil: &Coil) -> Result<(), Error<E>> {
+        const PWM_OFS_MASK: u32 = 0xFF;
+        const PWM_AUTOSCALE: u32 = 1 << 18;
+
+        let pwmconf = self.read_raw(Address::PWMCONF as u8)?;
+        let pwmconf = (pwmconf & !PWM_OFS_MASK) | pwm_ofs(coil) as u32 | PWM_AUTOSCALE;
+
+        self.write_raw(Address::PWMCONF as u8, pwmconf)
+    }
+
+    /// Also sets TCOOLTHRS, the StallGuard threshold velocity.
+    pub fn set_coolstep(&mut self, coolstep: &CoolStep, mech: &Mechanics) -> Result<(), Error<E>> {
+        self.write_raw(Address::TCOOLTHRS as u8, mech.tstep(coolstep.min_velocity))?;
+        self.write_raw(Address::COOLCONF as u8, coolstep.coolconf())
+    }
+
+    pub fn disable_coolstep(&mut self) -> Result<(), Error<E>> {
+        self.write_raw(Address::COOLCONF as u8, 0)
+    }
+
+    /// Chopper off time, 0 switches the outputs off, 1 to 15 on.
+    pub fn set_toff(&mut self, toff: u8) -> Result<(), Error<E>> {
+        let mut chopconf: reg::CHOPCONF = self.read()?;
+        chopconf.set_toff(toff as u32 & 0xF);
+        self.write(chopconf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{Bus, Config, SlaveAddr};
+    use crate::mock::MockUart;
+
+    const MECH: Mechanics = Mechanics {
+        full_steps: 200,
+        microsteps: 16,
+        mm_per_rev: 40.,
+    };
+
+    #[test]
+    fn coolconf_bit_packing() {
+        // semin 3:0, seup 6:5, semax 11:8, sedn 14:13, seimin 15
+        let coolstep = CoolStep {
+            semin: 15,
+            semax: 15,
+            up: CurrentUp::Step8,
+            down: CurrentDown::Per1,
+            min_current: MinCurrent::QuarterIrun,
+            min_velocity: Velocity::Rpm(30.),
+        };
+        assert_eq!(coolstep.coolconf(), 0xEF6F);
+
+        let coolstep = CoolStep {
+            semin: 5,
+            semax: 2,
+            up: CurrentUp::Step2,
+            down: CurrentDown::Per8,
+            min_current: MinCurrent::HalfIrun,
+            ..coolstep
+        };
+        assert_eq!(coolstep.coolconf(), 0x2225);
+    }
+
+    #[test]
+    fn sg_band_rounds_to_the_register_resolution() {
+        let coolstep = CoolStep::from_sg_band(128, 384, Velocity::Rpm(30.));
+        assert_eq!((coolstep.semin, coolstep.semax), (4, 7));
+        assert_eq!((coolstep.lower(), coolstep.upper()), (128, 384));
+        assert_eq!(coolstep.coolconf(), 0x0704);
+
+        // SEMIN 0 would turn CoolStep off, a band above the register range is cut
+        let coolstep = CoolStep::from_sg_band(0, 2000, Velocity::Rpm(30.));
+        assert_eq!((coolstep.semin, coolstep.semax), (1, 15));
+    }
+
+    #[test]
+    fn pwm_ofs_from_the_coil() {
+        // 374 * 1.5 Ohm * 1 A / 24 V = 23.4
+        let coil = Coil {
+            resistance_ohm: 1.5,
+            current_ma: 1000,
+            supply_mv: 24_000,
+        };
+        assert_eq!(pwm_ofs(&coil), 23);
+
+        assert_eq!(
+            pwm_ofs(&Coil {
+                supply_mv: 0,
+                ..coil
+            }),
+            u8::MAX
+        );
+        assert_eq!(
+            pwm_ofs(&Coil {
+                resistance_ohm: 30.,
+                ..coil
+            }),
+            u8::MAX
+        );
+    }
+
+    #[test]
+    fn hybrid_mode_sets_the_switch_over_tstep() {
+        let mut mock = MockUart::new(&[0]);
+        // SpreadCycle on
+        mock.regs[0][Address::GCONF as usize] = 0x04;
+        let mut bus = Bus::new(
+            mock,
+            Config {
+                timeout_polls: 10,
+                ..Default::default()
+            },
+        );
+        let mode = ChopperMode::Hybrid {
+            switch_over: Velocity::Rpm(60.),
+        };
+        bus.driver(SlaveAddr::ALL[0])
+            .set_chopper_mode(mode, &MECH)
+            .unwrap();
+
+        let regs = bus.free().regs[0];
+        assert_eq!(regs[Address::GCONF as usize], 0);
+        assert_eq!(regs[Address::TPWMTHRS as usize], 234);
+    }
+}
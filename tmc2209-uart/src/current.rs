@@ -0,0 +1,399 @@
+//! Motor run and hold current, datasheet chapter 9 Selecting Sense Resistors.
+//!
+//! The RMS current for a current scale `CS` (IRUN/IHOLD) is
+//!
+//! ```text
+//! I_rms = (CS + 1) / 32 * V_fs / (R_sense + 20 mOhm) / sqrt(2)
+//! ```
+//!
+//! After `standstill_ms` without steps the driver reduces the current from
+//! IRUN to IHOLD, one step every IHOLDDELAY * 2^18 clocks.
+
+use embedded_hal::serial::{Read, Write};
+use tmc2209::reg::{self, Address};
+
+use crate::bus::Error;
+use crate::driver::Tmc2209;
+use crate::units::FCLK_HZ;
+
+/// Resistance of the internal sense path added to the sense resistor.
+const RSENSE_INTERNAL_MOHM: f32 = 20.0;
+
+/// Clocks of one TPOWERDOWN/IHOLDDELAY unit.
+const DELAY_UNIT_CLOCKS: f32 = (1 << 18) as f32;
+
+const CS_MAX: u8 = 31;
+
+/// Full scale voltage of the sense resistor comparators, CHOPCONF.vsense.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Vsense {
+    /// 325 mV, vsense = 0, for high currents.
+    High,
+    /// 180 mV, vsense = 1, better resolution for low currents.
+    Low,
+}
+
+impl Vsense {
+    pub fn full_scale_mv(self) -> f32 {
+        match self {
+            Vsense::High => 325.0,
+            Vsense::Low => 180.0,
+        }
+    }
+
+    /// Highest RMS current with `rsense_mohm`.
+    pub fn max_current_ma(self, rsense_mohm: u16) -> u16 {
+        current_ma(CS_MAX, self, rsense_mohm)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CurrentConfig {
+    /// RMS current while moving.
+    pub run_ma: u16,
+    /// RMS current at standstill, 0 selects the lowest scale IHOLD 0, still
+    /// 1/32 of the full scale. The motor only freewheels with
+    /// PWMCONF.freewheel set.
+    pub hold_ma: u16,
+    pub rsense_mohm: u16,
+    /// `None` picks [`Vsense::Low`] when the run current fits, else [`Vsense::High`].
+    pub vsense: Option<Vsense>,
+    /// Standstill time before the current goes down to `hold_ma`.
+    pub standstill_ms: u32,
+    /// Duration of the ramp from `run_ma` down to `hold_ma`, 0 switches at once.
+    pub power_down_ms: u32,
+}
+
+impl CurrentConfig {
+    /// Run and hold current times `scale`, e.g. to derate a hot driver. A
+    /// run current below the lowest step is raised to it, a hold current
+    /// below it becomes 0, the lowest scale, so the result stays valid.
+    pub fn scaled(&self, scale: f32) -> Self {
+        let mut scaled = Self {
+            run_ma: (self.run_ma as f32 * scale) as u16,
+            hold_ma: (self.hold_ma as f32 * scale) as u16,
+            ..*self
+        };
+
+        let min_ma = current_ma(0, scaled.vsense(), self.rsense_mohm);
+        scaled.run_ma = scaled.run_ma.max(min_ma);
+        if scaled.hold_ma < min_ma {
+            scaled.hold_ma = 0;
+        }
+
+        scaled
+    }
+
+    fn vsense(&self) -> Vsense {
+        self.vsense.unwrap_or(
+            if self.run_ma <= Vsense::Low.max_current_ma(self.rsense_mohm) {
+                Vsense::Low
+            } else {
+                Vsense::High
+            },
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CurrentError {
+    RunTooHigh {
+        max_ma: u16,
+    },
+    RunTooLow {
+        min_ma: u16,
+    },
+    /// A hold current below the lowest step, use 0 for the lowest scale.
+    HoldTooLow {
+        min_ma: u16,
+    },
+    HoldAboveRun,
+    StandstillTooLong {
+        max_ms: u32,
+    },
+    RsenseZero,
+}
+
+/// Register values for a [`CurrentConfig`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Current {
+    pub irun: u8,
+    pub ihold: u8,
+    pub ihold_delay: u8,
+    pub tpowerdown: u8,
+    pub vsense: Vsense,
+}
+
+/// RMS current of current scale `cs`.
+pub fn current_ma(cs: u8, vsense: Vsense, rsense_mohm: u16) -> u16 {
+    let r_mohm = rsense_mohm as f32 + RSENSE_INTERNAL_MOHM;
+    let i_ma = (cs as f32 + 1.0) / 32.0 * vsense.full_scale_mv() / r_mohm * 1000.0
+        / core::f32::consts::SQRT_2;
+
+    (i_ma + 0.5) as u16
+}
+
+/// Current scale for `i_ma`, may be out of the 0-31 range.
+fn current_scale(i_ma: u16, vsense: Vsense, rsense_mohm: u16) -> i32 {
+    let r_mohm = rsense_mohm as f32 + RSENSE_INTERNAL_MOHM;
+    let cs = 32.0 * i_ma as f32 / 1000.0 * core::f32::consts::SQRT_2 * r_mohm
+        / vsense.full_scale_mv()
+        - 1.0;
+
+    if cs < 0.0 {
+        (cs - 0.5) as i32
+    } else {
+        (cs + 0.5) as i32
+    }
+}
+
+fn delay_units(ms: u32) -> f32 {
+    ms as f32 / 1000.0 * FCLK_HZ / DELAY_UNIT_CLOCKS
+}
+
+impl Current {
+    pub fn new(cfg: &CurrentConfig) -> Result<Self, CurrentError> {
+        if cfg.rsense_mohm == 0 {
+            return Err(CurrentError::RsenseZero);
+        }
+        if cfg.hold_ma > cfg.run_ma {
+            return Err(CurrentError::HoldAboveRun);
+        }
+
+        let vsense = cfg.vsense();
+
+        let irun = current_scale(cfg.run_ma, vsense, cfg.rsense_mohm);
+        if irun > CS_MAX as i32 {
+            return Err(CurrentError::RunTooHigh {
+                max_ma: vsense.max_current_ma(cfg.rsense_mohm),
+            });
+        }
+        if irun < 0 {
+            return Err(CurrentError::RunTooLow {
+                min_ma: current_ma(0, vsense, cfg.rsense_mohm),
+            });
+        }
+        let irun = irun as u8;
+
+        let ihold = if cfg.hold_ma == 0 {
+            0
+        } else {
+            let ihold = current_scale(cfg.hold_ma, vsense, cfg.rsense_mohm);
+            if ihold < 0 {
+                return Err(CurrentError::HoldTooLow {
+                    min_ma: current_ma(0, vsense, cfg.rsense_mohm),
+                });
+            }
+            ihold.min(irun as i32) as u8
+        };
+
+        let tpowerdown = delay_units(cfg.standstill_ms) + 0.5;
+        if tpowerdown >= 256.0 {
+            return Err(CurrentError::StandstillTooLong {
+                max_ms: (255.0 * DELAY_UNIT_CLOCKS / FCLK_HZ * 1000.0) as u32,
+            });
+        }
+
+        // the ramp goes one current scale step per IHOLDDELAY
+        let ramp_steps = (irun - ihold).max(1) as f32;
+        let ihold_delay = if cfg.power_down_ms == 0 {
+            0
+        } else {
+            (delay_units(cfg.power_down_ms) / ramp_steps + 0.5).clamp(1.0, 15.0) as u8
+        };
+
+        Ok(Self {
+            irun,
+            ihold,
+            ihold_delay,
+            tpowerdown: tpowerdown as u8,
+            vsense,
+        })
+    }
+
+    /// IHOLD_IRUN register value.
+    pub fn ihold_irun(&self) -> u32 {
+        (self.ihold as u32 & 0x1F)
+            | (self.irun as u32 & 0x1F) << 8
+            | (self.ihold_delay as u32 & 0xF) << 16
+    }
+
+    pub fn run_ma(&self, rsense_mohm: u16) -> u16 {
+        current_ma(self.irun, self.vsense, rsense_mohm)
+    }
+
+    pub fn hold_ma(&self, rsense_mohm: u16) -> u16 {
+        current_ma(self.ihold, self.vsense, rsense_mohm)
+    }
+}
+
+impl<'a, S, E> Tmc2209<'a, S>
+where
+    S: Read<u8, Error = E> + Write<u8, Error = E>,
+{
+    /// Scales the current from the UART registers with the external sense
+    /// resistor, VREF is ignored.
+    pub fn set_current(&mut self, current: &Current) -> Result<(), Error<E>> {
+        let mut gconf = self.gconf()?;
+        gconf.set_i_scale_analog(false);
+        gconf.set_internal_rsense(false);
+        self.write(gconf)?;
+
+        let mut chopconf: reg::CHOPCONF = self.read()?;
+        chopconf.set_vsense(current.vsense == Vsense::Low);
+        self.write(chopconf)?;
+
+        self.write_raw(Address::TPOWERDOWN as u8, current.tpowerdown as u32)?;
+        self.write_raw(Address::IHOLD_IRUN as u8, current.ihold_irun())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: CurrentConfig = CurrentConfig {
+        run_ma: 800,
+        hold_ma: 400,
+        rsense_mohm: 110,
+        vsense: None,
+        standstill_ms: 500,
+        power_down_ms: 200,
+    };
+
+    #[test]
+    fn current_of_a_scale() {
+        // 32 / 32 * 325 mV / 130 mOhm / sqrt(2)
+        assert_eq!(current_ma(31, Vsense::High, 110), 1768);
+        assert_eq!(current_ma(31, Vsense::Low, 110), 979);
+        assert_eq!(current_ma(0, Vsense::Low, 110), 31);
+    }
+
+    #[test]
+    fn picks_the_low_vsense_when_the_run_current_fits() {
+        let current = Current::new(&CONFIG).unwrap();
+        assert_eq!(current.vsense, Vsense::Low);
+        assert_eq!((current.irun, current.ihold), (25, 12));
+        assert!(current.run_ma(110).abs_diff(800) < 31);
+        assert!(current.hold_ma(110).abs_diff(400) < 31);
+
+        let current = Current::new(&CurrentConfig {
+            run_ma: 1500,
+            ..CONFIG
+        })
+        .unwrap();
+        assert_eq!(current.vsense, Vsense::High);
+        assert_eq!(current.irun, 26);
+    }
+
+    #[test]
+    fn standstill_timing() {
+        let current = Current::new(&CONFIG).unwrap();
+        // 500 ms at 12 MHz in units of 2^18 clocks
+        assert_eq!(current.tpowerdown, 23);
+        // 200 ms over the 13 steps from IRUN down to IHOLD
+        assert_eq!(current.ihold_delay, 1);
+        assert_eq!(current.ihold_irun(), 0x0001_190C);
+
+        let current = Current::new(&CurrentConfig {
+            power_down_ms: 0,
+            ..CONFIG
+        })
+        .unwrap();
+        assert_eq!(current.ihold_delay, 0);
+    }
+
+    #[test]
+    fn hold_current_zero_is_the_lowest_scale() {
+        let current = Current::new(&CurrentConfig {
+            hold_ma: 0,
+            ..CONFIG
+        })
+        .unwrap();
+
+        assert_eq!(current.ihold, 0);
+        assert_eq!(current.hold_ma(110), 31);
+    }
+
+    #[test]
+    fn out_of_range_currents_are_refused() {
+        let refused = |cfg| Current::new(&cfg).unwrap_err();
+
+        assert_eq!(
+            refused(CurrentConfig {
+                run_ma: 3000,
+                ..CONFIG
+            }),
+            CurrentError::RunTooHigh { max_ma: 1768 }
+        );
+        assert_eq!(
+            refused(CurrentConfig {
+                run_ma: 10,
+                hold_ma: 0,
+                ..CONFIG
+            }),
+            CurrentError::RunTooLow { min_ma: 31 }
+        );
+        // not rounded down to IHOLD 0 without a word
+        assert_eq!(
+            refused(CurrentConfig {
+                hold_ma: 10,
+                ..CONFIG
+            }),
+            CurrentError::HoldTooLow { min_ma: 31 }
+        );
+        assert_eq!(
+            refused(CurrentConfig {
+                hold_ma: 900,
+                ..CONFIG
+            }),
+            CurrentError::HoldAboveRun
+        );
+        assert_eq!(
+            refused(CurrentConfig {
+                rsense_mohm: 0,
+                ..CONFIG
+            }),
+            CurrentError::RsenseZero
+        );
+        assert!(matches!(
+            refused(CurrentConfig {
+                standstill_ms: 6000,
+                ..CONFIG
+            }),
+            CurrentError::StandstillTooLong { .. }
+        ));
+    }
+    #[test]
+    fn derated_currents_stay_valid() {
+        let config = CurrentConfig {
+            hold_ma: 80,
+            ..CONFIG
+        };
+
+        // the hold current drops below the lowest step, IHOLD 0 instead
+        let derated = config.scaled(0.3);
+        assert_eq!((derated.run_ma, derated.hold_ma), (240, 0));
+        assert!(Current::new(&derated).is_ok());
+
+        // the run current stays at the lowest step
+        let derated = config.scaled(0.01);
+        assert_eq!((derated.run_ma, derated.hold_ma), (31, 0));
+
+        assert_eq!(config.scaled(1.0), config);
+
+        for config in [
+            config,
+            CONFIG,
+            CurrentConfig {
+                run_ma: 1700,
+                ..CONFIG
+            },
+        ] {
+            for percent in 0..=100 {
+                let derated = config.scaled(percent as f32 / 100.0);
+                assert!(Current::new(&derated).is_ok(), "{:?}", derated);
+            }
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! TMC2209 UART datagrams, see the datasheet chapter 4.1 UART Interface.
+//!
+//! ```text
+//! read request   sync | slave | reg        | crc
+//! write request  sync | slave | reg | 0x80 | data[31:24] .. data[7:0] | crc
+//! read reply     sync | 0xFF  | reg        | data[31:24] .. data[7:0] | crc
+//! ```
+
+/// Sync nibble plus reserved bits, starts every datagram.
+pub const SYNC: u8 = 0x05;
+
+/// Address the driver uses for its replies.
+pub const MASTER_ADDR: u8 = 0xFF;
+
+pub const WRITE_FLAG: u8 = 0x80;
+
+pub const READ_REQUEST_LEN: usize = 4;
+pub const WRITE_REQUEST_LEN: usize = 8;
+pub const REPLY_LEN: usize = 8;
+
+/// Highest slave address selectable with the MS1/MS2 pins.
+pub const MAX_SLAVE_ADDR: u8 = 3;
+
+/// Reasons a reply datagram is rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplyError {
+    /// Wrong sync nibble or master address, the reply is out of frame.
+    Frame,
+    /// Reply for another register than the requested one.
+    Register {
+        expected: u8,
+        got: u8,
+    },
+    Crc {
+        expected: u8,
+        got: u8,
+    },
+}
+
+/// CRC8 over a datagram without its CRC byte, polynomial x^8 + x^2 + x + 1,
+/// bytes are processed LSB first.
+pub fn crc(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+
+    for &byte in data {
+        let mut byte = byte;
+        for _ in 0..8 {
+            if (crc >> 7) ^ (byte & 0x01) != 0 {
+                crc = (crc << 1) ^ 0x07;
+            } else {
+                crc <<= 1;
+            }
+            byte >>= 1;
+        }
+    }
+
+    crc
+}
+
+pub fn read_request(slave: u8, reg: u8) -> [u8; READ_REQUEST_LEN] {
+    let mut req = [SYNC, slave, reg & !WRITE_FLAG, 0];
+    req[3] = crc(&req[..3]);
+    req
+}
+
+pub fn write_request(slave: u8, reg: u8, data: u32) -> [u8; WRITE_REQUEST_LEN] {
+    let d = data.to_be_bytes();
+    let mut req = [SYNC, slave, reg | WRITE_FLAG, d[0], d[1], d[2], d[3], 0];
+    req[7] = crc(&req[..7]);
+    req
+}
+
+/// Checks a reply to a read request of `reg` and returns the register data.
+pub fn parse_reply(reply: &[u8; REPLY_LEN], reg: u8) -> Result<u32, ReplyError> {
+    if reply[0] & 0x0F != SYNC || reply[1] != MASTER_ADDR {
+        return Err(ReplyError::Frame);
+    }
+
+    let expected = crc(&reply[..7]);
+    if reply[7] != expected {
+        return Err(ReplyError::Crc {
+            expected,
+            got: reply[7],
+        });
+    }
+
+    if reply[2] != reg {
+        return Err(ReplyError::Register {
+            expected: reg,
+            got: reply[2],
+        });
+    }
+
+    Ok(u32::from_be_bytes([reply[3], reply[4], reply[5], reply[6]]))
+}
@@ -0,0 +1,237 @@
+//! Register access to one TMC2209 on a [`Bus`].
+
+use embedded_hal::serial::{Read, Write};
+use tmc2209::reg::{self, ReadableRegister, WritableRegister};
+
+use crate::bus::{Bus, Error, SlaveAddr};
+use crate::shadow::Shadow;
+
+/// The TMC2209 at one slave address, borrowed from [`Bus::driver`].
+///
+/// Writes are verified with the IFCNT interface counter and kept in a
+/// [`Shadow`] of the configuration registers.
+pub struct Tmc2209<'a, S> {
+    bus: &'a mut Bus<S>,
+    addr: SlaveAddr,
+}
+
+impl<'a, S, E> Tmc2209<'a, S>
+where
+    S: Read<u8, Error = E> + Write<u8, Error = E>,
+{
+    pub(crate) fn new(bus: &'a mut Bus<S>, addr: SlaveAddr) -> Self {
+        Self { bus, addr }
+    }
+
+    pub fn addr(&self) -> SlaveAddr {
+        self.addr
+    }
+
+    /// Configuration written so far.
+    pub fn shadow(&self) -> &Shadow {
+        self.bus.shadow(self.addr)
+    }
+
+    pub fn read<R: ReadableRegister>(&mut self) -> Result<R, Error<E>> {
+        self.read_raw(R::ADDRESS as u8).map(R::from)
+    }
+
+    pub fn write<R: WritableRegister>(&mut self, reg: R) -> Result<(), Error<E>> {
+        self.write_raw(R::ADDRESS as u8, reg.into())
+    }
+
+    pub fn gconf(&mut self) -> Result<reg::GCONF, Error<E>> {
+        self.read()
+    }
+
+    /// Interface transmission counter, incremented on every successful write.
+    pub fn ifcnt(&mut self) -> Result<u8, Error<E>> {
+        self.read_raw(reg::Address::IFCNT as u8)
+            .map(|data| data as u8)
+    }
+
+    pub fn drv_status(&mut self) -> Result<reg::DRV_STATUS, Error<E>> {
+        self.read()
+    }
+
+    pub fn ioin(&mut self) -> Result<reg::IOIN, Error<E>> {
+        self.read()
+    }
+
+    /// Reads register `reg`, retries on failure.
+    pub fn read_raw(&mut self, reg: u8) -> Result<u32, Error<E>> {
+        self.bus.read_raw(self.addr, reg)
+    }
+
+    /// Writes register `reg` and checks that IFCNT advanced by one.
+    pub fn write_raw(&mut self, reg: u8, data: u32) -> Result<(), Error<E>> {
+        self.bus.write_raw(self.addr, reg, data)
+    }
+
+    /// Writes the shadowed registers back to the driver. VACTUAL goes back
+    /// as 0, a restore never starts the motor.
+    pub fn restore(&mut self) -> Result<(), Error<E>> {
+        let shadow = *self.shadow();
+
+        for (reg, data) in shadow.iter() {
+            let data = if reg == reg::Address::VACTUAL as u8 {
+                0
+            } else {
+                data
+            };
+            self.write_raw(reg, data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks the GSTAT reset flag, after a driver reset clears the flag and
+    /// restores the shadowed registers. Returns true if the driver was reset.
+    pub fn restore_after_reset(&mut self) -> Result<bool, Error<E>> {
+        let gstat: reg::GSTAT = self.read()?;
+        if !gstat.reset() {
+            return Ok(false);
+        }
+
+        // flags are cleared by writing 1
+        self.write(gstat)?;
+        self.restore()?;
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Config;
+    use crate::mock::MockUart;
+
+    const CONFIG: Config = Config {
+        single_wire: true,
+        timeout_polls: 10,
+        retries: 2,
+    };
+
+    #[test]
+    fn typed_reads() {
+        let mut mock = MockUart::new(&[0]);
+        mock.regs[0][reg::Address::DRV_STATUS as usize] = 0x8000_0003;
+        mock.regs[0][reg::Address::IFCNT as usize] = 17;
+        let mut bus = Bus::new(mock, CONFIG);
+        let mut driver = bus.driver(SlaveAddr::ALL[0]);
+
+        let status = driver.drv_status().unwrap();
+        assert!(status.otpw() && status.ot() && status.stst());
+        assert!(!status.s2ga());
+        assert_eq!(driver.ifcnt(), Ok(17));
+    }
+
+    #[test]
+    fn typed_write_reads_back() {
+        let mut bus = Bus::new(MockUart::new(&[0]), CONFIG);
+        let mut driver = bus.driver(SlaveAddr::ALL[0]);
+
+        let mut gconf = reg::GCONF::default();
+        gconf.set_pdn_disable(true);
+        gconf.set_mstep_reg_select(true);
+        driver.write(gconf).unwrap();
+
+        assert_eq!(driver.gconf(), Ok(gconf));
+        assert_eq!(driver.ifcnt(), Ok(1));
+        assert_eq!(driver.shadow().get::<reg::GCONF>(), Some(gconf));
+    }
+
+    #[test]
+    fn absent_driver_times_out() {
+        let mut bus = Bus::new(MockUart::new(&[1]), CONFIG);
+
+        assert_eq!(bus.driver(SlaveAddr::ALL[0]).ifcnt(), Err(Error::Timeout));
+        assert_eq!(bus.free().reads, 1 + CONFIG.retries as u32);
+    }
+
+    #[test]
+    fn dropped_write_is_retried() {
+        let mut mock = MockUart::new(&[0]);
+        mock.drop_writes = 1;
+        let mut bus = Bus::new(mock, CONFIG);
+        let mut driver = bus.driver(SlaveAddr::ALL[0]);
+
+        driver
+            .write_raw(reg::Address::TPOWERDOWN as u8, 20)
+            .unwrap();
+        assert_eq!(driver.ifcnt(), Ok(1));
+        assert_eq!(
+            driver.shadow().raw(reg::Address::TPOWERDOWN as u8),
+            Some(20)
+        );
+    }
+
+    #[test]
+    fn write_the_driver_never_counts_fails() {
+        let mut mock = MockUart::new(&[0]);
+        mock.regs[0][reg::Address::IFCNT as usize] = 255;
+        mock.drop_writes = 1 + CONFIG.retries as u32;
+        let mut bus = Bus::new(mock, CONFIG);
+        let mut driver = bus.driver(SlaveAddr::ALL[0]);
+
+        assert_eq!(
+            driver.write_raw(reg::Address::TPOWERDOWN as u8, 20),
+            Err(Error::NotCounted {
+                before: 255,
+                after: 255
+            })
+        );
+        // the shadow holds what the driver really has
+        assert_eq!(driver.shadow().raw(reg::Address::TPOWERDOWN as u8), None);
+
+        // IFCNT wraps from 255 to 0 on the next good write
+        driver
+            .write_raw(reg::Address::TPOWERDOWN as u8, 20)
+            .unwrap();
+        assert_eq!(driver.ifcnt(), Ok(0));
+    }
+
+    #[test]
+    fn restores_the_shadow_after_a_reset() {
+        // a driver fresh out of reset flags it in GSTAT
+        let mut mock = MockUart::new(&[0]);
+        mock.regs[0][reg::Address::GSTAT as usize] = 1;
+        let mut bus = Bus::new(mock, CONFIG);
+        let mut driver = bus.driver(SlaveAddr::ALL[0]);
+
+        driver.write_raw(reg::Address::GCONF as u8, 0x1C0).unwrap();
+        driver
+            .write_raw(reg::Address::CHOPCONF as u8, 0x1000_0053)
+            .unwrap();
+        assert_eq!(driver.ifcnt(), Ok(2));
+
+        // GSTAT is cleared and both shadowed registers are written again
+        assert_eq!(driver.restore_after_reset(), Ok(true));
+        assert_eq!(driver.ifcnt(), Ok(5));
+        assert!(!driver.read::<reg::GSTAT>().unwrap().reset());
+        assert_eq!(driver.restore_after_reset(), Ok(false));
+
+        let mock = bus.free();
+        assert_eq!(mock.regs[0][reg::Address::GCONF as usize], 0x1C0);
+        assert_eq!(mock.regs[0][reg::Address::CHOPCONF as usize], 0x1000_0053);
+    }
+
+    #[test]
+    fn a_reset_driver_comes_back_standing() {
+        let mut mock = MockUart::new(&[0]);
+        mock.regs[0][reg::Address::GSTAT as usize] = 1;
+        let mut bus = Bus::new(mock, CONFIG);
+        let mut driver = bus.driver(SlaveAddr::ALL[0]);
+
+        driver.write_raw(reg::Address::GCONF as u8, 0x1C0).unwrap();
+        driver.write_raw(reg::Address::VACTUAL as u8, 5000).unwrap();
+
+        assert_eq!(driver.restore_after_reset(), Ok(true));
+        assert_eq!(driver.shadow().raw(reg::Address::VACTUAL as u8), Some(0));
+
+        let mock = bus.free();
+        assert_eq!(mock.regs[0][reg::Address::GCONF as usize], 0x1C0);
+        assert_eq!(mock.regs[0][reg::Address::VACTUAL as usize], 0);
+    }
+}
@@ -0,0 +1,151 @@
+//! Sensorless homing with StallGuard4.
+//!
+//! The motor runs towards the end stop until the driver reports a stall on
+//! DIAG. The state machine only counts, the firmware feeds it from the step
+//! timer and DIAG interrupts and stops the step timer when told to.
+
+use crate::stallguard::StallGuard;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HomingConfig {
+    pub stallguard: StallGuard,
+    /// Steps after start that ignore stalls, StallGuard is not reliable while
+    /// the motor accelerates.
+    pub blank_steps: u32,
+    /// Give up when no stall is seen within this many steps.
+    pub max_steps: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HomingState {
+    Idle,
+    Running {
+        steps: u32,
+    },
+    /// Stall seen `steps` steps after the start, this is the home position.
+    Homed {
+        steps: u32,
+    },
+    /// No stall within `max_steps`.
+    Failed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Homing {
+    config: HomingConfig,
+    state: HomingState,
+}
+
+impl Homing {
+    pub fn new(config: HomingConfig) -> Self {
+        Self {
+            config,
+            state: HomingState::Idle,
+        }
+    }
+
+    pub fn config(&self) -> &HomingConfig {
+        &self.config
+    }
+
+    pub fn state(&self) -> HomingState {
+        self.state
+    }
+
+    pub fn is_running(&self) -> bool {
+        matches!(self.state, HomingState::Running { .. })
+    }
+
+    pub fn start(&mut self) {
+        self.state = HomingState::Running { steps: 0 };
+    }
+
+    pub fn abort(&mut self) {
+        self.state = HomingState::Idle;
+    }
+
+    /// Called per emitted step, returns false when the step timer must stop.
+    pub fn on_step(&mut self) -> bool {
+        if let HomingState::Running { steps } = self.state {
+            let steps = steps + 1;
+            if steps >= self.config.max_steps {
+                self.state = HomingState::Failed;
+                return false;
+            }
+            self.state = HomingState::Running { steps };
+            return true;
+        }
+
+        false
+    }
+
+    /// Called on a DIAG stall, returns true when the stall is the end stop and
+    /// the step timer must stop.
+    pub fn on_stall(&mut self) -> bool {
+        match self.state {
+            HomingState::Running { steps } if steps >= self.config.blank_steps => {
+                self.state = HomingState::Homed { steps };
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn homing() -> Homing {
+        Homing::new(HomingConfig {
+            stallguard: StallGuard::new(100, 500., 16),
+            blank_steps: 2,
+            max_steps: 5,
+        })
+    }
+
+    #[test]
+    fn stall_after_the_blank_steps_is_home() {
+        let mut homing = homing();
+        assert!(!homing.on_step());
+        assert!(!homing.on_stall());
+
+        homing.start();
+        assert!(homing.on_step());
+        // still accelerating
+        assert!(!homing.on_stall());
+        assert!(homing.is_running());
+
+        assert!(homing.on_step());
+        assert!(homing.on_stall());
+        assert_eq!(homing.state(), HomingState::Homed { steps: 2 });
+
+        // a late stall report changes nothing
+        assert!(!homing.on_stall());
+        assert!(!homing.on_step());
+    }
+
+    #[test]
+    fn gives_up_after_max_steps() {
+        let mut homing = homing();
+        homing.start();
+
+        for _ in 0..4 {
+            assert!(homing.on_step());
+        }
+        assert!(!homing.on_step());
+        assert_eq!(homing.state(), HomingState::Failed);
+        assert!(!homing.on_stall());
+    }
+
+    #[test]
+    fn abort_returns_to_idle() {
+        let mut homing = homing();
+        homing.start();
+        homing.on_step();
+
+        homing.abort();
+        assert_eq!(homing.state(), HomingState::Idle);
+        assert!(!homing.on_step());
+    }
+}
@@ -0,0 +1,34 @@
+//! TMC2209 driver over its UART interface.
+//!
+//! Register types come from the [`tmc2209`] crate, this crate adds:
+//!
+//! - the transport: request framing, the single wire echo, reply CRC checks
+//!   and retries
+//! - writes verified with the IFCNT counter and a [`Shadow`] of the write only
+//!   registers
+//! - a [`Bus`] shared by up to four drivers, each at its own [`SlaveAddr`]
+//! - StallGuard4 based sensorless [`homing`]
+//! - [`chopper`] mode and CoolStep setup with speeds in physical [`units`]
+//! - run and hold [`current`] in mA
+//!
+//! It is generic over the `embedded-hal` serial traits, so it runs on
+//! `hal::usart::Usart` as well as on a host side mock.
+
+#![no_std]
+
+mod bus;
+pub mod chopper;
+pub mod current;
+pub mod datagram;
+mod driver;
+pub mod homing;
+#[cfg(test)]
+mod mock;
+pub mod shadow;
+pub mod stallguard;
+pub mod units;
+
+pub use bus::{Bus, Config, Error, Present, SlaveAddr};
+pub use driver::Tmc2209;
+pub use shadow::Shadow;
+pub use tmc2209::reg;
@@ -0,0 +1,155 @@
+//! Simulated TMC2209s on a single wire UART for the host tests.
+
+extern crate std;
+
+use std::collections::VecDeque;
+use std::vec::Vec;
+
+use embedded_hal::serial::{Read, Write};
+
+use crate::datagram::{self, MASTER_ADDR, READ_REQUEST_LEN, SYNC, WRITE_FLAG, WRITE_REQUEST_LEN};
+
+const GSTAT: usize = 0x01;
+const IFCNT: usize = 0x02;
+
+/// Register files of the drivers at slave addresses 0-3 on one wire.
+///
+/// Every sent byte comes back as its echo, a complete request is answered
+/// right after it, a write bumps IFCNT as the driver does.
+pub struct MockUart {
+    pub regs: [[u32; 128]; 4],
+    /// Slave addresses that answer.
+    pub present: [bool; 4],
+    /// The next replies go out with a broken CRC.
+    pub bad_crc: u32,
+    /// The next read requests get no reply.
+    pub silent: u32,
+    /// The next echoed bytes come back flipped, as if another node talked.
+    pub bad_echo: u32,
+    /// The next writes are lost, IFCNT stays as it is.
+    pub drop_writes: u32,
+    /// Read requests seen, retries included.
+    pub reads: u32,
+    request: Vec<u8>,
+    rx: VecDeque<u8>,
+}
+
+impl MockUart {
+    /// A driver at every address in `present`, all registers 0.
+    pub fn new(present: &[u8]) -> Self {
+        let mut mock = Self {
+            regs: [[0; 128]; 4],
+            present: [false; 4],
+            bad_crc: 0,
+            silent: 0,
+            bad_echo: 0,
+            drop_writes: 0,
+            reads: 0,
+            request: Vec::new(),
+            rx: VecDeque::new(),
+        };
+        for &addr in present {
+            mock.present[addr as usize] = true;
+        }
+
+        mock
+    }
+
+    /// Bytes left on the wire, e.g. from an aborted transfer.
+    pub fn inject(&mut self, bytes: &[u8]) {
+        self.rx.extend(bytes);
+    }
+
+    /// Bytes sent but not read back yet.
+    pub fn pending(&self) -> usize {
+        self.rx.len()
+    }
+
+    fn on_request(&mut self) {
+        let request = core::mem::take(&mut self.request);
+        let (slave, reg) = (request[1] as usize, request[2] & !WRITE_FLAG);
+        let last = request.len() - 1;
+
+        if request[last] != datagram::crc(&request[..last]) {
+            return;
+        }
+        if request.len() == READ_REQUEST_LEN {
+            self.reads += 1;
+        }
+        if !self.present[slave] {
+            return;
+        }
+
+        if request.len() == READ_REQUEST_LEN {
+            if self.silent > 0 {
+                self.silent -= 1;
+                return;
+            }
+
+            let data = self.regs[slave][reg as usize].to_be_bytes();
+            let mut reply = [
+                SYNC,
+                MASTER_ADDR,
+                reg,
+                data[0],
+                data[1],
+                data[2],
+                data[3],
+                0,
+            ];
+            reply[7] = datagram::crc(&reply[..7]);
+            if self.bad_crc > 0 {
+                self.bad_crc -= 1;
+                reply[7] ^= 0xFF;
+            }
+            self.rx.extend(reply);
+        } else if self.drop_writes > 0 {
+            self.drop_writes -= 1;
+        } else {
+            let data = u32::from_be_bytes([request[3], request[4], request[5], request[6]]);
+            let regs = &mut self.regs[slave];
+            match reg as usize {
+                // flags are cleared by writing 1
+                GSTAT => regs[GSTAT] &= !data,
+                reg => regs[reg] = data,
+            }
+            regs[IFCNT] = (regs[IFCNT] + 1) & 0xFF;
+        }
+    }
+}
+
+impl Read<u8> for MockUart {
+    type Error = ();
+
+    fn read(&mut self) -> nb::Result<u8, ()> {
+        self.rx.pop_front().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+impl Write<u8> for MockUart {
+    type Error = ();
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), ()> {
+        if self.bad_echo > 0 {
+            self.bad_echo -= 1;
+            self.rx.push_back(!byte);
+        } else {
+            self.rx.push_back(byte);
+        }
+
+        self.request.push(byte);
+        let len = match self.request.get(2) {
+            Some(reg) if reg & WRITE_FLAG != 0 => WRITE_REQUEST_LEN,
+            _ => READ_REQUEST_LEN,
+        };
+        if self.request.len() == len {
+            self.on_request();
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), ()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+//! Shadow copy of the configuration registers written to a TMC2209.
+//!
+//! Most of the configuration registers are write only, the shadow is the only
+//! place the firmware can learn the configured state from, e.g. to write it
+//! back after the driver lost it on a reset.
+
+use tmc2209::reg::{Address, Register};
+
+/// Registers kept in the shadow.
+pub const SHADOWED: [Address; 11] = [
+    Address::GCONF,
+    Address::SLAVECONF,
+    Address::IHOLD_IRUN,
+    Address::TPOWERDOWN,
+    Address::TPWMTHRS,
+    Address::TCOOLTHRS,
+    Address::VACTUAL,
+    Address::SGTHRS,
+    Address::COOLCONF,
+    Address::CHOPCONF,
+    Address::PWMCONF,
+];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Shadow {
+    values: [u32; SHADOWED.len()],
+    /// Bit N is set once `values[N]` was written.
+    written: u16,
+}
+
+impl Shadow {
+    fn slot(addr: u8) -> Option<usize> {
+        SHADOWED.iter().position(|&a| a as u8 == addr)
+    }
+
+    /// Remembers a value written to register `addr`, other registers are ignored.
+    pub fn record(&mut self, addr: u8, data: u32) {
+        if let Some(slot) = Self::slot(addr) {
+            self.values[slot] = data;
+            self.written |= 1 << slot;
+        }
+    }
+
+    /// Last value written to register `addr`.
+    pub fn raw(&self, addr: u8) -> Option<u32> {
+        Self::slot(addr)
+            .filter(|&slot| self.written & (1 << slot) != 0)
+            .map(|slot| self.values[slot])
+    }
+
+    pub fn get<R: Register + From<u32>>(&self) -> Option<R> {
+        self.raw(R::ADDRESS as u8).map(R::from)
+    }
+
+    /// Written registers as `(address, value)` in the order of [`SHADOWED`].
+    pub fn iter(&self) -> impl Iterator<Item = (u8, u32)> + '_ {
+        SHADOWED
+            .iter()
+            .enumerate()
+            .filter(|&(slot, _)| self.written & (1 << slot) != 0)
+            .map(|(slot, &addr)| (addr as u8, self.values[slot]))
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tmc2209::reg::IHOLD_IRUN;
+
+    #[test]
+    fn records_only_the_shadowed_registers() {
+        let mut shadow = Shadow::default();
+
+        shadow.record(Address::GCONF as u8, 0x1C0);
+        shadow.record(Address::IFCNT as u8, 5);
+        shadow.record(Address::DRV_STATUS as u8, 1);
+
+        assert_eq!(shadow.raw(Address::GCONF as u8), Some(0x1C0));
+        assert_eq!(shadow.raw(Address::IFCNT as u8), None);
+        assert_eq!(shadow.raw(Address::DRV_STATUS as u8), None);
+        assert_eq!(shadow.raw(Address::CHOPCONF as u8), None);
+    }
+
+    #[test]
+    fn keeps_the_last_value_in_register_order() {
+        let mut shadow = Shadow::default();
+
+        let mut current = IHOLD_IRUN::default();
+        current.set_irun(20);
+        current.set_ihold(10);
+        shadow.record(Address::PWMCONF as u8, 1);
+        shadow.record(Address::IHOLD_IRUN as u8, 0);
+        shadow.record(Address::IHOLD_IRUN as u8, current.into());
+        shadow.record(Address::GCONF as u8, 2);
+
+        assert_eq!(shadow.get::<IHOLD_IRUN>(), Some(current));
+        assert!(shadow.iter().eq([
+            (Address::GCONF as u8, 2),
+            (Address::IHOLD_IRUN as u8, current.into()),
+            (Address::PWMCONF as u8, 1),
+        ]));
+
+        shadow.clear();
+        assert_eq!(shadow.iter().count(), 0);
+    }
+
+    #[test]
+    fn every_shadowed_register_has_a_slot() {
+        let mut shadow = Shadow::default();
+        for (i, &addr) in SHADOWED.iter().enumerate() {
+            shadow.record(addr as u8, i as u32);
+        }
+
+        assert!(shadow
+            .iter()
+            .map(|(_, data)| data)
+            .eq(0..SHADOWED.len() as u32));
+    }
+}
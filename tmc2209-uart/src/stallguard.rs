@@ -0,0 +1,178 @@
+//! StallGuard4 load measurement and stall detection.
+//!
+//! StallGuard4 works in StealthChop mode only. The driver compares SG_RESULT
+//! with SGTHRS and pulses DIAG when `SG_RESULT <= 2 * SGTHRS` while the motor
+//! runs faster than TCOOLTHRS (`TSTEP <= TCOOLTHRS`).
+
+use embedded_hal::serial::{Read, Write};
+use tmc2209::reg::{self, Address};
+
+use crate::bus::Error;
+use crate::driver::Tmc2209;
+use crate::units;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StallGuard {
+    /// Stall threshold, higher is more sensitive.
+    pub sgthrs: u8,
+    /// Lowest velocity, as a TSTEP value, stall detection is active at.
+    pub tcoolthrs: u32,
+}
+
+impl StallGuard {
+    /// Detection active from `min_step_hz` up.
+    pub fn new(sgthrs: u8, min_step_hz: f32, microsteps: u16) -> Self {
+        Self {
+            sgthrs,
+            tcoolthrs: units::tstep(min_step_hz, microsteps),
+        }
+    }
+}
+
+/// Collects SG_RESULT while the motor runs unloaded at the homing speed and
+/// suggests a SGTHRS just below the unloaded load value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tuner {
+    min: u16,
+    max: u16,
+    sum: u32,
+    count: u32,
+}
+
+impl Default for Tuner {
+    fn default() -> Self {
+        Self {
+            min: u16::MAX,
+            max: 0,
+            sum: 0,
+            count: 0,
+        }
+    }
+}
+
+impl Tuner {
+    pub fn add(&mut self, sg_result: u16) {
+        self.min = self.min.min(sg_result);
+        self.max = self.max.max(sg_result);
+        self.sum += sg_result as u32;
+        self.count += 1;
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn min(&self) -> Option<u16> {
+        (self.count > 0).then_some(self.min)
+    }
+
+    pub fn max(&self) -> Option<u16> {
+        (self.count > 0).then_some(self.max)
+    }
+
+    pub fn mean(&self) -> Option<u16> {
+        (self.count > 0).then(|| (self.sum / self.count) as u16)
+    }
+
+    /// SGTHRS that triggers at `margin_percent` below the lowest unloaded
+    /// SG_RESULT, `None` without samples.
+    pub fn sgthrs(&self, margin_percent: u8) -> Option<u8> {
+        let min = self.min()? as u32;
+        let margin = margin_percent.min(100) as u32;
+        let trigger = min * (100 - margin) / 100;
+
+        Some((trigger / 2).min(u8::MAX as u32) as u8)
+    }
+}
+
+impl<'a, S, E> Tmc2209<'a, S>
+where
+    S: Read<u8, Error = E> + Write<u8, Error = E>,
+{
+    /// Switches to StealthChop and sets up stall detection on DIAG.
+    pub fn enable_stallguard(&mut self, sg: StallGuard) -> Result<(), Error<E>> {
+        let mut gconf = self.gconf()?;
+        gconf.set_en_spread_cycle(false);
+        self.write(gconf)?;
+
+        self.write_raw(Address::TCOOLTHRS as u8, sg.tcoolthrs)?;
+        self.write_raw(Address::SGTHRS as u8, sg.sgthrs as u32)
+    }
+
+    /// Stall detection off, DIAG stays low.
+    pub fn disable_stallguard(&mut self) -> Result<(), Error<E>> {
+        self.write_raw(Address::SGTHRS as u8, 0)?;
+        self.write_raw(Address::TCOOLTHRS as u8, 0)
+    }
+
+    /// Motor load, 0 is the highest load, 510 the lowest.
+    pub fn sg_result(&mut self) -> Result<u16, Error<E>> {
+        self.read_raw(reg::Address::SG_RESULT as u8)
+            .map(|data| (data & 0x3FF) as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{Bus, Config, SlaveAddr};
+    use crate::mock::MockUart;
+
+    #[test]
+    fn tuner_without_samples_suggests_nothing() {
+        let tuner = Tuner::default();
+
+        assert_eq!(tuner.sgthrs(30), None);
+        assert_eq!((tuner.min(), tuner.mean(), tuner.max()), (None, None, None));
+    }
+
+    #[test]
+    fn threshold_triggers_below_the_lowest_load() {
+        let mut tuner = Tuner::default();
+        for sg_result in [300, 280, 320] {
+            tuner.add(sg_result);
+        }
+
+        assert_eq!(
+            (tuner.min(), tuner.mean(), tuner.max()),
+            (Some(280), Some(300), Some(320))
+        );
+        // stall at SG_RESULT <= 2 * SGTHRS, 10 % below 280 is 252
+        assert_eq!(tuner.sgthrs(10), Some(126));
+        assert_eq!(tuner.sgthrs(0), Some(140));
+        assert_eq!(tuner.sgthrs(200), Some(0));
+    }
+
+    #[test]
+    fn threshold_is_capped_at_255() {
+        let mut tuner = Tuner::default();
+        tuner.add(510);
+
+        assert_eq!(tuner.sgthrs(0), Some(255));
+    }
+
+    #[test]
+    fn enable_switches_to_stealthchop() {
+        let mut mock = MockUart::new(&[0]);
+        // SpreadCycle on
+        mock.regs[0][Address::GCONF as usize] = 0x04;
+        let mut bus = Bus::new(
+            mock,
+            Config {
+                timeout_polls: 10,
+                ..Default::default()
+            },
+        );
+
+        let stallguard = StallGuard::new(100, 1000., 16);
+        assert_eq!(stallguard.tcoolthrs, 750);
+        bus.driver(SlaveAddr::ALL[0])
+            .enable_stallguard(stallguard)
+            .unwrap();
+
+        let regs = bus.free().regs[0];
+        assert_eq!(regs[Address::GCONF as usize], 0);
+        assert_eq!(regs[Address::TCOOLTHRS as usize], 750);
+        assert_eq!(regs[Address::SGTHRS as usize], 100);
+    }
+}
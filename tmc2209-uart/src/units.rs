@@ -0,0 +1,101 @@
+//! Conversions between physical units and TMC2209 register values.
+
+/// Internal clock of the TMC2209, TSTEP and the thresholds count its periods.
+pub const FCLK_HZ: f32 = 12_000_000.0;
+
+/// TSTEP register value at `step_hz` steps per second with `microsteps`
+/// microsteps per full step, TSTEP measures the time between 1/256 microsteps.
+pub fn tstep(step_hz: f32, microsteps: u16) -> u32 {
+    if step_hz <= 0.0 {
+        return TSTEP_MAX;
+    }
+
+    let tstep = FCLK_HZ * microsteps as f32 / (256.0 * step_hz);
+    if tstep >= TSTEP_MAX as f32 {
+        TSTEP_MAX
+    } else {
+        tstep as u32
+    }
+}
+
+/// TSTEP reads this at standstill, the register is 20 bit wide.
+pub const TSTEP_MAX: u32 = (1 << 20) - 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Velocity {
+    /// STEP input frequency.
+    StepHz(f32),
+    /// Motor revolutions per minute.
+    Rpm(f32),
+    /// Axis speed.
+    MmPerSec(f32),
+}
+
+/// Motor and axis mechanics, needed to convert a [`Velocity`] to steps.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mechanics {
+    /// Full steps per revolution, 200 for a 1.8° motor.
+    pub full_steps: u16,
+    /// Microsteps per full step on the STEP input.
+    pub microsteps: u16,
+    /// Axis travel per motor revolution, e.g. 40 mm for a GT2 belt on a 20 tooth pulley.
+    pub mm_per_rev: f32,
+}
+
+impl Mechanics {
+    pub fn steps_per_rev(&self) -> f32 {
+        self.full_steps as f32 * self.microsteps as f32
+    }
+
+    pub fn step_hz(&self, velocity: Velocity) -> f32 {
+        match velocity {
+            Velocity::StepHz(hz) => hz,
+            Velocity::Rpm(rpm) => rpm / 60.0 * self.steps_per_rev(),
+            Velocity::MmPerSec(mm_s) => mm_s / self.mm_per_rev * self.steps_per_rev(),
+        }
+    }
+
+    /// TSTEP at `velocity`, the value to compare with TPWMTHRS and TCOOLTHRS.
+    pub fn tstep(&self, velocity: Velocity) -> u32 {
+        tstep(self.step_hz(velocity), self.microsteps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MECH: Mechanics = Mechanics {
+        full_steps: 200,
+        microsteps: 16,
+        mm_per_rev: 40.,
+    };
+
+    #[test]
+    fn tstep_counts_clocks_per_256th_microstep() {
+        // TSTEP = fCLK / (fSTEP * 256 / microsteps)
+        assert_eq!(tstep(1000., 16), 750);
+        assert_eq!(tstep(12_000., 256), 1000);
+        // 1 rps of a 200 step motor at 16 microsteps, 234.4 truncated
+        assert_eq!(tstep(3200., 16), 234);
+        assert_eq!(tstep(46_875., 256), 256);
+    }
+
+    #[test]
+    fn tstep_saturates_at_standstill() {
+        assert_eq!(tstep(0., 16), TSTEP_MAX);
+        assert_eq!(tstep(-5., 16), TSTEP_MAX);
+        assert_eq!(tstep(0.1, 256), TSTEP_MAX);
+        assert_eq!(TSTEP_MAX, 0xF_FFFF);
+    }
+
+    #[test]
+    fn velocities_convert_to_step_rate() {
+        assert_eq!(MECH.steps_per_rev(), 3200.);
+        assert_eq!(MECH.step_hz(Velocity::StepHz(500.)), 500.);
+        assert_eq!(MECH.step_hz(Velocity::Rpm(60.)), 3200.);
+        assert_eq!(MECH.step_hz(Velocity::MmPerSec(40.)), 3200.);
+        assert_eq!(MECH.tstep(Velocity::Rpm(60.)), 234);
+        assert_eq!(MECH.tstep(Velocity::MmPerSec(20.)), 468);
+    }
+}